@@ -0,0 +1,14 @@
+-- Databases created before migrations existed already have this table.
+CREATE TABLE IF NOT EXISTS users (
+    id TEXT PRIMARY KEY,
+    email TEXT UNIQUE NOT NULL,
+    password_hash TEXT NOT NULL,
+    first_name TEXT,
+    last_name TEXT,
+    phone TEXT,
+    membership_id TEXT,
+    membership_level TEXT NOT NULL DEFAULT 'Bronze',
+    points INTEGER NOT NULL DEFAULT 0,
+    created_at DATETIME NOT NULL,
+    updated_at DATETIME NOT NULL
+);
@@ -0,0 +1,6 @@
+-- Access tokens issued to a user before `revoked_before` are refused, e.g.
+-- after a password reset. One row per user; later revocations move it forward.
+CREATE TABLE IF NOT EXISTS user_token_revocations (
+    user_id TEXT PRIMARY KEY,
+    revoked_before DATETIME NOT NULL
+);
@@ -0,0 +1,26 @@
+-- TOTP second factor, one per user. `confirmed_at` stays NULL until the user
+-- proves their authenticator works; only confirmed rows are enforced at login.
+-- `last_used_step` is the time step of the last accepted code, so a code
+-- cannot be replayed.
+CREATE TABLE IF NOT EXISTS user_totp (
+    user_id TEXT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    secret TEXT NOT NULL,
+    confirmed_at DATETIME,
+    last_used_step INTEGER,
+    created_at DATETIME NOT NULL
+);
+
+-- Single-use codes for signing in without the authenticator, stored as hashes.
+CREATE TABLE IF NOT EXISTS mfa_recovery_codes (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash TEXT NOT NULL,
+    created_at DATETIME NOT NULL,
+    used_at DATETIME
+);
+
+CREATE INDEX IF NOT EXISTS idx_mfa_recovery_codes_user_id ON mfa_recovery_codes (user_id);
+
+-- Wrong guesses made against a token that is checked together with a code,
+-- such as an MFA challenge.
+ALTER TABLE one_time_tokens ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
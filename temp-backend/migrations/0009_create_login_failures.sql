@@ -0,0 +1,10 @@
+-- Recent failed sign-in attempts, keyed by `account:<email>` or `ip:<address>`.
+-- Counting restarts once `first_failed_at` falls out of the window;
+-- `locked_until` is set on accounts that reached the limit.
+CREATE TABLE IF NOT EXISTS login_failures (
+    key TEXT PRIMARY KEY,
+    failures INTEGER NOT NULL,
+    first_failed_at DATETIME NOT NULL,
+    last_failed_at DATETIME NOT NULL,
+    locked_until DATETIME
+);
@@ -0,0 +1,28 @@
+-- One row per sign-in, for listing and signing out devices. Refresh tokens
+-- rotated from the sign-in use its id as their `family_id`, and access tokens
+-- carry it in their `sid` claim.
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    user_agent TEXT,
+    ip_address TEXT,
+    created_at DATETIME NOT NULL,
+    last_seen_at DATETIME NOT NULL,
+    expires_at DATETIME NOT NULL,
+    revoked_at DATETIME
+);
+
+CREATE INDEX IF NOT EXISTS idx_sessions_user_id ON sessions (user_id);
+
+-- Sign-ins from before sessions were recorded; a family with no usable
+-- refresh token left is already over
+INSERT OR IGNORE INTO sessions (id, user_id, created_at, last_seen_at, expires_at, revoked_at)
+SELECT
+    family_id,
+    user_id,
+    MIN(created_at),
+    MAX(created_at),
+    MAX(expires_at),
+    CASE WHEN SUM(used_at IS NULL AND revoked_at IS NULL) = 0 THEN MAX(COALESCE(revoked_at, used_at)) END
+FROM refresh_tokens
+GROUP BY family_id, user_id;
@@ -0,0 +1,25 @@
+-- Sign-ins started with an external OpenID Connect provider and not yet
+-- finished. Keyed by a hash of the `state` sent to the provider; each row is
+-- taken exactly once by the callback.
+CREATE TABLE IF NOT EXISTS oidc_login_attempts (
+    state_hash TEXT PRIMARY KEY,
+    provider TEXT NOT NULL,
+    nonce TEXT NOT NULL,
+    code_verifier TEXT NOT NULL,
+    created_at DATETIME NOT NULL,
+    expires_at DATETIME NOT NULL
+);
+
+-- Accounts at external providers linked to a local user, identified by the
+-- provider's stable `sub` claim rather than the email address.
+CREATE TABLE IF NOT EXISTS user_identities (
+    provider TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    email TEXT,
+    created_at DATETIME NOT NULL,
+    last_login_at DATETIME NOT NULL,
+    PRIMARY KEY (provider, subject)
+);
+
+CREATE INDEX IF NOT EXISTS idx_user_identities_user_id ON user_identities (user_id);
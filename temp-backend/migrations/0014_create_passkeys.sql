@@ -0,0 +1,28 @@
+-- WebAuthn credentials (passkeys) users sign in with. `credential_id` is the
+-- authenticator's id for the credential, base64url-encoded; `public_key` is
+-- its COSE key. `sign_count` is the last signature counter seen, to spot
+-- cloned authenticators.
+CREATE TABLE IF NOT EXISTS passkeys (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    credential_id TEXT UNIQUE NOT NULL,
+    public_key BLOB NOT NULL,
+    sign_count INTEGER NOT NULL DEFAULT 0,
+    name TEXT NOT NULL,
+    created_at DATETIME NOT NULL,
+    last_used_at DATETIME
+);
+
+CREATE INDEX IF NOT EXISTS idx_passkeys_user_id ON passkeys (user_id);
+
+-- Challenges handed to the browser for a registration or sign-in ceremony,
+-- keyed by their hash. Each row is taken exactly once when the browser's
+-- answer comes back. Sign-in challenges have no user: the passkey says who
+-- it belongs to.
+CREATE TABLE IF NOT EXISTS passkey_challenges (
+    challenge_hash TEXT PRIMARY KEY,
+    ceremony TEXT NOT NULL,
+    user_id TEXT REFERENCES users(id) ON DELETE CASCADE,
+    created_at DATETIME NOT NULL,
+    expires_at DATETIME NOT NULL
+);
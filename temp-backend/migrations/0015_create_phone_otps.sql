@@ -0,0 +1,21 @@
+ALTER TABLE users ADD COLUMN phone_verified_at DATETIME;
+
+-- A verified number signs in to one account only
+CREATE UNIQUE INDEX IF NOT EXISTS idx_users_verified_phone ON users (phone) WHERE phone_verified_at IS NOT NULL;
+
+-- Codes sent by text message. `phone` is the number the code was sent to, in
+-- E.164 form; only a hash of the code is kept. A code is used up when it
+-- checks out, when a newer one is sent, or after too many wrong guesses.
+CREATE TABLE IF NOT EXISTS phone_otps (
+    id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    purpose TEXT NOT NULL,
+    phone TEXT NOT NULL,
+    code_hash TEXT NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    created_at DATETIME NOT NULL,
+    expires_at DATETIME NOT NULL,
+    used_at DATETIME
+);
+
+CREATE INDEX IF NOT EXISTS idx_phone_otps_user_id ON phone_otps (user_id, purpose);
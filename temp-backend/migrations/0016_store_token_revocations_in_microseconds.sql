@@ -0,0 +1,14 @@
+-- Keep revocation times to the microsecond, matching the `iat_us` claim of
+-- access tokens, so a token issued in the same second as a revocation but
+-- after it keeps working. Existing revocations keep their whole seconds.
+CREATE TABLE user_token_revocations_new (
+    user_id TEXT PRIMARY KEY,
+    revoked_before_us INTEGER NOT NULL
+);
+
+INSERT INTO user_token_revocations_new (user_id, revoked_before_us)
+SELECT user_id, CAST(strftime('%s', revoked_before) AS INTEGER) * 1000000
+FROM user_token_revocations;
+
+DROP TABLE user_token_revocations;
+ALTER TABLE user_token_revocations_new RENAME TO user_token_revocations;
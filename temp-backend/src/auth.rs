@@ -0,0 +1,526 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::{header::AUTHORIZATION, request::Parts, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use anyhow::Context;
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{future::Future, pin::Pin};
+use utoipa::ToSchema;
+
+use crate::{
+    error::AppError,
+    models::{Claims, Role},
+    tokens::{hash_token, API_TOKEN_PREFIX, LAST_SEEN_RESOLUTION_MINUTES},
+    AppState,
+};
+
+/// A fine-grained capability checked by [`require_permission`]. Roles are
+/// bundles of permissions; see [`Role::permissions`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Permission {
+    /// Look up and list other users' accounts
+    #[serde(rename = "users:read")]
+    UsersRead,
+    /// Edit other users' membership data
+    #[serde(rename = "users:write")]
+    UsersWrite,
+    /// Disable, delete and force password resets on accounts
+    #[serde(rename = "users:manage")]
+    UsersManage,
+    /// Change a user's role
+    #[serde(rename = "roles:assign")]
+    RolesAssign,
+}
+
+impl Permission {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::UsersRead => "users:read",
+            Permission::UsersWrite => "users:write",
+            Permission::UsersManage => "users:manage",
+            Permission::RolesAssign => "roles:assign",
+        }
+    }
+}
+
+/// What an API token may be used for. The permission scopes only grant what
+/// the owner's role also allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+pub enum Scope {
+    /// Read the owner's profile
+    #[serde(rename = "profile:read")]
+    ProfileRead,
+    /// Update the owner's profile
+    #[serde(rename = "profile:write")]
+    ProfileWrite,
+    #[serde(rename = "users:read")]
+    UsersRead,
+    #[serde(rename = "users:write")]
+    UsersWrite,
+    #[serde(rename = "users:manage")]
+    UsersManage,
+    #[serde(rename = "roles:assign")]
+    RolesAssign,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::ProfileRead => "profile:read",
+            Scope::ProfileWrite => "profile:write",
+            Scope::UsersRead => "users:read",
+            Scope::UsersWrite => "users:write",
+            Scope::UsersManage => "users:manage",
+            Scope::RolesAssign => "roles:assign",
+        }
+    }
+
+    /// The role permission needed to hold this scope, if any.
+    pub fn permission(&self) -> Option<Permission> {
+        match self {
+            Scope::ProfileRead | Scope::ProfileWrite => None,
+            Scope::UsersRead => Some(Permission::UsersRead),
+            Scope::UsersWrite => Some(Permission::UsersWrite),
+            Scope::UsersManage => Some(Permission::UsersManage),
+            Scope::RolesAssign => Some(Permission::RolesAssign),
+        }
+    }
+}
+
+impl From<Permission> for Scope {
+    fn from(permission: Permission) -> Self {
+        match permission {
+            Permission::UsersRead => Scope::UsersRead,
+            Permission::UsersWrite => Scope::UsersWrite,
+            Permission::UsersManage => Scope::UsersManage,
+            Permission::RolesAssign => Scope::RolesAssign,
+        }
+    }
+}
+
+impl std::str::FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "profile:read" => Ok(Scope::ProfileRead),
+            "profile:write" => Ok(Scope::ProfileWrite),
+            "users:read" => Ok(Scope::UsersRead),
+            "users:write" => Ok(Scope::UsersWrite),
+            "users:manage" => Ok(Scope::UsersManage),
+            "roles:assign" => Ok(Scope::RolesAssign),
+            other => Err(format!("unknown scope '{}'", other)),
+        }
+    }
+}
+
+impl Role {
+    pub fn permissions(&self) -> &'static [Permission] {
+        use Permission::*;
+
+        match self {
+            Role::Member => &[],
+            Role::Staff => &[UsersRead, UsersWrite],
+            Role::Admin => &[UsersRead, UsersWrite, UsersManage, RolesAssign],
+        }
+    }
+}
+
+/// The authenticated caller, extracted from an `Authorization: Bearer <token>` header.
+///
+/// Add it as a handler argument to require a valid, unrevoked access token or
+/// API token. Requests without one are rejected with `401` and a
+/// `WWW-Authenticate` header.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub user_id: String,
+    pub email: String,
+    pub roles: Vec<Role>,
+    /// For an API token, its id is the `jti` and its expiry the `exp`
+    pub claims: Claims,
+    /// The scopes of the API token or OAuth client token used, or `None` for
+    /// a session's access token, which may do anything the user's roles allow
+    pub scopes: Option<Vec<Scope>>,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        // Already authenticated by a `require_permission` layer
+        if let Some(user) = parts.extensions.get::<AuthUser>() {
+            return Ok(user.clone());
+        }
+
+        let token = bearer_token(&parts.headers).ok_or_else(|| {
+            AppError::unauthorized("missing_token", "Authorization bearer token is required")
+        })?;
+
+        authenticate_token(state, token).await
+    }
+}
+
+/// Accept a valid, unrevoked access token or API token, as the
+/// [`AuthUser`] extractor does for a bearer token.
+pub async fn authenticate_token(state: &AppState, token: &str) -> Result<AuthUser, AppError> {
+    if token.starts_with(API_TOKEN_PREFIX) {
+        return authenticate_api_token(state, token).await;
+    }
+
+    let claims = state
+        .jwt_service
+        .verify_token(token)
+        .await
+        .map_err(|_| AppError::invalid_token())?;
+
+    // A token issued to an OAuth client is limited to its scopes, like an API token
+    let scopes = claims
+        .scope
+        .as_ref()
+        .map(|scope| scope.split_whitespace().filter_map(|scope| scope.parse().ok()).collect());
+
+    Ok(AuthUser {
+        user_id: claims.sub.clone(),
+        email: claims.email.clone(),
+        roles: claims.roles.clone(),
+        claims,
+        scopes,
+    })
+}
+
+/// Accept an unrevoked, unexpired API token of an account that may sign in.
+/// The owner's current role and email apply, not those when it was created.
+async fn authenticate_api_token(state: &AppState, token: &str) -> Result<AuthUser, AppError> {
+    let api_token = state
+        .api_token_repo
+        .find_active_by_hash(&hash_token(token))
+        .await
+        .context("Failed to find API token")?
+        .ok_or_else(AppError::invalid_token)?;
+    let user = state
+        .user_repo
+        .find_by_id(&api_token.user_id)
+        .await
+        .context("Failed to find user")?
+        .filter(|user| user.disabled_at.is_none() && !user.password_reset_required)
+        .ok_or_else(AppError::invalid_token)?;
+
+    let stale_before = Utc::now() - Duration::minutes(LAST_SEEN_RESOLUTION_MINUTES);
+    if api_token.last_used_at.is_none_or(|last_used_at| last_used_at < stale_before) {
+        state
+            .api_token_repo
+            .mark_used(&api_token.id, stale_before)
+            .await
+            .context("Failed to record API token use")?;
+    }
+
+    Ok(AuthUser {
+        user_id: user.id.clone(),
+        email: user.email.clone(),
+        roles: vec![user.role],
+        claims: Claims {
+            sub: user.id,
+            email: user.email,
+            exp: api_token.expires_at.timestamp() as usize,
+            iat: api_token.created_at.timestamp() as usize,
+            iat_us: Some(api_token.created_at.timestamp_micros()),
+            jti: api_token.id.clone(),
+            roles: vec![user.role],
+            email_verified: user.email_verified_at.is_some(),
+            sid: None,
+            client_id: None,
+            scope: None,
+        },
+        scopes: Some(api_token.scopes()),
+    })
+}
+
+impl AuthUser {
+    /// Whether the user's roles grant `permission` and, for an API token,
+    /// its scopes include it.
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.roles.iter().any(|role| role.permissions().contains(&permission))
+            && self.has_scope(permission.into())
+    }
+
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.as_ref().is_none_or(|scopes| scopes.contains(&scope))
+    }
+}
+
+type GuardFuture = Pin<Box<dyn Future<Output = Result<Response, AppError>> + Send>>;
+
+/// Route-layer guard that rejects callers without `permission` with `403`
+/// (or `401` if unauthenticated):
+///
+/// ```ignore
+/// router.route_layer(middleware::from_fn_with_state(state, require_permission(Permission::UsersRead)))
+/// ```
+pub fn require_permission(
+    permission: Permission,
+) -> impl Fn(AuthUser, Request, Next) -> GuardFuture + Clone + Send + Sync + 'static {
+    move |user: AuthUser, mut request: Request, next: Next| -> GuardFuture {
+        Box::pin(async move {
+            if !user.has_permission(permission) {
+                return Err(AppError::forbidden(
+                    "insufficient_permissions",
+                    format!("This action requires the '{}' permission", permission.as_str()),
+                ));
+            }
+
+            // Let the handler's `AuthUser` reuse this verification
+            request.extensions_mut().insert(user);
+            Ok(next.run(request).await)
+        })
+    }
+}
+
+/// Route-layer guard that only lets an API token through if it has `scope`.
+/// Session access tokens always pass.
+pub fn require_scope(
+    scope: Scope,
+) -> impl Fn(AuthUser, Request, Next) -> GuardFuture + Clone + Send + Sync + 'static {
+    move |user: AuthUser, mut request: Request, next: Next| -> GuardFuture {
+        Box::pin(async move {
+            if !user.has_scope(scope) {
+                return Err(AppError::forbidden(
+                    "insufficient_scope",
+                    format!("This API token lacks the '{}' scope", scope.as_str()),
+                ));
+            }
+
+            request.extensions_mut().insert(user);
+            Ok(next.run(request).await)
+        })
+    }
+}
+
+/// Route-layer guard for account security (passwords, two-factor
+/// authentication, sessions, API tokens), which API tokens may never reach.
+pub async fn require_session(user: AuthUser, mut request: Request, next: Next) -> Result<Response, AppError> {
+    if user.scopes.is_some() {
+        return Err(AppError::forbidden(
+            "session_required",
+            "This action requires signing in; API tokens cannot be used",
+        ));
+    }
+
+    request.extensions_mut().insert(user);
+    Ok(next.run(request).await)
+}
+
+/// The token from an `Authorization: Bearer` header, if there is one.
+pub(crate) fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    let token = token.trim();
+
+    (scheme.eq_ignore_ascii_case("bearer") && !token.is_empty()).then_some(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::create_test_app_state;
+    use axum::{
+        body::Body,
+        http::{header::WWW_AUTHENTICATE, StatusCode},
+        middleware,
+        response::IntoResponse,
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+
+    async fn extract(state: &AppState, authorization: Option<&str>) -> Result<AuthUser, AppError> {
+        let mut builder = Request::builder().uri("/profile");
+        if let Some(authorization) = authorization {
+            builder = builder.header(AUTHORIZATION, authorization);
+        }
+        let (mut parts, _) = builder.body(()).unwrap().into_parts();
+
+        AuthUser::from_request_parts(&mut parts, state).await
+    }
+
+    #[tokio::test]
+    async fn test_valid_token() {
+        let state = create_test_app_state().await.unwrap();
+        let token = state.jwt_service.create_token("user-123", "user@example.com").unwrap();
+
+        let user = extract(&state, Some(&format!("Bearer {}", token))).await.unwrap();
+
+        assert_eq!(user.user_id, "user-123");
+        assert_eq!(user.email, "user@example.com");
+        assert!(user.roles.is_empty());
+        assert!(!user.claims.jti.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_scheme_is_case_insensitive() {
+        let state = create_test_app_state().await.unwrap();
+        let token = state.jwt_service.create_token("user-123", "user@example.com").unwrap();
+
+        assert!(extract(&state, Some(&format!("bearer {}", token))).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_missing_token() {
+        let state = create_test_app_state().await.unwrap();
+
+        for authorization in [None, Some("Basic dXNlcjpwYXNz"), Some("Bearer ")] {
+            let error = extract(&state, authorization).await.unwrap_err();
+            assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(error.code(), "missing_token");
+
+            let response = error.into_response();
+            assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_token() {
+        let state = create_test_app_state().await.unwrap();
+
+        let error = extract(&state, Some("Bearer invalid.token.here")).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.code(), "invalid_token");
+
+        let response = error.into_response();
+        assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer error=\"invalid_token\"");
+    }
+
+    #[tokio::test]
+    async fn test_revoked_token() {
+        let state = create_test_app_state().await.unwrap();
+        let token = state.jwt_service.create_token("user-123", "user@example.com").unwrap();
+        let claims = state.jwt_service.verify_token(&token).await.unwrap();
+        state.jwt_service.revoke_token(&claims).await.unwrap();
+
+        let error = extract(&state, Some(&format!("Bearer {}", token))).await.unwrap_err();
+        assert_eq!(error.code(), "invalid_token");
+    }
+
+    #[tokio::test]
+    async fn test_api_token() {
+        let state = create_test_app_state().await.unwrap();
+        let user = state.user_repo.create_user("user@example.com", "hash").await.unwrap();
+        state.user_repo.set_role(&user.id, Role::Staff).await.unwrap();
+        let expires_at = Utc::now() + Duration::days(1);
+        let api_token = state
+            .api_token_repo
+            .create(&user.id, "CI", &hash_token("pat_secret"), &[Scope::ProfileRead, Scope::UsersRead], expires_at)
+            .await
+            .unwrap();
+
+        let auth = extract(&state, Some("Bearer pat_secret")).await.unwrap();
+        assert_eq!(auth.user_id, user.id);
+        assert_eq!(auth.roles, [Role::Staff]);
+        assert_eq!(auth.claims.jti, api_token.id);
+        assert!(auth.has_scope(Scope::ProfileRead));
+        assert!(!auth.has_scope(Scope::ProfileWrite));
+        // The role grants users:write, but the token was not given it
+        assert!(auth.has_permission(Permission::UsersRead));
+        assert!(!auth.has_permission(Permission::UsersWrite));
+        assert!(state.api_token_repo.list_active(&user.id).await.unwrap()[0].last_used_at.is_some());
+
+        let error = extract(&state, Some("Bearer pat_unknown")).await.unwrap_err();
+        assert_eq!(error.code(), "invalid_token");
+
+        state.user_repo.set_disabled(&user.id, true).await.unwrap();
+        assert!(extract(&state, Some("Bearer pat_secret")).await.is_err());
+        state.user_repo.set_disabled(&user.id, false).await.unwrap();
+        state.api_token_repo.revoke(&user.id, &api_token.id).await.unwrap();
+        assert!(extract(&state, Some("Bearer pat_secret")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_api_tokens_are_limited_to_their_scopes() {
+        async fn handler(user: AuthUser) -> String {
+            user.user_id
+        }
+
+        let state = create_test_app_state().await.unwrap();
+        let user = state.user_repo.create_user("user@example.com", "hash").await.unwrap();
+        let expires_at = Utc::now() + Duration::days(1);
+        state
+            .api_token_repo
+            .create(&user.id, "CI", &hash_token("pat_secret"), &[Scope::ProfileRead], expires_at)
+            .await
+            .unwrap();
+        let session_token = state.jwt_service.create_token(&user.id, "user@example.com").unwrap();
+
+        let app = Router::new()
+            .route("/read", get(handler).route_layer(middleware::from_fn_with_state(state.clone(), require_scope(Scope::ProfileRead))))
+            .route("/write", get(handler).route_layer(middleware::from_fn_with_state(state.clone(), require_scope(Scope::ProfileWrite))))
+            .route("/security", get(handler).route_layer(middleware::from_fn_with_state(state.clone(), require_session)))
+            .with_state(state.clone());
+        let status = |path: &'static str, token: String| {
+            let app = app.clone();
+            async move {
+                let request = Request::builder()
+                    .uri(path)
+                    .header(AUTHORIZATION, format!("Bearer {}", token))
+                    .body(Body::empty())
+                    .unwrap();
+                app.oneshot(request).await.unwrap().status()
+            }
+        };
+
+        assert_eq!(status("/read", "pat_secret".to_string()).await, StatusCode::OK);
+        assert_eq!(status("/write", "pat_secret".to_string()).await, StatusCode::FORBIDDEN);
+        assert_eq!(status("/security", "pat_secret".to_string()).await, StatusCode::FORBIDDEN);
+        for path in ["/read", "/write", "/security"] {
+            assert_eq!(status(path, session_token.clone()).await, StatusCode::OK);
+        }
+    }
+
+    #[test]
+    fn test_role_permissions() {
+        assert!(Role::Member.permissions().is_empty());
+        assert!(Role::Staff.permissions().contains(&Permission::UsersRead));
+        assert!(!Role::Staff.permissions().contains(&Permission::UsersManage));
+        assert!(Role::Admin.permissions().contains(&Permission::RolesAssign));
+    }
+
+    async fn guarded_status(state: &AppState, roles: Option<&[Role]>) -> StatusCode {
+        async fn handler(user: AuthUser) -> String {
+            user.user_id
+        }
+
+        let app = Router::new()
+            .route("/guarded", get(handler))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                require_permission(Permission::UsersManage),
+            ))
+            .with_state(state.clone());
+
+        let mut request = Request::builder().uri("/guarded");
+        if let Some(roles) = roles {
+            let token = state
+                .jwt_service
+                .create_token_with_roles("user-123", "user@example.com", roles)
+                .unwrap();
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        let response = app.oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+        response.status()
+    }
+
+    #[tokio::test]
+    async fn test_require_permission() {
+        let state = create_test_app_state().await.unwrap();
+
+        assert_eq!(guarded_status(&state, None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(guarded_status(&state, Some(&[])).await, StatusCode::FORBIDDEN);
+        assert_eq!(guarded_status(&state, Some(&[Role::Member])).await, StatusCode::FORBIDDEN);
+        assert_eq!(guarded_status(&state, Some(&[Role::Staff])).await, StatusCode::FORBIDDEN);
+        assert_eq!(guarded_status(&state, Some(&[Role::Admin])).await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::anyhow;
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{header::USER_AGENT, request::Parts, HeaderMap},
+};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::{error::AppError, AppState};
+
+/// The address of the client that sent the request.
+///
+/// Taken from the connection, or from the last `X-Forwarded-For` entry when
+/// `server.trust_proxy_headers` is set. The server must be run with
+/// `into_make_service_with_connect_info::<SocketAddr>()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        if state.trust_proxy_headers {
+            if let Some(ip) = forwarded_for(&parts.headers) {
+                return Ok(Self(ip));
+            }
+        }
+
+        parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(address)| Self(address.ip()))
+            .ok_or_else(|| AppError::Internal(anyhow!("Client address unavailable; serve the app with connect info")))
+    }
+}
+
+/// Longest `User-Agent` kept on a session.
+const MAX_USER_AGENT_LEN: usize = 512;
+
+/// The client address plus its `User-Agent`, as recorded on new sessions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub ip: IpAddr,
+    pub user_agent: Option<String>,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientInfo {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let ClientIp(ip) = ClientIp::from_request_parts(parts, state).await?;
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().chars().take(MAX_USER_AGENT_LEN).collect::<String>())
+            .filter(|value| !value.is_empty());
+
+        Ok(Self { ip, user_agent })
+    }
+}
+
+/// The last `X-Forwarded-For` entry. The proxy appends the address it saw, so
+/// earlier entries come from the client and cannot be trusted.
+fn forwarded_for(headers: &HeaderMap) -> Option<IpAddr> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::create_test_app_state;
+    use axum::http::Request;
+
+    async fn extract(state: &AppState, forwarded: Option<&str>) -> Result<ClientIp, AppError> {
+        let mut builder = Request::builder();
+        if let Some(forwarded) = forwarded {
+            builder = builder.header("x-forwarded-for", forwarded);
+        }
+        let (mut parts, _) = builder.body(()).unwrap().into_parts();
+        parts
+            .extensions
+            .insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))));
+
+        ClientIp::from_request_parts(&mut parts, state).await
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_for_is_ignored_unless_trusted() {
+        let mut state = create_test_app_state().await.unwrap();
+
+        let ip = extract(&state, Some("203.0.113.9")).await.unwrap();
+        assert_eq!(ip.0.to_string(), "10.0.0.1");
+
+        state.trust_proxy_headers = true;
+        let ip = extract(&state, Some("198.51.100.1, 203.0.113.9")).await.unwrap();
+        assert_eq!(ip.0.to_string(), "203.0.113.9");
+
+        // Falls back to the connection when the header is missing or malformed
+        let ip = extract(&state, Some("not-an-ip")).await.unwrap();
+        assert_eq!(ip.0.to_string(), "10.0.0.1");
+    }
+}
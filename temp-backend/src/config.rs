@@ -0,0 +1,1250 @@
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::{net::SocketAddr, path::PathBuf};
+use thiserror::Error;
+
+/// Secret used when no JWT secret or signing key is configured. Only allowed in development.
+pub const DEV_JWT_SECRET: &str = "your-secret-key-change-this-in-production";
+
+/// Config file read when `APP_CONFIG` is not set. It is optional.
+pub const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read config file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("failed to parse config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[error("invalid value for environment variable {var}: {message}")]
+    Env { var: String, message: String },
+    #[error("invalid configuration:\n  - {}", .0.join("\n  - "))]
+    Invalid(Vec<String>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Environment {
+    #[default]
+    Development,
+    Staging,
+    Production,
+}
+
+impl std::str::FromStr for Environment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "development" | "dev" => Ok(Self::Development),
+            "staging" => Ok(Self::Staging),
+            "production" | "prod" => Ok(Self::Production),
+            other => Err(format!("unknown environment '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    pub environment: Environment,
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub jwt: JwtConfig,
+    pub cors: CorsConfig,
+    pub mail: MailConfig,
+    pub sms: SmsConfig,
+    pub login_protection: LoginProtectionConfig,
+    pub rate_limit: RateLimitConfig,
+    pub password_hashing: PasswordHashingConfig,
+    pub password_policy: PasswordPolicyConfig,
+    pub oidc: OidcConfig,
+    pub oauth: OAuthConfig,
+    pub webauthn: WebAuthnConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: String,
+    /// Take the client address from the last `X-Forwarded-For` entry. Only
+    /// enable behind a reverse proxy that sets it; otherwise clients can spoof it.
+    pub trust_proxy_headers: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: "127.0.0.1:3000".to_string(),
+            trust_proxy_headers: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub max_connections: u32,
+    /// Apply pending migrations at startup. When false, startup fails if the
+    /// schema is behind and migrations must be run with `--migrate-only`.
+    pub auto_migrate: bool,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: "sqlite:./app.db".to_string(),
+            max_connections: 5,
+            auto_migrate: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    /// HS256 shared secret, used when no signing key file is configured
+    pub secret: Option<String>,
+    /// PEM private key (RSA or Ed25519) used to sign new tokens
+    pub signing_key_file: Option<PathBuf>,
+    pub signing_key_id: Option<String>,
+    /// Keys that only verify, kept during rotation
+    pub previous_keys: Vec<PreviousKeyConfig>,
+    pub access_token_ttl_hours: i64,
+    pub denylist_prune_interval_secs: u64,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        Self {
+            secret: None,
+            signing_key_file: None,
+            signing_key_id: None,
+            previous_keys: vec![],
+            access_token_ttl_hours: crate::jwt::ACCESS_TOKEN_TTL_HOURS,
+            denylist_prune_interval_secs: 60 * 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PreviousKeyConfig {
+    pub kid: String,
+    pub path: PathBuf,
+    /// When the key stopped signing; it verifies for one access-token lifetime after
+    pub retired_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Allowed origins, or `["*"]` for any origin
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec!["*".to_string()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    Smtp,
+    /// Write each message to `mail.file_dir` instead of sending it
+    #[default]
+    File,
+}
+
+impl std::str::FromStr for MailTransport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "smtp" => Ok(Self::Smtp),
+            "file" => Ok(Self::File),
+            other => Err(format!("unknown mail transport '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Upgrade a plain connection with STARTTLS (usually port 587)
+    #[default]
+    Starttls,
+    /// Connect over TLS from the start (usually port 465)
+    Tls,
+    /// No encryption; only for a local relay or test server
+    None,
+}
+
+impl std::str::FromStr for SmtpTls {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "starttls" => Ok(Self::Starttls),
+            "tls" => Ok(Self::Tls),
+            "none" => Ok(Self::None),
+            other => Err(format!("unknown SMTP TLS mode '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MailConfig {
+    pub transport: MailTransport,
+    /// Sender address, e.g. `Example <no-reply@example.com>`
+    pub from: String,
+    /// Base URL of the web app; links in emails point here
+    pub app_url: String,
+    pub smtp_host: Option<String>,
+    pub smtp_port: u16,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    pub smtp_tls: SmtpTls,
+    pub file_dir: PathBuf,
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        Self {
+            transport: MailTransport::File,
+            from: "no-reply@localhost".to_string(),
+            app_url: "http://localhost:3000".to_string(),
+            smtp_host: None,
+            smtp_port: 587,
+            smtp_username: None,
+            smtp_password: None,
+            smtp_tls: SmtpTls::Starttls,
+            file_dir: PathBuf::from("mail"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmsTransport {
+    /// POST each message as JSON to `sms.http_url`, a gateway or a relay to one
+    Http,
+    /// Write each message to the server log instead of sending it
+    #[default]
+    Log,
+    /// Write each message to `sms.file_dir` instead of sending it
+    File,
+}
+
+impl std::str::FromStr for SmsTransport {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "http" => Ok(Self::Http),
+            "log" => Ok(Self::Log),
+            "file" => Ok(Self::File),
+            other => Err(format!("unknown SMS transport '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmsConfig {
+    pub transport: SmsTransport,
+    /// Sender name or number, if the gateway lets you choose one
+    pub sender: Option<String>,
+    /// Country calling code for numbers entered in national format, e.g.
+    /// `66` turns `081 234 5678` into `+66812345678`
+    pub default_country_code: String,
+    pub http_url: Option<String>,
+    /// Sent as `Authorization: Bearer <token>`
+    pub http_token: Option<String>,
+    pub file_dir: PathBuf,
+}
+
+impl Default for SmsConfig {
+    fn default() -> Self {
+        Self {
+            transport: SmsTransport::Log,
+            sender: None,
+            default_country_code: "66".to_string(),
+            http_url: None,
+            http_token: None,
+            file_dir: PathBuf::from("sms"),
+        }
+    }
+}
+
+/// Limits on failed sign-in attempts, enforced by [`crate::login_guard::LoginGuard`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginProtectionConfig {
+    /// Failures are counted over this window, starting at the first one
+    pub failure_window_minutes: i64,
+    /// Failures on one account within the window before it is locked
+    pub max_account_failures: u32,
+    /// How long a locked account stays locked, unless unlocked from the emailed link
+    pub lockout_minutes: i64,
+    /// Failures from one IP address within the window before it is blocked
+    pub max_ip_failures: u32,
+    /// Failures on an account after which each attempt must wait, starting at
+    /// one second and doubling up to `max_delay_secs`
+    pub delay_after_failures: u32,
+    pub max_delay_secs: i64,
+}
+
+impl Default for LoginProtectionConfig {
+    fn default() -> Self {
+        Self {
+            failure_window_minutes: 15,
+            max_account_failures: 5,
+            lockout_minutes: 15,
+            max_ip_failures: 20,
+            delay_after_failures: 3,
+            max_delay_secs: 30,
+        }
+    }
+}
+
+/// A token bucket: up to `burst` requests at once, refilled at `per_minute`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitRule {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+/// Request limits per route group, counted per authenticated user or, for
+/// anonymous requests, per client IP address.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// `/auth/*`
+    pub auth: RateLimitRule,
+    /// `/profile/*`
+    pub profile: RateLimitRule,
+    /// `/admin/*`
+    pub admin: RateLimitRule,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            auth: RateLimitRule { burst: 20, per_minute: 20 },
+            profile: RateLimitRule { burst: 120, per_minute: 120 },
+            admin: RateLimitRule { burst: 300, per_minute: 300 },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PasswordAlgorithm {
+    #[default]
+    Argon2id,
+    Bcrypt,
+}
+
+impl std::str::FromStr for PasswordAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "argon2id" => Ok(Self::Argon2id),
+            "bcrypt" => Ok(Self::Bcrypt),
+            other => Err(format!("unknown password hashing algorithm '{}'", other)),
+        }
+    }
+}
+
+/// How new passwords are hashed. Stored hashes that do not match are upgraded
+/// the next time their owner signs in.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordHashingConfig {
+    pub algorithm: PasswordAlgorithm,
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+    pub bcrypt_cost: u32,
+}
+
+impl Default for PasswordHashingConfig {
+    fn default() -> Self {
+        // OWASP's minimum recommendation for argon2id
+        Self {
+            algorithm: PasswordAlgorithm::Argon2id,
+            argon2_memory_kib: 19 * 1024,
+            argon2_iterations: 2,
+            argon2_parallelism: 1,
+            bcrypt_cost: 12,
+        }
+    }
+}
+
+/// Rules for new passwords, enforced by [`crate::password_policy::PasswordPolicy`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PasswordPolicyConfig {
+    /// In characters
+    pub min_length: usize,
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    /// Minimum estimated strength, from 0 (anything) to 4 (strong)
+    pub min_strength: u8,
+    /// Reject passwords containing the local part of the account's email
+    pub reject_email: bool,
+    /// SHA-1 hashes of breached passwords: a file of `HASH[:COUNT]` lines, or a
+    /// directory of k-anonymity range files as published by Have I Been Pwned
+    /// (`<first 5 hex digits>.txt`, each line `SUFFIX:COUNT`).
+    pub breached_passwords_path: Option<PathBuf>,
+}
+
+impl Default for PasswordPolicyConfig {
+    fn default() -> Self {
+        Self {
+            min_length: 8,
+            max_length: 128,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            min_strength: 2,
+            reject_email: true,
+            breached_passwords_path: None,
+        }
+    }
+}
+
+/// External OpenID Connect providers users can sign in with.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    pub providers: Vec<OidcProviderConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OidcProviderConfig {
+    /// Used in URLs, e.g. `google` for `/auth/oidc/google/authorize`
+    pub name: String,
+    /// Issuer URL; `/.well-known/openid-configuration` is fetched from here
+    pub issuer: String,
+    pub client_id: String,
+    /// Also settable with `OIDC_<NAME>_CLIENT_SECRET`. Leave unset for a
+    /// public client that relies on PKCE alone.
+    pub client_secret: Option<String>,
+    /// Where the provider sends the user back with a code; must be registered with it
+    pub redirect_uri: String,
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    /// Sign a provider account that is new here into the existing account
+    /// with the same verified email address. Whoever controls that address at
+    /// the provider gets into the account, so only turn this on for providers
+    /// that own their users' addresses, like a company's single sign-on.
+    #[serde(default)]
+    pub link_existing_accounts: bool,
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    ["openid", "email", "profile"].map(String::from).to_vec()
+}
+
+/// This server acting as an OAuth 2.0 / OpenID Connect provider for other apps.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OAuthConfig {
+    /// Public base URL of this API: the `iss` of ID tokens, and where
+    /// discovery is served
+    pub issuer: String,
+    /// The web app's consent page, which clients send users to. It calls
+    /// `/oauth/authorize` with the user's session. Required with clients.
+    pub authorization_endpoint: Option<String>,
+    pub clients: Vec<OAuthClientConfig>,
+}
+
+impl Default for OAuthConfig {
+    fn default() -> Self {
+        Self {
+            issuer: "http://localhost:3000".to_string(),
+            authorization_endpoint: None,
+            clients: vec![],
+        }
+    }
+}
+
+/// An app allowed to sign users in through this server.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OAuthClientConfig {
+    pub client_id: String,
+    /// Shown to users when they are asked to consent
+    pub name: String,
+    /// Also settable with `OAUTH_<CLIENT_ID>_CLIENT_SECRET`. Leave unset for
+    /// a public client such as a single-page or mobile app, which relies on
+    /// PKCE alone.
+    pub client_secret: Option<String>,
+    /// Exact URLs the client may receive codes at
+    pub redirect_uris: Vec<String>,
+    /// Scopes the client may ask for: `openid`, `email`, `profile` and API token scopes
+    pub scopes: Vec<String>,
+    /// First-party apps skip the consent step
+    #[serde(default)]
+    pub trusted: bool,
+}
+
+/// Passkey (WebAuthn) sign-in.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebAuthnConfig {
+    /// Relying party id: the web app's domain, e.g. `example.com`. Passkeys
+    /// only work on this domain and its subdomains, and cannot be moved to another.
+    pub rp_id: String,
+    /// Shown by the browser and authenticator when a passkey is created
+    pub rp_name: String,
+    /// Exact origins of the web app pages that create and use passkeys
+    pub origins: Vec<String>,
+}
+
+impl Default for WebAuthnConfig {
+    fn default() -> Self {
+        Self {
+            rp_id: "localhost".to_string(),
+            rp_name: "User Management API".to_string(),
+            origins: vec!["http://localhost:3000".to_string()],
+        }
+    }
+}
+
+/// Environment variable name for a per-client or per-provider setting.
+fn env_name(prefix: &str, name: &str, suffix: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("{}_{}_{}", prefix, name, suffix)
+}
+
+/// Plain `http` is only allowed for providers on this machine, such as a test IdP.
+fn is_local_http(url: &str) -> bool {
+    ["http://localhost", "http://127.0.0.1", "http://[::1]"].iter().any(|prefix| {
+        url.strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with(':') || rest.starts_with('/'))
+    })
+}
+
+impl AppConfig {
+    /// Load configuration from `APP_CONFIG` (or `config.toml` if present), apply
+    /// environment-variable overrides and validate the result.
+    pub fn load() -> Result<Self, ConfigError> {
+        let path = std::env::var("APP_CONFIG").ok().map(PathBuf::from);
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None if std::path::Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Self::from_file(DEFAULT_CONFIG_FILE.as_ref())?
+            }
+            None => Self::default(),
+        };
+
+        config.apply_env_overrides(|var| std::env::var(var).ok())?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &std::path::Path) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Override file values with environment variables. `env` looks a variable
+    /// up by name, which keeps this testable without touching the process env.
+    pub fn apply_env_overrides(
+        &mut self,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<(), ConfigError> {
+        if let Some(value) = env("APP_ENV") {
+            self.environment = value.parse().map_err(|message| ConfigError::Env {
+                var: "APP_ENV".to_string(),
+                message,
+            })?;
+        }
+        if let Some(value) = env("BIND_ADDRESS") {
+            self.server.bind_address = value;
+        }
+        if let Some(value) = env("TRUST_PROXY_HEADERS") {
+            self.server.trust_proxy_headers = parse_env("TRUST_PROXY_HEADERS", &value)?;
+        }
+        if let Some(value) = env("DATABASE_URL") {
+            self.database.url = value;
+        }
+        if let Some(value) = env("DATABASE_MAX_CONNECTIONS") {
+            self.database.max_connections = parse_env("DATABASE_MAX_CONNECTIONS", &value)?;
+        }
+        if let Some(value) = env("DATABASE_AUTO_MIGRATE") {
+            self.database.auto_migrate = parse_env("DATABASE_AUTO_MIGRATE", &value)?;
+        }
+        if let Some(value) = env("JWT_SECRET") {
+            self.jwt.secret = Some(value);
+        }
+        if let Some(value) = env("JWT_SIGNING_KEY_FILE") {
+            self.jwt.signing_key_file = Some(PathBuf::from(value));
+        }
+        if let Some(value) = env("JWT_SIGNING_KEY_ID") {
+            self.jwt.signing_key_id = Some(value);
+        }
+        if let Some(value) = env("JWT_PREVIOUS_KEYS") {
+            self.jwt.previous_keys = parse_previous_keys(&value)?;
+        }
+        if let Some(value) = env("JWT_ACCESS_TOKEN_TTL_HOURS") {
+            self.jwt.access_token_ttl_hours = parse_env("JWT_ACCESS_TOKEN_TTL_HOURS", &value)?;
+        }
+        if let Some(value) = env("CORS_ALLOWED_ORIGINS") {
+            self.cors.allowed_origins = value
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+        if let Some(value) = env("MAIL_TRANSPORT") {
+            self.mail.transport = parse_env("MAIL_TRANSPORT", &value)?;
+        }
+        if let Some(value) = env("MAIL_FROM") {
+            self.mail.from = value;
+        }
+        if let Some(value) = env("APP_URL") {
+            self.mail.app_url = value;
+        }
+        if let Some(value) = env("SMTP_HOST") {
+            self.mail.smtp_host = Some(value);
+        }
+        if let Some(value) = env("SMTP_PORT") {
+            self.mail.smtp_port = parse_env("SMTP_PORT", &value)?;
+        }
+        if let Some(value) = env("SMTP_USERNAME") {
+            self.mail.smtp_username = Some(value);
+        }
+        if let Some(value) = env("SMTP_PASSWORD") {
+            self.mail.smtp_password = Some(value);
+        }
+        if let Some(value) = env("SMTP_TLS") {
+            self.mail.smtp_tls = parse_env("SMTP_TLS", &value)?;
+        }
+        if let Some(value) = env("MAIL_FILE_DIR") {
+            self.mail.file_dir = PathBuf::from(value);
+        }
+        if let Some(value) = env("SMS_TRANSPORT") {
+            self.sms.transport = parse_env("SMS_TRANSPORT", &value)?;
+        }
+        if let Some(value) = env("SMS_SENDER") {
+            self.sms.sender = Some(value);
+        }
+        if let Some(value) = env("SMS_DEFAULT_COUNTRY_CODE") {
+            self.sms.default_country_code = value;
+        }
+        if let Some(value) = env("SMS_HTTP_URL") {
+            self.sms.http_url = Some(value);
+        }
+        if let Some(value) = env("SMS_HTTP_TOKEN") {
+            self.sms.http_token = Some(value);
+        }
+        if let Some(value) = env("SMS_FILE_DIR") {
+            self.sms.file_dir = PathBuf::from(value);
+        }
+        if let Some(value) = env("RATE_LIMIT_ENABLED") {
+            self.rate_limit.enabled = parse_env("RATE_LIMIT_ENABLED", &value)?;
+        }
+        if let Some(value) = env("PASSWORD_HASH_ALGORITHM") {
+            self.password_hashing.algorithm = parse_env("PASSWORD_HASH_ALGORITHM", &value)?;
+        }
+        if let Some(value) = env("BREACHED_PASSWORDS_PATH") {
+            self.password_policy.breached_passwords_path = Some(PathBuf::from(value));
+        }
+        for provider in &mut self.oidc.providers {
+            if let Some(value) = env(&env_name("OIDC", &provider.name, "CLIENT_SECRET")) {
+                provider.client_secret = Some(value);
+            }
+        }
+        if let Some(value) = env("OAUTH_ISSUER") {
+            self.oauth.issuer = value;
+        }
+        if let Some(value) = env("OAUTH_AUTHORIZATION_ENDPOINT") {
+            self.oauth.authorization_endpoint = Some(value);
+        }
+        for client in &mut self.oauth.clients {
+            if let Some(value) = env(&env_name("OAUTH", &client.client_id, "CLIENT_SECRET")) {
+                client.client_secret = Some(value);
+            }
+        }
+        if let Some(value) = env("WEBAUTHN_RP_ID") {
+            self.webauthn.rp_id = value;
+        }
+        if let Some(value) = env("WEBAUTHN_ORIGINS") {
+            self.webauthn.origins = value
+                .split(',')
+                .map(str::trim)
+                .filter(|origin| !origin.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+
+        Ok(())
+    }
+
+    /// Check the whole configuration, reporting every problem at once.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = vec![];
+        let is_dev = self.environment == Environment::Development;
+
+        if self.server.bind_address.parse::<SocketAddr>().is_err() {
+            errors.push(format!(
+                "server.bind_address '{}' is not a valid socket address (e.g. 127.0.0.1:3000)",
+                self.server.bind_address
+            ));
+        }
+
+        if !self.database.url.starts_with("sqlite:") {
+            errors.push(format!("database.url '{}' must start with 'sqlite:'", self.database.url));
+        }
+        if self.database.max_connections == 0 {
+            errors.push("database.max_connections must be at least 1".to_string());
+        }
+
+        match (&self.jwt.signing_key_file, &self.jwt.secret) {
+            (Some(_), _) => {
+                if self.jwt.signing_key_id.as_deref().unwrap_or("").is_empty() {
+                    errors.push("jwt.signing_key_id is required when jwt.signing_key_file is set".to_string());
+                }
+            }
+            (None, Some(secret)) => {
+                if !is_dev && (secret == DEV_JWT_SECRET || secret.len() < 32) {
+                    errors.push("jwt.secret must be at least 32 characters and not the development default".to_string());
+                }
+            }
+            (None, None) => {
+                if !is_dev {
+                    errors.push("jwt.secret or jwt.signing_key_file must be set outside development".to_string());
+                }
+            }
+        }
+        if self.jwt.access_token_ttl_hours <= 0 {
+            errors.push("jwt.access_token_ttl_hours must be positive".to_string());
+        }
+        if self.jwt.denylist_prune_interval_secs == 0 {
+            errors.push("jwt.denylist_prune_interval_secs must be positive".to_string());
+        }
+
+        if self.cors.allowed_origins.is_empty() {
+            errors.push("cors.allowed_origins must not be empty".to_string());
+        }
+        for origin in &self.cors.allowed_origins {
+            if origin == "*" {
+                if self.environment == Environment::Production {
+                    errors.push("cors.allowed_origins must list explicit origins in production".to_string());
+                }
+            } else if !(origin.starts_with("http://") || origin.starts_with("https://"))
+                || origin.parse::<axum::http::HeaderValue>().is_err()
+            {
+                errors.push(format!("cors.allowed_origins entry '{}' is not a valid origin", origin));
+            }
+        }
+
+        if self.mail.from.parse::<lettre::message::Mailbox>().is_err() {
+            errors.push(format!("mail.from '{}' is not a valid sender address", self.mail.from));
+        }
+        if !(self.mail.app_url.starts_with("http://") || self.mail.app_url.starts_with("https://")) {
+            errors.push(format!("mail.app_url '{}' must be an http(s) URL", self.mail.app_url));
+        }
+        match self.mail.transport {
+            MailTransport::Smtp => {
+                if self.mail.smtp_host.as_deref().unwrap_or("").is_empty() {
+                    errors.push("mail.smtp_host is required when mail.transport is smtp".to_string());
+                }
+            }
+            MailTransport::File => {
+                if self.environment == Environment::Production {
+                    errors.push("mail.transport must be smtp in production".to_string());
+                }
+            }
+        }
+
+        let country_code = &self.sms.default_country_code;
+        let is_calling_code = (1..=3).contains(&country_code.len())
+            && country_code.bytes().all(|b| b.is_ascii_digit())
+            && !country_code.starts_with('0');
+        if !is_calling_code {
+            errors.push(format!("sms.default_country_code '{}' must be a country calling code such as 66", country_code));
+        }
+        match self.sms.transport {
+            SmsTransport::Http => {
+                let url = self.sms.http_url.as_deref().unwrap_or("");
+                if !(url.starts_with("https://") || is_local_http(url)) {
+                    errors.push("sms.http_url must be an https URL when sms.transport is http".to_string());
+                }
+            }
+            SmsTransport::Log | SmsTransport::File => {
+                // Codes sent this way would be readable by anyone with the logs or files
+                if self.environment == Environment::Production {
+                    errors.push("sms.transport must be http in production".to_string());
+                }
+            }
+        }
+
+        let login = &self.login_protection;
+        if login.failure_window_minutes <= 0 || login.lockout_minutes <= 0 {
+            errors.push("login_protection.failure_window_minutes and lockout_minutes must be positive".to_string());
+        }
+        if login.max_account_failures == 0 || login.max_ip_failures == 0 {
+            errors.push("login_protection.max_account_failures and max_ip_failures must be at least 1".to_string());
+        }
+        if login.max_delay_secs < 0 {
+            errors.push("login_protection.max_delay_secs must not be negative".to_string());
+        }
+
+        let limits = &self.rate_limit;
+        for (group, rule) in [("auth", limits.auth), ("profile", limits.profile), ("admin", limits.admin)] {
+            if rule.burst == 0 || rule.per_minute == 0 {
+                errors.push(format!("rate_limit.{}.burst and per_minute must be at least 1", group));
+            }
+        }
+
+        let hashing = &self.password_hashing;
+        if argon2::Params::new(hashing.argon2_memory_kib, hashing.argon2_iterations, hashing.argon2_parallelism, None).is_err() {
+            errors.push(
+                "password_hashing argon2 parameters are out of range (argon2_memory_kib must be at least 8 × argon2_parallelism)"
+                    .to_string(),
+            );
+        }
+        if !(4..=31).contains(&hashing.bcrypt_cost) {
+            errors.push("password_hashing.bcrypt_cost must be between 4 and 31".to_string());
+        }
+
+        let policy = &self.password_policy;
+        if policy.min_length == 0 || policy.max_length < policy.min_length {
+            errors.push("password_policy.min_length must be at least 1 and not more than max_length".to_string());
+        }
+        if policy.min_strength > 4 {
+            errors.push("password_policy.min_strength must be between 0 and 4".to_string());
+        }
+        if let Some(path) = &policy.breached_passwords_path {
+            if !path.exists() {
+                errors.push(format!("password_policy.breached_passwords_path {} does not exist", path.display()));
+            }
+        }
+
+        for (i, provider) in self.oidc.providers.iter().enumerate() {
+            let name = &provider.name;
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-') {
+                errors.push(format!("oidc provider name '{}' must be lowercase letters, digits and '-'", name));
+            }
+            if self.oidc.providers[..i].iter().any(|other| &other.name == name) {
+                errors.push(format!("oidc provider '{}' is configured twice", name));
+            }
+            if !(provider.issuer.starts_with("https://") || is_local_http(&provider.issuer)) {
+                errors.push(format!("oidc provider '{}' issuer must be an https URL", name));
+            }
+            if provider.client_id.is_empty() {
+                errors.push(format!("oidc provider '{}' client_id is required", name));
+            }
+            if !(provider.redirect_uri.starts_with("https://") || provider.redirect_uri.starts_with("http://")) {
+                errors.push(format!("oidc provider '{}' redirect_uri must be an http(s) URL", name));
+            }
+            if !provider.scopes.iter().any(|scope| scope == "openid") {
+                errors.push(format!("oidc provider '{}' scopes must include 'openid'", name));
+            }
+        }
+
+        if !self.oauth.clients.is_empty() {
+            if !(self.oauth.issuer.starts_with("https://") || is_local_http(&self.oauth.issuer)) {
+                errors.push("oauth.issuer must be an https URL".to_string());
+            }
+            match &self.oauth.authorization_endpoint {
+                Some(url) if url.starts_with("https://") || is_local_http(url) => {}
+                Some(_) => errors.push("oauth.authorization_endpoint must be an https URL".to_string()),
+                None => errors.push("oauth.clients require oauth.authorization_endpoint, the web app's consent page".to_string()),
+            }
+            // Clients verify ID tokens with the keys published in the JWKS
+            if self.jwt.signing_key_file.is_none() {
+                errors.push("oauth.clients require jwt.signing_key_file, so ID tokens can be verified".to_string());
+            }
+        }
+        for (i, client) in self.oauth.clients.iter().enumerate() {
+            let id = &client.client_id;
+            if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c)) {
+                errors.push(format!("oauth client_id '{}' must be letters, digits, '-', '_' and '.'", id));
+            }
+            if self.oauth.clients[..i].iter().any(|other| &other.client_id == id) {
+                errors.push(format!("oauth client '{}' is configured twice", id));
+            }
+            if client.redirect_uris.is_empty() {
+                errors.push(format!("oauth client '{}' needs at least one redirect_uri", id));
+            }
+            for uri in &client.redirect_uris {
+                if !(uri.starts_with("https://") || is_local_http(uri)) || uri.contains('#') {
+                    errors.push(format!("oauth client '{}' redirect_uri '{}' must be an https URL without a fragment", id, uri));
+                }
+            }
+            for scope in client.scopes.iter().filter(|scope| !crate::oauth::is_known_scope(scope)) {
+                errors.push(format!("oauth client '{}' has unknown scope '{}'", id, scope));
+            }
+        }
+
+        let rp_id = &self.webauthn.rp_id;
+        if rp_id.is_empty() || rp_id.contains(['/', ':']) {
+            errors.push(format!("webauthn.rp_id '{}' must be a domain name, without scheme or port", rp_id));
+        }
+        if self.webauthn.origins.is_empty() {
+            errors.push("webauthn.origins must not be empty".to_string());
+        }
+        for origin in &self.webauthn.origins {
+            // Browsers only allow an origin to use the RP id of its own domain or a parent
+            let host = origin
+                .strip_prefix("https://")
+                .or_else(|| origin.strip_prefix("http://").filter(|_| is_local_http(origin)))
+                .map(|rest| rest.rsplit_once(':').map_or(rest, |(host, _)| host));
+            let matches = host.is_some_and(|host| host == rp_id || host.ends_with(&format!(".{}", rp_id)));
+            if !matches {
+                errors.push(format!("webauthn.origins entry '{}' must be an https origin on the domain '{}'", origin, rp_id));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+
+    /// The HS256 secret to sign with when no signing key file is configured.
+    pub fn jwt_secret(&self) -> &str {
+        self.jwt.secret.as_deref().unwrap_or(DEV_JWT_SECRET)
+    }
+}
+
+fn parse_env<T: std::str::FromStr>(var: &str, value: &str) -> Result<T, ConfigError>
+where
+    T::Err: std::fmt::Display,
+{
+    value.parse().map_err(|e: T::Err| ConfigError::Env {
+        var: var.to_string(),
+        message: e.to_string(),
+    })
+}
+
+/// Parse `kid=path@retired_at,...` as used by `JWT_PREVIOUS_KEYS`, with
+/// `retired_at` in RFC 3339 form.
+fn parse_previous_keys(value: &str) -> Result<Vec<PreviousKeyConfig>, ConfigError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let parse = || {
+                let (kid, rest) = entry.split_once('=')?;
+                let (path, retired_at) = rest.rsplit_once('@')?;
+                Some(PreviousKeyConfig {
+                    kid: kid.to_string(),
+                    path: PathBuf::from(path),
+                    retired_at: DateTime::parse_from_rfc3339(retired_at).ok()?.with_timezone(&Utc),
+                })
+            };
+            parse().ok_or_else(|| ConfigError::Env {
+                var: "JWT_PREVIOUS_KEYS".to_string(),
+                message: format!("entry '{}' must be kid=path@retired_at", entry),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env_from(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |var| vars.get(var).cloned()
+    }
+
+    #[test]
+    fn test_default_config_is_valid_for_development() {
+        let config = AppConfig::default();
+
+        assert_eq!(config.environment, Environment::Development);
+        assert_eq!(config.server.bind_address, "127.0.0.1:3000");
+        assert_eq!(config.database.url, "sqlite:./app.db");
+        assert_eq!(config.jwt_secret(), DEV_JWT_SECRET);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_parse_toml() {
+        let config: AppConfig = toml::from_str(
+            r#"
+            environment = "staging"
+
+            [server]
+            bind_address = "0.0.0.0:8080"
+
+            [database]
+            url = "sqlite:/var/lib/app/app.db"
+
+            [jwt]
+            signing_key_file = "/etc/app/signing.pem"
+            signing_key_id = "2024-06"
+            previous_keys = [{ kid = "2024-01", path = "/etc/app/old.pem", retired_at = "2024-06-01T00:00:00Z" }]
+
+            [cors]
+            allowed_origins = ["https://app.example.com"]
+
+            [login_protection]
+            max_account_failures = 10
+
+            [rate_limit]
+            auth = { burst = 5, per_minute = 2 }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.environment, Environment::Staging);
+        assert_eq!(config.server.bind_address, "0.0.0.0:8080");
+        assert_eq!(config.database.url, "sqlite:/var/lib/app/app.db");
+        assert_eq!(config.database.max_connections, 5);
+        assert!(config.database.auto_migrate);
+        assert_eq!(config.jwt.signing_key_id.as_deref(), Some("2024-06"));
+        assert_eq!(config.jwt.previous_keys[0].kid, "2024-01");
+        assert_eq!(config.jwt.previous_keys[0].retired_at.to_rfc3339(), "2024-06-01T00:00:00+00:00");
+        assert_eq!(config.jwt.access_token_ttl_hours, 24);
+        assert_eq!(config.login_protection.max_account_failures, 10);
+        assert_eq!(config.login_protection.lockout_minutes, 15);
+        assert_eq!(config.rate_limit.auth, RateLimitRule { burst: 5, per_minute: 2 });
+        assert_eq!(config.rate_limit.profile.burst, 120);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_unknown_field_is_rejected() {
+        let result = toml::from_str::<AppConfig>("[server]\nbind_adress = \"0.0.0.0:8080\"");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_env_overrides_file_values() {
+        let mut config = AppConfig::default();
+        let env = env_from(&[
+            ("APP_ENV", "production"),
+            ("BIND_ADDRESS", "0.0.0.0:9000"),
+            ("TRUST_PROXY_HEADERS", "true"),
+            ("DATABASE_URL", "sqlite::memory:"),
+            ("JWT_SECRET", "a-very-long-production-secret-value-1234"),
+            ("JWT_PREVIOUS_KEYS", "old=/keys/old.pem@2024-06-01T00:00:00Z, older=/keys/older.pem@2024-01-01T12:00:00+07:00"),
+            ("CORS_ALLOWED_ORIGINS", "https://a.example.com, https://b.example.com"),
+            ("MAIL_TRANSPORT", "smtp"),
+            ("SMTP_HOST", "smtp.example.com"),
+            ("SMTP_TLS", "tls"),
+            ("SMS_TRANSPORT", "http"),
+            ("SMS_HTTP_URL", "https://sms.example.com/send"),
+            ("SMS_DEFAULT_COUNTRY_CODE", "44"),
+        ]);
+
+        config.apply_env_overrides(env).unwrap();
+
+        assert_eq!(config.environment, Environment::Production);
+        assert_eq!(config.server.bind_address, "0.0.0.0:9000");
+        assert!(config.server.trust_proxy_headers);
+        assert_eq!(config.database.url, "sqlite::memory:");
+        assert_eq!(config.jwt_secret(), "a-very-long-production-secret-value-1234");
+        assert_eq!(config.jwt.previous_keys.len(), 2);
+        assert_eq!(config.jwt.previous_keys[1].path, PathBuf::from("/keys/older.pem"));
+        assert_eq!(config.jwt.previous_keys[1].retired_at.to_rfc3339(), "2024-01-01T05:00:00+00:00");
+        assert_eq!(config.cors.allowed_origins, vec!["https://a.example.com", "https://b.example.com"]);
+        assert_eq!(config.mail.transport, MailTransport::Smtp);
+        assert_eq!(config.mail.smtp_tls, SmtpTls::Tls);
+        assert_eq!(config.sms.transport, SmsTransport::Http);
+        assert_eq!(config.sms.default_country_code, "44");
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_env_values() {
+        let mut config = AppConfig::default();
+        let result = config.apply_env_overrides(env_from(&[("APP_ENV", "qa")]));
+        assert!(matches!(result, Err(ConfigError::Env { var, .. }) if var == "APP_ENV"));
+
+        let result = config.apply_env_overrides(env_from(&[("DATABASE_MAX_CONNECTIONS", "many")]));
+        assert!(matches!(result, Err(ConfigError::Env { var, .. }) if var == "DATABASE_MAX_CONNECTIONS"));
+
+        let result = config.apply_env_overrides(env_from(&[("JWT_PREVIOUS_KEYS", "no-path")]));
+        assert!(matches!(result, Err(ConfigError::Env { var, .. }) if var == "JWT_PREVIOUS_KEYS"));
+
+        // Previous keys need to say when they were retired
+        let result = config.apply_env_overrides(env_from(&[("JWT_PREVIOUS_KEYS", "old=/keys/old.pem")]));
+        assert!(matches!(result, Err(ConfigError::Env { var, .. }) if var == "JWT_PREVIOUS_KEYS"));
+        let result = config.apply_env_overrides(env_from(&[("JWT_PREVIOUS_KEYS", "old=/keys/old.pem@yesterday")]));
+        assert!(matches!(result, Err(ConfigError::Env { var, .. }) if var == "JWT_PREVIOUS_KEYS"));
+    }
+
+    #[test]
+    fn test_production_requires_secrets_and_explicit_origins() {
+        let config = AppConfig {
+            environment: Environment::Production,
+            ..AppConfig::default()
+        };
+
+        let Err(ConfigError::Invalid(errors)) = config.validate() else {
+            panic!("expected validation errors");
+        };
+        assert_eq!(errors.len(), 4);
+        assert!(errors.iter().any(|e| e.contains("jwt.secret")));
+        assert!(errors.iter().any(|e| e.contains("cors.allowed_origins")));
+        assert!(errors.iter().any(|e| e.contains("mail.transport")));
+        assert!(errors.iter().any(|e| e.contains("sms.transport")));
+    }
+
+    #[test]
+    fn test_validation_reports_every_problem() {
+        let mut config = AppConfig::default();
+        config.server.bind_address = "localhost".to_string();
+        config.database.url = "postgres://db".to_string();
+        config.jwt.signing_key_file = Some(PathBuf::from("key.pem"));
+        config.cors.allowed_origins = vec!["example.com".to_string()];
+        config.mail.transport = MailTransport::Smtp;
+        config.rate_limit.profile.per_minute = 0;
+        config.password_hashing.bcrypt_cost = 2;
+
+        let error = config.validate().unwrap_err();
+        let ConfigError::Invalid(errors) = &error else {
+            panic!("expected validation errors");
+        };
+        assert_eq!(errors.len(), 7);
+        assert!(errors.iter().any(|e| e.starts_with("rate_limit.profile")));
+        assert!(error.to_string().contains("server.bind_address 'localhost'"));
+    }
+
+    #[test]
+    fn test_oidc_providers() {
+        let mut config: AppConfig = toml::from_str(
+            r#"
+            [[oidc.providers]]
+            name = "acme-sso"
+            issuer = "https://sso.acme.test"
+            client_id = "temp-backend"
+            redirect_uri = "https://app.example.com/oidc/callback"
+            "#,
+        )
+        .unwrap();
+        config.apply_env_overrides(env_from(&[("OIDC_ACME_SSO_CLIENT_SECRET", "s3cret")])).unwrap();
+
+        let provider = &config.oidc.providers[0];
+        assert_eq!(provider.client_secret.as_deref(), Some("s3cret"));
+        assert_eq!(provider.scopes, ["openid", "email", "profile"]);
+        assert!(!provider.link_existing_accounts);
+        assert!(config.validate().is_ok());
+
+        config.oidc.providers.push(OidcProviderConfig {
+            issuer: "http://sso.acme.test".to_string(),
+            scopes: vec!["email".to_string()],
+            ..config.oidc.providers[0].clone()
+        });
+        let Err(ConfigError::Invalid(errors)) = config.validate() else {
+            panic!("expected validation errors");
+        };
+        assert_eq!(errors.len(), 3);
+
+        assert!(is_local_http("http://127.0.0.1:8080/realms/test"));
+        assert!(!is_local_http("http://localhost.evil.test"));
+    }
+
+    #[test]
+    fn test_oauth_clients() {
+        let mut config: AppConfig = toml::from_str(
+            r#"
+            [jwt]
+            signing_key_file = "/etc/app/signing.pem"
+            signing_key_id = "2024-06"
+
+            [oauth]
+            issuer = "https://api.example.com"
+            authorization_endpoint = "https://app.example.com/oauth/consent"
+
+            [[oauth.clients]]
+            client_id = "admin.tool"
+            name = "Admin tool"
+            redirect_uris = ["https://tool.example.com/callback"]
+            scopes = ["openid", "email", "users:read"]
+            "#,
+        )
+        .unwrap();
+        config.apply_env_overrides(env_from(&[("OAUTH_ADMIN_TOOL_CLIENT_SECRET", "s3cret")])).unwrap();
+
+        let client = &config.oauth.clients[0];
+        assert_eq!(client.client_secret.as_deref(), Some("s3cret"));
+        assert!(!client.trusted);
+        assert!(config.validate().is_ok());
+
+        config.jwt.signing_key_file = None;
+        config.oauth.authorization_endpoint = None;
+        config.oauth.clients[0].redirect_uris.push("https://tool.example.com/#fragment".to_string());
+        config.oauth.clients[0].scopes.push("everything".to_string());
+        let Err(ConfigError::Invalid(errors)) = config.validate() else {
+            panic!("expected validation errors");
+        };
+        assert_eq!(errors.len(), 4);
+        assert!(errors.iter().any(|e| e.contains("oauth.authorization_endpoint")));
+    }
+
+    #[test]
+    fn test_webauthn_origins() {
+        let mut config: AppConfig = toml::from_str(
+            r#"
+            [webauthn]
+            rp_id = "example.com"
+            rp_name = "Example"
+            origins = ["https://example.com"]
+            "#,
+        )
+        .unwrap();
+        config
+            .apply_env_overrides(env_from(&[("WEBAUTHN_ORIGINS", "https://example.com, https://app.example.com:8443")]))
+            .unwrap();
+        assert_eq!(config.webauthn.origins, ["https://example.com", "https://app.example.com:8443"]);
+        assert!(config.validate().is_ok());
+
+        // A passkey for example.com cannot be used from another site, or over plain http
+        config.webauthn.origins = vec!["https://notexample.com".to_string(), "http://example.com".to_string()];
+        config.webauthn.rp_id = "https://example.com".to_string();
+        let Err(ConfigError::Invalid(errors)) = config.validate() else {
+            panic!("expected validation errors");
+        };
+        assert_eq!(errors.len(), 3);
+    }
+}
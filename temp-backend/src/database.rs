@@ -1,38 +1,162 @@
-use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
-use anyhow::Result;
+use sqlx::{
+    migrate::{Migrate, Migrator},
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    SqlitePool,
+};
+use anyhow::{anyhow, Result};
+use std::{collections::HashSet, str::FromStr};
 
-pub async fn create_pool() -> Result<SqlitePool> {
+use crate::config::DatabaseConfig;
+
+/// Forward-only schema migrations from `migrations/`, embedded at compile time.
+/// Applied versions are recorded in the `_sqlx_migrations` table.
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub applied: bool,
+}
+
+pub async fn create_pool(config: &DatabaseConfig) -> Result<SqlitePool> {
     // Create database file if it doesn't exist
-    let database_url = "sqlite:./app.db";
-    
+    let options = SqliteConnectOptions::from_str(&config.url)?
+        .create_if_missing(true)
+        .foreign_keys(true);
+
     let pool = SqlitePoolOptions::new()
-        .max_connections(5)
-        .connect(database_url)
+        .max_connections(config.max_connections)
+        .connect_with(options)
         .await?;
 
     Ok(pool)
 }
 
-pub async fn create_tables(pool: &SqlitePool) -> Result<()> {
-    sqlx::query(
-        r#"
-        CREATE TABLE IF NOT EXISTS users (
-            id TEXT PRIMARY KEY,
-            email TEXT UNIQUE NOT NULL,
-            password_hash TEXT NOT NULL,
-            first_name TEXT,
-            last_name TEXT,
-            phone TEXT,
-            membership_id TEXT,
-            membership_level TEXT NOT NULL DEFAULT 'Bronze',
-            points INTEGER NOT NULL DEFAULT 0,
-            created_at DATETIME NOT NULL,
-            updated_at DATETIME NOT NULL
-        )
-        "#,
-    )
-    .execute(pool)
-    .await?;
+/// Apply every migration that has not been applied yet.
+pub async fn run_migrations(pool: &SqlitePool) -> Result<()> {
+    MIGRATOR.run(pool).await?;
 
     Ok(())
 }
+
+/// List every embedded migration and whether it has been applied.
+pub async fn migration_status(pool: &SqlitePool) -> Result<Vec<MigrationStatus>> {
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+
+    let applied: HashSet<i64> = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|migration| migration.version)
+        .collect();
+
+    Ok(MIGRATOR
+        .iter()
+        .map(|migration| MigrationStatus {
+            version: migration.version,
+            description: migration.description.to_string(),
+            applied: applied.contains(&migration.version),
+        })
+        .collect())
+}
+
+/// Fail if the schema is behind this binary, for deployments that migrate
+/// separately with `--migrate-only`.
+pub async fn ensure_migrated(pool: &SqlitePool) -> Result<()> {
+    let pending: Vec<String> = migration_status(pool)
+        .await?
+        .into_iter()
+        .filter(|status| !status.applied)
+        .map(|status| format!("{} {}", status.version, status.description))
+        .collect();
+
+    if pending.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Database has pending migrations ({}); run with --migrate-only first",
+            pending.join(", ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn memory_pool() -> SqlitePool {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_run_migrations_is_idempotent() {
+        let pool = memory_pool().await;
+
+        run_migrations(&pool).await.unwrap();
+        run_migrations(&pool).await.unwrap();
+
+        let status = migration_status(&pool).await.unwrap();
+        assert!(!status.is_empty());
+        assert!(status.iter().all(|s| s.applied));
+        assert!(ensure_migrated(&pool).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_pending_migrations_are_reported() {
+        let pool = memory_pool().await;
+
+        let status = migration_status(&pool).await.unwrap();
+        assert!(status.iter().all(|s| !s.applied));
+        assert_eq!(status[0].version, 1);
+        assert_eq!(status[0].description, "create users");
+
+        let error = ensure_migrated(&pool).await.unwrap_err();
+        assert!(error.to_string().contains("--migrate-only"));
+    }
+
+    #[tokio::test]
+    async fn test_migrates_database_created_before_migrations() {
+        let pool = memory_pool().await;
+
+        // Schema as created by the old `create_tables`, with existing data
+        sqlx::query(
+            r#"
+            CREATE TABLE users (
+                id TEXT PRIMARY KEY,
+                email TEXT UNIQUE NOT NULL,
+                password_hash TEXT NOT NULL,
+                first_name TEXT,
+                last_name TEXT,
+                phone TEXT,
+                membership_id TEXT,
+                membership_level TEXT NOT NULL DEFAULT 'Bronze',
+                points INTEGER NOT NULL DEFAULT 0,
+                created_at DATETIME NOT NULL,
+                updated_at DATETIME NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO users (id, email, password_hash, created_at, updated_at) VALUES ('1', 'old@example.com', 'hash', '2024-01-01', '2024-01-01')")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        run_migrations(&pool).await.unwrap();
+
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+        assert!(ensure_migrated(&pool).await.is_ok());
+    }
+}
@@ -0,0 +1,299 @@
+use axum::{
+    http::{header::{RETRY_AFTER, WWW_AUTHENTICATE}, HeaderValue, StatusCode},
+    response::{IntoResponse, Json as ResponseJson, Response},
+};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::models::{ErrorResponse, FieldError};
+
+/// Error returned by every handler. Each variant maps to one HTTP status and
+/// carries a stable machine-readable `code` for the `error` field of `ErrorResponse`.
+#[derive(Debug, Error)]
+pub enum AppError {
+    #[error("{0}")]
+    Validation(String),
+    /// `400` with a reason for each rejected field
+    #[error("{message}")]
+    InvalidFields { message: String, fields: Vec<FieldError> },
+    /// `400` with its own code, for protocols that define their error codes
+    #[error("{message}")]
+    BadRequest { code: &'static str, message: String },
+    #[error("{message}")]
+    Unauthorized { code: &'static str, message: String },
+    #[error("{message}")]
+    Forbidden { code: &'static str, message: String },
+    #[error("{message}")]
+    NotFound { code: &'static str, message: String },
+    #[error("{message}")]
+    Conflict { code: &'static str, message: String },
+    /// `429`; `retry_after` is in seconds
+    #[error("{message}")]
+    TooManyRequests { code: &'static str, message: String, retry_after: u64 },
+    /// `423`, for a resource that is temporarily locked
+    #[error("{message}")]
+    Locked { code: &'static str, message: String, retry_after: u64 },
+    /// Details are logged with a correlation id and never sent to the client.
+    #[error("Internal server error")]
+    Internal(#[source] anyhow::Error),
+}
+
+impl AppError {
+    pub fn validation(message: impl Into<String>) -> Self {
+        Self::Validation(message.into())
+    }
+
+    pub fn invalid_fields(message: impl Into<String>, fields: Vec<FieldError>) -> Self {
+        Self::InvalidFields { message: message.into(), fields }
+    }
+
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::BadRequest { code, message: message.into() }
+    }
+
+    pub fn unauthorized(code: &'static str, message: impl Into<String>) -> Self {
+        Self::Unauthorized { code, message: message.into() }
+    }
+
+    pub fn forbidden(code: &'static str, message: impl Into<String>) -> Self {
+        Self::Forbidden { code, message: message.into() }
+    }
+
+    pub fn not_found(code: &'static str, message: impl Into<String>) -> Self {
+        Self::NotFound { code, message: message.into() }
+    }
+
+    pub fn conflict(code: &'static str, message: impl Into<String>) -> Self {
+        Self::Conflict { code, message: message.into() }
+    }
+
+    pub fn too_many_requests(code: &'static str, message: impl Into<String>, retry_after: u64) -> Self {
+        Self::TooManyRequests { code, message: message.into(), retry_after }
+    }
+
+    pub fn locked(code: &'static str, message: impl Into<String>, retry_after: u64) -> Self {
+        Self::Locked { code, message: message.into(), retry_after }
+    }
+
+    /// The 401 returned for a malformed, expired or revoked access token.
+    pub fn invalid_token() -> Self {
+        Self::unauthorized("invalid_token", "Invalid or expired token")
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Validation(_) | Self::InvalidFields { .. } | Self::BadRequest { .. } => StatusCode::BAD_REQUEST,
+            Self::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+            Self::Forbidden { .. } => StatusCode::FORBIDDEN,
+            Self::NotFound { .. } => StatusCode::NOT_FOUND,
+            Self::Conflict { .. } => StatusCode::CONFLICT,
+            Self::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::Locked { .. } => StatusCode::LOCKED,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Seconds the client should wait before retrying, for errors that have one.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            Self::TooManyRequests { retry_after, .. } | Self::Locked { retry_after, .. } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    /// The per-field reasons of an `InvalidFields` error.
+    pub fn fields(&self) -> &[FieldError] {
+        match self {
+            Self::InvalidFields { fields, .. } => fields,
+            _ => &[],
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Validation(_) | Self::InvalidFields { .. } => "validation_error",
+            Self::BadRequest { code, .. }
+            | Self::Unauthorized { code, .. }
+            | Self::Forbidden { code, .. }
+            | Self::NotFound { code, .. }
+            | Self::Conflict { code, .. }
+            | Self::TooManyRequests { code, .. }
+            | Self::Locked { code, .. } => code,
+            Self::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let mut body = ErrorResponse {
+            error: self.code().to_string(),
+            message: self.to_string(),
+            correlation_id: None,
+            retry_after: self.retry_after(),
+            fields: self.fields().to_vec(),
+        };
+
+        if let Self::Internal(source) = &self {
+            let correlation_id = Uuid::new_v4().to_string();
+            tracing::error!(correlation_id = %correlation_id, error = ?source, "internal error");
+            body.correlation_id = Some(correlation_id);
+        }
+
+        let mut response = (self.status(), ResponseJson(body)).into_response();
+
+        // RFC 6750: a 401 must tell the client which scheme to use, and why
+        // a presented token was refused
+        if let Self::Unauthorized { code, .. } = &self {
+            let challenge = if *code == "invalid_token" {
+                HeaderValue::from_static("Bearer error=\"invalid_token\"")
+            } else {
+                HeaderValue::from_static("Bearer")
+            };
+            response.headers_mut().insert(WWW_AUTHENTICATE, challenge);
+        }
+
+        if let Some(retry_after) = self.retry_after() {
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+
+        response
+    }
+}
+
+impl From<anyhow::Error> for AppError {
+    fn from(error: anyhow::Error) -> Self {
+        // Repositories return anyhow errors; recover database errors that have a
+        // more specific meaning than "internal".
+        if let Some(sqlx_error) = error.downcast_ref::<sqlx::Error>() {
+            if let Some(mapped) = map_sqlx_error(sqlx_error) {
+                return mapped;
+            }
+        }
+
+        Self::Internal(error)
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(error: sqlx::Error) -> Self {
+        map_sqlx_error(&error).unwrap_or_else(|| Self::Internal(error.into()))
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for AppError {
+    fn from(error: jsonwebtoken::errors::Error) -> Self {
+        use jsonwebtoken::errors::ErrorKind;
+
+        match error.kind() {
+            // Failures to create a token are our fault, not the client's
+            ErrorKind::InvalidEcdsaKey
+            | ErrorKind::InvalidRsaKey(_)
+            | ErrorKind::RsaFailedSigning
+            | ErrorKind::InvalidKeyFormat
+            | ErrorKind::Json(_)
+            | ErrorKind::Crypto(_) => Self::Internal(error.into()),
+            _ => Self::invalid_token(),
+        }
+    }
+}
+
+fn map_sqlx_error(error: &sqlx::Error) -> Option<AppError> {
+    match error {
+        sqlx::Error::RowNotFound => Some(AppError::not_found("not_found", "Resource not found")),
+        sqlx::Error::Database(db_error) if db_error.is_unique_violation() => {
+            Some(AppError::conflict("conflict", "Resource already exists"))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    async fn response_body(error: AppError) -> (StatusCode, ErrorResponse) {
+        let response = error.into_response();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_client_error_response() {
+        let (status, body) = response_body(AppError::conflict("email_exists", "Email already exists")).await;
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(body.error, "email_exists");
+        assert_eq!(body.message, "Email already exists");
+        assert!(body.correlation_id.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_is_sent() {
+        let response = AppError::too_many_requests("too_many_attempts", "Slow down", 8).into_response();
+        assert_eq!(response.headers()[RETRY_AFTER], "8");
+
+        let (status, body) = response_body(AppError::locked("account_locked", "Locked", 900)).await;
+        assert_eq!(status, StatusCode::LOCKED);
+        assert_eq!(body.retry_after, Some(900));
+    }
+
+    #[tokio::test]
+    async fn test_field_errors_are_sent() {
+        let field = FieldError {
+            field: "password".to_string(),
+            code: "too_short".to_string(),
+            message: "Must be at least 8 characters long".to_string(),
+        };
+        let (status, body) = response_body(AppError::invalid_fields("Password is not allowed", vec![field.clone()])).await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.error, "validation_error");
+        assert_eq!(body.fields, vec![field]);
+
+        let (_, body) = response_body(AppError::validation("Email is required")).await;
+        assert!(body.fields.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_internal_error_hides_details() {
+        let error = AppError::from(anyhow::anyhow!("disk on fire at /var/lib/app.db"));
+        let (status, body) = response_body(error).await;
+
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body.error, "internal_error");
+        assert_eq!(body.message, "Internal server error");
+        assert!(body.correlation_id.is_some());
+    }
+
+    #[test]
+    fn test_sqlx_errors_are_mapped() {
+        let error = AppError::from(sqlx::Error::RowNotFound);
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+
+        let error = AppError::from(sqlx::Error::PoolTimedOut);
+        assert_eq!(error.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // Context added by `anyhow` does not hide the underlying sqlx error
+        let error = AppError::from(
+            Err::<(), _>(sqlx::Error::RowNotFound)
+                .context("Failed to load user")
+                .unwrap_err(),
+        );
+        assert_eq!(error.code(), "not_found");
+    }
+
+    #[test]
+    fn test_jwt_errors_are_mapped() {
+        use jsonwebtoken::errors::ErrorKind;
+
+        let error = AppError::from(jsonwebtoken::errors::Error::from(ErrorKind::ExpiredSignature));
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.code(), "invalid_token");
+
+        let error = AppError::from(jsonwebtoken::errors::Error::from(ErrorKind::InvalidKeyFormat));
+        assert_eq!(error.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
@@ -1,23 +1,286 @@
+use anyhow::Context;
 use axum::{
-    extract::{Json, State},
-    http::StatusCode,
+    extract::{Json, Path, Query, State},
+    http::{header::{AUTHORIZATION, CACHE_CONTROL}, HeaderMap, HeaderName, StatusCode},
     response::Json as ResponseJson,
+    Form,
 };
-use axum_extra::{
-    headers::{authorization::Bearer, Authorization},
-    TypedHeader,
-};
-use bcrypt::{hash, verify, DEFAULT_COST};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{Duration, Utc};
+use std::net::IpAddr;
 
 use crate::{
-    models::{AuthResponse, ErrorResponse, LoginRequest, RegisterRequest, UserProfile, UpdateProfileRequest},
+    auth::{authenticate_token, AuthUser, Scope},
+    client_ip::ClientInfo,
+    config::OAuthClientConfig,
+    error::AppError,
+    mfa::{self, normalize_recovery_code, MFA_CHALLENGE_MAX_ATTEMPTS},
+    models::{ApiTokenInfo, ApiTokenList, AuthResponse, AuthenticationCredential, ChangeEmailRequest, ChangePasswordRequest, ConfirmEmailChangeRequest, ConfirmPhoneRequest, ConfirmTotpRequest, ConsumeMagicLinkRequest, CreateApiTokenRequest, CreatedApiToken, CurrentPasswordRequest, ForgotPasswordRequest, JwkSet, ListUsersQuery, LoginRequest, LoginResponse, LogoutRequest, MagicLinkRequest, MfaChallenge, MfaStatus, OAuthAuthorizationCode, OAuthAuthorizationDetails, OAuthAuthorizeParams, OAuthConsentDecision, OAuthRedirect, OAuthTokenReference, OAuthTokenRequest, OAuthTokenResponse, OidcAuthorization, OidcCallbackRequest, OpenIdConfiguration, Passkey, PasskeyCeremony, PasskeyCreationOptions, PasskeyInfo, PasskeyList, PasskeyLoginRequest, PasskeyRequestOptions, PhoneLoginRequest, PhoneOtpPurpose, PhoneOtpRequest, RecoveryCodes, RefreshRequest, RegisterPasskeyRequest, RegisterRequest, ResetPasswordRequest, Role, RolePermissions, SessionInfo, SessionList, TokenIntrospection, TokenPurpose, TotpEnrollment, UnlockAccountRequest, UpdateMembershipRequest, UpdateRoleRequest, User, UserAccount, UserInfo, UserPage, UserProfile, UpdateProfileRequest, VerifyEmailRequest, VerifyMfaRequest, MEMBERSHIP_LEVELS},
+    oauth::{has_scope, OAuthServer},
+    oidc::ExternalIdentity,
+    tokens::{generate_opaque_token, generate_otp_code, hash_otp_code, hash_token, pkce_challenge, ACCOUNT_UNLOCK_TTL_HOURS, API_TOKEN_DEFAULT_TTL_DAYS, API_TOKEN_MAX_TTL_DAYS, API_TOKEN_PREFIX, EMAIL_VERIFICATION_TTL_HOURS, MAGIC_LINK_TTL_MINUTES, MFA_CHALLENGE_TTL_MINUTES, OAUTH_CODE_TTL_MINUTES, OIDC_LOGIN_TTL_MINUTES, PASSKEY_CHALLENGE_TTL_MINUTES, PASSWORD_RESET_TTL_MINUTES, PHONE_OTP_MAX_ATTEMPTS, PHONE_OTP_RESEND_SECONDS, PHONE_OTP_TTL_MINUTES, REFRESH_TOKEN_TTL_DAYS},
+    webauthn,
     AppState,
 };
 
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 100;
+
+/// Refuse to issue tokens for accounts an admin has locked.
+fn ensure_can_sign_in(user: &User) -> Result<(), AppError> {
+    if user.disabled_at.is_some() {
+        return Err(AppError::forbidden("account_disabled", "This account has been disabled"));
+    }
+    if user.password_reset_required {
+        return Err(AppError::forbidden(
+            "password_reset_required",
+            "You must reset your password before signing in",
+        ));
+    }
+    Ok(())
+}
+
+/// Re-authenticate before a sensitive change, even though the caller holds a valid token.
+fn verify_current_password(state: &AppState, user: &User, password: &str) -> Result<(), AppError> {
+    if !state.password_hasher.verify(password, &user.password_hash)? {
+        return Err(AppError::forbidden("invalid_password", "Current password is incorrect"));
+    }
+    Ok(())
+}
+
+/// Rehash a just-verified password if its stored hash predates the current
+/// policy. A failure is only logged; the old hash keeps working.
+async fn upgrade_password_hash(state: &AppState, user: &User, password: &str) {
+    if !state.password_hasher.needs_rehash(&user.password_hash) {
+        return;
+    }
+
+    let result = match state.password_hasher.hash(password) {
+        Ok(new_hash) => state.user_repo.rehash_password(&user.id, &user.password_hash, &new_hash).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        tracing::warn!(error = %e, user_id = %user.id, "failed to upgrade password hash");
+    }
+}
+
+/// Sign the user out everywhere: revoke their refresh tokens, their API
+/// tokens and every access token issued to them so far.
+async fn revoke_all_sessions(state: &AppState, user_id: &str) -> Result<(), AppError> {
+    state
+        .session_repo
+        .revoke_all_for_user(user_id, None)
+        .await
+        .context("Failed to revoke sessions")?;
+    state
+        .api_token_repo
+        .revoke_all_for_user(user_id)
+        .await
+        .context("Failed to revoke API tokens")?;
+    state
+        .jwt_service
+        .revoke_all_for_user(user_id)
+        .await
+        .context("Failed to revoke access tokens")?;
+
+    Ok(())
+}
+
+/// Send `user` a fresh verification link, invalidating any earlier one.
+async fn send_verification_email(state: &AppState, user: &User) -> Result<(), AppError> {
+    state
+        .one_time_token_repo
+        .invalidate(&user.id, TokenPurpose::EmailVerification)
+        .await
+        .context("Failed to invalidate verification tokens")?;
+
+    let token = generate_opaque_token();
+    let expires_at = Utc::now() + Duration::hours(EMAIL_VERIFICATION_TTL_HOURS);
+    state
+        .one_time_token_repo
+        .create(&user.id, TokenPurpose::EmailVerification, &user.email, &hash_token(&token), expires_at)
+        .await
+        .context("Failed to store verification token")?;
+
+    state
+        .mail
+        .send_email_verification(&user.email, &token)
+        .await
+        .context("Failed to send verification email")?;
+
+    Ok(())
+}
+
+/// Issue an access token plus a refresh token. A `session_id` of `None`
+/// starts a new session (a fresh login) on the `client`'s device; rotation
+/// passes the existing session, which is also its refresh token family.
+async fn issue_auth_response(
+    state: &AppState,
+    user: &User,
+    session_id: Option<&str>,
+    client: &ClientInfo,
+) -> Result<AuthResponse, AppError> {
+    let expires_at = Utc::now() + Duration::days(REFRESH_TOKEN_TTL_DAYS);
+    let ip_address = client.ip.to_string();
+    let session_id = match session_id {
+        Some(session_id) => {
+            state
+                .session_repo
+                .record_refresh(session_id, &ip_address, expires_at)
+                .await
+                .context("Failed to update session")?;
+            session_id.to_string()
+        }
+        None => {
+            state
+                .session_repo
+                .create(&user.id, client.user_agent.as_deref(), &ip_address, expires_at)
+                .await
+                .context("Failed to create session")?
+                .id
+        }
+    };
+
+    // Generate JWT token
+    let token = state
+        .jwt_service
+        .create_user_token(user, &session_id)
+        .context("Failed to generate token")?;
+
+    // Generate and store refresh token
+    let refresh_token = generate_opaque_token();
+    state
+        .refresh_token_repo
+        .create(&user.id, &session_id, &hash_token(&refresh_token), expires_at)
+        .await
+        .context("Failed to store refresh token")?;
+
+    Ok(AuthResponse {
+        token,
+        refresh_token,
+        user_id: user.id.clone(),
+        email: user.email.clone(),
+    })
+}
+
+/// Count a failed sign-in. If it locked the account, email the owner an
+/// unlock link and return the `423` for this attempt.
+async fn record_login_failure(state: &AppState, email: &str, ip: IpAddr) -> Result<(), AppError> {
+    if !state.login_guard.record_failure(email, ip).await? {
+        return Ok(());
+    }
+
+    let locked = state.login_guard.locked_error();
+
+    // In the background, like `forgot_password`, so the response time does
+    // not reveal whether the account exists
+    let (state, email) = (state.clone(), email.to_string());
+    tokio::spawn(async move {
+        if let Err(e) = send_unlock_email(&state, &email).await {
+            tracing::warn!(error = ?e, "failed to send account unlock email");
+        }
+    });
+
+    Err(locked)
+}
+
+async fn send_unlock_email(state: &AppState, email: &str) -> anyhow::Result<()> {
+    let Some(user) = state.user_repo.find_by_email(email).await? else {
+        return Ok(());
+    };
+    tracing::warn!(user_id = %user.id, "account locked after repeated failed sign-ins");
+
+    state
+        .one_time_token_repo
+        .invalidate(&user.id, TokenPurpose::AccountUnlock)
+        .await?;
+
+    let token = generate_opaque_token();
+    let expires_at = Utc::now() + Duration::hours(ACCOUNT_UNLOCK_TTL_HOURS);
+    state
+        .one_time_token_repo
+        .create(&user.id, TokenPurpose::AccountUnlock, &user.email, &hash_token(&token), expires_at)
+        .await?;
+
+    state
+        .mail
+        .send_account_unlock(&user.email, &token, state.login_guard.lockout_minutes())
+        .await
+}
+
+/// Whether the user has a confirmed authenticator, so signing in takes a second step.
+async fn mfa_enabled(state: &AppState, user_id: &str) -> Result<bool, AppError> {
+    let totp = state
+        .mfa_repo
+        .find_totp(user_id)
+        .await
+        .context("Failed to load authenticator")?;
+
+    Ok(totp.is_some_and(|totp| totp.confirmed_at.is_some()))
+}
+
+/// Start the second step of a sign-in, to be completed at `/auth/mfa/verify`.
+async fn start_mfa_challenge(state: &AppState, user: &User) -> Result<MfaChallenge, AppError> {
+    let mfa_token = generate_opaque_token();
+    let expires_at = Utc::now() + Duration::minutes(MFA_CHALLENGE_TTL_MINUTES);
+    state
+        .one_time_token_repo
+        .create(&user.id, TokenPurpose::MfaChallenge, &user.email, &hash_token(&mfa_token), expires_at)
+        .await
+        .context("Failed to store MFA challenge")?;
+
+    Ok(MfaChallenge {
+        mfa_required: true,
+        mfa_token,
+        expires_in: MFA_CHALLENGE_TTL_MINUTES * 60,
+    })
+}
+
+/// Check a code from the user's confirmed authenticator. An accepted code is
+/// used up and will not work again.
+async fn use_totp_code(state: &AppState, user_id: &str, code: &str) -> Result<bool, AppError> {
+    let totp = state
+        .mfa_repo
+        .find_totp(user_id)
+        .await
+        .context("Failed to load authenticator")?;
+    let Some(totp) = totp.filter(|totp| totp.confirmed_at.is_some()) else {
+        return Ok(false);
+    };
+    let Some(step) = mfa::verify_code(&totp.secret, code, totp.last_used_step)? else {
+        return Ok(false);
+    };
+
+    let used = state
+        .mfa_repo
+        .use_totp_step(user_id, step)
+        .await
+        .context("Failed to record authenticator code")?;
+    Ok(used)
+}
+
+/// Replace the user's recovery codes with a new set, returned in plain text this once.
+async fn issue_recovery_codes(state: &AppState, user_id: &str) -> Result<RecoveryCodes, AppError> {
+    let recovery_codes = mfa::generate_recovery_codes();
+    let code_hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| hash_token(&normalize_recovery_code(code)))
+        .collect();
+
+    state
+        .mfa_repo
+        .replace_recovery_codes(user_id, &code_hashes)
+        .await
+        .context("Failed to store recovery codes")?;
+
+    Ok(RecoveryCodes { recovery_codes })
+}
+
 /// Register a new user
 #[utoipa::path(
     post,
     path = "/auth/register",
+    tag = "auth",
     request_body = RegisterRequest,
     responses(
         (status = 201, description = "User registered successfully", body = AuthResponse),
@@ -27,202 +290,331 @@ use crate::{
 )]
 pub async fn register(
     State(state): State<AppState>,
+    client: ClientInfo,
     Json(payload): Json<RegisterRequest>,
-) -> Result<(StatusCode, ResponseJson<AuthResponse>), (StatusCode, ResponseJson<ErrorResponse>)> {
+) -> Result<(StatusCode, ResponseJson<AuthResponse>), AppError> {
     // Validate input
     if payload.email.is_empty() || payload.password.is_empty() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            ResponseJson(ErrorResponse {
-                error: "validation_error".to_string(),
-                message: "Email and password are required".to_string(),
-            }),
-        ));
+        return Err(AppError::validation("Email and password are required"));
     }
 
-    if payload.password.len() < 6 {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            ResponseJson(ErrorResponse {
-                error: "validation_error".to_string(),
-                message: "Password must be at least 6 characters long".to_string(),
-            }),
-        ));
+    if payload.email.parse::<lettre::Address>().is_err() {
+        return Err(AppError::validation("Email address is not valid"));
     }
 
+    state.password_policy.check("password", &payload.password, &payload.email)?;
+
     // Check if user already exists
-    match state.user_repo.find_by_email(&payload.email).await {
-        Ok(Some(_)) => {
-            return Err((
-                StatusCode::CONFLICT,
-                ResponseJson(ErrorResponse {
-                    error: "email_exists".to_string(),
-                    message: "Email already exists".to_string(),
-                }),
-            ));
-        }
-        Ok(None) => {}
-        Err(_) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                ResponseJson(ErrorResponse {
-                    error: "database_error".to_string(),
-                    message: "Failed to check existing user".to_string(),
-                }),
-            ));
-        }
+    let existing = state
+        .user_repo
+        .find_by_email(&payload.email)
+        .await
+        .context("Failed to check existing user")?;
+    if existing.is_some() {
+        return Err(AppError::conflict("email_exists", "Email already exists"));
     }
 
     // Hash password
-    let password_hash = match hash(payload.password, DEFAULT_COST) {
-        Ok(hash) => hash,
-        Err(_) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                ResponseJson(ErrorResponse {
-                    error: "hash_error".to_string(),
-                    message: "Failed to hash password".to_string(),
-                }),
-            ));
-        }
-    };
+    let password_hash = state.password_hasher.hash(&payload.password)?;
 
-    // Create user
+    // Create user. A concurrent registration can still win the race for the email.
     let user = match state.user_repo.create_user(&payload.email, &password_hash).await {
         Ok(user) => user,
-        Err(_) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                ResponseJson(ErrorResponse {
-                    error: "database_error".to_string(),
-                    message: "Failed to create user".to_string(),
-                }),
-            ));
+        Err(e) => {
+            return Err(match AppError::from(e.context("Failed to create user")) {
+                AppError::Conflict { .. } => AppError::conflict("email_exists", "Email already exists"),
+                other => other,
+            });
         }
     };
 
-    // Generate JWT token
-    let token = match state.jwt_service.create_token(&user.id, &user.email) {
-        Ok(token) => token,
-        Err(_) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                ResponseJson(ErrorResponse {
-                    error: "token_error".to_string(),
-                    message: "Failed to generate token".to_string(),
-                }),
-            ));
-        }
-    };
+    // The account exists either way; the user can ask for another link
+    if let Err(e) = send_verification_email(&state, &user).await {
+        tracing::warn!(user_id = %user.id, error = ?e, "failed to send verification email");
+    }
 
-    Ok((
-        StatusCode::CREATED,
-        ResponseJson(AuthResponse {
-            token,
-            user_id: user.id,
-            email: user.email,
-        }),
-    ))
+    let response = issue_auth_response(&state, &user, None, &client).await?;
+
+    Ok((StatusCode::CREATED, ResponseJson(response)))
 }
 
 /// Login user
 #[utoipa::path(
     post,
     path = "/auth/login",
+    tag = "auth",
     request_body = LoginRequest,
     responses(
-        (status = 200, description = "Login successful", body = AuthResponse),
+        (status = 200, description = "Login successful, or a second factor is needed (`mfa_required`)", body = LoginResponse),
         (status = 400, description = "Bad request", body = ErrorResponse),
-        (status = 401, description = "Invalid credentials", body = ErrorResponse)
+        (status = 401, description = "Invalid credentials", body = ErrorResponse),
+        (status = 403, description = "Account disabled or password reset required", body = ErrorResponse),
+        (status = 423, description = "Account locked after too many failed attempts", body = ErrorResponse),
+        (status = 429, description = "Too many failed attempts; wait `retry_after` seconds", body = ErrorResponse)
     )
 )]
 pub async fn login(
     State(state): State<AppState>,
+    client: ClientInfo,
     Json(payload): Json<LoginRequest>,
-) -> Result<ResponseJson<AuthResponse>, (StatusCode, ResponseJson<ErrorResponse>)> {
+) -> Result<ResponseJson<LoginResponse>, AppError> {
     // Validate input
     if payload.email.is_empty() || payload.password.is_empty() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            ResponseJson(ErrorResponse {
-                error: "validation_error".to_string(),
-                message: "Email and password are required".to_string(),
-            }),
-        ));
+        return Err(AppError::validation("Email and password are required"));
     }
 
+    let invalid_credentials = || AppError::unauthorized("invalid_credentials", "Invalid email or password");
+
+    state.login_guard.check(&payload.email, client.ip).await?;
+
     // Find user by email
-    let user = match state.user_repo.find_by_email(&payload.email).await {
-        Ok(Some(user)) => user,
-        Ok(None) => {
-            return Err((
-                StatusCode::UNAUTHORIZED,
-                ResponseJson(ErrorResponse {
-                    error: "invalid_credentials".to_string(),
-                    message: "Invalid email or password".to_string(),
-                }),
-            ));
-        }
-        Err(_) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                ResponseJson(ErrorResponse {
-                    error: "database_error".to_string(),
-                    message: "Failed to find user".to_string(),
-                }),
-            ));
+    let user = state
+        .user_repo
+        .find_by_email(&payload.email)
+        .await
+        .context("Failed to find user")?;
+
+    // Verify password. Unknown emails count as failures too, so lockouts do
+    // not reveal which accounts exist.
+    let user = match user {
+        Some(user) if state.password_hasher.verify(&payload.password, &user.password_hash)? => user,
+        _ => {
+            record_login_failure(&state, &payload.email, client.ip).await?;
+            return Err(invalid_credentials());
         }
     };
 
-    // Verify password
-    let is_valid = match verify(&payload.password, &user.password_hash) {
-        Ok(valid) => valid,
-        Err(_) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                ResponseJson(ErrorResponse {
-                    error: "verification_error".to_string(),
-                    message: "Failed to verify password".to_string(),
-                }),
-            ));
-        }
+    // Only reveal the account state to someone who knows the password
+    ensure_can_sign_in(&user)?;
+
+    upgrade_password_hash(&state, &user, &payload.password).await;
+
+    if mfa_enabled(&state, &user.id).await? {
+        return Ok(ResponseJson(LoginResponse::MfaRequired(start_mfa_challenge(&state, &user).await?)));
+    }
+
+    state.login_guard.clear_account(&payload.email).await?;
+    let response = issue_auth_response(&state, &user, None, &client).await?;
+
+    Ok(ResponseJson(LoginResponse::Authenticated(response)))
+}
+
+/// Complete a login with a second factor
+///
+/// Takes the `mfa_token` returned by `/auth/login` and either a code from the
+/// authenticator app or a recovery code. Each code works once. The token
+/// stops working after a few wrong codes; sign in again to get a new one.
+/// Wrong codes also count towards locking the account.
+#[utoipa::path(
+    post,
+    path = "/auth/mfa/verify",
+    tag = "auth",
+    request_body = VerifyMfaRequest,
+    responses(
+        (status = 200, description = "Login successful", body = AuthResponse),
+        (status = 400, description = "Neither or both of code and recovery_code given", body = ErrorResponse),
+        (status = 401, description = "Invalid or expired MFA token, or wrong code", body = ErrorResponse),
+        (status = 403, description = "Account disabled or password reset required", body = ErrorResponse),
+        (status = 423, description = "Account locked after too many failed attempts", body = ErrorResponse),
+        (status = 429, description = "Too many failed attempts; wait `retry_after` seconds", body = ErrorResponse)
+    )
+)]
+pub async fn verify_mfa(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<VerifyMfaRequest>,
+) -> Result<ResponseJson<AuthResponse>, AppError> {
+    let invalid_mfa_token = || AppError::unauthorized("invalid_mfa_token", "MFA token is invalid or has expired; sign in again");
+
+    let token_hash = hash_token(&payload.mfa_token);
+    let challenge = state
+        .one_time_token_repo
+        .find_active(TokenPurpose::MfaChallenge, &token_hash)
+        .await
+        .context("Failed to load MFA challenge")?
+        .ok_or_else(invalid_mfa_token)?;
+
+    state.login_guard.check(&challenge.email, client.ip).await?;
+
+    let accepted = match (payload.code.as_deref(), payload.recovery_code.as_deref()) {
+        (Some(code), None) => use_totp_code(&state, &challenge.user_id, code).await?,
+        (None, Some(recovery_code)) => state
+            .mfa_repo
+            .use_recovery_code(&challenge.user_id, &hash_token(&normalize_recovery_code(recovery_code)))
+            .await
+            .context("Failed to use recovery code")?,
+        _ => return Err(AppError::validation("Provide either code or recovery_code")),
     };
 
-    if !is_valid {
-        return Err((
-            StatusCode::UNAUTHORIZED,
-            ResponseJson(ErrorResponse {
-                error: "invalid_credentials".to_string(),
-                message: "Invalid email or password".to_string(),
-            }),
+    if !accepted {
+        state
+            .one_time_token_repo
+            .record_failed_attempt(&challenge.id, MFA_CHALLENGE_MAX_ATTEMPTS)
+            .await
+            .context("Failed to record MFA attempt")?;
+        record_login_failure(&state, &challenge.email, client.ip).await?;
+        return Err(AppError::unauthorized("invalid_mfa_code", "Authentication code is incorrect"));
+    }
+
+    // A concurrent request may have completed the challenge first
+    state
+        .one_time_token_repo
+        .consume(TokenPurpose::MfaChallenge, &token_hash)
+        .await
+        .context("Failed to consume MFA challenge")?
+        .ok_or_else(invalid_mfa_token)?;
+
+    let user = state
+        .user_repo
+        .find_by_id(&challenge.user_id)
+        .await
+        .context("Failed to find user")?
+        .ok_or_else(invalid_mfa_token)?;
+    ensure_can_sign_in(&user)?;
+
+    state.login_guard.clear_account(&challenge.email).await?;
+    let response = issue_auth_response(&state, &user, None, &client).await?;
+
+    Ok(ResponseJson(response))
+}
+
+/// Exchange a refresh token for a new access token and a rotated refresh token
+#[utoipa::path(
+    post,
+    path = "/auth/refresh",
+    tag = "auth",
+    request_body = RefreshRequest,
+    responses(
+        (status = 200, description = "Tokens refreshed successfully", body = AuthResponse),
+        (status = 400, description = "Bad request", body = ErrorResponse),
+        (status = 401, description = "Invalid, expired or reused refresh token", body = ErrorResponse),
+        (status = 403, description = "Account disabled or password reset required", body = ErrorResponse)
+    )
+)]
+pub async fn refresh(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<RefreshRequest>,
+) -> Result<ResponseJson<AuthResponse>, AppError> {
+    // Validate input
+    if payload.refresh_token.is_empty() {
+        return Err(AppError::validation("Refresh token is required"));
+    }
+
+    let invalid_token = || AppError::unauthorized("invalid_refresh_token", "Invalid or expired refresh token");
+
+    // Find stored token by hash
+    let stored = state
+        .refresh_token_repo
+        .find_by_hash(&hash_token(&payload.refresh_token))
+        .await
+        .context("Failed to find refresh token")?
+        .ok_or_else(invalid_token)?;
+
+    if stored.revoked_at.is_some() || stored.expires_at <= Utc::now() {
+        return Err(invalid_token());
+    }
+
+    // A token that was already rotated is being replayed: assume it was stolen
+    // and revoke every token descended from the same login.
+    let first_use = state
+        .refresh_token_repo
+        .mark_used(&stored.id)
+        .await
+        .context("Failed to rotate refresh token")?;
+
+    if !first_use {
+        state
+            .session_repo
+            .revoke(&stored.user_id, &stored.family_id)
+            .await
+            .context("Failed to revoke session")?;
+
+        return Err(AppError::unauthorized(
+            "refresh_token_reused",
+            "Refresh token has already been used; all sessions from this login were revoked",
         ));
     }
 
-    // Generate JWT token
-    let token = match state.jwt_service.create_token(&user.id, &user.email) {
-        Ok(token) => token,
-        Err(_) => {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                ResponseJson(ErrorResponse {
-                    error: "token_error".to_string(),
-                    message: "Failed to generate token".to_string(),
-                }),
-            ));
+    // Look up the owner so the new access token carries the current email and role
+    let user = state
+        .user_repo
+        .find_by_id(&stored.user_id)
+        .await
+        .context("Failed to find user")?
+        .ok_or_else(invalid_token)?;
+
+    ensure_can_sign_in(&user)?;
+
+    let response = issue_auth_response(&state, &user, Some(&stored.family_id), &client).await?;
+
+    Ok(ResponseJson(response))
+}
+
+/// Logout: revoke the presented access token and, optionally, the session of a refresh token
+#[utoipa::path(
+    post,
+    path = "/auth/logout",
+    tag = "auth",
+    request_body(content = LogoutRequest, description = "Optional refresh token to revoke as well"),
+    responses(
+        (status = 204, description = "Logged out successfully"),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "The request used an API token", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn logout(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    payload: Option<Json<LogoutRequest>>,
+) -> Result<StatusCode, AppError> {
+    // Deny the access token until it expires
+    state
+        .jwt_service
+        .revoke_token(&auth.claims)
+        .await
+        .context("Failed to revoke token")?;
+
+    // End the refresh token's session, but only if it belongs to this user
+    let refresh_token = payload.and_then(|Json(payload)| payload.refresh_token);
+    if let Some(refresh_token) = refresh_token {
+        let stored = state
+            .refresh_token_repo
+            .find_by_hash(&hash_token(&refresh_token))
+            .await
+            .context("Failed to find refresh token")?;
+
+        if let Some(stored) = stored {
+            state
+                .session_repo
+                .revoke(&auth.user_id, &stored.family_id)
+                .await
+                .context("Failed to revoke session")?;
         }
-    };
+    }
 
-    Ok(ResponseJson(AuthResponse {
-        token,
-        user_id: user.id,
-        email: user.email,
-    }))
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Public keys for verifying access and ID tokens
+#[utoipa::path(
+    get,
+    path = "/.well-known/jwks.json",
+    tag = "auth",
+    responses(
+        (status = 200, description = "JSON Web Key Set", body = JwkSet)
+    )
+)]
+pub async fn jwks(State(state): State<AppState>) -> ResponseJson<JwkSet> {
+    ResponseJson(state.jwt_service.jwks())
 }
 
 /// Get user profile
 #[utoipa::path(
     get,
     path = "/profile",
+    tag = "profile",
     responses(
         (status = 200, description = "Profile retrieved successfully", body = UserProfile),
         (status = 401, description = "Unauthorized", body = ErrorResponse),
@@ -232,46 +624,24 @@ pub async fn login(
 )]
 pub async fn get_profile(
     State(state): State<AppState>,
-    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
-) -> Result<ResponseJson<UserProfile>, (StatusCode, ResponseJson<ErrorResponse>)> {
-    // Verify JWT token
-    let claims = match state.jwt_service.verify_token(authorization.token()) {
-        Ok(claims) => claims,
-        Err(_) => {
-            return Err((
-                StatusCode::UNAUTHORIZED,
-                ResponseJson(ErrorResponse {
-                    error: "invalid_token".to_string(),
-                    message: "Invalid or expired token".to_string(),
-                }),
-            ));
-        }
-    };
-
+    auth: AuthUser,
+) -> Result<ResponseJson<UserProfile>, AppError> {
     // Get user profile
-    match state.user_repo.get_profile(&claims.sub).await {
-        Ok(Some(profile)) => Ok(ResponseJson(profile)),
-        Ok(None) => Err((
-            StatusCode::NOT_FOUND,
-            ResponseJson(ErrorResponse {
-                error: "user_not_found".to_string(),
-                message: "User not found".to_string(),
-            }),
-        )),
-        Err(_) => Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            ResponseJson(ErrorResponse {
-                error: "database_error".to_string(),
-                message: "Failed to retrieve profile".to_string(),
-            }),
-        )),
-    }
+    let profile = state
+        .user_repo
+        .get_profile(&auth.user_id)
+        .await
+        .context("Failed to retrieve profile")?
+        .ok_or_else(|| AppError::not_found("user_not_found", "User not found"))?;
+
+    Ok(ResponseJson(profile))
 }
 
 /// Update user profile
 #[utoipa::path(
     put,
     path = "/profile",
+    tag = "profile",
     request_body = UpdateProfileRequest,
     responses(
         (status = 200, description = "Profile updated successfully", body = UserProfile),
@@ -282,318 +652,4635 @@ pub async fn get_profile(
 )]
 pub async fn update_profile(
     State(state): State<AppState>,
-    TypedHeader(authorization): TypedHeader<Authorization<Bearer>>,
+    auth: AuthUser,
     Json(payload): Json<UpdateProfileRequest>,
-) -> Result<ResponseJson<UserProfile>, (StatusCode, ResponseJson<ErrorResponse>)> {
-    // Verify JWT token
-    let claims = match state.jwt_service.verify_token(authorization.token()) {
-        Ok(claims) => claims,
-        Err(_) => {
-            return Err((
-                StatusCode::UNAUTHORIZED,
-                ResponseJson(ErrorResponse {
-                    error: "invalid_token".to_string(),
-                    message: "Invalid or expired token".to_string(),
-                }),
-            ));
-        }
-    };
-
+) -> Result<ResponseJson<UserProfile>, AppError> {
     // Update user profile
-    match state.user_repo.update_profile(&claims.sub, &payload).await {
-        Ok(Some(profile)) => Ok(ResponseJson(profile)),
-        Ok(None) => Err((
-            StatusCode::NOT_FOUND,
-            ResponseJson(ErrorResponse {
-                error: "user_not_found".to_string(),
-                message: "User not found".to_string(),
-            }),
-        )),
-        Err(_) => Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            ResponseJson(ErrorResponse {
-                error: "database_error".to_string(),
-                message: "Failed to update profile".to_string(),
-            }),
-        )),
+    let profile = state
+        .user_repo
+        .update_profile(&auth.user_id, &payload)
+        .await
+        .context("Failed to update profile")?
+        .ok_or_else(|| AppError::not_found("user_not_found", "User not found"))?;
+
+    Ok(ResponseJson(profile))
+}
+
+/// Send a fresh code for `purpose` to `phone` (E.164), replacing any earlier one.
+async fn send_phone_otp(state: &AppState, user_id: &str, purpose: PhoneOtpPurpose, phone: &str) -> anyhow::Result<()> {
+    let code = generate_otp_code();
+    let expires_at = Utc::now() + Duration::minutes(PHONE_OTP_TTL_MINUTES);
+    state
+        .phone_otp_repo
+        .create(user_id, purpose, phone, &hash_otp_code(phone, &code), expires_at)
+        .await?;
+
+    match purpose {
+        PhoneOtpPurpose::PhoneVerification => state.sms.send_phone_verification_code(phone, &code).await,
+        PhoneOtpPurpose::PhoneLogin => state.sms.send_login_code(phone, &code).await,
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::{
-        models::{LoginRequest, RegisterRequest},
-        test_helpers::create_test_app_state,
-    };
-    use axum::{
-        extract::{Json, State},
-        http::StatusCode,
+/// Seconds until the user may be sent another code for `purpose`, if they
+/// were sent one too recently.
+async fn phone_otp_retry_after(state: &AppState, user_id: &str, purpose: PhoneOtpPurpose) -> anyhow::Result<Option<u64>> {
+    let last_sent_at = state.phone_otp_repo.last_sent_at(user_id, purpose).await?;
+
+    Ok(last_sent_at
+        .map(|sent_at| (sent_at + Duration::seconds(PHONE_OTP_RESEND_SECONDS) - Utc::now()).num_seconds())
+        .filter(|seconds| *seconds > 0)
+        .map(|seconds| seconds as u64))
+}
+
+/// Check a code the user entered against their current code for `purpose`,
+/// sent to `phone`. A wrong code counts against the code's attempts; a right
+/// one is used up.
+async fn check_phone_otp(
+    state: &AppState,
+    user_id: &str,
+    purpose: PhoneOtpPurpose,
+    phone: &str,
+    code: &str,
+) -> Result<bool, AppError> {
+    let Some(otp) = state
+        .phone_otp_repo
+        .find_active(user_id, purpose)
+        .await
+        .context("Failed to load phone code")?
+    else {
+        return Ok(false);
     };
 
-    #[tokio::test]
-    async fn test_register_success() {
-        let app_state = create_test_app_state().await.unwrap();
-        
-        let request = RegisterRequest {
-            email: "test@example.com".to_string(),
-            password: "password123".to_string(),
-        };
+    // A code sent to a number the user has since replaced no longer works
+    if otp.phone != phone {
+        return Ok(false);
+    }
 
-        let result = register(State(app_state), Json(request)).await;
-        
-        assert!(result.is_ok());
-        let (status, response) = result.unwrap();
-        assert_eq!(status, StatusCode::CREATED);
-        assert_eq!(response.email, "test@example.com");
-        assert!(!response.token.is_empty());
-        assert!(!response.user_id.is_empty());
+    if hash_otp_code(phone, code.trim()) != otp.code_hash {
+        state
+            .phone_otp_repo
+            .record_failed_attempt(&otp.id, PHONE_OTP_MAX_ATTEMPTS)
+            .await
+            .context("Failed to record wrong phone code")?;
+        return Ok(false);
     }
 
-    #[tokio::test]
-    async fn test_register_empty_email() {
-        let app_state = create_test_app_state().await.unwrap();
-        
-        let request = RegisterRequest {
-            email: "".to_string(),
-            password: "password123".to_string(),
-        };
+    Ok(state
+        .phone_otp_repo
+        .consume(&otp.id)
+        .await
+        .context("Failed to use phone code")?)
+}
 
-        let result = register(State(app_state), Json(request)).await;
-        
-        assert!(result.is_err());
-        let (status, response) = result.unwrap_err();
-        assert_eq!(status, StatusCode::BAD_REQUEST);
-        assert_eq!(response.error, "validation_error");
-        assert_eq!(response.message, "Email and password are required");
-    }
+/// The phone number on the user's profile in E.164 form, and as entered.
+fn profile_phone(state: &AppState, profile: &UserProfile) -> Result<(String, String), AppError> {
+    let phone = profile
+        .phone
+        .as_deref()
+        .filter(|phone| !phone.trim().is_empty())
+        .ok_or_else(|| AppError::validation("Add a phone number to your profile first"))?;
+    let normalized = state
+        .sms
+        .normalize(phone)
+        .ok_or_else(|| AppError::validation("The phone number on your profile is not a valid number"))?;
 
-    #[tokio::test]
-    async fn test_register_empty_password() {
-        let app_state = create_test_app_state().await.unwrap();
-        
-        let request = RegisterRequest {
-            email: "test@example.com".to_string(),
-            password: "".to_string(),
-        };
+    Ok((normalized, phone.to_string()))
+}
 
-        let result = register(State(app_state), Json(request)).await;
-        
-        assert!(result.is_err());
-        let (status, response) = result.unwrap_err();
-        assert_eq!(status, StatusCode::BAD_REQUEST);
-        assert_eq!(response.error, "validation_error");
-        assert_eq!(response.message, "Email and password are required");
-    }
+/// Verify the profile's phone number
+///
+/// Texts a six-digit code to the phone number on the profile. Pass it to
+/// `/profile/phone/verify/confirm` within 5 minutes. A verified number can
+/// sign in with `/auth/phone/login`.
+#[utoipa::path(
+    post,
+    path = "/profile/phone/verify",
+    tag = "profile",
+    responses(
+        (status = 202, description = "Code sent"),
+        (status = 400, description = "No phone number on the profile, or not a valid one", body = ErrorResponse),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "The request used an API token", body = ErrorResponse),
+        (status = 409, description = "The number is already verified, by this or another account", body = ErrorResponse),
+        (status = 429, description = "A code was sent less than a minute ago", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn start_phone_verification(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> Result<StatusCode, AppError> {
+    let profile = state
+        .user_repo
+        .get_profile(&auth.user_id)
+        .await
+        .context("Failed to retrieve profile")?
+        .ok_or_else(user_not_found)?;
+    let (phone, _) = profile_phone(&state, &profile)?;
 
-    #[tokio::test]
-    async fn test_register_short_password() {
-        let app_state = create_test_app_state().await.unwrap();
-        
-        let request = RegisterRequest {
-            email: "test@example.com".to_string(),
-            password: "123".to_string(),
-        };
+    if profile.phone_verified_at.is_some() {
+        return Err(AppError::conflict("phone_already_verified", "Phone number is already verified"));
+    }
+    let owner = state
+        .user_repo
+        .find_by_verified_phone(&phone)
+        .await
+        .context("Failed to look up phone number")?;
+    if owner.is_some() {
+        return Err(AppError::conflict("phone_exists", "Another account already uses this phone number"));
+    }
 
-        let result = register(State(app_state), Json(request)).await;
-        
-        assert!(result.is_err());
-        let (status, response) = result.unwrap_err();
-        assert_eq!(status, StatusCode::BAD_REQUEST);
-        assert_eq!(response.error, "validation_error");
-        assert_eq!(response.message, "Password must be at least 6 characters long");
+    let purpose = PhoneOtpPurpose::PhoneVerification;
+    if let Some(retry_after) = phone_otp_retry_after(&state, &auth.user_id, purpose)
+        .await
+        .context("Failed to look up phone codes")?
+    {
+        return Err(AppError::too_many_requests(
+            "otp_recently_sent",
+            "A code was sent moments ago; wait before asking for another",
+            retry_after,
+        ));
     }
 
-    #[tokio::test]
-    async fn test_register_duplicate_email() {
-        let app_state = create_test_app_state().await.unwrap();
-        
-        // First registration
-        let request1 = RegisterRequest {
-            email: "test@example.com".to_string(),
-            password: "password123".to_string(),
-        };
-        let _ = register(State(app_state.clone()), Json(request1)).await.unwrap();
+    send_phone_otp(&state, &auth.user_id, purpose, &phone)
+        .await
+        .context("Failed to send verification code")?;
 
-        // Second registration with same email
-        let request2 = RegisterRequest {
-            email: "test@example.com".to_string(),
-            password: "password456".to_string(),
-        };
-        let result = register(State(app_state), Json(request2)).await;
-        
-        assert!(result.is_err());
-        let (status, response) = result.unwrap_err();
-        assert_eq!(status, StatusCode::CONFLICT);
-        assert_eq!(response.error, "email_exists");
-        assert_eq!(response.message, "Email already exists");
-    }
+    Ok(StatusCode::ACCEPTED)
+}
 
-    #[tokio::test]
-    async fn test_login_success() {
-        let app_state = create_test_app_state().await.unwrap();
-        
-        // First register a user
+/// Confirm the profile's phone number
+///
+/// Checks the code from `/profile/phone/verify`. The number is stored in
+/// international form (`+66812345678`) once verified. Five wrong codes use the
+/// code up.
+#[utoipa::path(
+    post,
+    path = "/profile/phone/verify/confirm",
+    tag = "profile",
+    request_body = ConfirmPhoneRequest,
+    responses(
+        (status = 200, description = "Phone number verified", body = UserProfile),
+        (status = 400, description = "Wrong, expired or used-up code", body = ErrorResponse),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "The request used an API token", body = ErrorResponse),
+        (status = 409, description = "Another account verified the number first", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn confirm_phone_verification(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<ConfirmPhoneRequest>,
+) -> Result<ResponseJson<UserProfile>, AppError> {
+    let invalid_code = || AppError::bad_request("invalid_otp", "The code is wrong or has expired");
+
+    let profile = state
+        .user_repo
+        .get_profile(&auth.user_id)
+        .await
+        .context("Failed to retrieve profile")?
+        .ok_or_else(user_not_found)?;
+    let (phone, entered_phone) = profile_phone(&state, &profile).map_err(|_| invalid_code())?;
+
+    let purpose = PhoneOtpPurpose::PhoneVerification;
+    if !check_phone_otp(&state, &auth.user_id, purpose, &phone, &payload.code).await? {
+        return Err(invalid_code());
+    }
+
+    let verified = match state.user_repo.mark_phone_verified(&auth.user_id, &entered_phone, &phone).await {
+        Ok(verified) => verified,
+        Err(e) => {
+            return Err(match AppError::from(e.context("Failed to mark phone verified")) {
+                AppError::Conflict { .. } => AppError::conflict("phone_exists", "Another account already uses this phone number"),
+                other => other,
+            });
+        }
+    };
+    if !verified {
+        return Err(invalid_code());
+    }
+
+    let profile = state
+        .user_repo
+        .get_profile(&auth.user_id)
+        .await
+        .context("Failed to retrieve profile")?
+        .ok_or_else(user_not_found)?;
+
+    Ok(ResponseJson(profile))
+}
+
+/// Change password
+///
+/// Requires the current password. Every session is signed out; the response
+/// carries new tokens so this client stays signed in.
+#[utoipa::path(
+    put,
+    path = "/profile/password",
+    tag = "profile",
+    request_body = ChangePasswordRequest,
+    responses(
+        (status = 200, description = "Password changed; use the new tokens", body = AuthResponse),
+        (status = 400, description = "New password is too weak", body = ErrorResponse),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "Current password is incorrect", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn change_password(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    client: ClientInfo,
+    Json(payload): Json<ChangePasswordRequest>,
+) -> Result<ResponseJson<AuthResponse>, AppError> {
+    let user = state
+        .user_repo
+        .find_by_id(&auth.user_id)
+        .await
+        .context("Failed to find user")?
+        .ok_or_else(user_not_found)?;
+    state.password_policy.check("new_password", &payload.new_password, &user.email)?;
+    verify_current_password(&state, &user, &payload.current_password)?;
+
+    let password_hash = state.password_hasher.hash(&payload.new_password)?;
+    state
+        .user_repo
+        .update_password(&user.id, &password_hash)
+        .await
+        .context("Failed to update password")?;
+
+    revoke_all_sessions(&state, &user.id).await?;
+
+    let response = issue_auth_response(&state, &user, None, &client).await?;
+
+    Ok(ResponseJson(response))
+}
+
+/// Change email address
+///
+/// Requires the current password. A confirmation link is sent to the new
+/// address and a notice to the current one; the address only changes once the
+/// link is used (see `/auth/confirm-email-change`).
+#[utoipa::path(
+    post,
+    path = "/profile/email",
+    tag = "profile",
+    request_body = ChangeEmailRequest,
+    responses(
+        (status = 202, description = "Confirmation email sent to the new address"),
+        (status = 400, description = "Invalid email address", body = ErrorResponse),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "Current password is incorrect", body = ErrorResponse),
+        (status = 409, description = "Email already exists", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn change_email(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<ChangeEmailRequest>,
+) -> Result<StatusCode, AppError> {
+    if payload.new_email.parse::<lettre::Address>().is_err() {
+        return Err(AppError::validation("Email address is not valid"));
+    }
+
+    let user = state
+        .user_repo
+        .find_by_id(&auth.user_id)
+        .await
+        .context("Failed to find user")?
+        .ok_or_else(user_not_found)?;
+    verify_current_password(&state, &user, &payload.current_password)?;
+
+    if payload.new_email == user.email {
+        return Err(AppError::validation("This is already your email address"));
+    }
+    let existing = state
+        .user_repo
+        .find_by_email(&payload.new_email)
+        .await
+        .context("Failed to check existing user")?;
+    if existing.is_some() {
+        return Err(AppError::conflict("email_exists", "Email already exists"));
+    }
+
+    state
+        .one_time_token_repo
+        .invalidate(&user.id, TokenPurpose::EmailChange)
+        .await
+        .context("Failed to invalidate email change tokens")?;
+
+    let token = generate_opaque_token();
+    let expires_at = Utc::now() + Duration::hours(EMAIL_VERIFICATION_TTL_HOURS);
+    state
+        .one_time_token_repo
+        .create(&user.id, TokenPurpose::EmailChange, &payload.new_email, &hash_token(&token), expires_at)
+        .await
+        .context("Failed to store email change token")?;
+
+    state
+        .mail
+        .send_email_change_confirmation(&payload.new_email, &token)
+        .await
+        .context("Failed to send email change confirmation")?;
+
+    if let Err(e) = state.mail.send_email_change_notice(&user.email, &payload.new_email).await {
+        tracing::warn!(user_id = %user.id, error = ?e, "failed to send email change notice");
+    }
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Confirm an email address change
+///
+/// Uses the token from the link sent to the new address. The new address counts
+/// as verified, and every session is signed out.
+#[utoipa::path(
+    post,
+    path = "/auth/confirm-email-change",
+    tag = "auth",
+    request_body = ConfirmEmailChangeRequest,
+    responses(
+        (status = 204, description = "Email address changed"),
+        (status = 400, description = "Invalid, expired or already used token", body = ErrorResponse),
+        (status = 409, description = "The address was taken by another account in the meantime", body = ErrorResponse)
+    )
+)]
+pub async fn confirm_email_change(
+    State(state): State<AppState>,
+    Json(payload): Json<ConfirmEmailChangeRequest>,
+) -> Result<StatusCode, AppError> {
+    let invalid_token = || AppError::validation("Email change link is invalid or has expired");
+
+    if payload.token.is_empty() {
+        return Err(invalid_token());
+    }
+
+    let token = state
+        .one_time_token_repo
+        .consume(TokenPurpose::EmailChange, &hash_token(&payload.token))
+        .await
+        .context("Failed to consume email change token")?
+        .ok_or_else(invalid_token)?;
+
+    let user = state
+        .user_repo
+        .find_by_id(&token.user_id)
+        .await
+        .context("Failed to find user")?
+        .ok_or_else(invalid_token)?;
+
+    if let Err(e) = state.user_repo.update_email(&user.id, &token.email).await {
+        return Err(match AppError::from(e.context("Failed to update email")) {
+            AppError::Conflict { .. } => AppError::conflict("email_exists", "Email already exists"),
+            other => other,
+        });
+    }
+
+    // Tokens carry the old address
+    revoke_all_sessions(&state, &user.id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Get two-factor authentication status
+#[utoipa::path(
+    get,
+    path = "/profile/mfa",
+    tag = "profile",
+    responses(
+        (status = 200, description = "Two-factor authentication status", body = MfaStatus),
+        (status = 401, description = "Unauthorized", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn get_mfa_status(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> Result<ResponseJson<MfaStatus>, AppError> {
+    let totp_enabled = mfa_enabled(&state, &auth.user_id).await?;
+    let recovery_codes_remaining = state
+        .mfa_repo
+        .count_unused_recovery_codes(&auth.user_id)
+        .await
+        .context("Failed to count recovery codes")?;
+
+    Ok(ResponseJson(MfaStatus {
+        totp_enabled,
+        recovery_codes_remaining,
+    }))
+}
+
+/// Start setting up an authenticator app
+///
+/// Returns a new secret as text, as an `otpauth://` URI and as a QR code.
+/// Two-factor authentication is not enforced until the setup is confirmed
+/// with a code at `/profile/mfa/totp/confirm`. Calling this again before then
+/// replaces the secret.
+#[utoipa::path(
+    post,
+    path = "/profile/mfa/totp",
+    tag = "profile",
+    responses(
+        (status = 200, description = "Secret generated", body = TotpEnrollment),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 409, description = "Two-factor authentication is already enabled", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn start_totp_enrollment(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> Result<ResponseJson<TotpEnrollment>, AppError> {
+    let user = state
+        .user_repo
+        .find_by_id(&auth.user_id)
+        .await
+        .context("Failed to find user")?
+        .ok_or_else(user_not_found)?;
+
+    let secret = mfa::generate_secret();
+    let started = state
+        .mfa_repo
+        .start_totp_enrollment(&user.id, &secret)
+        .await
+        .context("Failed to store authenticator secret")?;
+    if !started {
+        return Err(AppError::conflict("mfa_already_enabled", "Two-factor authentication is already enabled"));
+    }
+
+    let otpauth_uri = mfa::otpauth_uri(&secret, &user.email)?;
+    let qr_code_svg = mfa::qr_code_svg(&otpauth_uri)?;
+
+    Ok(ResponseJson(TotpEnrollment {
+        secret,
+        otpauth_uri,
+        qr_code_svg,
+    }))
+}
+
+/// Finish setting up an authenticator app
+///
+/// Takes a current code from the app. On success two-factor authentication is
+/// enabled and a set of recovery codes is returned; they are not shown again.
+#[utoipa::path(
+    post,
+    path = "/profile/mfa/totp/confirm",
+    tag = "profile",
+    request_body = ConfirmTotpRequest,
+    responses(
+        (status = 200, description = "Two-factor authentication enabled", body = RecoveryCodes),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "Code is incorrect", body = ErrorResponse),
+        (status = 409, description = "Setup was not started, or is already confirmed", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn confirm_totp_enrollment(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<ConfirmTotpRequest>,
+) -> Result<ResponseJson<RecoveryCodes>, AppError> {
+    let already_enabled = || AppError::conflict("mfa_already_enabled", "Two-factor authentication is already enabled");
+
+    let totp = state
+        .mfa_repo
+        .find_totp(&auth.user_id)
+        .await
+        .context("Failed to load authenticator")?
+        .ok_or_else(|| AppError::conflict("mfa_enrollment_not_started", "Start authenticator setup first"))?;
+    if totp.confirmed_at.is_some() {
+        return Err(already_enabled());
+    }
+
+    let step = mfa::verify_code(&totp.secret, &payload.code, None)?
+        .ok_or_else(|| AppError::forbidden("invalid_mfa_code", "Authentication code is incorrect"))?;
+
+    let confirmed = state
+        .mfa_repo
+        .confirm_totp(&auth.user_id, step)
+        .await
+        .context("Failed to enable authenticator")?;
+    if !confirmed {
+        return Err(already_enabled());
+    }
+
+    let recovery_codes = issue_recovery_codes(&state, &auth.user_id).await?;
+
+    Ok(ResponseJson(recovery_codes))
+}
+
+/// Turn off two-factor authentication
+///
+/// Requires the current password. Removes the authenticator and recovery
+/// codes; also cancels a setup that was not confirmed.
+#[utoipa::path(
+    post,
+    path = "/profile/mfa/totp/disable",
+    tag = "profile",
+    request_body = CurrentPasswordRequest,
+    responses(
+        (status = 204, description = "Two-factor authentication turned off"),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "Current password is incorrect", body = ErrorResponse),
+        (status = 409, description = "Two-factor authentication is not enabled", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn disable_totp(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<CurrentPasswordRequest>,
+) -> Result<StatusCode, AppError> {
+    let user = state
+        .user_repo
+        .find_by_id(&auth.user_id)
+        .await
+        .context("Failed to find user")?
+        .ok_or_else(user_not_found)?;
+    verify_current_password(&state, &user, &payload.current_password)?;
+
+    let deleted = state
+        .mfa_repo
+        .delete_totp(&user.id)
+        .await
+        .context("Failed to remove authenticator")?;
+    if !deleted {
+        return Err(AppError::conflict("mfa_not_enabled", "Two-factor authentication is not enabled"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Generate new recovery codes
+///
+/// Requires the current password. Earlier recovery codes stop working.
+#[utoipa::path(
+    post,
+    path = "/profile/mfa/recovery-codes",
+    tag = "profile",
+    request_body = CurrentPasswordRequest,
+    responses(
+        (status = 200, description = "New recovery codes", body = RecoveryCodes),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "Current password is incorrect", body = ErrorResponse),
+        (status = 409, description = "Two-factor authentication is not enabled", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn regenerate_recovery_codes(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<CurrentPasswordRequest>,
+) -> Result<ResponseJson<RecoveryCodes>, AppError> {
+    let user = state
+        .user_repo
+        .find_by_id(&auth.user_id)
+        .await
+        .context("Failed to find user")?
+        .ok_or_else(user_not_found)?;
+    verify_current_password(&state, &user, &payload.current_password)?;
+
+    if !mfa_enabled(&state, &user.id).await? {
+        return Err(AppError::conflict("mfa_not_enabled", "Two-factor authentication is not enabled"));
+    }
+
+    let recovery_codes = issue_recovery_codes(&state, &user.id).await?;
+
+    Ok(ResponseJson(recovery_codes))
+}
+
+/// List the devices the user is signed in on
+#[utoipa::path(
+    get,
+    path = "/sessions",
+    tag = "profile",
+    responses(
+        (status = 200, description = "Active sessions", body = SessionList),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "The request used an API token", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn list_sessions(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> Result<ResponseJson<SessionList>, AppError> {
+    let sessions = state
+        .session_repo
+        .list_active(&auth.user_id)
+        .await
+        .context("Failed to list sessions")?
+        .into_iter()
+        .map(|session| SessionInfo {
+            current: auth.claims.sid.as_deref() == Some(session.id.as_str()),
+            id: session.id,
+            user_agent: session.user_agent,
+            ip_address: session.ip_address,
+            created_at: session.created_at,
+            last_seen_at: session.last_seen_at,
+        })
+        .collect();
+
+    Ok(ResponseJson(SessionList { sessions }))
+}
+
+/// Sign out of one session
+///
+/// Its access and refresh tokens stop working immediately.
+#[utoipa::path(
+    delete,
+    path = "/sessions/{id}",
+    tag = "profile",
+    params(("id" = String, Path, description = "Session id")),
+    responses(
+        (status = 204, description = "Session revoked"),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "The request used an API token", body = ErrorResponse),
+        (status = 404, description = "No such active session", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn revoke_session(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Path(session_id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let revoked = state
+        .session_repo
+        .revoke(&auth.user_id, &session_id)
+        .await
+        .context("Failed to revoke session")?;
+    if !revoked {
+        return Err(AppError::not_found("session_not_found", "Session not found"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Sign out everywhere else
+///
+/// Revokes every session except the one making the request.
+#[utoipa::path(
+    delete,
+    path = "/sessions",
+    tag = "profile",
+    responses(
+        (status = 204, description = "Other sessions revoked"),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "The request used an API token", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn revoke_other_sessions(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> Result<StatusCode, AppError> {
+    state
+        .session_repo
+        .revoke_all_for_user(&auth.user_id, auth.claims.sid.as_deref())
+        .await
+        .context("Failed to revoke sessions")?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Create an API token
+///
+/// The token is returned once and cannot be shown again. Scopes for role
+/// permissions can only be granted by users whose role has them.
+#[utoipa::path(
+    post,
+    path = "/api-tokens",
+    tag = "profile",
+    request_body = CreateApiTokenRequest,
+    responses(
+        (status = 201, description = "Token created", body = CreatedApiToken),
+        (status = 400, description = "Missing name or scopes, or invalid lifetime", body = ErrorResponse),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "A scope needs a permission the user lacks, or the request used an API token", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn create_api_token(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<CreateApiTokenRequest>,
+) -> Result<(StatusCode, ResponseJson<CreatedApiToken>), AppError> {
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > 100 {
+        return Err(AppError::validation("Name must be between 1 and 100 characters"));
+    }
+    if payload.scopes.is_empty() {
+        return Err(AppError::validation("At least one scope is required"));
+    }
+    let expires_in_days = payload.expires_in_days.unwrap_or(API_TOKEN_DEFAULT_TTL_DAYS);
+    if !(1..=API_TOKEN_MAX_TTL_DAYS).contains(&expires_in_days) {
+        return Err(AppError::validation(format!(
+            "expires_in_days must be between 1 and {}",
+            API_TOKEN_MAX_TTL_DAYS
+        )));
+    }
+
+    let mut scopes: Vec<Scope> = vec![];
+    for scope in payload.scopes {
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+    if let Some(permission) = scopes
+        .iter()
+        .filter_map(Scope::permission)
+        .find(|permission| !auth.has_permission(*permission))
+    {
+        return Err(AppError::forbidden(
+            "insufficient_permissions",
+            format!("Granting this scope requires the '{}' permission", permission.as_str()),
+        ));
+    }
+
+    let token = format!("{}{}", API_TOKEN_PREFIX, generate_opaque_token());
+    let api_token = state
+        .api_token_repo
+        .create(
+            &auth.user_id,
+            name,
+            &hash_token(&token),
+            &scopes,
+            Utc::now() + Duration::days(expires_in_days),
+        )
+        .await
+        .context("Failed to create API token")?;
+
+    Ok((
+        StatusCode::CREATED,
+        ResponseJson(CreatedApiToken { token, api_token: api_token.into() }),
+    ))
+}
+
+/// List the user's API tokens
+#[utoipa::path(
+    get,
+    path = "/api-tokens",
+    tag = "profile",
+    responses(
+        (status = 200, description = "Unrevoked, unexpired API tokens", body = ApiTokenList),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "The request used an API token", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn list_api_tokens(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> Result<ResponseJson<ApiTokenList>, AppError> {
+    let api_tokens = state
+        .api_token_repo
+        .list_active(&auth.user_id)
+        .await
+        .context("Failed to list API tokens")?
+        .into_iter()
+        .map(ApiTokenInfo::from)
+        .collect();
+
+    Ok(ResponseJson(ApiTokenList { api_tokens }))
+}
+
+/// Revoke an API token
+#[utoipa::path(
+    delete,
+    path = "/api-tokens/{id}",
+    tag = "profile",
+    params(("id" = String, Path, description = "API token id")),
+    responses(
+        (status = 204, description = "Token revoked"),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "The request used an API token", body = ErrorResponse),
+        (status = 404, description = "No such unrevoked token", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn revoke_api_token(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Path(token_id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let revoked = state
+        .api_token_repo
+        .revoke(&auth.user_id, &token_id)
+        .await
+        .context("Failed to revoke API token")?;
+    if !revoked {
+        return Err(AppError::not_found("api_token_not_found", "API token not found"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Hand out a fresh challenge for a passkey ceremony; only its hash is kept.
+async fn issue_passkey_challenge(
+    state: &AppState,
+    ceremony: PasskeyCeremony,
+    user_id: Option<&str>,
+) -> Result<String, AppError> {
+    let challenge = generate_opaque_token();
+    state
+        .passkey_repo
+        .create_challenge(
+            &hash_token(&challenge),
+            ceremony,
+            user_id,
+            Utc::now() + Duration::minutes(PASSKEY_CHALLENGE_TTL_MINUTES),
+        )
+        .await
+        .context("Failed to store passkey challenge")?;
+
+    Ok(challenge)
+}
+
+/// Start adding a passkey
+///
+/// Returns options for `navigator.credentials.create()`. Pass the new
+/// credential to `/profile/passkeys` within 5 minutes.
+#[utoipa::path(
+    post,
+    path = "/profile/passkeys/register/options",
+    tag = "profile",
+    responses(
+        (status = 200, description = "Options for the browser", body = PasskeyCreationOptions),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "The request used an API token", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn start_passkey_registration(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> Result<ResponseJson<PasskeyCreationOptions>, AppError> {
+    let user = state
+        .user_repo
+        .find_by_id(&auth.user_id)
+        .await
+        .context("Failed to find user")?
+        .ok_or_else(user_not_found)?;
+    let existing = state
+        .passkey_repo
+        .list(&user.id)
+        .await
+        .context("Failed to list passkeys")?;
+
+    let challenge = issue_passkey_challenge(&state, PasskeyCeremony::Registration, Some(&user.id)).await?;
+
+    Ok(ResponseJson(state.webauthn.creation_options(&challenge, &user, &existing)))
+}
+
+/// Add a passkey
+///
+/// Checks the credential the browser created with the options from
+/// `/profile/passkeys/register/options` and stores it. The passkey then signs
+/// in with `/auth/passkey/login`.
+#[utoipa::path(
+    post,
+    path = "/profile/passkeys",
+    tag = "profile",
+    request_body = RegisterPasskeyRequest,
+    responses(
+        (status = 201, description = "Passkey added", body = PasskeyInfo),
+        (status = 400, description = "Missing name, expired challenge or a credential that did not check out", body = ErrorResponse),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "The request used an API token", body = ErrorResponse),
+        (status = 409, description = "The passkey is already registered", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn register_passkey(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Json(payload): Json<RegisterPasskeyRequest>,
+) -> Result<(StatusCode, ResponseJson<PasskeyInfo>), AppError> {
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > 100 {
+        return Err(AppError::validation("Name must be between 1 and 100 characters"));
+    }
+
+    let challenge_expired = || AppError::bad_request("passkey_challenge_expired", "Passkey setup has expired; start again");
+    let challenge = webauthn::challenge_of(&payload.credential.response.client_data_json)
+        .map_err(|_| AppError::bad_request("invalid_passkey", "The passkey could not be verified"))?;
+    state
+        .passkey_repo
+        .take_challenge(&hash_token(&challenge), PasskeyCeremony::Registration)
+        .await
+        .context("Failed to load passkey challenge")?
+        .filter(|challenge| challenge.user_id.as_deref() == Some(auth.user_id.as_str()))
+        .ok_or_else(challenge_expired)?;
+
+    let new_passkey = match state.webauthn.verify_registration(&payload.credential) {
+        Ok(new_passkey) => new_passkey,
+        Err(e) => {
+            tracing::warn!(user_id = %auth.user_id, error = ?e, "passkey registration failed");
+            return Err(AppError::bad_request("invalid_passkey", "The passkey could not be verified"));
+        }
+    };
+
+    let passkey = match state
+        .passkey_repo
+        .create(&auth.user_id, &new_passkey.credential_id, &new_passkey.public_key, new_passkey.sign_count, name)
+        .await
+    {
+        Ok(passkey) => passkey,
+        Err(e) => {
+            return Err(match AppError::from(e.context("Failed to store passkey")) {
+                AppError::Conflict { .. } => AppError::conflict("passkey_exists", "This passkey is already registered"),
+                other => other,
+            });
+        }
+    };
+
+    Ok((StatusCode::CREATED, ResponseJson(passkey.into())))
+}
+
+/// List the user's passkeys
+#[utoipa::path(
+    get,
+    path = "/profile/passkeys",
+    tag = "profile",
+    responses(
+        (status = 200, description = "The user's passkeys", body = PasskeyList),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "The request used an API token", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn list_passkeys(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> Result<ResponseJson<PasskeyList>, AppError> {
+    let passkeys = state
+        .passkey_repo
+        .list(&auth.user_id)
+        .await
+        .context("Failed to list passkeys")?
+        .into_iter()
+        .map(PasskeyInfo::from)
+        .collect();
+
+    Ok(ResponseJson(PasskeyList { passkeys }))
+}
+
+/// Remove a passkey
+///
+/// The passkey stays on the user's device but no longer signs in.
+#[utoipa::path(
+    delete,
+    path = "/profile/passkeys/{id}",
+    tag = "profile",
+    params(("id" = String, Path, description = "Passkey id")),
+    responses(
+        (status = 204, description = "Passkey removed"),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "The request used an API token", body = ErrorResponse),
+        (status = 404, description = "No such passkey", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn delete_passkey(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Path(passkey_id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    let deleted = state
+        .passkey_repo
+        .delete(&auth.user_id, &passkey_id)
+        .await
+        .context("Failed to delete passkey")?;
+    if !deleted {
+        return Err(AppError::not_found("passkey_not_found", "Passkey not found"));
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// List roles and the permissions they grant
+///
+/// Requires the `users:read` permission (staff, admin).
+#[utoipa::path(
+    get,
+    path = "/admin/roles",
+    tag = "admin",
+    responses(
+        (status = 200, description = "Roles and their permissions", body = [RolePermissions]),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "Missing the users:read permission", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = ["users:read"]))
+)]
+pub async fn list_roles() -> ResponseJson<Vec<RolePermissions>> {
+    let roles = [Role::Member, Role::Staff, Role::Admin]
+        .into_iter()
+        .map(|role| RolePermissions {
+            role,
+            permissions: role.permissions().iter().map(|p| p.as_str().to_string()).collect(),
+        })
+        .collect();
+
+    ResponseJson(roles)
+}
+
+/// Change a user's role
+///
+/// Requires the `roles:assign` permission (admin). The user's access tokens are
+/// revoked, so the new role takes effect at once: their sessions pick it up on
+/// the next refresh.
+#[utoipa::path(
+    put,
+    path = "/admin/users/{id}/role",
+    tag = "admin",
+    params(("id" = String, Path, description = "User id")),
+    request_body = UpdateRoleRequest,
+    responses(
+        (status = 200, description = "Role updated", body = UserProfile),
+        (status = 400, description = "Admins cannot demote themselves", body = ErrorResponse),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "Missing the roles:assign permission", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = ["roles:assign"]))
+)]
+pub async fn update_user_role(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Path(user_id): Path<String>,
+    Json(payload): Json<UpdateRoleRequest>,
+) -> Result<ResponseJson<UserProfile>, AppError> {
+    // Keep at least the acting admin able to undo mistakes
+    if user_id == auth.user_id && payload.role != Role::Admin {
+        return Err(AppError::validation("You cannot remove your own admin role"));
+    }
+
+    let profile = state
+        .user_repo
+        .set_role(&user_id, payload.role)
+        .await
+        .context("Failed to update role")?
+        .ok_or_else(|| AppError::not_found("user_not_found", "User not found"))?;
+
+    // Tokens carry the roles they were issued with; don't let a demoted user
+    // keep their old permissions until the tokens expire. Admins can only
+    // "change" their own role to admin (see above), which changes nothing, so
+    // their own tokens are left alone.
+    if user_id != auth.user_id {
+        state
+            .jwt_service
+            .revoke_all_for_user(&user_id)
+            .await
+            .context("Failed to revoke access tokens")?;
+    }
+
+    Ok(ResponseJson(profile))
+}
+
+/// Confirm an email address
+///
+/// Uses the token from the link sent at registration. Each token works once.
+#[utoipa::path(
+    post,
+    path = "/auth/verify-email",
+    tag = "auth",
+    request_body = VerifyEmailRequest,
+    responses(
+        (status = 204, description = "Email address verified"),
+        (status = 400, description = "Invalid, expired or already used token", body = ErrorResponse)
+    )
+)]
+pub async fn verify_email(
+    State(state): State<AppState>,
+    Json(payload): Json<VerifyEmailRequest>,
+) -> Result<StatusCode, AppError> {
+    let invalid_token = || AppError::validation("Verification link is invalid or has expired");
+
+    if payload.token.is_empty() {
+        return Err(invalid_token());
+    }
+
+    let token = state
+        .one_time_token_repo
+        .consume(TokenPurpose::EmailVerification, &hash_token(&payload.token))
+        .await
+        .context("Failed to consume verification token")?
+        .ok_or_else(invalid_token)?;
+
+    // A link sent to an address the user has since changed proves nothing
+    let verified = state
+        .user_repo
+        .mark_email_verified(&token.user_id, &token.email)
+        .await
+        .context("Failed to mark email verified")?;
+    if !verified {
+        return Err(invalid_token());
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Send a new verification email
+///
+/// Earlier links stop working.
+#[utoipa::path(
+    post,
+    path = "/auth/verify-email/resend",
+    tag = "auth",
+    responses(
+        (status = 202, description = "Verification email sent"),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 409, description = "Email address is already verified", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn resend_verification_email(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> Result<StatusCode, AppError> {
+    let user = state
+        .user_repo
+        .find_by_id(&auth.user_id)
+        .await
+        .context("Failed to find user")?
+        .ok_or_else(user_not_found)?;
+
+    if user.email_verified_at.is_some() {
+        return Err(AppError::conflict("email_already_verified", "Email address is already verified"));
+    }
+
+    send_verification_email(&state, &user).await?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// Request a password reset email
+///
+/// Always returns 202, whether or not the address belongs to an account, so the
+/// endpoint cannot be used to discover which emails are registered.
+#[utoipa::path(
+    post,
+    path = "/auth/forgot-password",
+    tag = "auth",
+    request_body = ForgotPasswordRequest,
+    responses(
+        (status = 202, description = "If the account exists, a reset email is on its way")
+    )
+)]
+pub async fn forgot_password(
+    State(state): State<AppState>,
+    Json(payload): Json<ForgotPasswordRequest>,
+) -> StatusCode {
+    // Do the work in the background so the response time does not reveal
+    // whether the account exists
+    tokio::spawn(async move {
+        if let Err(e) = send_password_reset_email(&state, &payload.email).await {
+            tracing::warn!(error = ?e, "failed to send password reset email");
+        }
+    });
+
+    StatusCode::ACCEPTED
+}
+
+async fn send_password_reset_email(state: &AppState, email: &str) -> anyhow::Result<()> {
+    let Some(user) = state.user_repo.find_by_email(email).await? else {
+        return Ok(());
+    };
+    if user.disabled_at.is_some() {
+        return Ok(());
+    }
+
+    state
+        .one_time_token_repo
+        .invalidate(&user.id, TokenPurpose::PasswordReset)
+        .await?;
+
+    let token = generate_opaque_token();
+    let expires_at = Utc::now() + Duration::minutes(PASSWORD_RESET_TTL_MINUTES);
+    state
+        .one_time_token_repo
+        .create(&user.id, TokenPurpose::PasswordReset, &user.email, &hash_token(&token), expires_at)
+        .await?;
+
+    state.mail.send_password_reset(&user.email, &token).await
+}
+
+/// Set a new password
+///
+/// Uses the token from the reset email. Every existing session of the user is
+/// signed out; log in again with the new password.
+#[utoipa::path(
+    post,
+    path = "/auth/reset-password",
+    tag = "auth",
+    request_body = ResetPasswordRequest,
+    responses(
+        (status = 204, description = "Password changed"),
+        (status = 400, description = "Invalid, expired or used token, or weak password", body = ErrorResponse)
+    )
+)]
+pub async fn reset_password(
+    State(state): State<AppState>,
+    Json(payload): Json<ResetPasswordRequest>,
+) -> Result<StatusCode, AppError> {
+    let invalid_token = || AppError::validation("Password reset link is invalid or has expired");
+
+    if payload.token.is_empty() {
+        return Err(invalid_token());
+    }
+    let token_hash = hash_token(&payload.token);
+    let token = state
+        .one_time_token_repo
+        .find_active(TokenPurpose::PasswordReset, &token_hash)
+        .await
+        .context("Failed to load password reset token")?
+        .ok_or_else(invalid_token)?;
+
+    let user = state
+        .user_repo
+        .find_by_id(&token.user_id)
+        .await
+        .context("Failed to find user")?
+        .ok_or_else(invalid_token)?;
+
+    // The link went to an address the account no longer uses
+    if user.email != token.email || user.disabled_at.is_some() {
+        return Err(invalid_token());
+    }
+
+    // Check the password before using up the token, so a rejected one can be retried
+    state.password_policy.check("new_password", &payload.new_password, &user.email)?;
+
+    state
+        .one_time_token_repo
+        .consume(TokenPurpose::PasswordReset, &token_hash)
+        .await
+        .context("Failed to consume password reset token")?
+        .ok_or_else(invalid_token)?;
+
+    let password_hash = state.password_hasher.hash(&payload.new_password)?;
+    state
+        .user_repo
+        .update_password(&user.id, &password_hash)
+        .await
+        .context("Failed to update password")?;
+
+    revoke_all_sessions(&state, &user.id).await?;
+
+    // Receiving the link proves the user controls the address
+    state
+        .user_repo
+        .mark_email_verified(&user.id, &user.email)
+        .await
+        .context("Failed to mark email verified")?;
+
+    // Proving control of the address is also enough to lift a lockout
+    state.login_guard.clear_account(&user.email).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Request a sign-in link
+///
+/// Emails a link that signs in without a password. Always returns 202, whether
+/// or not the address belongs to an account.
+#[utoipa::path(
+    post,
+    path = "/auth/magic-link",
+    tag = "auth",
+    request_body = MagicLinkRequest,
+    responses(
+        (status = 202, description = "If the account exists, a sign-in link is on its way")
+    )
+)]
+pub async fn request_magic_link(
+    State(state): State<AppState>,
+    Json(payload): Json<MagicLinkRequest>,
+) -> StatusCode {
+    // As with password resets, do not let the response time reveal whether
+    // the account exists
+    tokio::spawn(async move {
+        if let Err(e) = send_magic_link_email(&state, &payload.email).await {
+            tracing::warn!(error = ?e, "failed to send sign-in link");
+        }
+    });
+
+    StatusCode::ACCEPTED
+}
+
+async fn send_magic_link_email(state: &AppState, email: &str) -> anyhow::Result<()> {
+    let Some(user) = state.user_repo.find_by_email(email).await? else {
+        return Ok(());
+    };
+    if user.disabled_at.is_some() {
+        return Ok(());
+    }
+
+    state
+        .one_time_token_repo
+        .invalidate(&user.id, TokenPurpose::MagicLink)
+        .await?;
+
+    let token = generate_opaque_token();
+    let expires_at = Utc::now() + Duration::minutes(MAGIC_LINK_TTL_MINUTES);
+    state
+        .one_time_token_repo
+        .create(&user.id, TokenPurpose::MagicLink, &user.email, &hash_token(&token), expires_at)
+        .await?;
+
+    state.mail.send_magic_link(&user.email, &token).await
+}
+
+/// Sign in with a sign-in link
+///
+/// Exchanges the token from the link for tokens, like `/auth/login`. Accounts
+/// with two-factor authentication still get an MFA challenge. Opening the link
+/// also confirms the email address.
+#[utoipa::path(
+    post,
+    path = "/auth/magic-link/consume",
+    tag = "auth",
+    request_body = ConsumeMagicLinkRequest,
+    responses(
+        (status = 200, description = "Signed in, or a second factor is required", body = LoginResponse),
+        (status = 401, description = "Invalid, expired or already used link", body = ErrorResponse),
+        (status = 403, description = "Account disabled or password reset required", body = ErrorResponse)
+    )
+)]
+pub async fn consume_magic_link(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<ConsumeMagicLinkRequest>,
+) -> Result<ResponseJson<LoginResponse>, AppError> {
+    let invalid_link = || AppError::unauthorized("invalid_magic_link", "Sign-in link is invalid or has expired");
+
+    if payload.token.is_empty() {
+        return Err(invalid_link());
+    }
+
+    let token = state
+        .one_time_token_repo
+        .consume(TokenPurpose::MagicLink, &hash_token(&payload.token))
+        .await
+        .context("Failed to consume sign-in link")?
+        .ok_or_else(invalid_link)?;
+
+    // A link sent to an address the user has since changed no longer works
+    let user = state
+        .user_repo
+        .find_by_id(&token.user_id)
+        .await
+        .context("Failed to find user")?
+        .filter(|user| user.email == token.email)
+        .ok_or_else(invalid_link)?;
+    ensure_can_sign_in(&user)?;
+
+    state
+        .user_repo
+        .mark_email_verified(&user.id, &user.email)
+        .await
+        .context("Failed to mark email verified")?;
+
+    if mfa_enabled(&state, &user.id).await? {
+        return Ok(ResponseJson(LoginResponse::MfaRequired(start_mfa_challenge(&state, &user).await?)));
+    }
+
+    // Proving control of the address is also enough to lift a lockout, but
+    // only once the user is fully signed in
+    state.login_guard.clear_account(&user.email).await?;
+    let response = issue_auth_response(&state, &user, None, &client).await?;
+
+    Ok(ResponseJson(LoginResponse::Authenticated(response)))
+}
+
+/// Request a sign-in code by text message
+///
+/// Texts a six-digit code to a verified phone number, for signing in without
+/// a password. Always returns 202 for a valid number, whether or not it
+/// belongs to an account. Codes expire after 5 minutes; at most one is sent
+/// per minute.
+#[utoipa::path(
+    post,
+    path = "/auth/phone/otp",
+    tag = "auth",
+    request_body = PhoneOtpRequest,
+    responses(
+        (status = 202, description = "If the number is verified on an account, a code is on its way"),
+        (status = 400, description = "Not a valid phone number", body = ErrorResponse)
+    )
+)]
+pub async fn request_phone_otp(
+    State(state): State<AppState>,
+    Json(payload): Json<PhoneOtpRequest>,
+) -> Result<StatusCode, AppError> {
+    let phone = state
+        .sms
+        .normalize(&payload.phone)
+        .ok_or_else(|| AppError::validation("Phone number is not valid"))?;
+
+    // As with sign-in links, do not let the response time reveal whether the
+    // number belongs to an account
+    tokio::spawn(async move {
+        if let Err(e) = send_phone_login_code(&state, &phone).await {
+            tracing::warn!(error = ?e, "failed to send sign-in code");
+        }
+    });
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn send_phone_login_code(state: &AppState, phone: &str) -> anyhow::Result<()> {
+    let Some(user) = state.user_repo.find_by_verified_phone(phone).await? else {
+        return Ok(());
+    };
+    if user.disabled_at.is_some() {
+        return Ok(());
+    }
+
+    let purpose = PhoneOtpPurpose::PhoneLogin;
+    if phone_otp_retry_after(state, &user.id, purpose).await?.is_some() {
+        return Ok(());
+    }
+
+    send_phone_otp(state, &user.id, purpose, phone).await
+}
+
+/// Sign in with a code sent by text message
+///
+/// Exchanges the code from `/auth/phone/otp` for tokens, like `/auth/login`.
+/// Accounts with two-factor authentication still get an MFA challenge. Five
+/// wrong codes use the code up.
+#[utoipa::path(
+    post,
+    path = "/auth/phone/login",
+    tag = "auth",
+    request_body = PhoneLoginRequest,
+    responses(
+        (status = 200, description = "Signed in, or a second factor is required", body = LoginResponse),
+        (status = 401, description = "Unknown number, or a wrong, expired or used-up code", body = ErrorResponse),
+        (status = 403, description = "Account disabled or password reset required", body = ErrorResponse),
+        (status = 423, description = "Account temporarily locked after repeated failures", body = ErrorResponse),
+        (status = 429, description = "Too many failed attempts; retry later", body = ErrorResponse)
+    )
+)]
+pub async fn phone_login(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<PhoneLoginRequest>,
+) -> Result<ResponseJson<LoginResponse>, AppError> {
+    let invalid_code = || AppError::unauthorized("invalid_otp", "The code is wrong or has expired");
+
+    let phone = state.sms.normalize(&payload.phone).ok_or_else(invalid_code)?;
+    let user = state
+        .user_repo
+        .find_by_verified_phone(&phone)
+        .await
+        .context("Failed to find user")?;
+
+    // Wrong codes count towards the same lockout as wrong passwords, or a
+    // fresh code every minute would allow unlimited guessing. Unknown numbers
+    // are counted under the number, like unknown emails.
+    let guard_key = user.as_ref().map_or(phone.as_str(), |user| user.email.as_str());
+    state.login_guard.check(guard_key, client.ip).await?;
+
+    let user = match user {
+        Some(user) if check_phone_otp(&state, &user.id, PhoneOtpPurpose::PhoneLogin, &phone, &payload.code).await? => user,
+        _ => {
+            record_login_failure(&state, guard_key, client.ip).await?;
+            return Err(invalid_code());
+        }
+    };
+    ensure_can_sign_in(&user)?;
+
+    if mfa_enabled(&state, &user.id).await? {
+        return Ok(ResponseJson(LoginResponse::MfaRequired(start_mfa_challenge(&state, &user).await?)));
+    }
+
+    state.login_guard.clear_account(&user.email).await?;
+    let response = issue_auth_response(&state, &user, None, &client).await?;
+
+    Ok(ResponseJson(LoginResponse::Authenticated(response)))
+}
+
+fn oidc_provider_not_found() -> AppError {
+    AppError::not_found("oidc_provider_not_found", "Unknown sign-in provider")
+}
+
+/// Start signing in with an external provider
+///
+/// Returns the provider URL to send the user's browser to. The provider
+/// redirects back to its configured `redirect_uri` with `code` and `state`,
+/// which the client passes to `/auth/oidc/{provider}/callback` within
+/// 10 minutes.
+#[utoipa::path(
+    get,
+    path = "/auth/oidc/{provider}/authorize",
+    tag = "auth",
+    params(("provider" = String, Path, description = "Provider name from the server configuration")),
+    responses(
+        (status = 200, description = "Where to send the user", body = OidcAuthorization),
+        (status = 404, description = "No such provider", body = ErrorResponse)
+    )
+)]
+pub async fn start_oidc_login(
+    State(state): State<AppState>,
+    Path(provider): Path<String>,
+) -> Result<ResponseJson<OidcAuthorization>, AppError> {
+    if !state.oidc.has_provider(&provider) {
+        return Err(oidc_provider_not_found());
+    }
+
+    let request = state
+        .oidc
+        .authorization_request(&provider)
+        .await
+        .context("Failed to start OpenID Connect sign-in")?;
+
+    let expires_at = Utc::now() + Duration::minutes(OIDC_LOGIN_TTL_MINUTES);
+    state
+        .oidc_repo
+        .create_attempt(&provider, &hash_token(&request.state), &request.nonce, &request.code_verifier, expires_at)
+        .await
+        .context("Failed to store OpenID Connect sign-in")?;
+
+    Ok(ResponseJson(OidcAuthorization {
+        authorization_url: request.url,
+        state: request.state,
+    }))
+}
+
+/// The local user for an identity the provider vouched for. An identity seen
+/// before keeps its link. Otherwise it is linked to the account with the same
+/// email address, or a new account is created for it; either needs an address
+/// the provider has verified.
+async fn oidc_user(state: &AppState, provider: &str, identity: &ExternalIdentity) -> Result<User, AppError> {
+    let linked = state
+        .oidc_repo
+        .find_identity(provider, &identity.subject)
+        .await
+        .context("Failed to find linked identity")?;
+    if let Some(linked) = linked {
+        state
+            .oidc_repo
+            .record_login(provider, &identity.subject, identity.email.as_deref())
+            .await
+            .context("Failed to record sign-in")?;
+        return state
+            .user_repo
+            .find_by_id(&linked.user_id)
+            .await
+            .context("Failed to find user")?
+            .ok_or_else(user_not_found);
+    }
+
+    let email = identity
+        .email
+        .as_deref()
+        .filter(|_| identity.email_verified)
+        .ok_or_else(|| AppError::forbidden("oidc_email_unverified", "The provider did not share a verified email address"))?;
+
+    let user = match state.user_repo.find_by_email(email).await.context("Failed to find user")? {
+        // Unless the provider is trusted with it, an address is not enough to
+        // take over an account that was not signed up through the provider
+        Some(_) if !state.oidc.links_existing_accounts(provider) => {
+            return Err(AppError::conflict(
+                "email_exists",
+                "An account with this email address exists; sign in to it the way you usually do",
+            ))
+        }
+        Some(user) if user.email_verified_at.is_some() => user,
+        // Whoever registered the address never proved they own it, so they
+        // must not keep access to an account the provider's user now signs into
+        Some(_) => {
+            return Err(AppError::conflict(
+                "email_exists",
+                "An account with this email address exists; sign in with your password and verify the address first",
+            ))
+        }
+        None => {
+            // Only usable through the provider until the user resets the password
+            let password_hash = state.password_hasher.hash(&generate_opaque_token())?;
+            let user = match state.user_repo.create_user(email, &password_hash).await {
+                Ok(user) => user,
+                Err(e) => {
+                    return Err(match AppError::from(e.context("Failed to create user")) {
+                        AppError::Conflict { .. } => AppError::conflict("email_exists", "Email already exists"),
+                        other => other,
+                    });
+                }
+            };
+            state
+                .user_repo
+                .mark_email_verified(&user.id, email)
+                .await
+                .context("Failed to mark email verified")?;
+            user
+        }
+    };
+
+    state
+        .oidc_repo
+        .link_identity(provider, &identity.subject, &user.id, Some(email))
+        .await
+        .context("Failed to link identity")?;
+
+    Ok(user)
+}
+
+/// Finish signing in with an external provider
+///
+/// Exchanges the code for the provider's ID token and checks it, then signs
+/// in like `/auth/login`. The first sign-in creates a local account, or, for
+/// providers configured to, links the one with the same verified email address.
+/// Accounts with two-factor authentication still get an MFA challenge.
+#[utoipa::path(
+    post,
+    path = "/auth/oidc/{provider}/callback",
+    tag = "auth",
+    params(("provider" = String, Path, description = "Provider name from the server configuration")),
+    request_body = OidcCallbackRequest,
+    responses(
+        (status = 200, description = "Signed in, or a second factor is required", body = LoginResponse),
+        (status = 401, description = "Unknown or expired `state`, or the provider's answer did not check out", body = ErrorResponse),
+        (status = 403, description = "No verified email from the provider, account disabled or password reset required", body = ErrorResponse),
+        (status = 404, description = "No such provider", body = ErrorResponse),
+        (status = 409, description = "An account with the email address exists but is not verified", body = ErrorResponse)
+    )
+)]
+pub async fn finish_oidc_login(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Path(provider): Path<String>,
+    Json(payload): Json<OidcCallbackRequest>,
+) -> Result<ResponseJson<LoginResponse>, AppError> {
+    if !state.oidc.has_provider(&provider) {
+        return Err(oidc_provider_not_found());
+    }
+
+    let attempt = state
+        .oidc_repo
+        .take_attempt(&provider, &hash_token(&payload.state))
+        .await
+        .context("Failed to load OpenID Connect sign-in")?
+        .ok_or_else(|| AppError::unauthorized("invalid_oidc_state", "Sign-in has expired or was already used; start again"))?;
+
+    let identity = match state
+        .oidc
+        .finish(&provider, &payload.code, &attempt.nonce, &attempt.code_verifier)
+        .await
+    {
+        Ok(identity) => identity,
+        Err(e) => {
+            tracing::warn!(provider = %provider, error = ?e, "OpenID Connect sign-in failed");
+            return Err(AppError::unauthorized("oidc_sign_in_failed", "Sign-in with the provider failed"));
+        }
+    };
+
+    let user = oidc_user(&state, &provider, &identity).await?;
+    ensure_can_sign_in(&user)?;
+
+    if mfa_enabled(&state, &user.id).await? {
+        return Ok(ResponseJson(LoginResponse::MfaRequired(start_mfa_challenge(&state, &user).await?)));
+    }
+
+    let response = issue_auth_response(&state, &user, None, &client).await?;
+
+    Ok(ResponseJson(LoginResponse::Authenticated(response)))
+}
+
+/// Start signing in with a passkey
+///
+/// Returns options for `navigator.credentials.get()`. Pass the signed
+/// challenge to `/auth/passkey/login` within 5 minutes.
+#[utoipa::path(
+    post,
+    path = "/auth/passkey/options",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Options for the browser", body = PasskeyRequestOptions)
+    )
+)]
+pub async fn start_passkey_login(State(state): State<AppState>) -> Result<ResponseJson<PasskeyRequestOptions>, AppError> {
+    let challenge = issue_passkey_challenge(&state, PasskeyCeremony::Authentication, None).await?;
+
+    Ok(ResponseJson(state.webauthn.request_options(&challenge)))
+}
+
+/// Sign in with a passkey
+///
+/// Checks the challenge from `/auth/passkey/options` signed with one of the
+/// user's passkeys, and issues tokens like `/auth/login`. Passkeys verify the
+/// user on their device, so there is no second-factor challenge. Failures
+/// count towards the same limits as wrong passwords.
+#[utoipa::path(
+    post,
+    path = "/auth/passkey/login",
+    tag = "auth",
+    request_body = PasskeyLoginRequest,
+    responses(
+        (status = 200, description = "Signed in", body = AuthResponse),
+        (status = 401, description = "Expired challenge, unknown passkey or a signature that did not check out", body = ErrorResponse),
+        (status = 403, description = "Account disabled or password reset required", body = ErrorResponse),
+        (status = 423, description = "Account temporarily locked after repeated failures", body = ErrorResponse),
+        (status = 429, description = "Too many failed attempts; retry later", body = ErrorResponse)
+    )
+)]
+pub async fn passkey_login(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<PasskeyLoginRequest>,
+) -> Result<ResponseJson<AuthResponse>, AppError> {
+    // One answer for every failure, so callers cannot probe for passkeys
+    let failed = |reason: &str| {
+        tracing::warn!(credential_id = %payload.credential.id, reason, "passkey sign-in failed");
+        AppError::unauthorized("passkey_sign_in_failed", "Sign-in with the passkey failed")
+    };
+
+    // The account is not known until the passkey is, so failures up to that
+    // point count against the IP address only
+    state.login_guard.check_ip(client.ip).await?;
+    let (passkey, user) = match passkey_owner(&state, &payload.credential).await? {
+        Ok(found) => found,
+        Err(reason) => {
+            let error = failed(reason);
+            state.login_guard.record_ip_failure(client.ip).await?;
+            return Err(error);
+        }
+    };
+    state.login_guard.check(&user.email, client.ip).await?;
+
+    let sign_count = match state.webauthn.verify_authentication(&payload.credential, &passkey) {
+        Ok(sign_count) => sign_count,
+        Err(e) => {
+            let error = failed(&format!("{:#}", e));
+            record_login_failure(&state, &user.email, client.ip).await?;
+            return Err(error);
+        }
+    };
+    let recorded = state
+        .passkey_repo
+        .record_use(&passkey, sign_count)
+        .await
+        .context("Failed to record passkey use")?;
+    if !recorded {
+        return Err(failed("concurrent sign-in with the same passkey"));
+    }
+    ensure_can_sign_in(&user)?;
+
+    state.login_guard.clear_account(&user.email).await?;
+    let response = issue_auth_response(&state, &user, None, &client).await?;
+
+    Ok(ResponseJson(response))
+}
+
+/// Use up the challenge `credential` answers, and find the passkey that
+/// signed it and its owner. Errors say why not, for the log.
+async fn passkey_owner(
+    state: &AppState,
+    credential: &AuthenticationCredential,
+) -> Result<Result<(Passkey, User), &'static str>, AppError> {
+    let Ok(challenge) = webauthn::challenge_of(&credential.response.client_data_json) else {
+        return Ok(Err("invalid client data"));
+    };
+    let challenge = state
+        .passkey_repo
+        .take_challenge(&hash_token(&challenge), PasskeyCeremony::Authentication)
+        .await
+        .context("Failed to load passkey challenge")?;
+    if challenge.is_none() {
+        return Ok(Err("unknown or expired challenge"));
+    }
+
+    let Some(passkey) = state
+        .passkey_repo
+        .find_by_credential_id(&credential.raw_id)
+        .await
+        .context("Failed to find passkey")?
+    else {
+        return Ok(Err("unknown passkey"));
+    };
+    let Some(user) = state
+        .user_repo
+        .find_by_id(&passkey.user_id)
+        .await
+        .context("Failed to find user")?
+    else {
+        return Ok(Err("passkey owner not found"));
+    };
+
+    Ok(Ok((passkey, user)))
+}
+
+/// The signed-in user an OAuth client is asking about, if they may sign in.
+async fn oauth_user(state: &AppState, user_id: &str) -> Result<User, AppError> {
+    let user = state
+        .user_repo
+        .find_by_id(user_id)
+        .await
+        .context("Failed to find user")?
+        .ok_or_else(user_not_found)?;
+    ensure_can_sign_in(&user)?;
+    Ok(user)
+}
+
+/// Describe an OAuth authorization request
+///
+/// Called by the web app's consent page with the query parameters the client
+/// sent the user there with. Says which client is asking for which scopes,
+/// and whether the user still has to agree. Errors are for the user to see;
+/// never redirect to an unchecked `redirect_uri`.
+#[utoipa::path(
+    get,
+    path = "/oauth/authorize",
+    tag = "oauth",
+    params(OAuthAuthorizeParams),
+    responses(
+        (status = 200, description = "The request checked out", body = OAuthAuthorizationDetails),
+        (status = 400, description = "Unknown client, unregistered redirect URI, missing PKCE or a scope the client may not ask for", body = ErrorResponse),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "The request used an API token, or the account may not sign in", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn get_oauth_authorization(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Query(params): Query<OAuthAuthorizeParams>,
+) -> Result<ResponseJson<OAuthAuthorizationDetails>, AppError> {
+    let user = oauth_user(&state, &auth.user_id).await?;
+    let authorization = state.oauth.authorize(&params, &user)?;
+
+    let consent = state
+        .oauth_repo
+        .find_consent(&user.id, &authorization.client.client_id)
+        .await
+        .context("Failed to find consent")?;
+    let consented = consent.is_some_and(|consent| authorization.scopes.iter().all(|scope| has_scope(&consent, scope)));
+
+    Ok(ResponseJson(OAuthAuthorizationDetails {
+        client_id: authorization.client.client_id.clone(),
+        client_name: authorization.client.name.clone(),
+        consent_required: !(authorization.client.trusted || consented),
+        scopes: authorization.scopes,
+    }))
+}
+
+/// Answer an OAuth authorization request
+///
+/// Takes the same query parameters as `GET /oauth/authorize`. On approval the
+/// consent is remembered and the client gets an authorization code, redeemable
+/// once within 5 minutes; otherwise it gets `error=access_denied`. Either way
+/// the response says where to send the user's browser.
+#[utoipa::path(
+    post,
+    path = "/oauth/authorize",
+    tag = "oauth",
+    params(OAuthAuthorizeParams),
+    request_body = OAuthConsentDecision,
+    responses(
+        (status = 200, description = "Where to send the user", body = OAuthRedirect),
+        (status = 400, description = "Unknown client, unregistered redirect URI, missing PKCE or a scope the client may not ask for", body = ErrorResponse),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "The request used an API token, or the account may not sign in", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn decide_oauth_authorization(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Query(params): Query<OAuthAuthorizeParams>,
+    Json(payload): Json<OAuthConsentDecision>,
+) -> Result<ResponseJson<OAuthRedirect>, AppError> {
+    let user = oauth_user(&state, &auth.user_id).await?;
+    let authorization = state.oauth.authorize(&params, &user)?;
+    let client_id = &authorization.client.client_id;
+
+    if !payload.approve {
+        let redirect_to = state.oauth.redirect(&params, &[("error", "access_denied")])?;
+        return Ok(ResponseJson(OAuthRedirect { redirect_to }));
+    }
+
+    // Keep what was agreed to before, so asking for less does not ask again later
+    let mut scopes = state
+        .oauth_repo
+        .find_consent(&user.id, client_id)
+        .await
+        .context("Failed to find consent")?
+        .map(|consent| consent.split_whitespace().map(String::from).collect::<Vec<_>>())
+        .unwrap_or_default();
+    for scope in &authorization.scopes {
+        if !scopes.contains(scope) {
+            scopes.push(scope.clone());
+        }
+    }
+    state
+        .oauth_repo
+        .save_consent(&user.id, client_id, &scopes.join(" "))
+        .await
+        .context("Failed to save consent")?;
+
+    let code = generate_opaque_token();
+    let now = Utc::now();
+    state
+        .oauth_repo
+        .create_code(&OAuthAuthorizationCode {
+            code_hash: hash_token(&code),
+            client_id: client_id.clone(),
+            user_id: user.id.clone(),
+            redirect_uri: params.redirect_uri.clone(),
+            scope: authorization.scope(),
+            nonce: params.nonce.clone(),
+            code_challenge: params.code_challenge.clone(),
+            created_at: now,
+            expires_at: now + Duration::minutes(OAUTH_CODE_TTL_MINUTES),
+        })
+        .await
+        .context("Failed to store authorization code")?;
+
+    let redirect_to = state.oauth.redirect(&params, &[("code", &code)])?;
+    Ok(ResponseJson(OAuthRedirect { redirect_to }))
+}
+
+/// Client credentials from an `Authorization: Basic` header (RFC 6749, section 2.3.1).
+fn basic_client_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, credentials) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let credentials = String::from_utf8(STANDARD.decode(credentials.trim()).ok()?).ok()?;
+    let (client_id, client_secret) = credentials.split_once(':')?;
+    Some((client_id.to_string(), client_secret.to_string()))
+}
+
+fn invalid_client() -> AppError {
+    AppError::unauthorized("invalid_client", "Client authentication failed")
+}
+
+/// The registered client calling a back-channel endpoint, authenticated with
+/// HTTP Basic or the `client_id` and `client_secret` from the form body.
+fn authenticate_oauth_client<'a>(
+    state: &'a AppState,
+    headers: &HeaderMap,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+) -> Result<&'a OAuthClientConfig, AppError> {
+    let (client_id, client_secret) = match basic_client_credentials(headers) {
+        Some((client_id, client_secret)) => (client_id, Some(client_secret)),
+        None => (client_id.ok_or_else(invalid_client)?.to_string(), client_secret.map(String::from)),
+    };
+
+    state
+        .oauth
+        .authenticate_client(&client_id, client_secret.as_deref())
+        .ok_or_else(invalid_client)
+}
+
+/// Redeem an authorization code
+///
+/// The OAuth 2.0 token endpoint, taking a form body. Confidential clients
+/// authenticate with HTTP Basic or `client_secret`; public clients send only
+/// `client_id`. The `code_verifier` must match the code's PKCE challenge.
+/// Returns an access token limited to the granted scopes and, for the
+/// `openid` scope, an ID token signed with the keys at
+/// `/.well-known/jwks.json`.
+#[utoipa::path(
+    post,
+    path = "/oauth/token",
+    tag = "oauth",
+    request_body(content = OAuthTokenRequest, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "Tokens issued", body = OAuthTokenResponse),
+        (status = 400, description = "`invalid_grant` for an unknown, expired or used code, or a wrong redirect URI or verifier", body = ErrorResponse),
+        (status = 401, description = "`invalid_client`", body = ErrorResponse)
+    )
+)]
+pub async fn exchange_oauth_code(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(payload): Form<OAuthTokenRequest>,
+) -> Result<([(HeaderName, &'static str); 1], ResponseJson<OAuthTokenResponse>), AppError> {
+    if payload.grant_type != "authorization_code" {
+        return Err(AppError::bad_request("unsupported_grant_type", "grant_type must be 'authorization_code'"));
+    }
+
+    let client = authenticate_oauth_client(&state, &headers, payload.client_id.as_deref(), payload.client_secret.as_deref())?;
+
+    let invalid_grant = || AppError::bad_request("invalid_grant", "The authorization code is invalid, expired or was already used");
+    let code = payload.code.as_deref().ok_or_else(invalid_grant)?;
+    let code = state
+        .oauth_repo
+        .take_code(&hash_token(code))
+        .await
+        .context("Failed to load authorization code")?
+        .ok_or_else(invalid_grant)?;
+    let verified = code.client_id == client.client_id
+        && payload.redirect_uri.as_deref() == Some(code.redirect_uri.as_str())
+        && payload
+            .code_verifier
+            .as_deref()
+            .is_some_and(|verifier| pkce_challenge(verifier) == code.code_challenge);
+    if !verified {
+        return Err(invalid_grant());
+    }
+
+    // The account may have been disabled since the user agreed
+    let user = state
+        .user_repo
+        .find_by_id(&code.user_id)
+        .await
+        .context("Failed to find user")?
+        .filter(|user| ensure_can_sign_in(user).is_ok())
+        .ok_or_else(invalid_grant)?;
+
+    let access_token = state
+        .jwt_service
+        .create_client_token(&user, &client.client_id, &code.scope)
+        .context("Failed to generate token")?;
+    let id_token = if has_scope(&code.scope, "openid") {
+        let claims = state
+            .oauth
+            .id_token_claims(&user, &client.client_id, &code.scope, code.nonce.as_deref());
+        Some(state.jwt_service.sign_claims(&claims).context("Failed to generate ID token")?)
+    } else {
+        None
+    };
+
+    // RFC 6749, section 5.1: responses with tokens must not be cached
+    Ok((
+        [(CACHE_CONTROL, "no-store")],
+        ResponseJson(OAuthTokenResponse {
+            access_token,
+            token_type: "Bearer".to_string(),
+            expires_in: state.jwt_service.access_token_ttl().num_seconds(),
+            scope: code.scope,
+            id_token,
+        }),
+    ))
+}
+
+/// Claims about the user
+///
+/// The OpenID Connect UserInfo endpoint. Needs an access token from
+/// `/oauth/token` with the `openid` scope; the `email` and `profile` scopes
+/// decide which claims are included.
+#[utoipa::path(
+    get,
+    path = "/userinfo",
+    tag = "oauth",
+    responses(
+        (status = 200, description = "Claims about the user", body = UserInfo),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "The token was not issued with the `openid` scope", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn userinfo(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> Result<ResponseJson<UserInfo>, AppError> {
+    let scope = auth
+        .claims
+        .scope
+        .as_deref()
+        .filter(|scope| auth.claims.client_id.is_some() && has_scope(scope, "openid"))
+        .ok_or_else(|| AppError::forbidden("insufficient_scope", "This token lacks the 'openid' scope"))?;
+
+    let user = state
+        .user_repo
+        .find_by_id(&auth.user_id)
+        .await
+        .context("Failed to find user")?
+        .ok_or_else(user_not_found)?;
+
+    Ok(ResponseJson(OAuthServer::user_info(&user, scope)))
+}
+
+/// Introspect a token
+///
+/// Lets a service that received an access token or API token ask whether it
+/// is still active and whom it belongs to (RFC 7662), taking a form body.
+/// Only confidential clients may ask. Refresh tokens, ID tokens and tokens
+/// that are unknown, expired or revoked are reported as `active: false`.
+#[utoipa::path(
+    post,
+    path = "/oauth/introspect",
+    tag = "oauth",
+    request_body(content = OAuthTokenReference, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "What is known about the token", body = TokenIntrospection),
+        (status = 401, description = "`invalid_client`", body = ErrorResponse)
+    )
+)]
+pub async fn introspect_token(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(payload): Form<OAuthTokenReference>,
+) -> Result<ResponseJson<TokenIntrospection>, AppError> {
+    let client = authenticate_oauth_client(&state, &headers, payload.client_id.as_deref(), payload.client_secret.as_deref())?;
+    // A public client's id is no secret, so it would let anyone probe tokens
+    if client.client_secret.is_none() {
+        return Err(invalid_client());
+    }
+
+    let auth = match authenticate_token(&state, &payload.token).await {
+        Ok(auth) => auth,
+        Err(AppError::Internal(e)) => return Err(AppError::Internal(e)),
+        Err(_) => return Ok(ResponseJson(TokenIntrospection::default())),
+    };
+
+    let scope = auth.claims.scope.clone().or_else(|| {
+        auth.scopes
+            .as_ref()
+            .map(|scopes| scopes.iter().map(Scope::as_str).collect::<Vec<_>>().join(" "))
+    });
+
+    Ok(ResponseJson(TokenIntrospection {
+        active: true,
+        scope,
+        client_id: auth.claims.client_id,
+        username: Some(auth.email),
+        token_type: Some("Bearer".to_string()),
+        exp: Some(auth.claims.exp as i64),
+        iat: Some(auth.claims.iat as i64),
+        sub: Some(auth.user_id),
+        jti: Some(auth.claims.jti),
+        roles: auth.roles,
+    }))
+}
+
+/// Revoke a token
+///
+/// Ends an access token the client got from `/oauth/token` (RFC 7009), taking
+/// a form body. Clients may only revoke their own tokens: tokens of other
+/// clients, API tokens and the user's own sessions are left alone. Answers
+/// `200` for those and for unknown or already invalid tokens too.
+#[utoipa::path(
+    post,
+    path = "/oauth/revoke",
+    tag = "oauth",
+    request_body(content = OAuthTokenReference, content_type = "application/x-www-form-urlencoded"),
+    responses(
+        (status = 200, description = "The token no longer works"),
+        (status = 401, description = "`invalid_client`", body = ErrorResponse)
+    )
+)]
+pub async fn revoke_oauth_token(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Form(payload): Form<OAuthTokenReference>,
+) -> Result<StatusCode, AppError> {
+    let client = authenticate_oauth_client(&state, &headers, payload.client_id.as_deref(), payload.client_secret.as_deref())?;
+
+    // RFC 7009, section 2.1: only tokens issued to the client. Clients get no
+    // refresh tokens, so that leaves their access tokens.
+    let claims = state
+        .jwt_service
+        .decode_token(&payload.token)
+        .ok()
+        .filter(|claims| claims.client_id.as_deref() == Some(client.client_id.as_str()));
+    if let Some(claims) = claims {
+        state
+            .jwt_service
+            .revoke_token(&claims)
+            .await
+            .context("Failed to revoke token")?;
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// OpenID Connect discovery document
+#[utoipa::path(
+    get,
+    path = "/.well-known/openid-configuration",
+    tag = "oauth",
+    responses(
+        (status = 200, description = "Provider metadata", body = OpenIdConfiguration)
+    )
+)]
+pub async fn openid_configuration(
+    State(state): State<AppState>,
+) -> Result<ResponseJson<OpenIdConfiguration>, AppError> {
+    let algorithm = state
+        .jwt_service
+        .signing_algorithm()
+        .context("Failed to read signing key")?;
+
+    Ok(ResponseJson(state.oauth.discovery(&format!("{:?}", algorithm))))
+}
+
+/// Unlock an account
+///
+/// Uses the token from the email sent when the account was locked after too
+/// many failed sign-in attempts. The lock is lifted and the failure count reset.
+#[utoipa::path(
+    post,
+    path = "/auth/unlock-account",
+    tag = "auth",
+    request_body = UnlockAccountRequest,
+    responses(
+        (status = 204, description = "Account unlocked"),
+        (status = 400, description = "Invalid, expired or already used token", body = ErrorResponse)
+    )
+)]
+pub async fn unlock_account(
+    State(state): State<AppState>,
+    Json(payload): Json<UnlockAccountRequest>,
+) -> Result<StatusCode, AppError> {
+    let invalid_token = || AppError::validation("Unlock link is invalid or has expired");
+
+    if payload.token.is_empty() {
+        return Err(invalid_token());
+    }
+
+    let token = state
+        .one_time_token_repo
+        .consume(TokenPurpose::AccountUnlock, &hash_token(&payload.token))
+        .await
+        .context("Failed to consume unlock token")?
+        .ok_or_else(invalid_token)?;
+
+    state.login_guard.clear_account(&token.email).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn user_not_found() -> AppError {
+    AppError::not_found("user_not_found", "User not found")
+}
+
+/// List users, optionally filtered
+///
+/// Requires the `users:read` permission (staff, admin).
+#[utoipa::path(
+    get,
+    path = "/admin/users",
+    tag = "admin",
+    params(ListUsersQuery),
+    responses(
+        (status = 200, description = "One page of users", body = UserPage),
+        (status = 400, description = "Invalid pagination", body = ErrorResponse),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "Missing the users:read permission", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = ["users:read"]))
+)]
+pub async fn list_users(
+    State(state): State<AppState>,
+    Query(query): Query<ListUsersQuery>,
+) -> Result<ResponseJson<UserPage>, AppError> {
+    let page = query.page.unwrap_or(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_PAGE_SIZE);
+    if page == 0 || per_page == 0 || per_page > MAX_PAGE_SIZE {
+        return Err(AppError::validation(format!(
+            "page must be at least 1 and per_page between 1 and {}",
+            MAX_PAGE_SIZE
+        )));
+    }
+
+    let offset = i64::from(page - 1) * i64::from(per_page);
+    let (users, total) = state
+        .user_repo
+        .list_accounts(&query, i64::from(per_page), offset)
+        .await
+        .context("Failed to list users")?;
+
+    Ok(ResponseJson(UserPage { users, page, per_page, total }))
+}
+
+/// Get a user's account
+///
+/// Requires the `users:read` permission (staff, admin).
+#[utoipa::path(
+    get,
+    path = "/admin/users/{id}",
+    tag = "admin",
+    params(("id" = String, Path, description = "User id")),
+    responses(
+        (status = 200, description = "The user's account", body = UserAccount),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "Missing the users:read permission", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = ["users:read"]))
+)]
+pub async fn get_user(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+) -> Result<ResponseJson<UserAccount>, AppError> {
+    let account = state
+        .user_repo
+        .get_account(&user_id)
+        .await
+        .context("Failed to get user")?
+        .ok_or_else(user_not_found)?;
+
+    Ok(ResponseJson(account))
+}
+
+/// Change a user's membership level or points
+///
+/// Requires the `users:write` permission (staff, admin).
+#[utoipa::path(
+    put,
+    path = "/admin/users/{id}/membership",
+    tag = "admin",
+    params(("id" = String, Path, description = "User id")),
+    request_body = UpdateMembershipRequest,
+    responses(
+        (status = 200, description = "Membership updated", body = UserAccount),
+        (status = 400, description = "Unknown level or negative points", body = ErrorResponse),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "Missing the users:write permission", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = ["users:write"]))
+)]
+pub async fn update_membership(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+    Json(payload): Json<UpdateMembershipRequest>,
+) -> Result<ResponseJson<UserAccount>, AppError> {
+    if let Some(level) = &payload.membership_level {
+        if !MEMBERSHIP_LEVELS.contains(&level.as_str()) {
+            return Err(AppError::validation(format!(
+                "membership_level must be one of {}",
+                MEMBERSHIP_LEVELS.join(", ")
+            )));
+        }
+    }
+    if payload.points.is_some_and(|points| points < 0) {
+        return Err(AppError::validation("points cannot be negative"));
+    }
+
+    let account = state
+        .user_repo
+        .update_membership(&user_id, &payload)
+        .await
+        .context("Failed to update membership")?
+        .ok_or_else(user_not_found)?;
+
+    Ok(ResponseJson(account))
+}
+
+/// Disable an account
+///
+/// Requires the `users:manage` permission (admin). The user can no longer log in
+/// and is signed out of every session.
+#[utoipa::path(
+    post,
+    path = "/admin/users/{id}/disable",
+    tag = "admin",
+    params(("id" = String, Path, description = "User id")),
+    responses(
+        (status = 200, description = "Account disabled", body = UserAccount),
+        (status = 400, description = "Admins cannot disable themselves", body = ErrorResponse),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "Missing the users:manage permission", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = ["users:manage"]))
+)]
+pub async fn disable_user(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Path(user_id): Path<String>,
+) -> Result<ResponseJson<UserAccount>, AppError> {
+    if user_id == auth.user_id {
+        return Err(AppError::validation("You cannot disable your own account"));
+    }
+
+    let account = state
+        .user_repo
+        .set_disabled(&user_id, true)
+        .await
+        .context("Failed to disable user")?
+        .ok_or_else(user_not_found)?;
+
+    revoke_all_sessions(&state, &user_id).await?;
+
+    Ok(ResponseJson(account))
+}
+
+/// Re-enable a disabled account
+///
+/// Requires the `users:manage` permission (admin).
+#[utoipa::path(
+    post,
+    path = "/admin/users/{id}/enable",
+    tag = "admin",
+    params(("id" = String, Path, description = "User id")),
+    responses(
+        (status = 200, description = "Account enabled", body = UserAccount),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "Missing the users:manage permission", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = ["users:manage"]))
+)]
+pub async fn enable_user(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+) -> Result<ResponseJson<UserAccount>, AppError> {
+    let account = state
+        .user_repo
+        .set_disabled(&user_id, false)
+        .await
+        .context("Failed to enable user")?
+        .ok_or_else(user_not_found)?;
+
+    Ok(ResponseJson(account))
+}
+
+/// Force a password reset
+///
+/// Requires the `users:manage` permission (admin). The user is signed out of
+/// every session and cannot log in until they reset their password.
+#[utoipa::path(
+    post,
+    path = "/admin/users/{id}/force-password-reset",
+    tag = "admin",
+    params(("id" = String, Path, description = "User id")),
+    responses(
+        (status = 200, description = "Password reset required", body = UserAccount),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "Missing the users:manage permission", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = ["users:manage"]))
+)]
+pub async fn force_password_reset(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+) -> Result<ResponseJson<UserAccount>, AppError> {
+    let account = state
+        .user_repo
+        .require_password_reset(&user_id)
+        .await
+        .context("Failed to require password reset")?
+        .ok_or_else(user_not_found)?;
+
+    revoke_all_sessions(&state, &user_id).await?;
+
+    Ok(ResponseJson(account))
+}
+
+/// Delete an account
+///
+/// Requires the `users:manage` permission (admin). This cannot be undone.
+#[utoipa::path(
+    delete,
+    path = "/admin/users/{id}",
+    tag = "admin",
+    params(("id" = String, Path, description = "User id")),
+    responses(
+        (status = 204, description = "Account deleted"),
+        (status = 400, description = "Admins cannot delete themselves", body = ErrorResponse),
+        (status = 401, description = "Unauthorized", body = ErrorResponse),
+        (status = 403, description = "Missing the users:manage permission", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse)
+    ),
+    security(("bearer_auth" = ["users:manage"]))
+)]
+pub async fn delete_user(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Path(user_id): Path<String>,
+) -> Result<StatusCode, AppError> {
+    if user_id == auth.user_id {
+        return Err(AppError::validation("You cannot delete your own account"));
+    }
+
+    let deleted = state
+        .user_repo
+        .delete_user(&user_id)
+        .await
+        .context("Failed to delete user")?;
+
+    if !deleted {
+        return Err(user_not_found());
+    }
+
+    // Access tokens outlive the account unless they are revoked too
+    revoke_all_sessions(&state, &user_id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mailer::{token_from, Email, MemoryMailer},
+        config::LoginProtectionConfig,
+        login_guard::LoginGuard,
+        mfa::code_at,
+        sms::{code_from, MemorySmsSender, Sms, SmsService},
+        models::{ChangeEmailRequest, ChangePasswordRequest, ConfirmEmailChangeRequest, ConfirmTotpRequest, ConsumeMagicLinkRequest, CreateApiTokenRequest, CurrentPasswordRequest, ForgotPasswordRequest, ListUsersQuery, LoginRequest, LogoutRequest, MagicLinkRequest, OAuthAuthorizeParams, OAuthConsentDecision, OAuthTokenReference, OAuthTokenRequest, PasskeyLoginRequest, RefreshRequest, RegisterPasskeyRequest, RegisterRequest, ResetPasswordRequest, Role, UnlockAccountRequest, UpdateMembershipRequest, UpdateRoleRequest, VerifyEmailRequest, VerifyMfaRequest},
+        repository::LoginFailureRepository,
+        test_helpers::{create_test_app_state, create_test_app_state_with_outbox, create_test_pool, MockIdp, SoftAuthenticator},
+    };
+    use axum::{
+        extract::{FromRequestParts, Json, Path, Query, State},
+        http::{header::AUTHORIZATION, HeaderMap, Request, StatusCode},
+        Form,
+    };
+
+    async fn authenticate(state: &AppState, token: &str) -> Result<AuthUser, AppError> {
+        let (mut parts, _) = Request::builder()
+            .header(AUTHORIZATION, format!("Bearer {}", token))
+            .body(())
+            .unwrap()
+            .into_parts();
+
+        AuthUser::from_request_parts(&mut parts, state).await
+    }
+
+    fn test_client() -> ClientInfo {
+        ClientInfo {
+            ip: IpAddr::from([127, 0, 0, 1]),
+            user_agent: Some("test".to_string()),
+        }
+    }
+
+    async fn set_login_protection(state: &mut AppState, config: LoginProtectionConfig) {
+        let failures = LoginFailureRepository::new(create_test_pool().await.unwrap());
+        state.login_guard = std::sync::Arc::new(LoginGuard::new(failures, config));
+    }
+
+    fn authenticated(response: ResponseJson<LoginResponse>) -> AuthResponse {
+        match response.0 {
+            LoginResponse::Authenticated(response) => response,
+            LoginResponse::MfaRequired(_) => panic!("expected tokens, got an MFA challenge"),
+        }
+    }
+
+    fn mfa_token(response: ResponseJson<LoginResponse>) -> String {
+        match response.0 {
+            LoginResponse::MfaRequired(challenge) => challenge.mfa_token,
+            LoginResponse::Authenticated(_) => panic!("expected an MFA challenge, got tokens"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_success() {
+        let app_state = create_test_app_state().await.unwrap();
+        
+        let request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+
+        let result = register(State(app_state), test_client(), Json(request)).await;
+        
+        assert!(result.is_ok());
+        let (status, response) = result.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(response.email, "test@example.com");
+        assert!(!response.token.is_empty());
+        assert!(!response.user_id.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_register_empty_email() {
+        let app_state = create_test_app_state().await.unwrap();
+        
+        let request = RegisterRequest {
+            email: "".to_string(),
+            password: "password123".to_string(),
+        };
+
+        let result = register(State(app_state), test_client(), Json(request)).await;
+        
+        assert!(result.is_err());
+        let error = result.unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.code(), "validation_error");
+        assert_eq!(error.to_string(), "Email and password are required");
+    }
+
+    #[tokio::test]
+    async fn test_register_empty_password() {
+        let app_state = create_test_app_state().await.unwrap();
+        
+        let request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "".to_string(),
+        };
+
+        let result = register(State(app_state), test_client(), Json(request)).await;
+        
+        assert!(result.is_err());
+        let error = result.unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.code(), "validation_error");
+        assert_eq!(error.to_string(), "Email and password are required");
+    }
+
+    #[tokio::test]
+    async fn test_register_short_password() {
+        let app_state = create_test_app_state().await.unwrap();
+        
+        let request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "123".to_string(),
+        };
+
+        let result = register(State(app_state), test_client(), Json(request)).await;
+        
+        assert!(result.is_err());
+        let error = result.unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.code(), "validation_error");
+        assert_eq!(error.to_string(), "Password does not meet the requirements");
+        assert_eq!(error.fields().len(), 1);
+        assert_eq!(error.fields()[0].field, "password");
+        assert_eq!(error.fields()[0].code, "too_short");
+    }
+
+    #[tokio::test]
+    async fn test_register_rejects_password_containing_email() {
+        let app_state = create_test_app_state().await.unwrap();
+
+        let request = RegisterRequest {
+            email: "margaret@example.com".to_string(),
+            password: "Margaret-1984".to_string(),
+        };
+        let error = register(State(app_state), test_client(), Json(request)).await.unwrap_err();
+
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        let codes: Vec<_> = error.fields().iter().map(|field| field.code.as_str()).collect();
+        assert_eq!(codes, ["contains_email"]);
+    }
+
+    #[tokio::test]
+    async fn test_register_duplicate_email() {
+        let app_state = create_test_app_state().await.unwrap();
+        
+        // First registration
+        let request1 = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let _ = register(State(app_state.clone()), test_client(), Json(request1)).await.unwrap();
+
+        // Second registration with same email
+        let request2 = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password456".to_string(),
+        };
+        let result = register(State(app_state), test_client(), Json(request2)).await;
+        
+        assert!(result.is_err());
+        let error = result.unwrap_err();
+        assert_eq!(error.status(), StatusCode::CONFLICT);
+        assert_eq!(error.code(), "email_exists");
+        assert_eq!(error.to_string(), "Email already exists");
+    }
+
+    #[tokio::test]
+    async fn test_login_success() {
+        let app_state = create_test_app_state().await.unwrap();
+        
+        // First register a user
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let _ = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+
+        // Then login
+        let login_request = LoginRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let result = login(State(app_state), test_client(), Json(login_request)).await;
+        
+        assert!(result.is_ok());
+        let response = authenticated(result.unwrap());
+        assert_eq!(response.email, "test@example.com");
+        assert!(!response.token.is_empty());
+        assert!(!response.user_id.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_login_empty_email() {
+        let app_state = create_test_app_state().await.unwrap();
+        
+        let request = LoginRequest {
+            email: "".to_string(),
+            password: "password123".to_string(),
+        };
+
+        let result = login(State(app_state), test_client(), Json(request)).await;
+        
+        assert!(result.is_err());
+        let error = result.unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.code(), "validation_error");
+        assert_eq!(error.to_string(), "Email and password are required");
+    }
+
+    #[tokio::test]
+    async fn test_login_empty_password() {
+        let app_state = create_test_app_state().await.unwrap();
+        
+        let request = LoginRequest {
+            email: "test@example.com".to_string(),
+            password: "".to_string(),
+        };
+
+        let result = login(State(app_state), test_client(), Json(request)).await;
+        
+        assert!(result.is_err());
+        let error = result.unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.code(), "validation_error");
+        assert_eq!(error.to_string(), "Email and password are required");
+    }
+
+    #[tokio::test]
+    async fn test_login_user_not_found() {
+        let app_state = create_test_app_state().await.unwrap();
+        
+        let request = LoginRequest {
+            email: "nonexistent@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+
+        let result = login(State(app_state), test_client(), Json(request)).await;
+        
+        assert!(result.is_err());
+        let error = result.unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.code(), "invalid_credentials");
+        assert_eq!(error.to_string(), "Invalid email or password");
+    }
+
+    #[tokio::test]
+    async fn test_login_wrong_password() {
+        let app_state = create_test_app_state().await.unwrap();
+        
+        // First register a user
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let _ = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+
+        // Then login with wrong password
+        let login_request = LoginRequest {
+            email: "test@example.com".to_string(),
+            password: "wrongpassword".to_string(),
+        };
+        let result = login(State(app_state), test_client(), Json(login_request)).await;
+        
+        assert!(result.is_err());
+        let error = result.unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.code(), "invalid_credentials");
+        assert_eq!(error.to_string(), "Invalid email or password");
+    }
+
+    #[tokio::test]
+    async fn test_jwt_token_validation() {
+        let app_state = create_test_app_state().await.unwrap();
+        
+        // Register and get token
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+
+        // Verify token can be decoded
+        let claims = app_state.jwt_service.verify_token(&register_response.token).await;
+        assert!(claims.is_ok());
+        
+        let claims = claims.unwrap();
+        assert_eq!(claims.email, "test@example.com");
+        assert_eq!(claims.sub, register_response.user_id);
+    }
+
+    #[tokio::test]
+    async fn test_password_hashing() {
+        let app_state = create_test_app_state().await.unwrap();
+        
+        // Register a user
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let _ = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+
+        // Verify password is hashed in database
+        let user = app_state.user_repo.find_by_email("test@example.com").await.unwrap().unwrap();
+        assert_ne!(user.password_hash, "password123"); // Should be hashed, not plain text
+        assert!(user.password_hash.starts_with("$argon2id$")); // PHC string format
+    }
+
+    #[tokio::test]
+    async fn test_login_upgrades_legacy_password_hash() {
+        let app_state = create_test_app_state().await.unwrap();
+        let legacy_hash = bcrypt::hash("password123", 4).unwrap();
+        app_state.user_repo.create_user("legacy@example.com", &legacy_hash).await.unwrap();
+
+        // A wrong password leaves the hash alone
+        let wrong = LoginRequest {
+            email: "legacy@example.com".to_string(),
+            password: "password124".to_string(),
+        };
+        assert!(login(State(app_state.clone()), test_client(), Json(wrong)).await.is_err());
+        let user = app_state.user_repo.find_by_email("legacy@example.com").await.unwrap().unwrap();
+        assert_eq!(user.password_hash, legacy_hash);
+
+        let request = || LoginRequest {
+            email: "legacy@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        authenticated(login(State(app_state.clone()), test_client(), Json(request())).await.unwrap());
+
+        let user = app_state.user_repo.find_by_email("legacy@example.com").await.unwrap().unwrap();
+        assert!(user.password_hash.starts_with("$argon2id$"));
+        assert!(!app_state.password_hasher.needs_rehash(&user.password_hash));
+
+        // The password still works against the new hash
+        authenticated(login(State(app_state.clone()), test_client(), Json(request())).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_register_login_flow() {
+        let app_state = create_test_app_state().await.unwrap();
+        
+        // Register
+        let register_request = RegisterRequest {
+            email: "user@example.com".to_string(),
+            password: "mypassword123".to_string(),
+        };
+        let (register_status, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        
+        assert_eq!(register_status, StatusCode::CREATED);
+        assert_eq!(register_response.email, "user@example.com");
+        
+        // Login
+        let login_request = LoginRequest {
+            email: "user@example.com".to_string(),
+            password: "mypassword123".to_string(),
+        };
+        let login_response = authenticated(login(State(app_state), test_client(), Json(login_request)).await.unwrap());
+        
+        assert_eq!(login_response.email, "user@example.com");
+        assert_eq!(login_response.user_id, register_response.user_id); // Same user ID
+        // Different tokens (new token generated on login)
+        assert_ne!(login_response.token, register_response.token);
+    }
+
+    #[tokio::test]
+    async fn test_register_returns_refresh_token() {
+        let app_state = create_test_app_state().await.unwrap();
+
+        let request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, response) = register(State(app_state), test_client(), Json(request)).await.unwrap();
+
+        assert!(!response.refresh_token.is_empty());
+        assert_ne!(response.refresh_token, response.token);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_rotates_token() {
+        let app_state = create_test_app_state().await.unwrap();
+
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+
+        let refresh_request = RefreshRequest {
+            refresh_token: register_response.refresh_token.clone(),
+        };
+        let response = refresh(State(app_state.clone()), test_client(), Json(refresh_request)).await.unwrap();
+
+        assert_eq!(response.user_id, register_response.user_id);
+        assert_eq!(response.email, "test@example.com");
+        assert_ne!(response.refresh_token, register_response.refresh_token);
+        assert!(app_state.jwt_service.verify_token(&response.token).await.is_ok());
+
+        // The rotated token works in turn
+        let refresh_request = RefreshRequest {
+            refresh_token: response.refresh_token.clone(),
+        };
+        assert!(refresh(State(app_state), test_client(), Json(refresh_request)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_reuse_revokes_family() {
+        let app_state = create_test_app_state().await.unwrap();
+
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+
+        let original = register_response.refresh_token.clone();
+        let rotated = refresh(
+            State(app_state.clone()),
+            test_client(),
+            Json(RefreshRequest { refresh_token: original.clone() }),
+        )
+        .await
+        .unwrap();
+
+        // Replaying the original token is detected as reuse
+        let result = refresh(
+            State(app_state.clone()),
+            test_client(),
+            Json(RefreshRequest { refresh_token: original }),
+        )
+        .await;
+        let error = result.unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.code(), "refresh_token_reused");
+
+        // ...and the legitimately rotated token is revoked too
+        let result = refresh(
+            State(app_state),
+            test_client(),
+            Json(RefreshRequest { refresh_token: rotated.refresh_token.clone() }),
+        )
+        .await;
+        let error = result.unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.code(), "invalid_refresh_token");
+    }
+
+    #[tokio::test]
+    async fn test_refresh_unknown_token() {
+        let app_state = create_test_app_state().await.unwrap();
+
+        let request = RefreshRequest {
+            refresh_token: "not-a-real-token".to_string(),
+        };
+        let result = refresh(State(app_state), test_client(), Json(request)).await;
+
+        let error = result.unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.code(), "invalid_refresh_token");
+        assert_eq!(error.to_string(), "Invalid or expired refresh token");
+    }
+
+    #[tokio::test]
+    async fn test_refresh_empty_token() {
+        let app_state = create_test_app_state().await.unwrap();
+
+        let request = RefreshRequest {
+            refresh_token: "".to_string(),
+        };
+        let result = refresh(State(app_state), test_client(), Json(request)).await;
+
+        let error = result.unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error.code(), "validation_error");
+    }
+
+    #[tokio::test]
+    async fn test_logout_revokes_access_token() {
+        let app_state = create_test_app_state().await.unwrap();
+
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+
+        let auth = authenticate(&app_state, &register_response.token).await.unwrap();
+        let status = logout(State(app_state.clone()), auth, None).await.unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        // The token is rejected from now on
+        let result = authenticate(&app_state, &register_response.token).await;
+        let error = result.unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.code(), "invalid_token");
+
+        // The refresh token was not part of the request, so it still works
+        let refresh_request = RefreshRequest {
+            refresh_token: register_response.refresh_token.clone(),
+        };
+        assert!(refresh(State(app_state), test_client(), Json(refresh_request)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_logout_with_refresh_token_revokes_family() {
+        let app_state = create_test_app_state().await.unwrap();
+
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+
+        let logout_request = LogoutRequest {
+            refresh_token: Some(register_response.refresh_token.clone()),
+        };
+        let auth = authenticate(&app_state, &register_response.token).await.unwrap();
+        logout(State(app_state.clone()), auth, Some(Json(logout_request)))
+        .await
+        .unwrap();
+
+        let refresh_request = RefreshRequest {
+            refresh_token: register_response.refresh_token.clone(),
+        };
+        let error = refresh(State(app_state), test_client(), Json(refresh_request)).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.code(), "invalid_refresh_token");
+    }
+
+    #[tokio::test]
+    async fn test_logout_invalid_token() {
+        let app_state = create_test_app_state().await.unwrap();
+
+        let result = authenticate(&app_state, "invalid.token.here").await;
+
+        let error = result.unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.code(), "invalid_token");
+    }
+
+    #[tokio::test]
+    async fn test_jwks_hides_shared_secret() {
+        let app_state = create_test_app_state().await.unwrap();
+
+        let response = jwks(State(app_state)).await;
+
+        assert!(response.keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_and_update_profile() {
+        let app_state = create_test_app_state().await.unwrap();
+
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+
+        let auth = authenticate(&app_state, &register_response.token).await.unwrap();
+        let profile = get_profile(State(app_state.clone()), auth.clone()).await.unwrap();
+        assert_eq!(profile.email, "test@example.com");
+        assert!(profile.first_name.is_none());
+
+        let update_request = UpdateProfileRequest {
+            first_name: Some("Somchai".to_string()),
+            last_name: Some("Jaidee".to_string()),
+            phone: Some("0812345678".to_string()),
+        };
+        let profile = update_profile(State(app_state), auth, Json(update_request)).await.unwrap();
+        assert_eq!(profile.first_name.as_deref(), Some("Somchai"));
+        assert_eq!(profile.phone.as_deref(), Some("0812345678"));
+    }
+
+    #[tokio::test]
+    async fn test_revoking_a_session_signs_it_out_immediately() {
+        let app_state = create_test_app_state().await.unwrap();
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, first) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        let login_request = || LoginRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let second = authenticated(login(State(app_state.clone()), test_client(), Json(login_request())).await.unwrap());
+        let third = authenticated(login(State(app_state.clone()), test_client(), Json(login_request())).await.unwrap());
+
+        let auth = authenticate(&app_state, &first.token).await.unwrap();
+        let sessions = list_sessions(State(app_state.clone()), auth.clone()).await.unwrap().0.sessions;
+        assert_eq!(sessions.len(), 3);
+        assert_eq!(sessions.iter().filter(|session| session.current).count(), 1);
+        assert!(sessions.iter().all(|session| session.user_agent.as_deref() == Some("test")));
+
+        // The second device is signed out at once, access and refresh tokens alike
+        let second_session = authenticate(&app_state, &second.token).await.unwrap().claims.sid.unwrap();
+        let status = revoke_session(State(app_state.clone()), auth.clone(), Path(second_session.clone())).await.unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(authenticate(&app_state, &second.token).await.unwrap_err().code(), "invalid_token");
+        let refresh_request = RefreshRequest { refresh_token: second.refresh_token };
+        assert!(refresh(State(app_state.clone()), test_client(), Json(refresh_request)).await.is_err());
+
+        let error = revoke_session(State(app_state.clone()), auth.clone(), Path(second_session)).await.unwrap_err();
+        assert_eq!(error.code(), "session_not_found");
+
+        // Signing out everywhere else keeps only the current session
+        revoke_other_sessions(State(app_state.clone()), auth.clone()).await.unwrap();
+        assert!(authenticate(&app_state, &third.token).await.is_err());
+        assert!(authenticate(&app_state, &first.token).await.is_ok());
+        let sessions = list_sessions(State(app_state), auth).await.unwrap().0.sessions;
+        assert_eq!(sessions.len(), 1);
+        assert!(sessions[0].current);
+    }
+
+    #[tokio::test]
+    async fn test_api_token_lifecycle() {
+        let app_state = create_test_app_state().await.unwrap();
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        let auth = authenticate(&app_state, &register_response.token).await.unwrap();
+        let request = |scopes: Vec<Scope>, expires_in_days| CreateApiTokenRequest {
+            name: " Nightly export ".to_string(),
+            scopes,
+            expires_in_days,
+        };
+
+        // A member cannot grant scopes for permissions their role lacks
+        let error = create_api_token(State(app_state.clone()), auth.clone(), Json(request(vec![Scope::UsersRead], None)))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), "insufficient_permissions");
+        let error = create_api_token(State(app_state.clone()), auth.clone(), Json(request(vec![], None))).await.unwrap_err();
+        assert_eq!(error.code(), "validation_error");
+        let error = create_api_token(State(app_state.clone()), auth.clone(), Json(request(vec![Scope::ProfileRead], Some(366))))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), "validation_error");
+
+        let scopes = vec![Scope::ProfileRead, Scope::ProfileRead];
+        let (status, ResponseJson(created)) = create_api_token(State(app_state.clone()), auth.clone(), Json(request(scopes, None))).await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert!(created.token.starts_with(API_TOKEN_PREFIX));
+        assert_eq!(created.api_token.name, "Nightly export");
+        assert_eq!(created.api_token.scopes, [Scope::ProfileRead]);
+        let lifetime = created.api_token.expires_at - created.api_token.created_at;
+        assert!((lifetime - Duration::days(API_TOKEN_DEFAULT_TTL_DAYS)).num_seconds().abs() <= 1);
+
+        let token_auth = authenticate(&app_state, &created.token).await.unwrap();
+        assert_eq!(get_profile(State(app_state.clone()), token_auth).await.unwrap().email, "test@example.com");
+        let api_tokens = list_api_tokens(State(app_state.clone()), auth.clone()).await.unwrap().0.api_tokens;
+        assert_eq!(api_tokens.len(), 1);
+        assert!(api_tokens[0].last_used_at.is_some());
+
+        let token_id = created.api_token.id;
+        let status = revoke_api_token(State(app_state.clone()), auth.clone(), Path(token_id.clone())).await.unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(authenticate(&app_state, &created.token).await.unwrap_err().code(), "invalid_token");
+        let error = revoke_api_token(State(app_state), auth, Path(token_id)).await.unwrap_err();
+        assert_eq!(error.code(), "api_token_not_found");
+    }
+
+    #[tokio::test]
+    async fn test_password_reset_and_change_revoke_api_tokens() {
+        let (app_state, outbox) = create_test_app_state_with_outbox().await.unwrap();
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        let create_token = |auth: AuthUser| {
+            let app_state = app_state.clone();
+            async move {
+                let request = CreateApiTokenRequest {
+                    name: "Integration".to_string(),
+                    scopes: vec![Scope::ProfileRead],
+                    expires_in_days: None,
+                };
+                let (_, ResponseJson(created)) = create_api_token(State(app_state.clone()), auth, Json(request)).await.unwrap();
+                assert!(authenticate(&app_state, &created.token).await.is_ok());
+                created.token
+            }
+        };
+
+        // A forced reset followed by a password reset leaves no API token behind
+        let auth = authenticate(&app_state, &register_response.token).await.unwrap();
+        let api_token = create_token(auth).await;
+        let account = force_password_reset(State(app_state.clone()), Path(register_response.user_id.clone())).await.unwrap();
+        assert!(account.password_reset_required);
+        forgot_password(State(app_state.clone()), Json(ForgotPasswordRequest { email: "test@example.com".to_string() })).await;
+        let token = token_from(&wait_for_email(&outbox, "test@example.com", "Reset your password").await.unwrap());
+        let request = ResetPasswordRequest {
+            token,
+            new_password: "new-password456".to_string(),
+        };
+        reset_password(State(app_state.clone()), Json(request)).await.unwrap();
+        assert_eq!(authenticate(&app_state, &api_token).await.unwrap_err().code(), "invalid_token");
+
+        // So does a password change
+        let login_request = LoginRequest {
+            email: "test@example.com".to_string(),
+            password: "new-password456".to_string(),
+        };
+        let response = authenticated(login(State(app_state.clone()), test_client(), Json(login_request)).await.unwrap());
+        let auth = authenticate(&app_state, &response.token).await.unwrap();
+        let api_token = create_token(auth.clone()).await;
+        let request = ChangePasswordRequest {
+            current_password: "new-password456".to_string(),
+            new_password: "another-password789".to_string(),
+        };
+        let response = change_password(State(app_state.clone()), auth, test_client(), Json(request)).await.unwrap();
+        assert!(authenticate(&app_state, &response.token).await.is_ok());
+        assert_eq!(authenticate(&app_state, &api_token).await.unwrap_err().code(), "invalid_token");
+    }
+
+    #[tokio::test]
+    async fn test_token_includes_user_role() {
+        let app_state = create_test_app_state().await.unwrap();
+
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+
+        let auth = authenticate(&app_state, &register_response.token).await.unwrap();
+        assert_eq!(auth.roles, vec![Role::Member]);
+
+        // After promotion, refreshed tokens carry the new role
+        app_state.user_repo.set_role(&register_response.user_id, Role::Staff).await.unwrap();
+        let refresh_request = RefreshRequest {
+            refresh_token: register_response.refresh_token.clone(),
+        };
+        let response = refresh(State(app_state.clone()), test_client(), Json(refresh_request)).await.unwrap();
+        let auth = authenticate(&app_state, &response.token).await.unwrap();
+        assert_eq!(auth.roles, vec![Role::Staff]);
+    }
+
+    #[tokio::test]
+    async fn test_update_user_role() {
+        let app_state = create_test_app_state().await.unwrap();
+        let admin = app_state.user_repo.create_user("admin@example.com", "hash").await.unwrap();
+        let member = app_state.user_repo.create_user("member@example.com", "hash").await.unwrap();
+        let token = app_state
+            .jwt_service
+            .create_token_with_roles(&admin.id, &admin.email, &[Role::Admin])
+            .unwrap();
+        let auth = authenticate(&app_state, &token).await.unwrap();
+
+        let profile = update_user_role(
+            State(app_state.clone()),
+            auth.clone(),
+            Path(member.id.clone()),
+            Json(UpdateRoleRequest { role: Role::Staff }),
+        )
+        .await
+        .unwrap();
+        assert_eq!(profile.role, Role::Staff);
+
+        let error = update_user_role(
+            State(app_state.clone()),
+            auth.clone(),
+            Path(admin.id.clone()),
+            Json(UpdateRoleRequest { role: Role::Member }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+
+        let error = update_user_role(
+            State(app_state),
+            auth,
+            Path("non-existent-id".to_string()),
+            Json(UpdateRoleRequest { role: Role::Staff }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error.code(), "user_not_found");
+    }
+
+    #[tokio::test]
+    async fn test_demoted_admin_loses_permissions_at_once() {
+        let app_state = create_test_app_state().await.unwrap();
+        let admin = app_state.user_repo.create_user("admin@example.com", "hash").await.unwrap();
+        let other = app_state.user_repo.create_user("other@example.com", "hash").await.unwrap();
+        let token = app_state
+            .jwt_service
+            .create_token_with_roles(&admin.id, &admin.email, &[Role::Admin])
+            .unwrap();
+        let auth = authenticate(&app_state, &token).await.unwrap();
+        let set_role = |role| {
+            update_user_role(
+                State(app_state.clone()),
+                auth.clone(),
+                Path(other.id.clone()),
+                Json(UpdateRoleRequest { role }),
+            )
+        };
+
+        assert_eq!(set_role(Role::Admin).await.unwrap().role, Role::Admin);
+        let admin_token = app_state
+            .jwt_service
+            .create_token_with_roles(&other.id, &other.email, &[Role::Admin])
+            .unwrap();
+        assert_eq!(authenticate(&app_state, &admin_token).await.unwrap().roles, vec![Role::Admin]);
+
+        assert_eq!(set_role(Role::Member).await.unwrap().role, Role::Member);
+
+        // The admin token stops working; tokens with the new role do
+        let error = authenticate(&app_state, &admin_token).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        let member_token = app_state
+            .jwt_service
+            .create_token_with_roles(&other.id, &other.email, &[Role::Member])
+            .unwrap();
+        assert!(authenticate(&app_state, &member_token).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_list_roles() {
+        let roles = list_roles().await;
+
+        let admin = roles.iter().find(|r| r.role == Role::Admin).unwrap();
+        assert!(admin.permissions.contains(&"roles:assign".to_string()));
+        let member = roles.iter().find(|r| r.role == Role::Member).unwrap();
+        assert!(member.permissions.is_empty());
+    }
+
+    async fn admin_auth(state: &AppState) -> AuthUser {
+        let admin = state.user_repo.create_user("admin@example.com", "hash").await.unwrap();
+        let token = state
+            .jwt_service
+            .create_token_with_roles(&admin.id, &admin.email, &[Role::Admin])
+            .unwrap();
+        authenticate(state, &token).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_disabled_user_cannot_login_or_refresh() {
+        let app_state = create_test_app_state().await.unwrap();
+        let admin = admin_auth(&app_state).await;
+
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        let user_id = register_response.user_id.clone();
+
+        let account = disable_user(State(app_state.clone()), admin.clone(), Path(user_id.clone())).await.unwrap();
+        assert!(account.disabled_at.is_some());
+
+        let login_request = || LoginRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let error = login(State(app_state.clone()), test_client(), Json(login_request())).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error.code(), "account_disabled");
+
+        // A wrong password does not reveal that the account is disabled
+        let wrong_password = LoginRequest {
+            email: "test@example.com".to_string(),
+            password: "wrongpassword".to_string(),
+        };
+        let error = login(State(app_state.clone()), test_client(), Json(wrong_password)).await.unwrap_err();
+        assert_eq!(error.code(), "invalid_credentials");
+
+        let refresh_request = RefreshRequest {
+            refresh_token: register_response.refresh_token.clone(),
+        };
+        let error = refresh(State(app_state.clone()), test_client(), Json(refresh_request)).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+
+        let account = enable_user(State(app_state.clone()), Path(user_id)).await.unwrap();
+        assert!(account.disabled_at.is_none());
+        assert!(login(State(app_state.clone()), test_client(), Json(login_request())).await.is_ok());
+
+        let error = disable_user(State(app_state.clone()), admin.clone(), Path(admin.user_id.clone())).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_force_password_reset_blocks_login() {
+        let app_state = create_test_app_state().await.unwrap();
+
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+
+        let account = force_password_reset(State(app_state.clone()), Path(register_response.user_id.clone()))
+            .await
+            .unwrap();
+        assert!(account.password_reset_required);
+
+        let login_request = LoginRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let error = login(State(app_state.clone()), test_client(), Json(login_request)).await.unwrap_err();
+        assert_eq!(error.code(), "password_reset_required");
+
+        let refresh_request = RefreshRequest {
+            refresh_token: register_response.refresh_token.clone(),
+        };
+        let error = refresh(State(app_state.clone()), test_client(), Json(refresh_request)).await.unwrap_err();
+        assert_eq!(error.code(), "invalid_refresh_token");
+
+        let error = force_password_reset(State(app_state), Path("non-existent-id".to_string()))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code(), "user_not_found");
+    }
+
+    #[tokio::test]
+    async fn test_list_users_and_update_membership() {
+        let app_state = create_test_app_state().await.unwrap();
+        let admin = admin_auth(&app_state).await;
+        let user = app_state.user_repo.create_user("member@example.com", "hash").await.unwrap();
+
+        let query = ListUsersQuery {
+            per_page: Some(1),
+            page: Some(2),
+            ..Default::default()
+        };
+        let page = list_users(State(app_state.clone()), Query(query)).await.unwrap();
+        assert_eq!(page.total, 2);
+        assert_eq!(page.users.len(), 1);
+
+        let query = ListUsersQuery {
+            per_page: Some(1000),
+            ..Default::default()
+        };
+        let error = list_users(State(app_state.clone()), Query(query)).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+
+        let request = UpdateMembershipRequest {
+            membership_level: Some("Gold".to_string()),
+            points: Some(1200),
+        };
+        let account = update_membership(State(app_state.clone()), Path(user.id.clone()), Json(request))
+            .await
+            .unwrap();
+        assert_eq!(account.membership_level, "Gold");
+        assert_eq!(account.points, 1200);
+
+        let request = UpdateMembershipRequest {
+            membership_level: Some("Diamond".to_string()),
+            points: None,
+        };
+        let error = update_membership(State(app_state.clone()), Path(user.id.clone()), Json(request))
+            .await
+            .unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+
+        let status = delete_user(State(app_state.clone()), admin, Path(user.id.clone())).await.unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let error = get_user(State(app_state), Path(user.id)).await.unwrap_err();
+        assert_eq!(error.code(), "user_not_found");
+    }
+
+    #[tokio::test]
+    async fn test_register_rejects_invalid_email() {
+        let app_state = create_test_app_state().await.unwrap();
+
+        let register_request = RegisterRequest {
+            email: "not-an-email".to_string(),
+            password: "password123".to_string(),
+        };
+        let error = register(State(app_state), test_client(), Json(register_request)).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_verify_email_flow() {
+        let (app_state, outbox) = create_test_app_state_with_outbox().await.unwrap();
+
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+
+        let auth = authenticate(&app_state, &register_response.token).await.unwrap();
+        assert!(!auth.claims.email_verified);
+
+        let token = token_from(&outbox.last_to("test@example.com").unwrap());
+        let status = verify_email(State(app_state.clone()), Json(VerifyEmailRequest { token: token.clone() }))
+            .await
+            .unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        // The token is single-use
+        let error = verify_email(State(app_state.clone()), Json(VerifyEmailRequest { token }))
+            .await
+            .unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+
+        let profile = app_state.user_repo.get_profile(&register_response.user_id).await.unwrap().unwrap();
+        assert!(profile.email_verified_at.is_some());
+
+        // New tokens report the verified address
+        let refresh_request = RefreshRequest {
+            refresh_token: register_response.refresh_token.clone(),
+        };
+        let response = refresh(State(app_state.clone()), test_client(), Json(refresh_request)).await.unwrap();
+        let auth = authenticate(&app_state, &response.token).await.unwrap();
+        assert!(auth.claims.email_verified);
+
+        let error = resend_verification_email(State(app_state), auth).await.unwrap_err();
+        assert_eq!(error.code(), "email_already_verified");
+    }
+
+    #[tokio::test]
+    async fn test_resend_verification_invalidates_earlier_link() {
+        let (app_state, outbox) = create_test_app_state_with_outbox().await.unwrap();
+
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        let first_token = token_from(&outbox.last_to("test@example.com").unwrap());
+
+        let auth = authenticate(&app_state, &register_response.token).await.unwrap();
+        let status = resend_verification_email(State(app_state.clone()), auth).await.unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert_eq!(outbox.sent().len(), 2);
+
+        let error = verify_email(State(app_state.clone()), Json(VerifyEmailRequest { token: first_token }))
+            .await
+            .unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+
+        let second_token = token_from(&outbox.last_to("test@example.com").unwrap());
+        assert!(verify_email(State(app_state), Json(VerifyEmailRequest { token: second_token }))
+            .await
+            .is_ok());
+    }
+
+    /// Wait for an email with `subject` sent from a background task.
+    async fn wait_for_email(outbox: &MemoryMailer, to: &str, subject: &str) -> Option<Email> {
+        for _ in 0..100 {
+            if let Some(email) = outbox.sent().into_iter().find(|e| e.to == to && e.subject == subject) {
+                return Some(email);
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn test_forgot_password_unknown_email() {
+        let (app_state, outbox) = create_test_app_state_with_outbox().await.unwrap();
+
+        let request = ForgotPasswordRequest {
+            email: "nobody@example.com".to_string(),
+        };
+        let status = forgot_password(State(app_state), Json(request)).await;
+
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(wait_for_email(&outbox, "nobody@example.com", "Reset your password").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_magic_link_login() {
+        let (app_state, outbox) = create_test_app_state_with_outbox().await.unwrap();
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+
+        let request = |email: &str| MagicLinkRequest { email: email.to_string() };
+        assert_eq!(request_magic_link(State(app_state.clone()), Json(request("nobody@example.com"))).await, StatusCode::ACCEPTED);
+        assert!(wait_for_email(&outbox, "nobody@example.com", "Your sign-in link").await.is_none());
+
+        assert_eq!(request_magic_link(State(app_state.clone()), Json(request("test@example.com"))).await, StatusCode::ACCEPTED);
+        let token = token_from(&wait_for_email(&outbox, "test@example.com", "Your sign-in link").await.unwrap());
+
+        let consume = |token: &str| ConsumeMagicLinkRequest { token: token.to_string() };
+        let response = authenticated(
+            consume_magic_link(State(app_state.clone()), test_client(), Json(consume(&token))).await.unwrap(),
+        );
+        assert_eq!(response.user_id, register_response.user_id);
+        assert!(authenticate(&app_state, &response.token).await.is_ok());
+
+        // The link proved the address, and works only once
+        let user = app_state.user_repo.find_by_id(&response.user_id).await.unwrap().unwrap();
+        assert!(user.email_verified_at.is_some());
+        let error = consume_magic_link(State(app_state.clone()), test_client(), Json(consume(&token))).await.unwrap_err();
+        assert_eq!(error.code(), "invalid_magic_link");
+
+        // Accounts with two-factor authentication still need the second factor
+        register_with_totp(&app_state, "mfa@example.com").await;
+        request_magic_link(State(app_state.clone()), Json(request("mfa@example.com"))).await;
+        let token = token_from(&wait_for_email(&outbox, "mfa@example.com", "Your sign-in link").await.unwrap());
+        let response = consume_magic_link(State(app_state), test_client(), Json(consume(&token))).await.unwrap();
+        mfa_token(response);
+    }
+
+    #[tokio::test]
+    async fn test_magic_link_lifts_lockout_only_when_signed_in() {
+        let (mut app_state, outbox) = create_test_app_state_with_outbox().await.unwrap();
+        set_login_protection(&mut app_state, LoginProtectionConfig {
+            max_account_failures: 1,
+            ..Default::default()
+        })
+        .await;
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let _ = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        register_with_totp(&app_state, "mfa@example.com").await;
+        let ip = test_client().ip;
+        for email in ["test@example.com", "mfa@example.com"] {
+            assert!(app_state.login_guard.record_failure(email, ip).await.unwrap());
+        }
+
+        let sign_in = |email: &'static str| {
+            let (app_state, outbox) = (app_state.clone(), outbox.clone());
+            async move {
+                request_magic_link(State(app_state.clone()), Json(MagicLinkRequest { email: email.to_string() })).await;
+                let token = token_from(&wait_for_email(&outbox, email, "Your sign-in link").await.unwrap());
+                consume_magic_link(State(app_state), test_client(), Json(ConsumeMagicLinkRequest { token }))
+                    .await
+                    .unwrap()
+            }
+        };
+
+        authenticated(sign_in("test@example.com").await);
+        assert!(app_state.login_guard.check("test@example.com", ip).await.is_ok());
+
+        // Without the second factor the account stays locked
+        mfa_token(sign_in("mfa@example.com").await);
+        let error = app_state.login_guard.check("mfa@example.com", ip).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::LOCKED);
+    }
+
+    async fn wait_for_sms(outbox: &MemorySmsSender, to: &str, text: &str) -> Option<Sms> {
+        for _ in 0..100 {
+            if let Some(sms) = outbox.sent().into_iter().rev().find(|s| s.to == to && s.body.contains(text)) {
+                return Some(sms);
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn test_phone_verification_and_login() {
+        let mut app_state = create_test_app_state().await.unwrap();
+        let outbox = MemorySmsSender::default();
+        app_state.sms = std::sync::Arc::new(SmsService::new(std::sync::Arc::new(outbox.clone()), "66"));
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, registered) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        let auth = authenticate(&app_state, &registered.token).await.unwrap();
+        let set_phone = |phone: &str| UpdateProfileRequest {
+            first_name: None,
+            last_name: None,
+            phone: Some(phone.to_string()),
+        };
+
+        // Nothing to verify until the profile has a number
+        let error = start_phone_verification(State(app_state.clone()), auth.clone()).await.unwrap_err();
+        assert_eq!(error.code(), "validation_error");
+        let profile = update_profile(State(app_state.clone()), auth.clone(), Json(set_phone("081-234-5678"))).await.unwrap();
+        assert!(profile.phone_verified_at.is_none());
+
+        let status = start_phone_verification(State(app_state.clone()), auth.clone()).await.unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        let code = code_from(&wait_for_sms(&outbox, "+66812345678", "verify").await.unwrap());
+        let error = start_phone_verification(State(app_state.clone()), auth.clone()).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(error.code(), "otp_recently_sent");
+
+        let confirm = |code: &str| ConfirmPhoneRequest { code: code.to_string() };
+        let wrong = if code == "000000" { "111111" } else { "000000" };
+        let error = confirm_phone_verification(State(app_state.clone()), auth.clone(), Json(confirm(wrong))).await.unwrap_err();
+        assert_eq!(error.code(), "invalid_otp");
+        let profile = confirm_phone_verification(State(app_state.clone()), auth.clone(), Json(confirm(&code))).await.unwrap();
+        assert_eq!(profile.phone.as_deref(), Some("+66812345678"));
+        assert!(profile.phone_verified_at.is_some());
+        let error = start_phone_verification(State(app_state.clone()), auth.clone()).await.unwrap_err();
+        assert_eq!(error.code(), "phone_already_verified");
+
+        // Another account cannot claim the same number
+        let other_request = RegisterRequest {
+            email: "other@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, other) = register(State(app_state.clone()), test_client(), Json(other_request)).await.unwrap();
+        let other_auth = authenticate(&app_state, &other.token).await.unwrap();
+        let _ = update_profile(State(app_state.clone()), other_auth.clone(), Json(set_phone("+66 81 234 5678"))).await.unwrap();
+        let error = start_phone_verification(State(app_state.clone()), other_auth).await.unwrap_err();
+        assert_eq!(error.code(), "phone_exists");
+
+        // Sign in with a code; unknown numbers get the same answer but no message
+        let request = |phone: &str| PhoneOtpRequest { phone: phone.to_string() };
+        let error = request_phone_otp(State(app_state.clone()), Json(request("12"))).await.unwrap_err();
+        assert_eq!(error.code(), "validation_error");
+        let status = request_phone_otp(State(app_state.clone()), Json(request("0899999999"))).await.unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+        assert!(wait_for_sms(&outbox, "+66899999999", "sign-in").await.is_none());
+        request_phone_otp(State(app_state.clone()), Json(request("0812345678"))).await.unwrap();
+        let code = code_from(&wait_for_sms(&outbox, "+66812345678", "sign-in").await.unwrap());
+
+        let sign_in = |code: &str| PhoneLoginRequest {
+            phone: "081 234 5678".to_string(),
+            code: code.to_string(),
+        };
+        let response = authenticated(phone_login(State(app_state.clone()), test_client(), Json(sign_in(&code))).await.unwrap());
+        assert_eq!(response.user_id, registered.user_id);
+        assert!(authenticate(&app_state, &response.token).await.is_ok());
+        let error = phone_login(State(app_state.clone()), test_client(), Json(sign_in(&code))).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.code(), "invalid_otp");
+
+        // Changing the number drops its verification, and with it phone sign-in
+        let profile = update_profile(State(app_state.clone()), auth, Json(set_phone("0898765432"))).await.unwrap();
+        assert!(profile.phone_verified_at.is_none());
+        let error = phone_login(State(app_state), test_client(), Json(sign_in(&code))).await.unwrap_err();
+        assert_eq!(error.code(), "invalid_otp");
+    }
+
+    #[tokio::test]
+    async fn test_wrong_phone_codes_lock_the_account() {
+        let mut app_state = create_test_app_state().await.unwrap();
+        set_login_protection(&mut app_state, LoginProtectionConfig {
+            max_account_failures: 3,
+            delay_after_failures: 10,
+            ..Default::default()
+        })
+        .await;
+        let outbox = MemorySmsSender::default();
+        app_state.sms = std::sync::Arc::new(SmsService::new(std::sync::Arc::new(outbox.clone()), "66"));
+        let user = app_state.user_repo.create_user("test@example.com", "hash").await.unwrap();
+        let request = UpdateProfileRequest {
+            first_name: None,
+            last_name: None,
+            phone: Some("0812345678".to_string()),
+        };
+        app_state.user_repo.update_profile(&user.id, &request).await.unwrap();
+        assert!(app_state.user_repo.mark_phone_verified(&user.id, "0812345678", "+66812345678").await.unwrap());
+
+        // Each resend brings a fresh code, but the failures add up on the account
+        let sign_in = |code: String| PhoneLoginRequest {
+            phone: "+66812345678".to_string(),
+            code,
+        };
+        let resend = || {
+            let (app_state, outbox, user_id) = (app_state.clone(), outbox.clone(), user.id.clone());
+            async move {
+                let count = outbox.sent().len();
+                send_phone_otp(&app_state, &user_id, PhoneOtpPurpose::PhoneLogin, "+66812345678").await.unwrap();
+                assert_eq!(outbox.sent().len(), count + 1);
+                code_from(&outbox.last_to("+66812345678").unwrap())
+            }
+        };
+        let wrong = |code: &str| if code == "000000" { "111111".to_string() } else { "000000".to_string() };
+        for _ in 0..2 {
+            let code = resend().await;
+            let error = phone_login(State(app_state.clone()), test_client(), Json(sign_in(wrong(&code)))).await.unwrap_err();
+            assert_eq!(error.code(), "invalid_otp");
+        }
+        let code = resend().await;
+        let error = phone_login(State(app_state.clone()), test_client(), Json(sign_in(wrong(&code)))).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::LOCKED);
+
+        // Even the right code is refused while locked
+        let code = resend().await;
+        let error = phone_login(State(app_state), test_client(), Json(sign_in(code))).await.unwrap_err();
+        assert_eq!(error.code(), "account_locked");
+    }
+
+    #[tokio::test]
+    async fn test_oidc_login() {
+        let idp = MockIdp::start().await;
+        let mut app_state = create_test_app_state().await.unwrap();
+        app_state.oidc = std::sync::Arc::new(crate::oidc::OidcClient::new(&idp.config()).unwrap());
+        let provider = || Path("mock".to_string());
+
+        // Stand in for the browser: start a sign-in and approve it at the provider
+        let sign_in = |subject: &'static str, email: &'static str| {
+            let app_state = app_state.clone();
+            let idp = &idp;
+            async move {
+                let authorization = start_oidc_login(State(app_state.clone()), provider()).await.unwrap();
+                let url = reqwest::Url::parse(&authorization.authorization_url).unwrap();
+                let request = OidcCallbackRequest {
+                    code: idp.authorize(&url, subject, email),
+                    state: authorization.state.clone(),
+                };
+                finish_oidc_login(State(app_state), test_client(), provider(), Json(request)).await
+            }
+        };
+
+        // A new user gets an account with a verified address
+        let response = authenticated(sign_in("sub-1", "new@example.com").await.unwrap());
+        assert!(authenticate(&app_state, &response.token).await.is_ok());
+        let user = app_state.user_repo.find_by_id(&response.user_id).await.unwrap().unwrap();
+        assert!(user.email_verified_at.is_some());
+
+        // The link is by subject, so it survives an address change at the provider
+        let again = authenticated(sign_in("sub-1", "renamed@example.com").await.unwrap());
+        assert_eq!(again.user_id, response.user_id);
+
+        // Existing accounts are not signed into by email address
         let register_request = RegisterRequest {
             email: "test@example.com".to_string(),
             password: "password123".to_string(),
         };
-        let _ = register(State(app_state.clone()), Json(register_request)).await.unwrap();
+        let (_, registered) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        app_state.user_repo.mark_email_verified(&registered.user_id, "test@example.com").await.unwrap();
+        let error = sign_in("sub-2", "test@example.com").await.unwrap_err();
+        assert_eq!(error.code(), "email_exists");
+        assert!(app_state.oidc_repo.find_identity("mock", "sub-2").await.unwrap().is_none());
 
-        // Then login
-        let login_request = LoginRequest {
+        // A state works once, and only for a known provider
+        let authorization = start_oidc_login(State(app_state.clone()), provider()).await.unwrap();
+        let url = reqwest::Url::parse(&authorization.authorization_url).unwrap();
+        let request = || OidcCallbackRequest {
+            code: idp.authorize(&url, "sub-1", "new@example.com"),
+            state: authorization.state.clone(),
+        };
+        authenticated(finish_oidc_login(State(app_state.clone()), test_client(), provider(), Json(request())).await.unwrap());
+        let error = finish_oidc_login(State(app_state.clone()), test_client(), provider(), Json(request())).await.unwrap_err();
+        assert_eq!(error.code(), "invalid_oidc_state");
+        let error = start_oidc_login(State(app_state.clone()), Path("other".to_string())).await.unwrap_err();
+        assert_eq!(error.code(), "oidc_provider_not_found");
+
+        // The provider must vouch for the address before it is trusted
+        idp.tamper_next_id_token(serde_json::json!({ "email_verified": false }));
+        let error = sign_in("sub-3", "unverified@example.com").await.unwrap_err();
+        assert_eq!(error.code(), "oidc_email_unverified");
+        idp.tamper_next_id_token(serde_json::json!({ "nonce": "replayed" }));
+        let error = sign_in("sub-3", "unverified@example.com").await.unwrap_err();
+        assert_eq!(error.code(), "oidc_sign_in_failed");
+    }
+
+    #[tokio::test]
+    async fn test_oidc_login_links_existing_accounts_when_trusted() {
+        let idp = MockIdp::start().await;
+        let mut app_state = create_test_app_state().await.unwrap();
+        let mut config = idp.config();
+        config.providers[0].link_existing_accounts = true;
+        app_state.oidc = std::sync::Arc::new(crate::oidc::OidcClient::new(&config).unwrap());
+        let sign_in = |subject: &'static str| {
+            let (app_state, idp) = (app_state.clone(), &idp);
+            async move {
+                let provider = || Path("mock".to_string());
+                let authorization = start_oidc_login(State(app_state.clone()), provider()).await.unwrap();
+                let url = reqwest::Url::parse(&authorization.authorization_url).unwrap();
+                let request = OidcCallbackRequest {
+                    code: idp.authorize(&url, subject, "test@example.com"),
+                    state: authorization.state.clone(),
+                };
+                finish_oidc_login(State(app_state), test_client(), provider(), Json(request)).await
+            }
+        };
+
+        // An existing account is linked only once its address is verified
+        let register_request = RegisterRequest {
             email: "test@example.com".to_string(),
             password: "password123".to_string(),
         };
-        let result = login(State(app_state), Json(login_request)).await;
-        
-        assert!(result.is_ok());
-        let response = result.unwrap();
-        assert_eq!(response.email, "test@example.com");
-        assert!(!response.token.is_empty());
-        assert!(!response.user_id.is_empty());
+        let (_, registered) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        let error = sign_in("sub-1").await.unwrap_err();
+        assert_eq!(error.code(), "email_exists");
+        app_state.user_repo.mark_email_verified(&registered.user_id, "test@example.com").await.unwrap();
+        let linked = authenticated(sign_in("sub-1").await.unwrap());
+        assert_eq!(linked.user_id, registered.user_id);
     }
 
     #[tokio::test]
-    async fn test_login_empty_email() {
+    async fn test_passkey_registration_and_login() {
         let app_state = create_test_app_state().await.unwrap();
-        
-        let request = LoginRequest {
-            email: "".to_string(),
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
             password: "password123".to_string(),
         };
+        let (_, registered) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        let auth = authenticate(&app_state, &registered.token).await.unwrap();
+        let mut authenticator = SoftAuthenticator::new("localhost", "http://localhost:3000");
 
-        let result = login(State(app_state), Json(request)).await;
-        
-        assert!(result.is_err());
-        let (status, response) = result.unwrap_err();
-        assert_eq!(status, StatusCode::BAD_REQUEST);
-        assert_eq!(response.error, "validation_error");
-        assert_eq!(response.message, "Email and password are required");
+        // Register a passkey with the options the browser was given
+        let options = start_passkey_registration(State(app_state.clone()), auth.clone()).await.unwrap();
+        assert_eq!(options.rp.id, "localhost");
+        assert!(options.exclude_credentials.is_empty());
+        let credential = authenticator.register(&options.challenge, &registered.user_id);
+        let request = |name: &str| RegisterPasskeyRequest {
+            name: name.to_string(),
+            credential: credential.clone(),
+        };
+        let error = register_passkey(State(app_state.clone()), auth.clone(), Json(request(" "))).await.unwrap_err();
+        assert_eq!(error.code(), "validation_error");
+        let (status, passkey) = register_passkey(State(app_state.clone()), auth.clone(), Json(request("Laptop"))).await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(passkey.name, "Laptop");
+
+        // The challenge works once, and the next options exclude the passkey
+        let error = register_passkey(State(app_state.clone()), auth.clone(), Json(request("Laptop"))).await.unwrap_err();
+        assert_eq!(error.code(), "passkey_challenge_expired");
+        let options = start_passkey_registration(State(app_state.clone()), auth.clone()).await.unwrap();
+        assert_eq!(options.exclude_credentials[0].id, credential.raw_id);
+
+        // Sign in with it
+        let sign_in = |credential| PasskeyLoginRequest { credential };
+        let options = start_passkey_login(State(app_state.clone())).await.unwrap();
+        let assertion = authenticator.sign_in(&options.challenge);
+        let response = passkey_login(State(app_state.clone()), test_client(), Json(sign_in(assertion.clone()))).await.unwrap();
+        assert_eq!(response.user_id, registered.user_id);
+        assert!(authenticate(&app_state, &response.token).await.is_ok());
+
+        // A replayed assertion, or one for a challenge that was never issued, fails
+        let error = passkey_login(State(app_state.clone()), test_client(), Json(sign_in(assertion))).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.code(), "passkey_sign_in_failed");
+        let assertion = authenticator.sign_in("made-up-challenge");
+        let error = passkey_login(State(app_state.clone()), test_client(), Json(sign_in(assertion))).await.unwrap_err();
+        assert_eq!(error.code(), "passkey_sign_in_failed");
+
+        // Disabled accounts cannot sign in with a passkey either
+        app_state.user_repo.set_disabled(&registered.user_id, true).await.unwrap();
+        let options = start_passkey_login(State(app_state.clone())).await.unwrap();
+        let assertion = authenticator.sign_in(&options.challenge);
+        let error = passkey_login(State(app_state.clone()), test_client(), Json(sign_in(assertion))).await.unwrap_err();
+        assert_eq!(error.code(), "account_disabled");
+        app_state.user_repo.set_disabled(&registered.user_id, false).await.unwrap();
+
+        // A removed passkey no longer signs in
+        let passkeys = list_passkeys(State(app_state.clone()), auth.clone()).await.unwrap();
+        assert_eq!(passkeys.passkeys.len(), 1);
+        assert!(passkeys.passkeys[0].last_used_at.is_some());
+        let status = delete_passkey(State(app_state.clone()), auth.clone(), Path(passkey.id.clone())).await.unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let error = delete_passkey(State(app_state.clone()), auth, Path(passkey.id.clone())).await.unwrap_err();
+        assert_eq!(error.code(), "passkey_not_found");
+        let options = start_passkey_login(State(app_state.clone())).await.unwrap();
+        let assertion = authenticator.sign_in(&options.challenge);
+        let error = passkey_login(State(app_state), test_client(), Json(sign_in(assertion))).await.unwrap_err();
+        assert_eq!(error.code(), "passkey_sign_in_failed");
     }
 
     #[tokio::test]
-    async fn test_login_empty_password() {
-        let app_state = create_test_app_state().await.unwrap();
-        
-        let request = LoginRequest {
+    async fn test_failed_passkey_sign_ins_are_throttled() {
+        let mut app_state = create_test_app_state().await.unwrap();
+        set_login_protection(&mut app_state, LoginProtectionConfig {
+            max_account_failures: 2,
+            max_ip_failures: 3,
+            delay_after_failures: 10,
+            ..Default::default()
+        })
+        .await;
+        let register_request = RegisterRequest {
             email: "test@example.com".to_string(),
-            password: "".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, registered) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        let auth = authenticate(&app_state, &registered.token).await.unwrap();
+        let mut authenticator = SoftAuthenticator::new("localhost", "http://localhost:3000");
+        let options = start_passkey_registration(State(app_state.clone()), auth.clone()).await.unwrap();
+        let request = RegisterPasskeyRequest {
+            name: "Laptop".to_string(),
+            credential: authenticator.register(&options.challenge, &registered.user_id),
         };
+        let _ = register_passkey(State(app_state.clone()), auth, Json(request)).await.unwrap();
 
-        let result = login(State(app_state), Json(request)).await;
-        
-        assert!(result.is_err());
-        let (status, response) = result.unwrap_err();
-        assert_eq!(status, StatusCode::BAD_REQUEST);
-        assert_eq!(response.error, "validation_error");
-        assert_eq!(response.message, "Email and password are required");
+        // A signature over another challenge counts against the account
+        let sign_in = |credential| PasskeyLoginRequest { credential };
+        for expected in ["passkey_sign_in_failed", "account_locked"] {
+            let options = start_passkey_login(State(app_state.clone())).await.unwrap();
+            let mut assertion = authenticator.sign_in(&options.challenge);
+            assertion.response.signature = authenticator.sign_in("another-challenge").response.signature;
+            let error = passkey_login(State(app_state.clone()), test_client(), Json(sign_in(assertion))).await.unwrap_err();
+            assert_eq!(error.code(), expected);
+        }
+
+        // Even a good signature is refused while locked
+        let options = start_passkey_login(State(app_state.clone())).await.unwrap();
+        let assertion = authenticator.sign_in(&options.challenge);
+        let error = passkey_login(State(app_state.clone()), test_client(), Json(sign_in(assertion))).await.unwrap_err();
+        assert_eq!(error.code(), "account_locked");
+
+        // Failures before the passkey is known count against the IP address
+        let assertion = authenticator.sign_in("made-up-challenge");
+        let error = passkey_login(State(app_state.clone()), test_client(), Json(sign_in(assertion))).await.unwrap_err();
+        assert_eq!(error.code(), "passkey_sign_in_failed");
+        let options = start_passkey_login(State(app_state.clone())).await.unwrap();
+        let assertion = authenticator.sign_in(&options.challenge);
+        let error = passkey_login(State(app_state), test_client(), Json(sign_in(assertion))).await.unwrap_err();
+        assert_eq!(error.code(), "too_many_attempts");
     }
 
     #[tokio::test]
-    async fn test_login_user_not_found() {
-        let app_state = create_test_app_state().await.unwrap();
-        
-        let request = LoginRequest {
-            email: "nonexistent@example.com".to_string(),
+    async fn test_oauth_authorization_code_flow() {
+        let mut app_state = create_test_app_state().await.unwrap();
+        let config = crate::config::OAuthConfig {
+            issuer: "https://api.example.com".to_string(),
+            authorization_endpoint: Some("https://app.example.com/oauth/consent".to_string()),
+            clients: vec![crate::config::OAuthClientConfig {
+                client_id: "spa".to_string(),
+                name: "Web app".to_string(),
+                client_secret: None,
+                redirect_uris: vec!["https://app.example.com/callback".to_string()],
+                scopes: ["openid", "email", "profile:read"].map(String::from).to_vec(),
+                trusted: false,
+            }],
+        };
+        app_state.oauth = std::sync::Arc::new(OAuthServer::new(&config));
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
             password: "password123".to_string(),
         };
+        let (_, registered) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        let auth = authenticate(&app_state, &registered.token).await.unwrap();
 
-        let result = login(State(app_state), Json(request)).await;
-        
-        assert!(result.is_err());
-        let (status, response) = result.unwrap_err();
-        assert_eq!(status, StatusCode::UNAUTHORIZED);
-        assert_eq!(response.error, "invalid_credentials");
-        assert_eq!(response.message, "Invalid email or password");
+        let verifier = generate_opaque_token();
+        let params = || OAuthAuthorizeParams {
+            response_type: "code".to_string(),
+            client_id: "spa".to_string(),
+            redirect_uri: "https://app.example.com/callback".to_string(),
+            scope: "openid email".to_string(),
+            state: Some("xyz".to_string()),
+            nonce: Some("n-1".to_string()),
+            code_challenge: pkce_challenge(&verifier),
+            code_challenge_method: "S256".to_string(),
+        };
+        // Stand in for the consent page: approve, and pick the code out of the redirect
+        let approve = || async {
+            let redirect = decide_oauth_authorization(State(app_state.clone()), auth.clone(), Query(params()), Json(OAuthConsentDecision { approve: true }))
+                .await
+                .unwrap();
+            let url = reqwest::Url::parse(&redirect.redirect_to).unwrap();
+            url.query_pairs().find(|(name, _)| name == "code").unwrap().1.into_owned()
+        };
+        let exchange = |code: String, verifier: &str| {
+            let request = OAuthTokenRequest {
+                grant_type: "authorization_code".to_string(),
+                code: Some(code),
+                redirect_uri: Some("https://app.example.com/callback".to_string()),
+                client_id: Some("spa".to_string()),
+                client_secret: None,
+                code_verifier: Some(verifier.to_string()),
+            };
+            exchange_oauth_code(State(app_state.clone()), HeaderMap::new(), Form(request))
+        };
+
+        let details = get_oauth_authorization(State(app_state.clone()), auth.clone(), Query(params())).await.unwrap();
+        assert_eq!(details.client_name, "Web app");
+        assert!(details.consent_required);
+
+        let denied = decide_oauth_authorization(State(app_state.clone()), auth.clone(), Query(params()), Json(OAuthConsentDecision { approve: false }))
+            .await
+            .unwrap();
+        assert!(denied.redirect_to.starts_with("https://app.example.com/callback?error=access_denied&state=xyz"));
+
+        // A code needs the right verifier, and works once
+        let code = approve().await;
+        assert_eq!(exchange(code.clone(), "wrong-verifier").await.unwrap_err().code(), "invalid_grant");
+        assert_eq!(exchange(code, &verifier).await.unwrap_err().code(), "invalid_grant");
+
+        let (_, ResponseJson(tokens)) = exchange(approve().await, &verifier).await.unwrap();
+        assert_eq!(tokens.scope, "openid email");
+        let details = get_oauth_authorization(State(app_state.clone()), auth.clone(), Query(params())).await.unwrap();
+        assert!(!details.consent_required);
+
+        // The access token is limited to the granted scopes and cannot manage the account
+        let client_auth = authenticate(&app_state, &tokens.access_token).await.unwrap();
+        assert_eq!(client_auth.claims.client_id.as_deref(), Some("spa"));
+        assert!(!client_auth.has_scope(Scope::ProfileRead));
+        let info = userinfo(State(app_state.clone()), client_auth).await.unwrap();
+        assert_eq!(info.sub, registered.user_id);
+        assert_eq!(info.email.as_deref(), Some("test@example.com"));
+        assert!(info.given_name.is_none());
+        let error = userinfo(State(app_state.clone()), auth).await.unwrap_err();
+        assert_eq!(error.code(), "insufficient_scope");
+
+        // The ID token is for the client only; it is no access token
+        let id_token = tokens.id_token.unwrap();
+        assert_eq!(authenticate(&app_state, &id_token).await.unwrap_err().code(), "invalid_token");
     }
 
     #[tokio::test]
-    async fn test_login_wrong_password() {
-        let app_state = create_test_app_state().await.unwrap();
-        
-        // First register a user
+    async fn test_token_introspection_and_revocation() {
+        let mut app_state = create_test_app_state().await.unwrap();
+        let client = |client_id: &str, client_secret: Option<&str>| crate::config::OAuthClientConfig {
+            client_id: client_id.to_string(),
+            name: client_id.to_string(),
+            client_secret: client_secret.map(String::from),
+            redirect_uris: vec!["https://app.example.com/callback".to_string()],
+            scopes: vec!["openid".to_string()],
+            trusted: false,
+        };
+        let config = crate::config::OAuthConfig {
+            issuer: "https://api.example.com".to_string(),
+            authorization_endpoint: Some("https://app.example.com/oauth/consent".to_string()),
+            clients: vec![client("gateway", Some("s3cret")), client("spa", None)],
+        };
+        app_state.oauth = std::sync::Arc::new(OAuthServer::new(&config));
         let register_request = RegisterRequest {
             email: "test@example.com".to_string(),
             password: "password123".to_string(),
         };
-        let _ = register(State(app_state.clone()), Json(register_request)).await.unwrap();
+        let (_, registered) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        let auth = authenticate(&app_state, &registered.token).await.unwrap();
+        let api_token_request = CreateApiTokenRequest {
+            name: "Export".to_string(),
+            scopes: vec![Scope::ProfileRead],
+            expires_in_days: None,
+        };
+        let (_, ResponseJson(api_token)) = create_api_token(State(app_state.clone()), auth, Json(api_token_request)).await.unwrap();
+
+        // The gateway authenticates with HTTP Basic
+        let mut headers = HeaderMap::new();
+        let credentials = STANDARD.encode("gateway:s3cret");
+        headers.insert(AUTHORIZATION, format!("Basic {}", credentials).parse().unwrap());
+        let reference = |token: &str| OAuthTokenReference {
+            token: token.to_string(),
+            token_type_hint: None,
+            client_id: None,
+            client_secret: None,
+        };
+        let introspect = |token: &str| introspect_token(State(app_state.clone()), headers.clone(), Form(reference(token)));
+        let revoke = |token: &str| revoke_oauth_token(State(app_state.clone()), headers.clone(), Form(reference(token)));
+
+        let session = introspect(&registered.token).await.unwrap();
+        assert!(session.active);
+        assert_eq!(session.sub.as_deref(), Some(registered.user_id.as_str()));
+        assert_eq!(session.roles, [Role::Member]);
+        assert!(session.scope.is_none() && session.exp.is_some());
+        let api = introspect(&api_token.token).await.unwrap();
+        assert!(api.active);
+        assert_eq!(api.scope.as_deref(), Some("profile:read"));
+        assert!(!introspect("not-a-token").await.unwrap().active);
+        assert!(!introspect(&registered.refresh_token).await.unwrap().active);
+
+        // Public clients may not introspect, and secrets are checked
+        let public = OAuthTokenReference { client_id: Some("spa".to_string()), ..reference(&registered.token) };
+        let error = introspect_token(State(app_state.clone()), HeaderMap::new(), Form(public)).await.unwrap_err();
+        assert_eq!(error.code(), "invalid_client");
+        let wrong = OAuthTokenReference {
+            client_id: Some("gateway".to_string()),
+            client_secret: Some("wrong".to_string()),
+            ..reference(&registered.token)
+        };
+        let error = revoke_oauth_token(State(app_state.clone()), HeaderMap::new(), Form(wrong)).await.unwrap_err();
+        assert_eq!(error.code(), "invalid_client");
+
+        // Clients revoke only the tokens they were issued
+        let user = app_state.user_repo.find_by_id(&registered.user_id).await.unwrap().unwrap();
+        let gateway_token = app_state.jwt_service.create_client_token(&user, "gateway", "openid").unwrap();
+        let spa_token = app_state.jwt_service.create_client_token(&user, "spa", "openid").unwrap();
+        let from_spa = |token: &str| OAuthTokenReference { client_id: Some("spa".to_string()), ..reference(token) };
+        let status = revoke_oauth_token(State(app_state.clone()), HeaderMap::new(), Form(from_spa(&gateway_token))).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert!(introspect(&gateway_token).await.unwrap().active);
+        assert_eq!(revoke(&spa_token).await.unwrap(), StatusCode::OK);
+        assert!(introspect(&spa_token).await.unwrap().active);
+        assert_eq!(revoke(&gateway_token).await.unwrap(), StatusCode::OK);
+        assert!(!introspect(&gateway_token).await.unwrap().active);
+
+        // The user's own sessions and API tokens are not the client's to end
+        let status = revoke_oauth_token(State(app_state.clone()), HeaderMap::new(), Form(from_spa(&registered.token))).await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(revoke(&registered.token).await.unwrap(), StatusCode::OK);
+        assert!(introspect(&registered.token).await.unwrap().active);
+        assert_eq!(revoke(&api_token.token).await.unwrap(), StatusCode::OK);
+        assert!(introspect(&api_token.token).await.unwrap().active);
+        assert_eq!(revoke(&registered.refresh_token).await.unwrap(), StatusCode::OK);
+        let refresh_request = RefreshRequest { refresh_token: registered.refresh_token.clone() };
+        assert!(refresh(State(app_state.clone()), test_client(), Json(refresh_request)).await.is_ok());
+        assert_eq!(revoke("not-a-token").await.unwrap(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_reset_password_flow() {
+        let (app_state, outbox) = create_test_app_state_with_outbox().await.unwrap();
+
+        let register_request = RegisterRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+
+        let request = ForgotPasswordRequest {
+            email: "test@example.com".to_string(),
+        };
+        let status = forgot_password(State(app_state.clone()), Json(request)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let email = wait_for_email(&outbox, "test@example.com", "Reset your password").await.unwrap();
+        let token = token_from(&email);
+
+        // A weak password is rejected without using up the token
+        let request = ResetPasswordRequest {
+            token: token.clone(),
+            new_password: "short".to_string(),
+        };
+        let error = reset_password(State(app_state.clone()), Json(request)).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+
+        let request = ResetPasswordRequest {
+            token: token.clone(),
+            new_password: "new-password456".to_string(),
+        };
+        let status = reset_password(State(app_state.clone()), Json(request)).await.unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        // Every earlier session is gone
+        let error = authenticate(&app_state, &register_response.token).await.unwrap_err();
+        assert_eq!(error.code(), "invalid_token");
+        let refresh_request = RefreshRequest {
+            refresh_token: register_response.refresh_token.clone(),
+        };
+        assert!(refresh(State(app_state.clone()), test_client(), Json(refresh_request)).await.is_err());
+
+        let old_login = LoginRequest {
+            email: "test@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        assert!(login(State(app_state.clone()), test_client(), Json(old_login)).await.is_err());
+        let new_login = LoginRequest {
+            email: "test@example.com".to_string(),
+            password: "new-password456".to_string(),
+        };
+        let response = authenticated(login(State(app_state.clone()), test_client(), Json(new_login)).await.unwrap());
+        assert!(authenticate(&app_state, &response.token).await.is_ok());
+
+        // The token is single-use
+        let request = ResetPasswordRequest {
+            token,
+            new_password: "another-password789".to_string(),
+        };
+        assert!(reset_password(State(app_state), Json(request)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reset_password_clears_forced_reset() {
+        let (app_state, outbox) = create_test_app_state_with_outbox().await.unwrap();
+        let user = app_state.user_repo.create_user("test@example.com", "hash").await.unwrap();
+        let account = force_password_reset(State(app_state.clone()), Path(user.id.clone())).await.unwrap();
+        assert!(account.password_reset_required);
+
+        let request = ForgotPasswordRequest {
+            email: "test@example.com".to_string(),
+        };
+        forgot_password(State(app_state.clone()), Json(request)).await;
+        let token = token_from(&wait_for_email(&outbox, "test@example.com", "Reset your password").await.unwrap());
+
+        let request = ResetPasswordRequest {
+            token,
+            new_password: "new-password456".to_string(),
+        };
+        reset_password(State(app_state.clone()), Json(request)).await.unwrap();
 
-        // Then login with wrong password
         let login_request = LoginRequest {
             email: "test@example.com".to_string(),
-            password: "wrongpassword".to_string(),
+            password: "new-password456".to_string(),
         };
-        let result = login(State(app_state), Json(login_request)).await;
-        
-        assert!(result.is_err());
-        let (status, response) = result.unwrap_err();
-        assert_eq!(status, StatusCode::UNAUTHORIZED);
-        assert_eq!(response.error, "invalid_credentials");
-        assert_eq!(response.message, "Invalid email or password");
+        assert!(login(State(app_state), test_client(), Json(login_request)).await.is_ok());
     }
 
     #[tokio::test]
-    async fn test_jwt_token_validation() {
+    async fn test_change_password() {
         let app_state = create_test_app_state().await.unwrap();
-        
-        // Register and get token
+
         let register_request = RegisterRequest {
             email: "test@example.com".to_string(),
             password: "password123".to_string(),
         };
-        let (_, register_response) = register(State(app_state.clone()), Json(register_request)).await.unwrap();
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        let auth = authenticate(&app_state, &register_response.token).await.unwrap();
 
-        // Verify token can be decoded
-        let claims = app_state.jwt_service.verify_token(&register_response.token);
-        assert!(claims.is_ok());
-        
-        let claims = claims.unwrap();
-        assert_eq!(claims.email, "test@example.com");
-        assert_eq!(claims.sub, register_response.user_id);
+        let request = ChangePasswordRequest {
+            current_password: "wrongpassword".to_string(),
+            new_password: "new-password456".to_string(),
+        };
+        let error = change_password(State(app_state.clone()), auth.clone(), test_client(), Json(request)).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error.code(), "invalid_password");
+
+        let request = ChangePasswordRequest {
+            current_password: "password123".to_string(),
+            new_password: "new-password456".to_string(),
+        };
+        let response = change_password(State(app_state.clone()), auth, test_client(), Json(request)).await.unwrap();
+
+        // This client keeps working with the new tokens; earlier ones are refused
+        assert!(authenticate(&app_state, &response.token).await.is_ok());
+        assert!(authenticate(&app_state, &register_response.token).await.is_err());
+        let refresh_request = RefreshRequest {
+            refresh_token: register_response.refresh_token.clone(),
+        };
+        assert!(refresh(State(app_state.clone()), test_client(), Json(refresh_request)).await.is_err());
+
+        let login_request = LoginRequest {
+            email: "test@example.com".to_string(),
+            password: "new-password456".to_string(),
+        };
+        assert!(login(State(app_state), test_client(), Json(login_request)).await.is_ok());
     }
 
     #[tokio::test]
-    async fn test_password_hashing() {
-        let app_state = create_test_app_state().await.unwrap();
-        
-        // Register a user
+    async fn test_change_email_flow() {
+        let (app_state, outbox) = create_test_app_state_with_outbox().await.unwrap();
+        app_state.user_repo.create_user("taken@example.com", "hash").await.unwrap();
+
         let register_request = RegisterRequest {
-            email: "test@example.com".to_string(),
+            email: "old@example.com".to_string(),
             password: "password123".to_string(),
         };
-        let (_, register_response) = register(State(app_state.clone()), Json(register_request)).await.unwrap();
+        let (_, register_response) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        let auth = authenticate(&app_state, &register_response.token).await.unwrap();
 
-        // Verify password is hashed in database
-        let user = app_state.user_repo.find_by_email("test@example.com").await.unwrap().unwrap();
-        assert_ne!(user.password_hash, "password123"); // Should be hashed, not plain text
-        assert!(user.password_hash.starts_with("$2b$")); // bcrypt hash format
+        let request = ChangeEmailRequest {
+            new_email: "taken@example.com".to_string(),
+            current_password: "password123".to_string(),
+        };
+        let error = change_email(State(app_state.clone()), auth.clone(), Json(request)).await.unwrap_err();
+        assert_eq!(error.code(), "email_exists");
+
+        let request = ChangeEmailRequest {
+            new_email: "new@example.com".to_string(),
+            current_password: "password123".to_string(),
+        };
+        let status = change_email(State(app_state.clone()), auth, Json(request)).await.unwrap();
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        // Nothing changes until the new address is confirmed
+        let user = app_state.user_repo.find_by_id(&register_response.user_id).await.unwrap().unwrap();
+        assert_eq!(user.email, "old@example.com");
+        assert_eq!(
+            outbox.last_to("old@example.com").unwrap().subject,
+            "Your email address is being changed"
+        );
+
+        // The verification link for the old address cannot confirm the new one
+        let old_link = token_from(&outbox.sent()[0]);
+        let request = ConfirmEmailChangeRequest { token: old_link };
+        assert!(confirm_email_change(State(app_state.clone()), Json(request)).await.is_err());
+
+        let token = token_from(&outbox.last_to("new@example.com").unwrap());
+        let request = ConfirmEmailChangeRequest { token: token.clone() };
+        let status = confirm_email_change(State(app_state.clone()), Json(request)).await.unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let user = app_state.user_repo.find_by_id(&register_response.user_id).await.unwrap().unwrap();
+        assert_eq!(user.email, "new@example.com");
+        assert!(user.email_verified_at.is_some());
+        let refresh_request = RefreshRequest {
+            refresh_token: register_response.refresh_token.clone(),
+        };
+        assert!(refresh(State(app_state.clone()), test_client(), Json(refresh_request)).await.is_err());
+
+        let request = ConfirmEmailChangeRequest { token };
+        assert!(confirm_email_change(State(app_state), Json(request)).await.is_err());
+    }
+
+    /// Register a user and enable TOTP for them. Returns the auth, secret,
+    /// recovery codes and the time of the code that confirmed the authenticator.
+    async fn register_with_totp(state: &AppState, email: &str) -> (AuthUser, String, Vec<String>, u64) {
+        let register_request = RegisterRequest {
+            email: email.to_string(),
+            password: "password123".to_string(),
+        };
+        let (_, response) = register(State(state.clone()), test_client(), Json(register_request)).await.unwrap();
+        let auth = authenticate(state, &response.token).await.unwrap();
+
+        let enrollment = start_totp_enrollment(State(state.clone()), auth.clone()).await.unwrap();
+        assert!(enrollment.otpauth_uri.starts_with("otpauth://totp/"));
+        assert!(enrollment.qr_code_svg.contains("<svg"));
+
+        // Not enforced until confirmed
+        let login_request = LoginRequest {
+            email: email.to_string(),
+            password: "password123".to_string(),
+        };
+        authenticated(login(State(state.clone()), test_client(), Json(login_request)).await.unwrap());
+
+        let request = ConfirmTotpRequest { code: "000000".to_string() };
+        let error = confirm_totp_enrollment(State(state.clone()), auth.clone(), Json(request)).await.unwrap_err();
+        assert_eq!(error.code(), "invalid_mfa_code");
+
+        let now = Utc::now().timestamp() as u64;
+        let request = ConfirmTotpRequest { code: code_at(&enrollment.secret, now) };
+        let codes = confirm_totp_enrollment(State(state.clone()), auth.clone(), Json(request)).await.unwrap();
+
+        (auth, enrollment.secret.clone(), codes.0.recovery_codes, now)
+    }
+
+    async fn login_with_mfa(state: &AppState, email: &str) -> String {
+        let login_request = LoginRequest {
+            email: email.to_string(),
+            password: "password123".to_string(),
+        };
+        mfa_token(login(State(state.clone()), test_client(), Json(login_request)).await.unwrap())
     }
 
     #[tokio::test]
-    async fn test_register_login_flow() {
+    async fn test_totp_two_step_login() {
         let app_state = create_test_app_state().await.unwrap();
-        
-        // Register
+        let (auth, secret, recovery_codes, confirmed_at) = register_with_totp(&app_state, "mfa@example.com").await;
+        assert_eq!(recovery_codes.len(), crate::mfa::RECOVERY_CODE_COUNT);
+
+        let status = get_mfa_status(State(app_state.clone()), auth.clone()).await.unwrap();
+        assert!(status.totp_enabled);
+        assert_eq!(status.recovery_codes_remaining, 10);
+
+        let error = start_totp_enrollment(State(app_state.clone()), auth).await.unwrap_err();
+        assert_eq!(error.code(), "mfa_already_enabled");
+
+        // The code that confirmed the authenticator cannot be replayed
+        let request = VerifyMfaRequest {
+            mfa_token: login_with_mfa(&app_state, "mfa@example.com").await,
+            code: Some(code_at(&secret, confirmed_at)),
+            recovery_code: None,
+        };
+        let error = verify_mfa(State(app_state.clone()), test_client(), Json(request)).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(error.code(), "invalid_mfa_code");
+
+        // The next step's code is accepted, once, and the challenge is single-use
+        let mfa_token = login_with_mfa(&app_state, "mfa@example.com").await;
+        let request = VerifyMfaRequest {
+            mfa_token: mfa_token.clone(),
+            code: Some(code_at(&secret, confirmed_at + 30)),
+            recovery_code: None,
+        };
+        let response = verify_mfa(State(app_state.clone()), test_client(), Json(request)).await.unwrap();
+        assert!(authenticate(&app_state, &response.token).await.is_ok());
+
+        let request = VerifyMfaRequest {
+            mfa_token,
+            code: None,
+            recovery_code: Some(recovery_codes[0].clone()),
+        };
+        let error = verify_mfa(State(app_state.clone()), test_client(), Json(request)).await.unwrap_err();
+        assert_eq!(error.code(), "invalid_mfa_token");
+
+        // Recovery codes work once, in any case and without the dash
+        let typed = recovery_codes[0].replace('-', "").to_uppercase();
+        let request = VerifyMfaRequest {
+            mfa_token: login_with_mfa(&app_state, "mfa@example.com").await,
+            code: None,
+            recovery_code: Some(typed.clone()),
+        };
+        assert!(verify_mfa(State(app_state.clone()), test_client(), Json(request)).await.is_ok());
+
+        let request = VerifyMfaRequest {
+            mfa_token: login_with_mfa(&app_state, "mfa@example.com").await,
+            code: None,
+            recovery_code: Some(typed),
+        };
+        assert_eq!(verify_mfa(State(app_state), test_client(), Json(request)).await.unwrap_err().code(), "invalid_mfa_code");
+    }
+
+    #[tokio::test]
+    async fn test_mfa_challenge_expires_after_failed_attempts() {
+        let mut app_state = create_test_app_state().await.unwrap();
+        // Leave the account limits out of the way of the per-challenge one
+        set_login_protection(&mut app_state, LoginProtectionConfig {
+            delay_after_failures: 100,
+            max_account_failures: 100,
+            ..Default::default()
+        })
+        .await;
+        let (_, _, recovery_codes, _) = register_with_totp(&app_state, "mfa@example.com").await;
+        let mfa_token = login_with_mfa(&app_state, "mfa@example.com").await;
+
+        let request = VerifyMfaRequest {
+            mfa_token: mfa_token.clone(),
+            code: Some("123456".to_string()),
+            recovery_code: Some(recovery_codes[0].clone()),
+        };
+        let error = verify_mfa(State(app_state.clone()), test_client(), Json(request)).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::BAD_REQUEST);
+
+        for _ in 0..MFA_CHALLENGE_MAX_ATTEMPTS {
+            let request = VerifyMfaRequest {
+                mfa_token: mfa_token.clone(),
+                code: None,
+                recovery_code: Some("wrong-guess".to_string()),
+            };
+            let error = verify_mfa(State(app_state.clone()), test_client(), Json(request)).await.unwrap_err();
+            assert_eq!(error.code(), "invalid_mfa_code");
+        }
+
+        let request = VerifyMfaRequest {
+            mfa_token,
+            code: None,
+            recovery_code: Some(recovery_codes[0].clone()),
+        };
+        let error = verify_mfa(State(app_state), test_client(), Json(request)).await.unwrap_err();
+        assert_eq!(error.code(), "invalid_mfa_token");
+    }
+
+    #[tokio::test]
+    async fn test_disable_totp_and_regenerate_recovery_codes() {
+        let app_state = create_test_app_state().await.unwrap();
+        let (auth, _, old_codes, _) = register_with_totp(&app_state, "mfa@example.com").await;
+
+        let wrong_password = || CurrentPasswordRequest { current_password: "wrong-password".to_string() };
+        let password = || CurrentPasswordRequest { current_password: "password123".to_string() };
+
+        let error = regenerate_recovery_codes(State(app_state.clone()), auth.clone(), Json(wrong_password())).await.unwrap_err();
+        assert_eq!(error.code(), "invalid_password");
+
+        let new_codes = regenerate_recovery_codes(State(app_state.clone()), auth.clone(), Json(password())).await.unwrap();
+        assert_ne!(new_codes.recovery_codes, old_codes);
+        let request = VerifyMfaRequest {
+            mfa_token: login_with_mfa(&app_state, "mfa@example.com").await,
+            code: None,
+            recovery_code: Some(old_codes[0].clone()),
+        };
+        assert!(verify_mfa(State(app_state.clone()), test_client(), Json(request)).await.is_err());
+
+        let error = disable_totp(State(app_state.clone()), auth.clone(), Json(wrong_password())).await.unwrap_err();
+        assert_eq!(error.code(), "invalid_password");
+        let status = disable_totp(State(app_state.clone()), auth.clone(), Json(password())).await.unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let status = get_mfa_status(State(app_state.clone()), auth.clone()).await.unwrap();
+        assert!(!status.totp_enabled);
+        assert_eq!(status.recovery_codes_remaining, 0);
+        let login_request = LoginRequest {
+            email: "mfa@example.com".to_string(),
+            password: "password123".to_string(),
+        };
+        authenticated(login(State(app_state.clone()), test_client(), Json(login_request)).await.unwrap());
+
+        let error = disable_totp(State(app_state.clone()), auth.clone(), Json(password())).await.unwrap_err();
+        assert_eq!(error.code(), "mfa_not_enabled");
+        let error = regenerate_recovery_codes(State(app_state), auth, Json(password())).await.unwrap_err();
+        assert_eq!(error.code(), "mfa_not_enabled");
+    }
+
+    #[tokio::test]
+    async fn test_login_lockout_and_unlock_by_email() {
+        let (mut app_state, outbox) = create_test_app_state_with_outbox().await.unwrap();
+        set_login_protection(&mut app_state, LoginProtectionConfig {
+            max_account_failures: 3,
+            delay_after_failures: 10,
+            ..Default::default()
+        })
+        .await;
         let register_request = RegisterRequest {
             email: "user@example.com".to_string(),
-            password: "mypassword123".to_string(),
+            password: "password123".to_string(),
         };
-        let (register_status, register_response) = register(State(app_state.clone()), Json(register_request)).await.unwrap();
-        
-        assert_eq!(register_status, StatusCode::CREATED);
-        assert_eq!(register_response.email, "user@example.com");
-        
-        // Login
-        let login_request = LoginRequest {
+        let (status, _) = register(State(app_state.clone()), test_client(), Json(register_request)).await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        let login_request = |password: &str| LoginRequest {
             email: "user@example.com".to_string(),
-            password: "mypassword123".to_string(),
+            password: password.to_string(),
         };
-        let login_response = login(State(app_state), Json(login_request)).await.unwrap();
-        
-        assert_eq!(login_response.email, "user@example.com");
-        assert_eq!(login_response.user_id, register_response.user_id); // Same user ID
-        // Different tokens (new token generated on login)
-        assert_ne!(login_response.token, register_response.token);
+
+        for _ in 0..2 {
+            let error = login(State(app_state.clone()), test_client(), Json(login_request("wrong"))).await.unwrap_err();
+            assert_eq!(error.code(), "invalid_credentials");
+        }
+        let error = login(State(app_state.clone()), test_client(), Json(login_request("wrong"))).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::LOCKED);
+        assert_eq!(error.code(), "account_locked");
+
+        // Even the right password is refused while locked
+        let error = login(State(app_state.clone()), test_client(), Json(login_request("password123"))).await.unwrap_err();
+        assert_eq!(error.code(), "account_locked");
+        assert!(error.retry_after().is_some());
+
+        let email = wait_for_email(&outbox, "user@example.com", "Your account has been locked").await.unwrap();
+        let request = UnlockAccountRequest { token: token_from(&email) };
+        let status = unlock_account(State(app_state.clone()), Json(request)).await.unwrap();
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        authenticated(login(State(app_state.clone()), test_client(), Json(login_request("password123"))).await.unwrap());
+
+        let request = UnlockAccountRequest { token: token_from(&email) };
+        assert!(unlock_account(State(app_state), Json(request)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_repeated_failures_are_slowed_down() {
+        let app_state = create_test_app_state().await.unwrap();
+        let login_request = || LoginRequest {
+            email: "nobody@example.com".to_string(),
+            password: "wrong".to_string(),
+        };
+
+        // Unknown emails are treated like real accounts
+        for _ in 0..3 {
+            let error = login(State(app_state.clone()), test_client(), Json(login_request())).await.unwrap_err();
+            assert_eq!(error.code(), "invalid_credentials");
+        }
+        let error = login(State(app_state), test_client(), Json(login_request())).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(error.code(), "too_many_attempts");
+        assert_eq!(error.retry_after(), Some(1));
     }
 }
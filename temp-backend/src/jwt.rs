@@ -1,55 +1,459 @@
-use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use anyhow::{Result, anyhow};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use serde::Serialize;
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, DecodeRsaPublicKey},
+    traits::PublicKeyParts,
+    RsaPrivateKey, RsaPublicKey,
+};
+use std::sync::{Arc, RwLock};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
 
-use crate::models::Claims;
+use crate::{
+    models::{Claims, Jwk, JwkSet, Role, User},
+    repository::{RevokedTokenRepository, SessionRepository},
+    tokens::LAST_SEEN_RESOLUTION_MINUTES,
+};
 
-pub struct JwtService {
-    encoding_key: EncodingKey,
+/// Lifetime of an access token issued by [`JwtService::create_token`].
+pub const ACCESS_TOKEN_TTL_HOURS: i64 = 24;
+
+/// A signing or verification key, identified in token headers by its `kid`.
+pub struct JwtKey {
+    kid: String,
+    algorithm: Algorithm,
+    encoding_key: Option<EncodingKey>, // None for verification-only keys
     decoding_key: DecodingKey,
+    jwk: Option<Jwk>,                  // None for shared secrets, which are never published
+    retired_at: Option<DateTime<Utc>>,
+}
+
+impl JwtKey {
+    /// HS256 shared-secret key. It cannot be published in the JWKS.
+    pub fn hmac(kid: &str, secret: &str) -> Self {
+        Self {
+            kid: kid.to_string(),
+            algorithm: Algorithm::HS256,
+            encoding_key: Some(EncodingKey::from_secret(secret.as_ref())),
+            decoding_key: DecodingKey::from_secret(secret.as_ref()),
+            jwk: None,
+            retired_at: None,
+        }
+    }
+
+    /// Load an RSA (RS256) or Ed25519 (EdDSA) key from PEM. A private key can sign
+    /// and verify; a public key (`BEGIN PUBLIC KEY`) can only verify.
+    pub fn from_pem(kid: &str, pem: &str) -> Result<Self> {
+        let rsa_private = RsaPrivateKey::from_pkcs8_pem(pem)
+            .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem));
+        if let Ok(private_key) = rsa_private {
+            let encoding_key = EncodingKey::from_rsa_pem(pem.as_bytes())
+                .map_err(|e| anyhow!("Invalid RSA private key: {}", e))?;
+            return Self::rsa(kid, &RsaPublicKey::from(&private_key), Some(encoding_key));
+        }
+
+        let rsa_public = RsaPublicKey::from_public_key_pem(pem)
+            .or_else(|_| RsaPublicKey::from_pkcs1_pem(pem));
+        if let Ok(public_key) = rsa_public {
+            return Self::rsa(kid, &public_key, None);
+        }
+
+        if let Ok(signing_key) = ed25519_dalek::SigningKey::from_pkcs8_pem(pem) {
+            let encoding_key = EncodingKey::from_ed_pem(pem.as_bytes())
+                .map_err(|e| anyhow!("Invalid Ed25519 private key: {}", e))?;
+            return Self::ed25519(kid, &signing_key.verifying_key(), Some(encoding_key));
+        }
+
+        if let Ok(verifying_key) = ed25519_dalek::VerifyingKey::from_public_key_pem(pem) {
+            return Self::ed25519(kid, &verifying_key, None);
+        }
+
+        Err(anyhow!("Key '{}' is not a PEM-encoded RSA or Ed25519 key", kid))
+    }
+
+    fn rsa(kid: &str, public_key: &RsaPublicKey, encoding_key: Option<EncodingKey>) -> Result<Self> {
+        let n = URL_SAFE_NO_PAD.encode(public_key.n().to_bytes_be());
+        let e = URL_SAFE_NO_PAD.encode(public_key.e().to_bytes_be());
+        let decoding_key = DecodingKey::from_rsa_components(&n, &e)
+            .map_err(|e| anyhow!("Invalid RSA public key: {}", e))?;
+
+        Ok(Self {
+            kid: kid.to_string(),
+            algorithm: Algorithm::RS256,
+            encoding_key,
+            decoding_key,
+            jwk: Some(Jwk {
+                kty: "RSA".to_string(),
+                key_use: "sig".to_string(),
+                alg: "RS256".to_string(),
+                kid: kid.to_string(),
+                n: Some(n),
+                e: Some(e),
+                crv: None,
+                x: None,
+            }),
+            retired_at: None,
+        })
+    }
+
+    fn ed25519(
+        kid: &str,
+        verifying_key: &ed25519_dalek::VerifyingKey,
+        encoding_key: Option<EncodingKey>,
+    ) -> Result<Self> {
+        let x = URL_SAFE_NO_PAD.encode(verifying_key.as_bytes());
+        let decoding_key = DecodingKey::from_ed_components(&x)
+            .map_err(|e| anyhow!("Invalid Ed25519 public key: {}", e))?;
+
+        Ok(Self {
+            kid: kid.to_string(),
+            algorithm: Algorithm::EdDSA,
+            encoding_key,
+            decoding_key,
+            jwk: Some(Jwk {
+                kty: "OKP".to_string(),
+                key_use: "sig".to_string(),
+                alg: "EdDSA".to_string(),
+                kid: kid.to_string(),
+                n: None,
+                e: None,
+                crv: Some("Ed25519".to_string()),
+                x: Some(x),
+            }),
+            retired_at: None,
+        })
+    }
+
+    /// Mark the key as retired: it no longer signs, but keeps verifying until
+    /// every token it could have signed has expired.
+    pub fn retired(mut self, at: DateTime<Utc>) -> Self {
+        self.retired_at = Some(at);
+        self
+    }
+
+    pub fn kid(&self) -> &str {
+        &self.kid
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    pub fn can_sign(&self) -> bool {
+        self.encoding_key.is_some()
+    }
+}
+
+struct KeyRing {
+    active_kid: String,
+    keys: Vec<JwtKey>,
+}
+
+pub struct JwtService {
+    keys: RwLock<KeyRing>,
+    access_token_ttl: Duration,
+    denylist: Option<Arc<RevokedTokenRepository>>,
+    sessions: Option<Arc<SessionRepository>>,
 }
 
 impl JwtService {
     pub fn new(secret: &str) -> Self {
         Self {
-            encoding_key: EncodingKey::from_secret(secret.as_ref()),
-            decoding_key: DecodingKey::from_secret(secret.as_ref()),
+            keys: RwLock::new(KeyRing {
+                active_kid: "default".to_string(),
+                keys: vec![JwtKey::hmac("default", secret)],
+            }),
+            access_token_ttl: Duration::hours(ACCESS_TOKEN_TTL_HOURS),
+            denylist: None,
+            sessions: None,
         }
     }
 
+    /// Sign with `active` and keep verifying with `previous` keys, each of
+    /// which must say when it was retired (see [`JwtKey::retired`]).
+    pub fn with_keys(active: JwtKey, previous: Vec<JwtKey>) -> Result<Self> {
+        if !active.can_sign() {
+            return Err(anyhow!("Active key '{}' has no private key", active.kid));
+        }
+
+        let mut keys = vec![];
+        for key in previous {
+            if key.kid == active.kid {
+                return Err(anyhow!("Duplicate key id '{}'", key.kid));
+            }
+            if key.retired_at.is_none() {
+                return Err(anyhow!("Previous key '{}' has no retirement time", key.kid));
+            }
+            keys.push(key);
+        }
+        let active_kid = active.kid.clone();
+        keys.push(active);
+
+        Ok(Self {
+            keys: RwLock::new(KeyRing { active_kid, keys }),
+            access_token_ttl: Duration::hours(ACCESS_TOKEN_TTL_HOURS),
+            denylist: None,
+            sessions: None,
+        })
+    }
+
+    pub fn with_access_token_ttl(mut self, ttl: Duration) -> Self {
+        self.access_token_ttl = ttl;
+        self
+    }
+
+    /// Check every verified token against a persisted `jti` denylist.
+    pub fn with_denylist(mut self, denylist: Arc<RevokedTokenRepository>) -> Self {
+        self.denylist = Some(denylist);
+        self
+    }
+
+    /// Refuse tokens whose session has been revoked, and record session activity.
+    pub fn with_sessions(mut self, sessions: Arc<SessionRepository>) -> Self {
+        self.sessions = Some(sessions);
+        self
+    }
+
+    /// Make `key` the signing key. The previous signing key is retired and keeps
+    /// verifying its tokens until they expire.
+    pub fn rotate(&self, key: JwtKey) -> Result<()> {
+        if !key.can_sign() {
+            return Err(anyhow!("Key '{}' has no private key", key.kid));
+        }
+
+        let now = Utc::now();
+        let mut ring = self.keys.write().map_err(|_| anyhow!("Key ring lock poisoned"))?;
+        if ring.keys.iter().any(|k| k.kid == key.kid) {
+            return Err(anyhow!("Duplicate key id '{}'", key.kid));
+        }
+
+        let active_kid = ring.active_kid.clone();
+        let ttl = self.access_token_ttl;
+        ring.keys.retain(|k| !Self::is_expired(k, ttl, now));
+        for k in ring.keys.iter_mut().filter(|k| k.kid == active_kid) {
+            k.retired_at = Some(now);
+        }
+        ring.active_kid = key.kid.clone();
+        ring.keys.push(key);
+
+        Ok(())
+    }
+
+    /// Public keys that currently verify tokens, for `/.well-known/jwks.json`.
+    pub fn jwks(&self) -> JwkSet {
+        let now = Utc::now();
+        let keys = match self.keys.read() {
+            Ok(ring) => ring
+                .keys
+                .iter()
+                .filter(|k| !Self::is_expired(k, self.access_token_ttl, now))
+                .filter_map(|k| k.jwk.clone())
+                .collect(),
+            Err(_) => vec![],
+        };
+
+        JwkSet { keys }
+    }
+
+    fn is_expired(key: &JwtKey, ttl: Duration, now: DateTime<Utc>) -> bool {
+        key.retired_at.is_some_and(|retired_at| retired_at + ttl <= now)
+    }
+
     pub fn create_token(&self, user_id: &str, email: &str) -> Result<String> {
+        self.create_token_with_roles(user_id, email, &[])
+    }
+
+    pub fn create_token_with_roles(&self, user_id: &str, email: &str, roles: &[Role]) -> Result<String> {
+        self.sign(self.new_claims(user_id, email, roles))
+    }
+
+    /// Create an access token for `session_id` describing `user`'s current
+    /// role and email verification.
+    pub fn create_user_token(&self, user: &User, session_id: &str) -> Result<String> {
+        let mut claims = self.new_claims(&user.id, &user.email, &[user.role]);
+        claims.email_verified = user.email_verified_at.is_some();
+        claims.sid = Some(session_id.to_string());
+        self.sign(claims)
+    }
+
+    fn new_claims(&self, user_id: &str, email: &str, roles: &[Role]) -> Claims {
         let now = Utc::now();
-        let exp = now + Duration::hours(24); // Token expires in 24 hours
+        let exp = now + self.access_token_ttl;
 
-        let claims = Claims {
+        Claims {
             sub: user_id.to_string(),
             email: email.to_string(),
             exp: exp.timestamp() as usize,
             iat: now.timestamp() as usize,
-        };
+            iat_us: Some(now.timestamp_micros()),
+            jti: Uuid::new_v4().to_string(),
+            roles: roles.to_vec(),
+            email_verified: false,
+            sid: None,
+            client_id: None,
+            scope: None,
+        }
+    }
+
+    /// Create an access token for an OAuth client acting for `user`, limited
+    /// to `scope`. It belongs to no session.
+    pub fn create_client_token(&self, user: &User, client_id: &str, scope: &str) -> Result<String> {
+        let mut claims = self.new_claims(&user.id, &user.email, &[user.role]);
+        claims.email_verified = user.email_verified_at.is_some();
+        claims.client_id = Some(client_id.to_string());
+        claims.scope = Some(scope.to_string());
+        self.sign(claims)
+    }
+
+    pub fn access_token_ttl(&self) -> Duration {
+        self.access_token_ttl
+    }
+
+    /// Algorithm of the key new tokens are signed with.
+    pub fn signing_algorithm(&self) -> Result<Algorithm> {
+        let ring = self.keys.read().map_err(|_| anyhow!("Key ring lock poisoned"))?;
+        ring.keys
+            .iter()
+            .find(|k| k.kid == ring.active_kid)
+            .map(|k| k.algorithm)
+            .ok_or_else(|| anyhow!("No active signing key"))
+    }
+
+    fn sign(&self, claims: Claims) -> Result<String> {
+        self.sign_claims(&claims)
+    }
+
+    /// Sign any claims with the active key, e.g. an OpenID Connect ID token.
+    /// Access tokens are refused if they carry an `aud`, so other tokens
+    /// signed this way cannot stand in for them.
+    pub fn sign_claims<T: Serialize>(&self, claims: &T) -> Result<String> {
+        let ring = self.keys.read().map_err(|_| anyhow!("Key ring lock poisoned"))?;
+        let key = ring
+            .keys
+            .iter()
+            .find(|k| k.kid == ring.active_kid)
+            .ok_or_else(|| anyhow!("No active signing key"))?;
+        let encoding_key = key
+            .encoding_key
+            .as_ref()
+            .ok_or_else(|| anyhow!("Active key '{}' cannot sign", key.kid))?;
+
+        let mut header = Header::new(key.algorithm);
+        header.kid = Some(key.kid.clone());
 
-        let token = encode(&Header::default(), &claims, &self.encoding_key)
+        let token = encode(&header, claims, encoding_key)
             .map_err(|e| anyhow!("Failed to create token: {}", e))?;
 
         Ok(token)
     }
 
-    pub fn verify_token(&self, token: &str) -> Result<Claims> {
-        let validation = Validation::new(Algorithm::HS256);
-        let token_data = decode::<Claims>(token, &self.decoding_key, &validation)
+    /// Check the signature and expiry of a token without consulting the denylist.
+    pub fn decode_token(&self, token: &str) -> Result<Claims> {
+        let header = decode_header(token).map_err(|e| anyhow!("Invalid token: {}", e))?;
+
+        let ring = self.keys.read().map_err(|_| anyhow!("Key ring lock poisoned"))?;
+        // Tokens issued before key ids were introduced carry no `kid`
+        let kid = header.kid.as_deref().unwrap_or(&ring.active_kid);
+        let key = ring
+            .keys
+            .iter()
+            .find(|k| k.kid == kid)
+            .filter(|k| !Self::is_expired(k, self.access_token_ttl, Utc::now()))
+            .ok_or_else(|| anyhow!("Invalid token: unknown key id '{}'", kid))?;
+
+        let validation = Validation::new(key.algorithm);
+        let token_data = decode::<Claims>(token, &key.decoding_key, &validation)
             .map_err(|e| anyhow!("Invalid token: {}", e))?;
 
         Ok(token_data.claims)
     }
+
+    pub async fn verify_token(&self, token: &str) -> Result<Claims> {
+        let claims = self.decode_token(token)?;
+
+        if let Some(denylist) = &self.denylist {
+            if denylist.is_revoked(&claims.jti).await? {
+                return Err(anyhow!("Token has been revoked"));
+            }
+
+            let issued_at = match claims.iat_us {
+                Some(iat_us) => DateTime::from_timestamp_micros(iat_us),
+                None => DateTime::from_timestamp(claims.iat as i64, 0),
+            }
+            .ok_or_else(|| anyhow!("Invalid token issue time"))?;
+            if denylist.is_revoked_for_user(&claims.sub, issued_at).await? {
+                return Err(anyhow!("Token has been revoked"));
+            }
+        }
+
+        if let (Some(sessions), Some(sid)) = (&self.sessions, &claims.sid) {
+            let session = sessions
+                .find(sid)
+                .await?
+                .filter(|session| session.user_id == claims.sub && session.revoked_at.is_none())
+                .ok_or_else(|| anyhow!("Session has been revoked"))?;
+
+            let stale_before = Utc::now() - Duration::minutes(LAST_SEEN_RESOLUTION_MINUTES);
+            if session.last_seen_at < stale_before {
+                sessions.mark_seen(sid, stale_before).await?;
+            }
+        }
+
+        Ok(claims)
+    }
+
+    /// Deny a token until its natural expiry.
+    pub async fn revoke_token(&self, claims: &Claims) -> Result<()> {
+        let denylist = self
+            .denylist
+            .as_ref()
+            .ok_or_else(|| anyhow!("Token revocation is not configured"))?;
+        let expires_at = DateTime::from_timestamp(claims.exp as i64, 0)
+            .ok_or_else(|| anyhow!("Invalid token expiry"))?;
+
+        denylist.revoke(&claims.jti, &claims.sub, expires_at).await
+    }
+
+    /// Deny every token issued to `user_id` so far.
+    pub async fn revoke_all_for_user(&self, user_id: &str) -> Result<()> {
+        let denylist = self
+            .denylist
+            .as_ref()
+            .ok_or_else(|| anyhow!("Token revocation is not configured"))?;
+
+        denylist.revoke_all_for_user(user_id).await
+    }
+}
+
+/// Periodically delete denylist entries for tokens that have expired anyway,
+/// so the table only ever holds tokens that could still be presented.
+pub fn spawn_denylist_pruner(
+    denylist: Arc<RevokedTokenRepository>,
+    interval: std::time::Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = denylist.prune_expired().await {
+                tracing::warn!(error = %e, "failed to prune revoked token denylist");
+            }
+        }
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_helpers::create_test_pool;
     use chrono::Utc;
 
-    #[test]
-    fn test_create_and_verify_token() {
+    #[tokio::test]
+    async fn test_create_and_verify_token() {
         let jwt_service = JwtService::new("test-secret-key");
         let user_id = "test-user-id";
         let email = "test@example.com";
@@ -59,24 +463,24 @@ mod tests {
         assert!(!token.is_empty());
 
         // Verify token
-        let claims = jwt_service.verify_token(&token).unwrap();
+        let claims = jwt_service.verify_token(&token).await.unwrap();
         assert_eq!(claims.sub, user_id);
         assert_eq!(claims.email, email);
         assert!(claims.exp > Utc::now().timestamp() as usize);
         assert!(claims.iat <= Utc::now().timestamp() as usize);
     }
 
-    #[test]
-    fn test_verify_invalid_token() {
+    #[tokio::test]
+    async fn test_verify_invalid_token() {
         let jwt_service = JwtService::new("test-secret-key");
         let invalid_token = "invalid.token.here";
 
-        let result = jwt_service.verify_token(invalid_token);
+        let result = jwt_service.verify_token(invalid_token).await;
         assert!(result.is_err());
     }
 
-    #[test]
-    fn test_verify_token_with_wrong_secret() {
+    #[tokio::test]
+    async fn test_verify_token_with_wrong_secret() {
         let jwt_service1 = JwtService::new("secret1");
         let jwt_service2 = JwtService::new("secret2");
 
@@ -84,18 +488,18 @@ mod tests {
         let token = jwt_service1.create_token("user-id", "test@example.com").unwrap();
 
         // Try to verify with second service (different secret)
-        let result = jwt_service2.verify_token(&token);
+        let result = jwt_service2.verify_token(&token).await;
         assert!(result.is_err());
     }
 
-    #[test]
-    fn test_token_contains_correct_claims() {
+    #[tokio::test]
+    async fn test_token_contains_correct_claims() {
         let jwt_service = JwtService::new("test-secret");
         let user_id = "user-123";
         let email = "user@example.com";
 
         let token = jwt_service.create_token(user_id, email).unwrap();
-        let claims = jwt_service.verify_token(&token).unwrap();
+        let claims = jwt_service.verify_token(&token).await.unwrap();
 
         assert_eq!(claims.sub, user_id);
         assert_eq!(claims.email, email);
@@ -106,4 +510,215 @@ mod tests {
         assert!(claims.exp > now);
         assert!(claims.exp <= expected_exp + 60); // Allow 1 minute variance
     }
+
+    #[tokio::test]
+    async fn test_tokens_have_unique_jti() {
+        let jwt_service = JwtService::new("test-secret");
+
+        let token1 = jwt_service.create_token("user-id", "test@example.com").unwrap();
+        let token2 = jwt_service.create_token("user-id", "test@example.com").unwrap();
+
+        let claims1 = jwt_service.verify_token(&token1).await.unwrap();
+        let claims2 = jwt_service.verify_token(&token2).await.unwrap();
+        assert!(!claims1.jti.is_empty());
+        assert_ne!(claims1.jti, claims2.jti);
+    }
+
+    #[tokio::test]
+    async fn test_revoked_token_is_rejected() {
+        let pool = create_test_pool().await.unwrap();
+        let denylist = Arc::new(RevokedTokenRepository::new(pool));
+        let jwt_service = JwtService::new("test-secret").with_denylist(denylist);
+
+        let token = jwt_service.create_token("user-id", "test@example.com").unwrap();
+        let other_token = jwt_service.create_token("user-id", "test@example.com").unwrap();
+        let claims = jwt_service.verify_token(&token).await.unwrap();
+
+        jwt_service.revoke_token(&claims).await.unwrap();
+
+        assert!(jwt_service.verify_token(&token).await.is_err());
+        assert!(jwt_service.decode_token(&token).is_ok());
+        assert!(jwt_service.verify_token(&other_token).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_revoke_all_for_user_rejects_earlier_tokens() {
+        let pool = create_test_pool().await.unwrap();
+        let denylist = Arc::new(RevokedTokenRepository::new(pool));
+        let jwt_service = JwtService::new("test-secret").with_denylist(denylist);
+
+        let token = jwt_service.create_token("user-id", "test@example.com").unwrap();
+        let other_user_token = jwt_service.create_token("other-id", "other@example.com").unwrap();
+
+        // Tokens issued in the same second are told apart by `iat_us`
+        jwt_service.revoke_all_for_user("user-id").await.unwrap();
+        let new_token = jwt_service.create_token("user-id", "test@example.com").unwrap();
+
+        assert!(jwt_service.verify_token(&token).await.is_err());
+        assert!(jwt_service.verify_token(&new_token).await.is_ok());
+        assert!(jwt_service.verify_token(&other_user_token).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_revoked_session_rejects_its_tokens() {
+        let pool = create_test_pool().await.unwrap();
+        let user = crate::repository::UserRepository::new(pool.clone())
+            .create_user("test@example.com", "password")
+            .await
+            .unwrap();
+        let sessions = Arc::new(SessionRepository::new(pool));
+        let jwt_service = JwtService::new("test-secret").with_sessions(sessions.clone());
+
+        let expires_at = Utc::now() + Duration::days(30);
+        let session = sessions.create(&user.id, None, "192.0.2.1", expires_at).await.unwrap();
+        let other = sessions.create(&user.id, None, "192.0.2.2", expires_at).await.unwrap();
+        let token = jwt_service.create_user_token(&user, &session.id).unwrap();
+        let other_token = jwt_service.create_user_token(&user, &other.id).unwrap();
+
+        let claims = jwt_service.verify_token(&token).await.unwrap();
+        assert_eq!(claims.sid.as_deref(), Some(session.id.as_str()));
+
+        sessions.revoke(&user.id, &session.id).await.unwrap();
+        assert!(jwt_service.verify_token(&token).await.is_err());
+        assert!(jwt_service.verify_token(&other_token).await.is_ok());
+
+        // Tokens from before sessions carry no `sid` and are unaffected
+        let legacy_token = jwt_service.create_token(&user.id, &user.email).unwrap();
+        assert!(jwt_service.verify_token(&legacy_token).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_revoke_without_denylist_fails() {
+        let jwt_service = JwtService::new("test-secret");
+
+        let token = jwt_service.create_token("user-id", "test@example.com").unwrap();
+        let claims = jwt_service.verify_token(&token).await.unwrap();
+
+        assert!(jwt_service.revoke_token(&claims).await.is_err());
+    }
+
+    const RSA_PRIVATE_KEY: &str = include_str!("../tests/fixtures/jwt_rsa_private.pem");
+    const ED25519_PRIVATE_KEY: &str = include_str!("../tests/fixtures/jwt_ed25519_private.pem");
+    const ED25519_PUBLIC_KEY: &str = include_str!("../tests/fixtures/jwt_ed25519_public.pem");
+
+    #[tokio::test]
+    async fn test_rs256_token() {
+        let key = JwtKey::from_pem("rsa-1", RSA_PRIVATE_KEY).unwrap();
+        assert_eq!(key.algorithm(), Algorithm::RS256);
+        let jwt_service = JwtService::with_keys(key, vec![]).unwrap();
+
+        let token = jwt_service.create_token("user-id", "test@example.com").unwrap();
+        let header = decode_header(&token).unwrap();
+        assert_eq!(header.alg, Algorithm::RS256);
+        assert_eq!(header.kid.as_deref(), Some("rsa-1"));
+
+        let claims = jwt_service.verify_token(&token).await.unwrap();
+        assert_eq!(claims.sub, "user-id");
+    }
+
+    #[tokio::test]
+    async fn test_eddsa_token_verifies_with_public_key_only() {
+        let signer = JwtService::with_keys(JwtKey::from_pem("ed-1", ED25519_PRIVATE_KEY).unwrap(), vec![]).unwrap();
+        let token = signer.create_token("user-id", "test@example.com").unwrap();
+        assert_eq!(decode_header(&token).unwrap().alg, Algorithm::EdDSA);
+
+        // A verifier holding only the public key accepts the token
+        let public_key = JwtKey::from_pem("ed-1", ED25519_PUBLIC_KEY).unwrap();
+        assert!(!public_key.can_sign());
+        let verifier = JwtService::with_keys(
+            JwtKey::from_pem("rsa-1", RSA_PRIVATE_KEY).unwrap(),
+            vec![public_key.retired(Utc::now())],
+        )
+        .unwrap();
+        assert!(verifier.verify_token(&token).await.is_ok());
+
+        // A verification-only key cannot be made active
+        let public_key = JwtKey::from_pem("ed-1", ED25519_PUBLIC_KEY).unwrap();
+        assert!(JwtService::with_keys(public_key, vec![]).is_err());
+    }
+
+    #[test]
+    fn test_invalid_pem_is_rejected() {
+        assert!(JwtKey::from_pem("bad", "not a key").is_err());
+    }
+
+    #[test]
+    fn test_jwks_publishes_only_public_keys() {
+        let hmac_service = JwtService::new("test-secret");
+        assert!(hmac_service.jwks().keys.is_empty());
+
+        let jwt_service = JwtService::with_keys(
+            JwtKey::from_pem("rsa-1", RSA_PRIVATE_KEY).unwrap(),
+            vec![JwtKey::from_pem("ed-1", ED25519_PUBLIC_KEY).unwrap().retired(Utc::now())],
+        )
+        .unwrap();
+        let jwks = jwt_service.jwks();
+        assert_eq!(jwks.keys.len(), 2);
+
+        let rsa = jwks.keys.iter().find(|k| k.kid == "rsa-1").unwrap();
+        assert_eq!(rsa.kty, "RSA");
+        assert_eq!(rsa.alg, "RS256");
+        assert!(rsa.n.is_some() && rsa.e.is_some());
+
+        let ed = jwks.keys.iter().find(|k| k.kid == "ed-1").unwrap();
+        assert_eq!(ed.kty, "OKP");
+        assert_eq!(ed.crv.as_deref(), Some("Ed25519"));
+        assert!(ed.x.is_some());
+
+        let json = serde_json::to_value(&jwks).unwrap();
+        assert_eq!(json["keys"][0]["use"], "sig");
+        assert!(json["keys"][0].get("d").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rotation_keeps_old_tokens_valid() {
+        let jwt_service = JwtService::with_keys(JwtKey::from_pem("rsa-1", RSA_PRIVATE_KEY).unwrap(), vec![]).unwrap();
+        let old_token = jwt_service.create_token("user-id", "test@example.com").unwrap();
+
+        jwt_service.rotate(JwtKey::from_pem("ed-1", ED25519_PRIVATE_KEY).unwrap()).unwrap();
+        let new_token = jwt_service.create_token("user-id", "test@example.com").unwrap();
+
+        assert_eq!(decode_header(&new_token).unwrap().kid.as_deref(), Some("ed-1"));
+        assert!(jwt_service.verify_token(&old_token).await.is_ok());
+        assert!(jwt_service.verify_token(&new_token).await.is_ok());
+        assert_eq!(jwt_service.jwks().keys.len(), 2);
+
+        // Re-using a key id is refused
+        assert!(jwt_service.rotate(JwtKey::from_pem("rsa-1", RSA_PRIVATE_KEY).unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retired_key_stops_verifying_after_token_lifetime() {
+        let retired_at = Utc::now() - Duration::hours(ACCESS_TOKEN_TTL_HOURS + 1);
+        let old_signer = JwtService::with_keys(JwtKey::from_pem("rsa-1", RSA_PRIVATE_KEY).unwrap(), vec![]).unwrap();
+        let old_token = old_signer.create_token("user-id", "test@example.com").unwrap();
+
+        let jwt_service = JwtService::with_keys(
+            JwtKey::from_pem("ed-1", ED25519_PRIVATE_KEY).unwrap(),
+            vec![JwtKey::from_pem("rsa-1", RSA_PRIVATE_KEY).unwrap().retired(retired_at)],
+        )
+        .unwrap();
+
+        assert!(jwt_service.verify_token(&old_token).await.is_err());
+        assert_eq!(jwt_service.jwks().keys.len(), 1);
+
+        // A previous key has to say when it was retired
+        let result = JwtService::with_keys(
+            JwtKey::from_pem("ed-1", ED25519_PRIVATE_KEY).unwrap(),
+            vec![JwtKey::from_pem("rsa-1", RSA_PRIVATE_KEY).unwrap()],
+        );
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_token_carries_roles() {
+        let jwt_service = JwtService::new("test-secret");
+
+        let token = jwt_service
+            .create_token_with_roles("user-id", "admin@example.com", &[Role::Admin])
+            .unwrap();
+        let claims = jwt_service.verify_token(&token).await.unwrap();
+
+        assert_eq!(claims.roles, vec![Role::Admin]);
+    }
 }
@@ -1,34 +1,90 @@
+// The endpoint list in `hello_handler` outgrows `json!`'s default recursion limit
+#![recursion_limit = "256"]
+
+pub mod auth;
+pub mod client_ip;
+pub mod config;
 pub mod database;
+pub mod error;
 pub mod handlers;
 pub mod jwt;
+pub mod login_guard;
+pub mod mailer;
+pub mod mfa;
 pub mod models;
+pub mod oauth;
+pub mod oidc;
+pub mod password;
+pub mod password_policy;
+pub mod rate_limit;
 pub mod repository;
+pub mod sms;
+pub mod tokens;
+pub mod webauthn;
 
 #[cfg(test)]
 mod test_helpers;
 
 use axum::{
-    http::Method,
-    routing::{get, post, put},
+    http::{header::RETRY_AFTER, HeaderValue, Method},
+    middleware,
+    routing::{delete, get, post, put},
     Router,
     response::Html,
 };
 use std::sync::Arc;
-use tower_http::cors::{Any, CorsLayer};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
 use utoipa::OpenApi;
 
 use crate::{
-    database::{create_pool, create_tables},
-    handlers::{login, register, get_profile, update_profile},
-    jwt::JwtService,
-    models::{AuthResponse, ErrorResponse, LoginRequest, RegisterRequest, UserProfile, UpdateProfileRequest},
-    repository::UserRepository,
+    auth::{require_permission, require_scope, require_session, Permission, Scope},
+    config::{AppConfig, CorsConfig, JwtConfig},
+    database::{create_pool, ensure_migrated, run_migrations},
+    handlers::{
+        change_email, change_password, consume_magic_link, create_api_token, decide_oauth_authorization, exchange_oauth_code, get_oauth_authorization, introspect_token, openid_configuration, revoke_oauth_token, userinfo, finish_oidc_login, confirm_email_change, confirm_totp_enrollment, delete_user, disable_totp, disable_user, enable_user, force_password_reset, forgot_password,
+        get_mfa_status, get_user, confirm_phone_verification, phone_login, request_phone_otp, start_phone_verification, jwks, list_api_tokens, list_passkeys, list_roles, list_sessions, list_users, login, logout, passkey_login, refresh, regenerate_recovery_codes, register, register_passkey, delete_passkey, get_profile, resend_verification_email, reset_password,
+        request_magic_link, revoke_api_token, start_oidc_login, start_passkey_login, start_passkey_registration, revoke_other_sessions, revoke_session, start_totp_enrollment, unlock_account, update_membership, update_profile, update_user_role, verify_email, verify_mfa,
+    },
+    jwt::{spawn_denylist_pruner, JwtKey, JwtService},
+    login_guard::{spawn_login_failure_pruner, LoginGuard},
+    rate_limit::{rate_limit, RateLimiter, RATELIMIT_LIMIT, RATELIMIT_REMAINING, RATELIMIT_RESET},
+    mailer::{build_mailer, MailService},
+    oauth::OAuthServer,
+    oidc::OidcClient,
+    password::PasswordHasher,
+    password_policy::PasswordPolicy,
+    sms::{build_sms_sender, SmsService},
+    webauthn::WebAuthn,
+    models::{ApiTokenInfo, ApiTokenList, AuthResponse, AuthenticationCredential, AuthenticatorAssertionResponse, AuthenticatorAttestationResponse, AuthenticatorSelection, ChangeEmailRequest, ChangePasswordRequest, ConfirmEmailChangeRequest, ConfirmPhoneRequest, ConfirmTotpRequest, ConsumeMagicLinkRequest, CreateApiTokenRequest, CreatedApiToken, CurrentPasswordRequest, ErrorResponse, FieldError, ForgotPasswordRequest, Jwk, JwkSet, LoginRequest, LoginResponse, LogoutRequest, MagicLinkRequest, MfaChallenge, MfaStatus, OAuthAuthorizationDetails, OAuthConsentDecision, OAuthRedirect, OAuthTokenReference, OAuthTokenRequest, OAuthTokenResponse, OidcAuthorization, OidcCallbackRequest, OpenIdConfiguration, PasskeyCreationOptions, PasskeyInfo, PasskeyList, PasskeyLoginRequest, PasskeyRequestOptions, PasskeyUserEntity, PhoneLoginRequest, PhoneOtpRequest, PublicKeyCredentialDescriptor, PublicKeyCredentialParameters, RecoveryCodes, RefreshRequest, RegisterPasskeyRequest, RegisterRequest, RegistrationCredential, RelyingParty, ResetPasswordRequest, Role, RolePermissions, SessionInfo, SessionList, TokenIntrospection, TotpEnrollment, UnlockAccountRequest, UpdateMembershipRequest, UpdateRoleRequest, UserAccount, UserInfo, UserPage, UserProfile, UpdateProfileRequest, VerifyEmailRequest, VerifyMfaRequest},
+    repository::{ApiTokenRepository, LoginFailureRepository, MfaRepository, OAuthRepository, OidcRepository, OneTimeTokenRepository, PasskeyRepository, PhoneOtpRepository, RefreshTokenRepository, RevokedTokenRepository, SessionRepository, UserRepository},
 };
 
 #[derive(Clone)]
 pub struct AppState {
     pub user_repo: Arc<UserRepository>,
+    pub refresh_token_repo: Arc<RefreshTokenRepository>,
+    pub session_repo: Arc<SessionRepository>,
+    pub api_token_repo: Arc<ApiTokenRepository>,
+    pub one_time_token_repo: Arc<OneTimeTokenRepository>,
+    pub mfa_repo: Arc<MfaRepository>,
+    pub oidc_repo: Arc<OidcRepository>,
+    pub oauth_repo: Arc<OAuthRepository>,
+    pub passkey_repo: Arc<PasskeyRepository>,
+    pub phone_otp_repo: Arc<PhoneOtpRepository>,
+    /// External OpenID Connect providers users can sign in with
+    pub oidc: Arc<OidcClient>,
+    /// Apps that sign users in through this server
+    pub oauth: Arc<OAuthServer>,
+    /// Relying party for passkeys
+    pub webauthn: Arc<WebAuthn>,
     pub jwt_service: Arc<JwtService>,
+    pub mail: Arc<MailService>,
+    pub sms: Arc<SmsService>,
+    pub login_guard: Arc<LoginGuard>,
+    pub password_hasher: Arc<PasswordHasher>,
+    pub password_policy: Arc<PasswordPolicy>,
+    /// See [`config::ServerConfig::trust_proxy_headers`]
+    pub trust_proxy_headers: bool,
 }
 
 #[derive(OpenApi)]
@@ -36,15 +92,70 @@ pub struct AppState {
     paths(
         handlers::register,
         handlers::login,
+        handlers::verify_mfa,
+        handlers::refresh,
+        handlers::logout,
+        handlers::verify_email,
+        handlers::resend_verification_email,
+        handlers::forgot_password,
+        handlers::reset_password,
+        handlers::request_magic_link,
+        handlers::consume_magic_link,
+        handlers::start_oidc_login,
+        handlers::finish_oidc_login,
+        handlers::start_passkey_login,
+        handlers::passkey_login,
+        handlers::request_phone_otp,
+        handlers::phone_login,
+        handlers::get_oauth_authorization,
+        handlers::decide_oauth_authorization,
+        handlers::exchange_oauth_code,
+        handlers::introspect_token,
+        handlers::revoke_oauth_token,
+        handlers::userinfo,
+        handlers::openid_configuration,
+        handlers::jwks,
         handlers::get_profile,
         handlers::update_profile,
+        handlers::start_phone_verification,
+        handlers::confirm_phone_verification,
+        handlers::change_password,
+        handlers::change_email,
+        handlers::list_sessions,
+        handlers::revoke_session,
+        handlers::revoke_other_sessions,
+        handlers::create_api_token,
+        handlers::list_api_tokens,
+        handlers::revoke_api_token,
+        handlers::confirm_email_change,
+        handlers::unlock_account,
+        handlers::get_mfa_status,
+        handlers::start_totp_enrollment,
+        handlers::confirm_totp_enrollment,
+        handlers::disable_totp,
+        handlers::regenerate_recovery_codes,
+        handlers::start_passkey_registration,
+        handlers::register_passkey,
+        handlers::list_passkeys,
+        handlers::delete_passkey,
+        handlers::list_roles,
+        handlers::update_user_role,
+        handlers::list_users,
+        handlers::get_user,
+        handlers::update_membership,
+        handlers::disable_user,
+        handlers::enable_user,
+        handlers::force_password_reset,
+        handlers::delete_user,
     ),
     components(
-        schemas(RegisterRequest, LoginRequest, AuthResponse, ErrorResponse, UserProfile, UpdateProfileRequest)
+        schemas(RegisterRequest, LoginRequest, RefreshRequest, LogoutRequest, AuthResponse, ErrorResponse, FieldError, UserProfile, UpdateProfileRequest, Jwk, JwkSet, Role, RolePermissions, UpdateRoleRequest, UserAccount, UserPage, UpdateMembershipRequest, VerifyEmailRequest, ForgotPasswordRequest, ResetPasswordRequest, ChangePasswordRequest, ChangeEmailRequest, ConfirmEmailChangeRequest, LoginResponse, MfaChallenge, VerifyMfaRequest, MfaStatus, TotpEnrollment, ConfirmTotpRequest, RecoveryCodes, CurrentPasswordRequest, UnlockAccountRequest, SessionInfo, SessionList, Scope, CreateApiTokenRequest, CreatedApiToken, ApiTokenInfo, ApiTokenList, MagicLinkRequest, ConsumeMagicLinkRequest, OidcAuthorization, OidcCallbackRequest, OAuthAuthorizationDetails, OAuthConsentDecision, OAuthRedirect, OAuthTokenRequest, OAuthTokenResponse, OAuthTokenReference, TokenIntrospection, UserInfo, OpenIdConfiguration, PasskeyCreationOptions, PasskeyRequestOptions, RegisterPasskeyRequest, PasskeyLoginRequest, PasskeyInfo, PasskeyList, RelyingParty, PasskeyUserEntity, PublicKeyCredentialParameters, PublicKeyCredentialDescriptor, AuthenticatorSelection, RegistrationCredential, AuthenticatorAttestationResponse, AuthenticationCredential, AuthenticatorAssertionResponse, PhoneOtpRequest, PhoneLoginRequest, ConfirmPhoneRequest)
     ),
     tags(
         (name = "auth", description = "Authentication API"),
-        (name = "profile", description = "User Profile API")
+        (name = "profile", description = "User Profile API"),
+        (name = "oauth", description = "OAuth 2.0 authorization server and OpenID Connect provider for other apps"),
+        (name = "admin", description = "Back-office API. Each operation lists the permission it requires as the bearer_auth scope")
     ),
     info(
         title = "User Management API",
@@ -75,51 +186,298 @@ impl Modify for SecurityAddon {
     }
 }
 
-pub async fn create_app() -> Result<Router, Box<dyn std::error::Error>> {
+pub async fn create_app(config: &AppConfig) -> Result<Router, Box<dyn std::error::Error>> {
     // Initialize database
-    let pool = create_pool().await?;
-    create_tables(&pool).await?;
+    let pool = create_pool(&config.database).await?;
+    if config.database.auto_migrate {
+        run_migrations(&pool).await?;
+    } else {
+        ensure_migrated(&pool).await?;
+    }
 
     // Initialize services
-    let user_repo = Arc::new(UserRepository::new(pool));
-    let jwt_service = Arc::new(JwtService::new("your-secret-key-change-this-in-production"));
+    let user_repo = Arc::new(UserRepository::new(pool.clone()));
+    let refresh_token_repo = Arc::new(RefreshTokenRepository::new(pool.clone()));
+    let session_repo = Arc::new(SessionRepository::new(pool.clone()));
+    let api_token_repo = Arc::new(ApiTokenRepository::new(pool.clone()));
+    let one_time_token_repo = Arc::new(OneTimeTokenRepository::new(pool.clone()));
+    let mfa_repo = Arc::new(MfaRepository::new(pool.clone()));
+    let oidc_repo = Arc::new(OidcRepository::new(pool.clone()));
+    let oauth_repo = Arc::new(OAuthRepository::new(pool.clone()));
+    let passkey_repo = Arc::new(PasskeyRepository::new(pool.clone()));
+    let phone_otp_repo = Arc::new(PhoneOtpRepository::new(pool.clone()));
+    let login_guard = Arc::new(LoginGuard::new(
+        LoginFailureRepository::new(pool.clone()),
+        config.login_protection.clone(),
+    ));
+    let revoked_token_repo = Arc::new(RevokedTokenRepository::new(pool));
+    let jwt_service = Arc::new(
+        build_jwt_service(config)?
+            .with_access_token_ttl(chrono::Duration::hours(config.jwt.access_token_ttl_hours))
+            .with_denylist(revoked_token_repo.clone())
+            .with_sessions(session_repo.clone()),
+    );
+
+    // Prune expired denylist entries periodically
+    spawn_denylist_pruner(
+        revoked_token_repo,
+        std::time::Duration::from_secs(config.jwt.denylist_prune_interval_secs),
+    );
+
+    spawn_login_failure_pruner(login_guard.clone());
+
+    let mail = Arc::new(MailService::new(build_mailer(&config.mail)?, &config.mail.app_url));
+    let sms = Arc::new(SmsService::new(build_sms_sender(&config.sms)?, &config.sms.default_country_code));
 
     let app_state = AppState {
         user_repo,
+        refresh_token_repo,
+        session_repo,
+        api_token_repo,
+        one_time_token_repo,
+        mfa_repo,
+        oidc_repo,
+        oauth_repo,
+        passkey_repo,
+        phone_otp_repo,
+        oidc: Arc::new(OidcClient::new(&config.oidc)?),
+        oauth: Arc::new(OAuthServer::new(&config.oauth)),
+        webauthn: Arc::new(WebAuthn::new(&config.webauthn)),
         jwt_service,
+        mail,
+        sms,
+        login_guard,
+        password_hasher: Arc::new(PasswordHasher::new(config.password_hashing.clone())),
+        password_policy: Arc::new(PasswordPolicy::load(config.password_policy.clone())?),
+        trust_proxy_headers: config.server.trust_proxy_headers,
     };
 
     // Setup CORS
-    let cors = CorsLayer::new()
-        .allow_methods([Method::GET, Method::POST, Method::PUT])
-        .allow_headers(Any)
-        .allow_origin(Any);
+    let cors = build_cors_layer(&config.cors);
+
+    // Back-office routes, each group guarded by the permission it needs
+    let guard = |permission| middleware::from_fn_with_state(app_state.clone(), require_permission(permission));
+    let admin_routes = Router::new()
+        .route("/admin/roles", get(list_roles))
+        .route("/admin/users", get(list_users))
+        .route("/admin/users/:id", get(get_user))
+        .route_layer(guard(Permission::UsersRead))
+        .merge(
+            Router::new()
+                .route("/admin/users/:id/membership", put(update_membership))
+                .route_layer(guard(Permission::UsersWrite)),
+        )
+        .merge(
+            Router::new()
+                .route("/admin/users/:id", delete(delete_user))
+                .route("/admin/users/:id/disable", post(disable_user))
+                .route("/admin/users/:id/enable", post(enable_user))
+                .route("/admin/users/:id/force-password-reset", post(force_password_reset))
+                .route_layer(guard(Permission::UsersManage)),
+        )
+        .merge(
+            Router::new()
+                .route("/admin/users/:id/role", put(update_user_role))
+                .route_layer(guard(Permission::RolesAssign)),
+        );
+
+    let auth_routes = Router::new()
+        .route("/auth/register", post(register))
+        .route("/auth/login", post(login))
+        .route("/auth/mfa/verify", post(verify_mfa))
+        .route("/auth/refresh", post(refresh))
+        .route(
+            "/auth/logout",
+            post(logout).route_layer(middleware::from_fn_with_state(app_state.clone(), require_session)),
+        )
+        .route("/auth/verify-email", post(verify_email))
+        .route("/auth/verify-email/resend", post(resend_verification_email))
+        .route("/auth/forgot-password", post(forgot_password))
+        .route("/auth/reset-password", post(reset_password))
+        .route("/auth/magic-link", post(request_magic_link))
+        .route("/auth/magic-link/consume", post(consume_magic_link))
+        .route("/auth/oidc/:provider/authorize", get(start_oidc_login))
+        .route("/auth/oidc/:provider/callback", post(finish_oidc_login))
+        .route("/auth/passkey/options", post(start_passkey_login))
+        .route("/auth/passkey/login", post(passkey_login))
+        .route("/auth/phone/otp", post(request_phone_otp))
+        .route("/auth/phone/login", post(phone_login))
+        .route("/auth/confirm-email-change", post(confirm_email_change))
+        .route("/auth/unlock-account", post(unlock_account))
+        .route(
+            "/oauth/authorize",
+            get(get_oauth_authorization)
+                .post(decide_oauth_authorization)
+                .route_layer(middleware::from_fn_with_state(app_state.clone(), require_session)),
+        )
+        .route("/oauth/token", post(exchange_oauth_code))
+        .route("/oauth/introspect", post(introspect_token))
+        .route("/oauth/revoke", post(revoke_oauth_token));
+
+    let account_security_routes = Router::new()
+        .route("/profile/password", put(change_password))
+        .route("/profile/email", post(change_email))
+        .route("/profile/mfa", get(get_mfa_status))
+        .route("/profile/mfa/totp", post(start_totp_enrollment))
+        .route("/profile/mfa/totp/confirm", post(confirm_totp_enrollment))
+        .route("/profile/mfa/totp/disable", post(disable_totp))
+        .route("/profile/mfa/recovery-codes", post(regenerate_recovery_codes))
+        .route("/profile/passkeys", get(list_passkeys).post(register_passkey))
+        .route("/profile/passkeys/register/options", post(start_passkey_registration))
+        .route("/profile/passkeys/:id", delete(delete_passkey))
+        .route("/profile/phone/verify", post(start_phone_verification))
+        .route("/profile/phone/verify/confirm", post(confirm_phone_verification))
+        .route("/sessions", get(list_sessions).delete(revoke_other_sessions))
+        .route("/sessions/:id", delete(revoke_session))
+        .route("/api-tokens", get(list_api_tokens).post(create_api_token))
+        .route("/api-tokens/:id", delete(revoke_api_token));
+
+    // API tokens reach the profile with the matching scope, but never account security
+    let scope = |scope| middleware::from_fn_with_state(app_state.clone(), require_scope(scope));
+    let profile_routes = Router::new()
+        .route("/profile", get(get_profile).route_layer(scope(Scope::ProfileRead)))
+        .route("/profile", put(update_profile).route_layer(scope(Scope::ProfileWrite)))
+        .route("/userinfo", get(userinfo))
+        .merge(account_security_routes.route_layer(middleware::from_fn_with_state(app_state.clone(), require_session)));
+
+    // Throttle each group with its own buckets
+    let (auth_routes, profile_routes, admin_routes) = if config.rate_limit.enabled {
+        let limit = |rule| {
+            middleware::from_fn_with_state(app_state.clone(), rate_limit(Arc::new(RateLimiter::new(rule))))
+        };
+        (
+            auth_routes.route_layer(limit(config.rate_limit.auth)),
+            profile_routes.route_layer(limit(config.rate_limit.profile)),
+            admin_routes.route_layer(limit(config.rate_limit.admin)),
+        )
+    } else {
+        (auth_routes, profile_routes, admin_routes)
+    };
 
     // Create routes
     let app = Router::new()
         .route("/", get(hello_handler))
-        .route("/auth/register", post(register))
-        .route("/auth/login", post(login))
-        .route("/profile", get(get_profile))
-        .route("/profile", put(update_profile))
+        .route("/.well-known/jwks.json", get(jwks))
+        .route("/.well-known/openid-configuration", get(openid_configuration))
         .route("/api-docs/openapi.json", get(|| async {
             axum::Json(ApiDoc::openapi())
         }))
         .route("/swagger-ui", get(swagger_ui))
+        .merge(auth_routes)
+        .merge(profile_routes)
+        .merge(admin_routes)
         .layer(cors)
         .with_state(app_state);
 
     Ok(app)
 }
 
+/// Sign with the configured asymmetric key if there is one, otherwise the HS256 secret.
+fn build_jwt_service(config: &AppConfig) -> Result<JwtService, Box<dyn std::error::Error>> {
+    let JwtConfig {
+        signing_key_file: Some(key_file),
+        signing_key_id: Some(kid),
+        previous_keys,
+        ..
+    } = &config.jwt
+    else {
+        return Ok(JwtService::new(config.jwt_secret()));
+    };
+
+    let read_key = |kid: &str, path: &std::path::Path| -> Result<JwtKey, Box<dyn std::error::Error>> {
+        let pem = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read JWT key '{}' from {}: {}", kid, path.display(), e))?;
+        Ok(JwtKey::from_pem(kid, &pem)?)
+    };
+
+    let active = read_key(kid, key_file)?;
+    let previous = previous_keys
+        .iter()
+        .map(|key| Ok(read_key(&key.kid, &key.path)?.retired(key.retired_at)))
+        .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+
+    Ok(JwtService::with_keys(active, previous)?)
+}
+
+fn build_cors_layer(config: &CorsConfig) -> CorsLayer {
+    let cors = CorsLayer::new()
+        .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers(Any)
+        .expose_headers([RETRY_AFTER, RATELIMIT_LIMIT, RATELIMIT_REMAINING, RATELIMIT_RESET]);
+
+    if config.allowed_origins.iter().any(|origin| origin == "*") {
+        cors.allow_origin(Any)
+    } else {
+        // Origins are checked by `AppConfig::validate`
+        let origins = config
+            .allowed_origins
+            .iter()
+            .filter_map(|origin| origin.parse::<HeaderValue>().ok());
+        cors.allow_origin(AllowOrigin::list(origins))
+    }
+}
+
 async fn hello_handler() -> axum::Json<serde_json::Value> {
     axum::Json(serde_json::json!({
         "message": "User Management API is running!",
         "endpoints": {
             "register": "POST /auth/register",
             "login": "POST /auth/login",
+            "verify_mfa": "POST /auth/mfa/verify",
+            "refresh": "POST /auth/refresh",
+            "logout": "POST /auth/logout",
+            "verify_email": "POST /auth/verify-email",
+            "resend_verification_email": "POST /auth/verify-email/resend",
+            "forgot_password": "POST /auth/forgot-password",
+            "reset_password": "POST /auth/reset-password",
+            "request_magic_link": "POST /auth/magic-link",
+            "consume_magic_link": "POST /auth/magic-link/consume",
+            "start_oidc_login": "GET /auth/oidc/{provider}/authorize",
+            "finish_oidc_login": "POST /auth/oidc/{provider}/callback",
+            "start_passkey_login": "POST /auth/passkey/options",
+            "passkey_login": "POST /auth/passkey/login",
+            "request_phone_otp": "POST /auth/phone/otp",
+            "phone_login": "POST /auth/phone/login",
+            "confirm_email_change": "POST /auth/confirm-email-change",
+            "unlock_account": "POST /auth/unlock-account",
+            "get_oauth_authorization": "GET /oauth/authorize",
+            "decide_oauth_authorization": "POST /oauth/authorize",
+            "exchange_oauth_code": "POST /oauth/token",
+            "introspect_token": "POST /oauth/introspect",
+            "revoke_oauth_token": "POST /oauth/revoke",
+            "userinfo": "GET /userinfo",
+            "openid_configuration": "GET /.well-known/openid-configuration",
+            "jwks": "GET /.well-known/jwks.json",
             "get_profile": "GET /profile",
             "update_profile": "PUT /profile",
+            "change_password": "PUT /profile/password",
+            "change_email": "POST /profile/email",
+            "get_mfa_status": "GET /profile/mfa",
+            "start_totp_enrollment": "POST /profile/mfa/totp",
+            "confirm_totp_enrollment": "POST /profile/mfa/totp/confirm",
+            "disable_totp": "POST /profile/mfa/totp/disable",
+            "regenerate_recovery_codes": "POST /profile/mfa/recovery-codes",
+            "start_passkey_registration": "POST /profile/passkeys/register/options",
+            "register_passkey": "POST /profile/passkeys",
+            "list_passkeys": "GET /profile/passkeys",
+            "delete_passkey": "DELETE /profile/passkeys/{id}",
+            "start_phone_verification": "POST /profile/phone/verify",
+            "confirm_phone_verification": "POST /profile/phone/verify/confirm",
+            "list_sessions": "GET /sessions",
+            "revoke_other_sessions": "DELETE /sessions",
+            "revoke_session": "DELETE /sessions/{id}",
+            "list_api_tokens": "GET /api-tokens",
+            "create_api_token": "POST /api-tokens",
+            "revoke_api_token": "DELETE /api-tokens/{id}",
+            "list_roles": "GET /admin/roles",
+            "update_user_role": "PUT /admin/users/{id}/role",
+            "list_users": "GET /admin/users",
+            "get_user": "GET /admin/users/{id}",
+            "update_membership": "PUT /admin/users/{id}/membership",
+            "disable_user": "POST /admin/users/{id}/disable",
+            "enable_user": "POST /admin/users/{id}/enable",
+            "force_password_reset": "POST /admin/users/{id}/force-password-reset",
+            "delete_user": "DELETE /admin/users/{id}",
             "api_docs": "GET /api-docs/openapi.json",
             "swagger_ui": "GET /swagger-ui"
         }
@@ -155,3 +513,47 @@ async fn swagger_ui() -> Html<&'static str> {
 </html>
 "#)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PreviousKeyConfig;
+    use chrono::{Duration, Utc};
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+    }
+
+    #[tokio::test]
+    async fn test_previous_keys_retire_at_their_configured_time() {
+        let rsa_only = |retired_at| {
+            let mut config = AppConfig::default();
+            config.jwt.signing_key_file = Some(fixture("jwt_ed25519_private.pem"));
+            config.jwt.signing_key_id = Some("ed-1".to_string());
+            config.jwt.previous_keys = vec![PreviousKeyConfig {
+                kid: "rsa-1".to_string(),
+                path: fixture("jwt_rsa_private.pem"),
+                retired_at,
+            }];
+            build_jwt_service(&config).unwrap()
+        };
+        let old_signer = JwtService::with_keys(
+            JwtKey::from_pem("rsa-1", include_str!("../tests/fixtures/jwt_rsa_private.pem")).unwrap(),
+            vec![],
+        )
+        .unwrap();
+        let old_token = old_signer.create_token("user-id", "test@example.com").unwrap();
+
+        // Retired recently: still verifies and is published
+        let jwt_service = rsa_only(Utc::now() - Duration::hours(1));
+        assert!(jwt_service.verify_token(&old_token).await.is_ok());
+        assert!(jwt_service.jwks().keys.iter().any(|k| k.kid == "rsa-1"));
+
+        // Retired more than a token lifetime ago, however recently the server started
+        let ttl = Duration::hours(config::JwtConfig::default().access_token_ttl_hours);
+        let jwt_service = rsa_only(Utc::now() - ttl - Duration::minutes(1));
+        assert!(jwt_service.verify_token(&old_token).await.is_err());
+        assert!(jwt_service.jwks().keys.iter().all(|k| k.kid != "rsa-1"));
+    }
+}
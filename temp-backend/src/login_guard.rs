@@ -0,0 +1,290 @@
+use anyhow::Context;
+use chrono::{DateTime, Duration, Utc};
+use std::{net::IpAddr, sync::Arc};
+use tokio::task::JoinHandle;
+
+use crate::{
+    config::LoginProtectionConfig,
+    error::AppError,
+    models::LoginFailures,
+    repository::LoginFailureRepository,
+};
+
+/// Counts failed sign-ins per account and per IP address, and refuses further
+/// attempts while either is over its limit: progressively longer waits on an
+/// account, then a temporary lockout; a block on an IP address for the rest of
+/// the window.
+pub struct LoginGuard {
+    failures: LoginFailureRepository,
+    config: LoginProtectionConfig,
+}
+
+fn account_key(email: &str) -> String {
+    format!("account:{}", email.trim().to_lowercase())
+}
+
+fn ip_key(ip: IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+/// Whole seconds from `now` until `until`, rounded up.
+fn seconds_until(until: DateTime<Utc>, now: DateTime<Utc>) -> u64 {
+    let millis = (until - now).num_milliseconds().max(0) as u64;
+    millis.div_ceil(1000)
+}
+
+fn too_many_attempts(retry_after: u64) -> AppError {
+    AppError::too_many_requests(
+        "too_many_attempts",
+        format!(
+            "Too many failed sign-in attempts. Try again in {} second{}",
+            retry_after,
+            if retry_after == 1 { "" } else { "s" }
+        ),
+        retry_after,
+    )
+}
+
+impl LoginGuard {
+    pub fn new(failures: LoginFailureRepository, config: LoginProtectionConfig) -> Self {
+        Self { failures, config }
+    }
+
+    fn window(&self) -> Duration {
+        Duration::minutes(self.config.failure_window_minutes)
+    }
+
+    /// When the next attempt on `account` is allowed, if it has to wait.
+    fn next_attempt_at(&self, account: &LoginFailures, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        if account.first_failed_at <= now - self.window() {
+            return None;
+        }
+
+        let over = account.failures - i64::from(self.config.delay_after_failures);
+        if over < 0 {
+            return None;
+        }
+        let delay = 2i64
+            .checked_pow(over as u32)
+            .unwrap_or(i64::MAX)
+            .min(self.config.max_delay_secs);
+
+        Some(account.last_failed_at + Duration::seconds(delay))
+    }
+
+    /// Refuse a sign-in attempt on `email` from `ip` while the account is locked
+    /// or must wait (`423`/`429`), or the address is blocked (`429`).
+    pub async fn check(&self, email: &str, ip: IpAddr) -> Result<(), AppError> {
+        let now = Utc::now();
+
+        let account = self
+            .failures
+            .find(&account_key(email))
+            .await
+            .context("Failed to load sign-in failures")?;
+        if let Some(account) = account {
+            if let Some(until) = account.locked_until.filter(|until| *until > now) {
+                return Err(AppError::locked(
+                    "account_locked",
+                    "This account is temporarily locked after too many failed sign-in attempts. \
+                     Use the link we emailed you to unlock it now",
+                    seconds_until(until, now),
+                ));
+            }
+            if let Some(next) = self.next_attempt_at(&account, now).filter(|next| *next > now) {
+                return Err(too_many_attempts(seconds_until(next, now)));
+            }
+        }
+
+        self.check_ip(ip).await
+    }
+
+    /// Refuse a sign-in attempt from `ip` while the address is blocked (`429`),
+    /// for sign-ins that do not name the account up front.
+    pub async fn check_ip(&self, ip: IpAddr) -> Result<(), AppError> {
+        let now = Utc::now();
+
+        let address = self
+            .failures
+            .find(&ip_key(ip))
+            .await
+            .context("Failed to load sign-in failures")?;
+        if let Some(address) = address {
+            let window_end = address.first_failed_at + self.window();
+            if address.failures >= i64::from(self.config.max_ip_failures) && window_end > now {
+                return Err(too_many_attempts(seconds_until(window_end, now)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Count a failed attempt on `email` from `ip`. Returns `true` if this
+    /// locked the account, so its owner can be sent an unlock link.
+    pub async fn record_failure(&self, email: &str, ip: IpAddr) -> Result<bool, AppError> {
+        let window_start = Utc::now() - self.window();
+
+        self.record_ip_failure(ip).await?;
+        let account = self
+            .failures
+            .record_failure(&account_key(email), window_start)
+            .await
+            .context("Failed to record sign-in failure")?;
+
+        if account.failures < i64::from(self.config.max_account_failures) {
+            return Ok(false);
+        }
+
+        let until = Utc::now() + Duration::minutes(self.config.lockout_minutes);
+        let locked = self
+            .failures
+            .lock(&account.key, until)
+            .await
+            .context("Failed to lock account")?;
+        Ok(locked)
+    }
+
+    /// Count a failed attempt from `ip` that cannot be tied to an account.
+    pub async fn record_ip_failure(&self, ip: IpAddr) -> Result<(), AppError> {
+        self.failures
+            .record_failure(&ip_key(ip), Utc::now() - self.window())
+            .await
+            .context("Failed to record sign-in failure")?;
+        Ok(())
+    }
+
+    /// The error for the attempt that locked the account.
+    pub fn locked_error(&self) -> AppError {
+        AppError::locked(
+            "account_locked",
+            "Too many failed sign-in attempts. This account is temporarily locked; \
+             we emailed a link to unlock it now",
+            (self.config.lockout_minutes * 60) as u64,
+        )
+    }
+
+    pub fn lockout_minutes(&self) -> i64 {
+        self.config.lockout_minutes
+    }
+
+    /// Forget the account's failures, after a successful sign-in or an unlock.
+    pub async fn clear_account(&self, email: &str) -> Result<(), AppError> {
+        self.failures
+            .clear(&account_key(email))
+            .await
+            .context("Failed to clear sign-in failures")?;
+        Ok(())
+    }
+
+    /// Delete counters that no longer affect anything.
+    pub async fn prune(&self) -> anyhow::Result<u64> {
+        self.failures.prune(Utc::now() - self.window()).await
+    }
+}
+
+/// Periodically delete stale failure counters, once per failure window.
+pub fn spawn_login_failure_pruner(guard: Arc<LoginGuard>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let interval = guard.window().to_std().unwrap_or(std::time::Duration::from_secs(15 * 60));
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = guard.prune().await {
+                tracing::warn!(error = %e, "failed to prune sign-in failures");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::create_test_pool;
+    use axum::http::StatusCode;
+
+    async fn guard(config: LoginProtectionConfig) -> LoginGuard {
+        let pool = create_test_pool().await.unwrap();
+        LoginGuard::new(LoginFailureRepository::new(pool), config)
+    }
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 0, 2, last])
+    }
+
+    #[tokio::test]
+    async fn test_account_is_delayed_then_locked() {
+        let guard = guard(LoginProtectionConfig {
+            max_account_failures: 3,
+            delay_after_failures: 2,
+            ..Default::default()
+        })
+        .await;
+
+        assert!(!guard.record_failure("user@example.com", ip(1)).await.unwrap());
+        guard.check("user@example.com", ip(1)).await.unwrap();
+
+        // From the second failure each attempt has to wait
+        assert!(!guard.record_failure("User@Example.com", ip(2)).await.unwrap());
+        let error = guard.check("user@example.com", ip(3)).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(error.retry_after(), Some(1));
+
+        // The failure that reaches the limit locks the account, once
+        assert!(guard.record_failure("user@example.com", ip(1)).await.unwrap());
+        assert!(!guard.record_failure("user@example.com", ip(1)).await.unwrap());
+        let error = guard.check("user@example.com", ip(4)).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::LOCKED);
+        assert_eq!(error.code(), "account_locked");
+        assert!(error.retry_after().unwrap() > 14 * 60);
+
+        // Other accounts are unaffected, and unlocking clears the account
+        guard.check("other@example.com", ip(4)).await.unwrap();
+        guard.clear_account("user@example.com").await.unwrap();
+        guard.check("user@example.com", ip(4)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_ip_is_blocked_across_accounts() {
+        let guard = guard(LoginProtectionConfig {
+            max_ip_failures: 3,
+            ..Default::default()
+        })
+        .await;
+
+        for n in 0..2 {
+            guard.record_failure(&format!("user{}@example.com", n), ip(1)).await.unwrap();
+        }
+        guard.check_ip(ip(1)).await.unwrap();
+
+        // Failures not tied to an account count too
+        guard.record_ip_failure(ip(1)).await.unwrap();
+        let error = guard.check("new@example.com", ip(1)).await.unwrap_err();
+        assert_eq!(error.code(), "too_many_attempts");
+        assert!(error.retry_after().unwrap() > 14 * 60);
+        let error = guard.check_ip(ip(1)).await.unwrap_err();
+        assert_eq!(error.code(), "too_many_attempts");
+        guard.check("new@example.com", ip(2)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delay_doubles_up_to_the_cap() {
+        let guard = guard(LoginProtectionConfig::default()).await;
+        let now = Utc::now();
+        let delay = |failures| {
+            let account = LoginFailures {
+                key: account_key("user@example.com"),
+                failures,
+                first_failed_at: now,
+                last_failed_at: now,
+                locked_until: None,
+            };
+            guard.next_attempt_at(&account, now).map(|next| (next - now).num_seconds())
+        };
+
+        assert_eq!(delay(2), None);
+        assert_eq!(delay(3), Some(1));
+        assert_eq!(delay(5), Some(4));
+        assert_eq!(delay(40), Some(30));
+        assert_eq!(delay(100), Some(30));
+    }
+}
@@ -0,0 +1,329 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use lettre::{
+    message::{header::ContentTransferEncoding, Body, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use uuid::Uuid;
+
+use crate::config::{MailConfig, MailTransport, SmtpTls};
+
+/// A plain-text email.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Delivers emails. Implementations decide where they go: an SMTP server,
+/// files on disk, or memory.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<()>;
+}
+
+fn build_message(from: &Mailbox, email: &Email) -> Result<Message> {
+    let to: Mailbox = email.to.parse().with_context(|| format!("Invalid recipient '{}'", email.to))?;
+
+    // Keep links intact: lettre picks quoted-printable for any line over 76
+    // characters, which wraps long URLs. 7bit allows lines up to 998 (RFC 5322).
+    let text = email.body.replace("\r\n", "\n");
+    let is_plain_7bit = text.is_ascii()
+        && !text.contains(['\r', '\0'])
+        && text.lines().all(|line| line.len() <= 998);
+    let body = if is_plain_7bit {
+        Body::dangerous_pre_encoded(text.replace('\n', "\r\n").into_bytes(), ContentTransferEncoding::SevenBit)
+    } else {
+        Body::new(text)
+    };
+
+    Message::builder()
+        .from(from.clone())
+        .to(to)
+        .subject(&email.subject)
+        .body(body)
+        .context("Failed to build email")
+}
+
+pub struct SmtpMailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    pub fn from_config(config: &MailConfig) -> Result<Self> {
+        let host = config.smtp_host.as_deref().context("mail.smtp_host is not set")?;
+        let mut builder = match config.smtp_tls {
+            SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+            SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+        }
+        .port(config.smtp_port);
+
+        if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(Self {
+            transport: builder.build(),
+            from: config.from.parse().context("Invalid mail.from")?,
+        })
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, email: &Email) -> Result<()> {
+        let message = build_message(&self.from, email)?;
+        self.transport.send(message).await.context("SMTP delivery failed")?;
+        Ok(())
+    }
+}
+
+/// Writes each email to `<dir>/<timestamp>-<id>.eml`, for local development.
+pub struct FileMailer {
+    dir: PathBuf,
+    from: Mailbox,
+}
+
+impl FileMailer {
+    pub fn new(dir: impl Into<PathBuf>, from: Mailbox) -> Self {
+        Self { dir: dir.into(), from }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, email: &Email) -> Result<()> {
+        let message = build_message(&self.from, email)?;
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+
+        let path = self.dir.join(format!(
+            "{}-{}.eml",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S"),
+            Uuid::new_v4()
+        ));
+        tokio::fs::write(&path, message.formatted())
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+
+        tracing::info!(to = %email.to, path = %path.display(), "email written to file");
+        Ok(())
+    }
+}
+
+/// Keeps sent emails in memory so tests can read them back. Clones share the outbox.
+#[derive(Clone, Default)]
+pub struct MemoryMailer {
+    sent: Arc<Mutex<Vec<Email>>>,
+}
+
+impl MemoryMailer {
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    /// The most recent email sent to `to`.
+    pub fn last_to(&self, to: &str) -> Option<Email> {
+        self.sent().into_iter().rev().find(|email| email.to == to)
+    }
+}
+
+#[async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, email: &Email) -> Result<()> {
+        self.sent.lock().unwrap().push(email.clone());
+        Ok(())
+    }
+}
+
+pub fn build_mailer(config: &MailConfig) -> Result<Arc<dyn Mailer>> {
+    Ok(match config.transport {
+        MailTransport::Smtp => Arc::new(SmtpMailer::from_config(config)?),
+        MailTransport::File => Arc::new(FileMailer::new(
+            &config.file_dir,
+            config.from.parse().context("Invalid mail.from")?,
+        )),
+    })
+}
+
+/// Composes the emails the application sends and hands them to a [`Mailer`].
+pub struct MailService {
+    mailer: Arc<dyn Mailer>,
+    app_url: String,
+}
+
+impl MailService {
+    pub fn new(mailer: Arc<dyn Mailer>, app_url: impl Into<String>) -> Self {
+        Self {
+            mailer,
+            app_url: app_url.into().trim_end_matches('/').to_string(),
+        }
+    }
+
+    fn link(&self, path: &str, token: &str) -> String {
+        format!("{}{}?token={}", self.app_url, path, token)
+    }
+
+    pub async fn send_email_verification(&self, to: &str, token: &str) -> Result<()> {
+        let email = Email {
+            to: to.to_string(),
+            subject: "Verify your email address".to_string(),
+            body: format!(
+                "Confirm your email address by opening this link:\n\n{}\n\n\
+                 The link expires in {} hours. If you did not create an account, ignore this email.\n",
+                self.link("/verify-email", token),
+                crate::tokens::EMAIL_VERIFICATION_TTL_HOURS
+            ),
+        };
+
+        self.mailer.send(&email).await
+    }
+
+    pub async fn send_email_change_confirmation(&self, to: &str, token: &str) -> Result<()> {
+        let email = Email {
+            to: to.to_string(),
+            subject: "Confirm your new email address".to_string(),
+            body: format!(
+                "Confirm that you want to use this address for your account by opening this link:\n\n{}\n\n\
+                 The link expires in {} hours. If you did not ask for this, ignore this email.\n",
+                self.link("/confirm-email-change", token),
+                crate::tokens::EMAIL_VERIFICATION_TTL_HOURS
+            ),
+        };
+
+        self.mailer.send(&email).await
+    }
+
+    /// Tell the current address that a change to `new_email` was requested.
+    pub async fn send_email_change_notice(&self, to: &str, new_email: &str) -> Result<()> {
+        let email = Email {
+            to: to.to_string(),
+            subject: "Your email address is being changed".to_string(),
+            body: format!(
+                "A request was made to change the email address of your account to {}.\n\n\
+                 If this was not you, reset your password right away.\n",
+                new_email
+            ),
+        };
+
+        self.mailer.send(&email).await
+    }
+
+    pub async fn send_password_reset(&self, to: &str, token: &str) -> Result<()> {
+        let email = Email {
+            to: to.to_string(),
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Choose a new password by opening this link:\n\n{}\n\n\
+                 The link expires in {} minutes and works once. If you did not ask to reset\n\
+                 your password, ignore this email; your password has not changed.\n",
+                self.link("/reset-password", token),
+                crate::tokens::PASSWORD_RESET_TTL_MINUTES
+            ),
+        };
+
+        self.mailer.send(&email).await
+    }
+
+    pub async fn send_magic_link(&self, to: &str, token: &str) -> Result<()> {
+        let email = Email {
+            to: to.to_string(),
+            subject: "Your sign-in link".to_string(),
+            body: format!(
+                "Sign in by opening this link:\n\n{}\n\n\
+                 The link expires in {} minutes and works once. If you did not ask to sign in,\n\
+                 ignore this email; nobody can sign in without the link.\n",
+                self.link("/magic-link", token),
+                crate::tokens::MAGIC_LINK_TTL_MINUTES
+            ),
+        };
+
+        self.mailer.send(&email).await
+    }
+
+    pub async fn send_account_unlock(&self, to: &str, token: &str, lockout_minutes: i64) -> Result<()> {
+        let email = Email {
+            to: to.to_string(),
+            subject: "Your account has been locked".to_string(),
+            body: format!(
+                "We locked your account after several failed sign-in attempts. It unlocks by itself\n\
+                 in {} minutes, or right away by opening this link:\n\n{}\n\n\
+                 If these attempts were not you, someone may be guessing your password;\n\
+                 consider changing it.\n",
+                lockout_minutes,
+                self.link("/unlock-account", token)
+            ),
+        };
+
+        self.mailer.send(&email).await
+    }
+}
+
+/// Extract the `token` query parameter from the link in an email body.
+#[cfg(test)]
+pub fn token_from(email: &Email) -> String {
+    let start = email.body.find("token=").expect("email contains a token link") + "token=".len();
+    email.body[start..]
+        .split(|c: char| c.is_whitespace() || c == '&')
+        .next()
+        .unwrap()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_memory_mailer_records_emails() {
+        let mailer = MemoryMailer::default();
+        let service = MailService::new(Arc::new(mailer.clone()), "https://app.example.com/");
+
+        service.send_email_verification("user@example.com", "abc123").await.unwrap();
+
+        let email = mailer.last_to("user@example.com").unwrap();
+        assert!(email.body.contains("https://app.example.com/verify-email?token=abc123"));
+        assert_eq!(token_from(&email), "abc123");
+    }
+
+    #[tokio::test]
+    async fn test_file_mailer_writes_eml() {
+        let dir = std::env::temp_dir().join(format!("mailer-test-{}", Uuid::new_v4()));
+        let mailer = FileMailer::new(&dir, "no-reply@example.com".parse().unwrap());
+        let email = Email {
+            to: "user@example.com".to_string(),
+            subject: "Hello".to_string(),
+            body: "Hi there".to_string(),
+        };
+
+        mailer.send(&email).await.unwrap();
+
+        let entry = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap();
+        let contents = std::fs::read_to_string(entry.path()).unwrap();
+        assert!(contents.contains("To: user@example.com"));
+        assert!(contents.contains("Subject: Hello"));
+        assert!(contents.contains("Content-Transfer-Encoding: 7bit"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_recipient_is_rejected() {
+        let mailer = FileMailer::new(std::env::temp_dir(), "no-reply@example.com".parse().unwrap());
+        let email = Email {
+            to: "not an address".to_string(),
+            subject: "Hello".to_string(),
+            body: "Hi".to_string(),
+        };
+
+        assert!(mailer.send(&email).await.is_err());
+    }
+}
@@ -1,15 +1,69 @@
-use temp_backend::create_app;
+use std::net::SocketAddr;
+use temp_backend::{
+    config::AppConfig,
+    create_app,
+    database::{create_pool, migration_status, run_migrations},
+    models::Role,
+    repository::UserRepository,
+};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let app = create_app().await?;
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "info".into()),
+        )
+        .init();
 
-    println!("Server starting on http://localhost:3000");
-    println!("API documentation available at http://localhost:3000/api-docs/openapi.json");
-    println!("Swagger UI available at http://localhost:3000/swagger-ui");
+    let config = match AppConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000").await?;
-    axum::serve(listener, app).await?;
+    // Upgrade the schema and exit, so deployments can migrate before traffic arrives
+    if std::env::args().any(|arg| arg == "--migrate-only") {
+        let pool = create_pool(&config.database).await?;
+        run_migrations(&pool).await?;
+        for status in migration_status(&pool).await? {
+            println!("Migration {:04} {} ... applied", status.version, status.description);
+        }
+        return Ok(());
+    }
+
+    // Promote an existing account, to bootstrap the first administrator
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(position) = args.iter().position(|arg| arg == "--make-admin") {
+        let Some(email) = args.get(position + 1) else {
+            eprintln!("Usage: --make-admin <email>");
+            std::process::exit(1);
+        };
+
+        let pool = create_pool(&config.database).await?;
+        run_migrations(&pool).await?;
+        let user_repo = UserRepository::new(pool);
+        let Some(user) = user_repo.find_by_email(email).await? else {
+            eprintln!("No user registered with email {}", email);
+            std::process::exit(1);
+        };
+        user_repo.set_role(&user.id, Role::Admin).await?;
+        println!("{} is now an admin", email);
+        return Ok(());
+    }
+
+    let app = create_app(&config).await?;
+    let address = &config.server.bind_address;
+
+    println!("Server starting on http://{} ({:?})", address, config.environment);
+    println!("API documentation available at http://{}/api-docs/openapi.json", address);
+    println!("Swagger UI available at http://{}/swagger-ui", address);
+
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    // Connection info gives handlers the client address (see `ClientIp`)
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
     Ok(())
 }
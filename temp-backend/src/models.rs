@@ -1,7 +1,46 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::auth::Scope;
+
+/// Valid values of `membership_level`, lowest first.
+pub const MEMBERSHIP_LEVELS: [&str; 4] = ["Bronze", "Silver", "Gold", "Platinum"];
+
+/// Access level of a user. Permissions granted to each role are defined in `auth`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Member,
+    Staff,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Member => "member",
+            Role::Staff => "staff",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "member" => Ok(Role::Member),
+            "staff" => Ok(Role::Staff),
+            "admin" => Ok(Role::Admin),
+            other => Err(format!("unknown role '{}'", other)),
+        }
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, FromRow, ToSchema)]
 pub struct User {
@@ -14,6 +53,10 @@ pub struct User {
     pub membership_id: Option<String>,
     pub membership_level: String, // Bronze, Silver, Gold, Platinum
     pub points: i32,
+    pub role: Role,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub password_reset_required: bool,
+    pub email_verified_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -33,10 +76,732 @@ pub struct LoginRequest {
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct AuthResponse {
     pub token: String,
+    pub refresh_token: String,
+    pub user_id: String,
+    pub email: String,
+}
+
+/// Returned by `/auth/login` when the account has two-factor authentication
+/// enabled. Complete the login at `/auth/mfa/verify`.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct MfaChallenge {
+    /// Always `true`; lets clients tell this apart from an `AuthResponse`
+    pub mfa_required: bool,
+    pub mfa_token: String,
+    /// Seconds until `mfa_token` expires
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Authenticated(AuthResponse),
+    MfaRequired(MfaChallenge),
+}
+
+/// Send exactly one of `code` and `recovery_code`.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct VerifyMfaRequest {
+    pub mfa_token: String,
+    /// Current code from the authenticator app
+    pub code: Option<String>,
+    /// One of the recovery codes issued when two-factor authentication was enabled
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct LogoutRequest {
+    /// Also revoke this refresh token and every token rotated from the same login
+    pub refresh_token: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct RefreshToken {
+    pub id: String,
+    pub user_id: String,
+    pub family_id: String, // shared by every token rotated from the same login
+    pub token_hash: String,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// A sign-in on one device. Its id is the `family_id` of the refresh tokens
+/// rotated from it and the `sid` claim of its access tokens.
+#[derive(Debug, FromRow)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>, // as of the last sign-in or refresh
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// A device the user is signed in on.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct SessionInfo {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    /// Whether this is the session making the request
+    pub current: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct SessionList {
+    /// Most recently used first
+    pub sessions: Vec<SessionInfo>,
+}
+
+/// A personal access token for scripts and integrations. Only its hash is stored.
+#[derive(Debug, FromRow)]
+pub struct ApiToken {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    pub token_hash: String,
+    pub scopes: String, // space-separated
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl ApiToken {
+    pub fn scopes(&self) -> Vec<Scope> {
+        self.scopes.split_whitespace().filter_map(|scope| scope.parse().ok()).collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ApiTokenInfo {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// Accurate to a few minutes; `null` if the token was never used
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<ApiToken> for ApiTokenInfo {
+    fn from(token: ApiToken) -> Self {
+        Self {
+            scopes: token.scopes(),
+            id: token.id,
+            name: token.name,
+            created_at: token.created_at,
+            expires_at: token.expires_at,
+            last_used_at: token.last_used_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreateApiTokenRequest {
+    /// What the token is for, e.g. the script that uses it
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    /// Days until the token expires (default 30, at most 365)
+    pub expires_in_days: Option<i64>,
+}
+
+/// `token` is shown once; only its hash is stored.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CreatedApiToken {
+    pub token: String,
+    pub api_token: ApiTokenInfo,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ApiTokenList {
+    /// Newest first
+    pub api_tokens: Vec<ApiTokenInfo>,
+}
+
+/// A WebAuthn credential a user signs in with.
+#[derive(Debug, Clone, FromRow)]
+pub struct Passkey {
+    pub id: String,
+    pub user_id: String,
+    pub credential_id: String, // base64url
+    pub public_key: Vec<u8>,   // COSE key
+    pub sign_count: i64,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PasskeyInfo {
+    pub id: String,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    /// `null` if the passkey was never used to sign in
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<Passkey> for PasskeyInfo {
+    fn from(passkey: Passkey) -> Self {
+        Self {
+            id: passkey.id,
+            name: passkey.name,
+            created_at: passkey.created_at,
+            last_used_at: passkey.last_used_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PasskeyList {
+    /// Oldest first
+    pub passkeys: Vec<PasskeyInfo>,
+}
+
+/// Which WebAuthn ceremony a challenge was issued for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+pub enum PasskeyCeremony {
+    Registration,
+    Authentication,
+}
+
+/// A challenge handed to the browser; only its hash is stored.
+#[derive(Debug, FromRow)]
+pub struct PasskeyChallenge {
+    pub challenge_hash: String,
+    pub ceremony: PasskeyCeremony,
+    pub user_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct RelyingParty {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyUserEntity {
+    /// The user's id, base64url-encoded
+    pub id: String,
+    pub name: String,
+    pub display_name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PublicKeyCredentialParameters {
+    /// Always `public-key`
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// COSE algorithm: -7 (ES256), -8 (EdDSA) or -257 (RS256)
+    pub alg: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct PublicKeyCredentialDescriptor {
+    /// Always `public-key`
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// Credential id, base64url-encoded
+    pub id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticatorSelection {
+    pub resident_key: String,
+    pub require_resident_key: bool,
+    pub user_verification: String,
+}
+
+/// Options for `navigator.credentials.create()`, in the JSON form that
+/// `PublicKeyCredential.parseCreationOptionsFromJSON()` takes: binary values
+/// are base64url-encoded.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyCreationOptions {
+    pub challenge: String,
+    pub rp: RelyingParty,
+    pub user: PasskeyUserEntity,
+    pub pub_key_cred_params: Vec<PublicKeyCredentialParameters>,
+    /// Milliseconds
+    pub timeout: u64,
+    /// The user's passkeys, so an authenticator does not register twice
+    pub exclude_credentials: Vec<PublicKeyCredentialDescriptor>,
+    pub authenticator_selection: AuthenticatorSelection,
+    /// Always `none`: the authenticator's make and model are not checked
+    pub attestation: String,
+}
+
+/// Options for `navigator.credentials.get()`, in the JSON form that
+/// `PublicKeyCredential.parseRequestOptionsFromJSON()` takes. No credentials
+/// are listed: the user picks one of their passkeys for this site.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PasskeyRequestOptions {
+    pub challenge: String,
+    pub rp_id: String,
+    /// Milliseconds
+    pub timeout: u64,
+    pub allow_credentials: Vec<PublicKeyCredentialDescriptor>,
+    pub user_verification: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AuthenticatorAttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+/// A new credential from `navigator.credentials.create()`, as its
+/// `toJSON()` returns it.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RegistrationCredential {
+    pub id: String,
+    pub raw_id: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub response: AuthenticatorAttestationResponse,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AuthenticatorAssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+    /// The user's id as given at registration
+    #[serde(rename = "userHandle")]
+    pub user_handle: Option<String>,
+}
+
+/// A signed challenge from `navigator.credentials.get()`, as its `toJSON()`
+/// returns it.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticationCredential {
+    pub id: String,
+    pub raw_id: String,
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub response: AuthenticatorAssertionResponse,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RegisterPasskeyRequest {
+    /// To tell the user's passkeys apart, e.g. the device it is on
+    pub name: String,
+    pub credential: RegistrationCredential,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PasskeyLoginRequest {
+    pub credential: AuthenticationCredential,
+}
+
+/// A sign-in started with an external OpenID Connect provider. `state`
+/// identifies it when the user comes back; only its hash is stored.
+#[derive(Debug, FromRow)]
+pub struct OidcLoginAttempt {
+    pub state_hash: String,
+    pub provider: String,
+    pub nonce: String,
+    pub code_verifier: String, // PKCE
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// An account at an external provider, linked to a local user.
+#[derive(Debug, FromRow)]
+pub struct UserIdentity {
+    pub provider: String,
+    pub subject: String, // the provider's `sub` claim
+    pub user_id: String,
+    pub email: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_login_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct OidcAuthorization {
+    /// Send the user's browser here to sign in with the provider
+    pub authorization_url: String,
+    /// Also in `authorization_url`; the provider returns it with the code
+    pub state: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct OidcCallbackRequest {
+    /// `code` query parameter the provider redirected back with
+    pub code: String,
+    /// `state` query parameter the provider redirected back with
+    pub state: String,
+}
+
+/// A code handed to an OAuth client, redeemed once at `/oauth/token`.
+#[derive(Debug, FromRow)]
+pub struct OAuthAuthorizationCode {
+    pub code_hash: String,
+    pub client_id: String,
+    pub user_id: String,
+    pub redirect_uri: String,
+    pub scope: String, // space-separated
+    pub nonce: Option<String>,
+    pub code_challenge: String, // PKCE S256
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Query parameters of an OAuth 2.0 authorization request, as the client sent
+/// them to the consent page.
+#[derive(Debug, Clone, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OAuthAuthorizeParams {
+    /// Must be `code`
+    pub response_type: String,
+    pub client_id: String,
+    /// One of the client's registered redirect URIs, exactly
+    pub redirect_uri: String,
+    /// Space-separated, e.g. `openid email profile:read`
+    pub scope: String,
+    /// Returned to the client unchanged
+    pub state: Option<String>,
+    /// Copied into the ID token
+    pub nonce: Option<String>,
+    /// PKCE challenge; required
+    pub code_challenge: String,
+    /// Must be `S256`
+    pub code_challenge_method: String,
+}
+
+/// What the consent page should show.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct OAuthAuthorizationDetails {
+    pub client_id: String,
+    pub client_name: String,
+    /// The scopes that will be granted: those requested, less any the user's role does not allow
+    pub scopes: Vec<String>,
+    /// `false` if the user already agreed to these scopes, or the client is trusted
+    pub consent_required: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct OAuthConsentDecision {
+    pub approve: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct OAuthRedirect {
+    /// Send the user's browser here: the client's redirect URI with a `code`, or an `error`
+    pub redirect_to: String,
+}
+
+/// Form body of `/oauth/token` (RFC 6749, section 4.1.3).
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct OAuthTokenRequest {
+    /// Must be `authorization_code`
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    /// Required unless sent with HTTP Basic authentication
+    pub client_id: Option<String>,
+    /// For confidential clients not using HTTP Basic authentication
+    pub client_secret: Option<String>,
+    /// PKCE verifier for the code's challenge
+    pub code_verifier: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct OAuthTokenResponse {
+    pub access_token: String,
+    /// Always `Bearer`
+    pub token_type: String,
+    /// Seconds until `access_token` expires
+    pub expires_in: i64,
+    /// Space-separated scopes granted
+    pub scope: String,
+    /// Present when the `openid` scope was granted
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id_token: Option<String>,
+}
+
+/// Form body of `/oauth/introspect` (RFC 7662) and `/oauth/revoke` (RFC 7009).
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct OAuthTokenReference {
+    pub token: String,
+    /// `access_token` or `refresh_token`; optional, as every kind of token is looked up anyway
+    pub token_type_hint: Option<String>,
+    /// Required unless sent with HTTP Basic authentication
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+}
+
+/// What `/oauth/introspect` knows about a token (RFC 7662). An unknown,
+/// expired or revoked token only has `active: false`.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct TokenIntrospection {
+    pub active: bool,
+    /// Space-separated scopes; absent for a session's access token, which may
+    /// do anything the user's roles allow
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+    /// The OAuth client the token was issued to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// The user's email address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// Always `Bearer`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+    /// The user's id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    /// The access token's id, or the API token's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jti: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<Role>,
+}
+
+/// Standard claims about the user, by scope: `email` adds `email` and
+/// `email_verified`, `profile` adds the names.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct UserInfo {
+    pub sub: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub given_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub family_name: Option<String>,
+}
+
+/// OpenID Connect Discovery 1.0 provider metadata.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct OpenIdConfiguration {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub userinfo_endpoint: String,
+    pub introspection_endpoint: String,
+    pub revocation_endpoint: String,
+    pub jwks_uri: String,
+    pub scopes_supported: Vec<String>,
+    pub response_types_supported: Vec<String>,
+    pub grant_types_supported: Vec<String>,
+    pub subject_types_supported: Vec<String>,
+    pub id_token_signing_alg_values_supported: Vec<String>,
+    pub token_endpoint_auth_methods_supported: Vec<String>,
+    pub code_challenge_methods_supported: Vec<String>,
+    pub claims_supported: Vec<String>,
+}
+
+/// What a [`OneTimeToken`] may be used for. A token only works for its own purpose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+pub enum TokenPurpose {
+    EmailVerification,
+    PasswordReset,
+    /// Confirms a new address; the token's `email` is the address to switch to
+    EmailChange,
+    /// Second step of a login to an account with two-factor authentication
+    MfaChallenge,
+    /// Unlocks an account locked after too many failed sign-ins
+    AccountUnlock,
+    /// Signs in without a password
+    MagicLink,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow)]
+pub struct OneTimeToken {
+    pub id: String,
     pub user_id: String,
+    pub purpose: TokenPurpose,
+    pub email: String, // the address the token was sent to
+    pub token_hash: String,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+    pub attempts: i64, // wrong codes entered against the token
+}
+
+/// What a [`PhoneOtp`] may be used for. A code only works for its own purpose.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+pub enum PhoneOtpPurpose {
+    /// Confirms the number on the user's profile
+    PhoneVerification,
+    /// Signs in without a password
+    PhoneLogin,
+}
+
+/// A code sent by text message; only its hash is stored.
+#[derive(Debug, FromRow)]
+pub struct PhoneOtp {
+    pub id: String,
+    pub user_id: String,
+    pub purpose: PhoneOtpPurpose,
+    pub phone: String, // the number the code was sent to, E.164
+    pub code_hash: String,
+    pub attempts: i64, // wrong codes entered so far
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PhoneOtpRequest {
+    /// In international form (`+66812345678`) or, for the server's default
+    /// country, national form (`081 234 5678`)
+    pub phone: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct PhoneLoginRequest {
+    pub phone: String,
+    /// The six-digit code from the text message
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ConfirmPhoneRequest {
+    /// The six-digit code from the text message
+    pub code: String,
+}
+
+/// Failed sign-in attempts counted against an account or IP address.
+#[derive(Debug, Clone, FromRow)]
+pub struct LoginFailures {
+    pub key: String, // `account:<email>` or `ip:<address>`
+    pub failures: i64,
+    pub first_failed_at: DateTime<Utc>,
+    pub last_failed_at: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+/// A user's TOTP authenticator. Only enforced once `confirmed_at` is set.
+#[derive(Debug, FromRow)]
+pub struct UserTotp {
+    pub user_id: String,
+    pub secret: String, // base32
+    pub confirmed_at: Option<DateTime<Utc>>,
+    pub last_used_step: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct VerifyEmailRequest {
+    /// The token from the verification link
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UnlockAccountRequest {
+    /// The token from the unlock link
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ForgotPasswordRequest {
+    pub email: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct MagicLinkRequest {
     pub email: String,
 }
 
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ConsumeMagicLinkRequest {
+    /// The token from the sign-in link
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ResetPasswordRequest {
+    /// The token from the password reset link
+    pub token: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChangeEmailRequest {
+    pub new_email: String,
+    pub current_password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ConfirmEmailChangeRequest {
+    /// The token from the link sent to the new address
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct MfaStatus {
+    pub totp_enabled: bool,
+    pub recovery_codes_remaining: i64,
+}
+
+/// Scan `qr_code_svg` or enter `secret` in an authenticator app, then confirm
+/// with a code at `/profile/mfa/totp/confirm`.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TotpEnrollment {
+    pub secret: String,
+    pub otpauth_uri: String,
+    pub qr_code_svg: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ConfirmTotpRequest {
+    pub code: String,
+}
+
+/// Shown once; only hashes are stored.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+/// Re-authentication for changes that weaken or reset the second factor.
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct CurrentPasswordRequest {
+    pub current_password: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, FromRow, ToSchema)]
 pub struct UserProfile {
     pub id: String,
@@ -47,6 +812,12 @@ pub struct UserProfile {
     pub membership_id: Option<String>,
     pub membership_level: String,
     pub points: i32,
+    pub role: Role,
+    /// When the current email address was confirmed; `null` until then
+    pub email_verified_at: Option<DateTime<Utc>>,
+    /// When the current phone number was confirmed by text message; `null`
+    /// until then. Only a verified number signs in
+    pub phone_verified_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
 }
 
@@ -57,16 +828,137 @@ pub struct UpdateProfileRequest {
     pub phone: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UpdateRoleRequest {
+    pub role: Role,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RolePermissions {
+    pub role: Role,
+    pub permissions: Vec<String>,
+}
+
+/// A user's account as seen by support staff.
+#[derive(Debug, Serialize, Deserialize, FromRow, ToSchema)]
+pub struct UserAccount {
+    pub id: String,
+    pub email: String,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub phone: Option<String>,
+    pub membership_id: Option<String>,
+    pub membership_level: String,
+    pub points: i32,
+    pub role: Role,
+    /// Set while the account is disabled and cannot sign in
+    pub disabled_at: Option<DateTime<Utc>>,
+    /// The user must reset their password before they can sign in again
+    pub password_reset_required: bool,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Filters and pagination for listing users. Filters match substrings.
+#[derive(Debug, Default, Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListUsersQuery {
+    /// Page number, starting at 1
+    pub page: Option<u32>,
+    /// Users per page (default 20, at most 100)
+    pub per_page: Option<u32>,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    pub membership_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct UserPage {
+    pub users: Vec<UserAccount>,
+    pub page: u32,
+    pub per_page: u32,
+    /// Number of users matching the filters, across all pages
+    pub total: i64,
+}
+
+/// Fields left out are unchanged.
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct UpdateMembershipRequest {
+    pub membership_level: Option<String>,
+    pub points: Option<i32>,
+}
+
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct ErrorResponse {
     pub error: String,
     pub message: String,
+    /// Set on internal errors; quote it when reporting a problem so it can be found in the logs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<String>,
+    /// Set on `429` and `423`: seconds to wait before trying again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+    /// Set on some `400`s: what is wrong with each rejected field
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+/// One reason a request field was rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct FieldError {
+    /// Name of the field in the request body
+    pub field: String,
+    /// Stable machine-readable reason, e.g. `too_short` or `breached`
+    pub code: String,
+    pub message: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String, // user id
     pub email: String,
     pub exp: usize, // expiration time
     pub iat: usize, // issued at
+    /// `iat` in microseconds, to tell tokens from a revocation in the same
+    /// second; tokens from before it have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat_us: Option<i64>,
+    pub jti: String, // unique token id, used for revocation
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    #[serde(default)]
+    pub email_verified: bool,
+    /// The session the token was issued to; tokens from before sessions have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<String>,
+    /// The OAuth client a token was issued to; `None` for the user's own sign-ins
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// Space-separated scopes granted to the client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<String>,
+}
+
+/// A public signing key in JSON Web Key format (RFC 7517)
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Jwk {
+    pub kty: String,
+    #[serde(rename = "use")]
+    pub key_use: String,
+    pub alg: String,
+    pub kid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<String>, // RSA modulus
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub e: Option<String>, // RSA exponent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crv: Option<String>, // OKP curve
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<String>, // OKP public key
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
 }
@@ -0,0 +1,325 @@
+use chrono::{Duration, Utc};
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::{
+    auth::Scope,
+    config::{OAuthClientConfig, OAuthConfig},
+    error::AppError,
+    models::{OAuthAuthorizeParams, OpenIdConfiguration, User, UserInfo},
+    tokens::{hash_token, ID_TOKEN_TTL_MINUTES},
+};
+
+/// OpenID Connect scopes; they decide what the ID token and `/userinfo` say
+/// about the user. Every other scope is an API token [`Scope`].
+pub const OIDC_SCOPES: [&str; 3] = ["openid", "email", "profile"];
+
+pub fn is_known_scope(scope: &str) -> bool {
+    OIDC_SCOPES.contains(&scope) || scope.parse::<Scope>().is_ok()
+}
+
+/// Whether a space-separated scope string includes `scope`.
+pub fn has_scope(scopes: &str, scope: &str) -> bool {
+    scopes.split_whitespace().any(|s| s == scope)
+}
+
+/// An authorization request that checked out: the client, and the scopes it
+/// will get.
+pub struct Authorization<'a> {
+    pub client: &'a OAuthClientConfig,
+    pub scopes: Vec<String>,
+}
+
+impl Authorization<'_> {
+    pub fn scope(&self) -> String {
+        self.scopes.join(" ")
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct IdTokenClaims<'a> {
+    iss: &'a str,
+    aud: &'a str,
+    exp: i64,
+    iat: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nonce: Option<&'a str>,
+    #[serde(flatten)]
+    user: UserInfo,
+}
+
+/// The clients registered with this server as an OAuth 2.0 authorization
+/// server and OpenID Connect provider, and the rules for what they may ask.
+pub struct OAuthServer {
+    issuer: String,
+    /// The web app's consent page, which clients send users to
+    authorization_endpoint: String,
+    clients: Vec<OAuthClientConfig>,
+}
+
+impl OAuthServer {
+    pub fn new(config: &OAuthConfig) -> Self {
+        let issuer = config.issuer.trim_end_matches('/').to_string();
+        // Only unset without clients, when no one is sent anywhere
+        let authorization_endpoint = config
+            .authorization_endpoint
+            .clone()
+            .unwrap_or_else(|| format!("{}/oauth/authorize", issuer));
+        Self {
+            issuer,
+            authorization_endpoint,
+            clients: config.clients.clone(),
+        }
+    }
+
+    pub fn issuer(&self) -> &str {
+        &self.issuer
+    }
+
+    pub fn client(&self, client_id: &str) -> Option<&OAuthClientConfig> {
+        self.clients.iter().find(|client| client.client_id == client_id)
+    }
+
+    /// The client, if `client_secret` is right for it. Public clients have no
+    /// secret and must not send one.
+    pub fn authenticate_client(&self, client_id: &str, client_secret: Option<&str>) -> Option<&OAuthClientConfig> {
+        let client = self.client(client_id)?;
+        // Compare hashes so the time taken says nothing about the secret
+        let authenticated = match (&client.client_secret, client_secret) {
+            (Some(expected), Some(given)) => hash_token(expected) == hash_token(given),
+            (None, None) => true,
+            _ => false,
+        };
+        authenticated.then_some(client)
+    }
+
+    /// Check an authorization request for `user`. Errors are meant for the
+    /// user, never for a redirect: the redirect URI may not be the client's.
+    pub fn authorize(&self, params: &OAuthAuthorizeParams, user: &User) -> Result<Authorization<'_>, AppError> {
+        let client = self
+            .client(&params.client_id)
+            .ok_or_else(|| AppError::bad_request("invalid_client", "Unknown client"))?;
+        if !client.redirect_uris.contains(&params.redirect_uri) {
+            return Err(AppError::bad_request("invalid_request", "redirect_uri is not registered for this client"));
+        }
+        if params.response_type != "code" {
+            return Err(AppError::bad_request("unsupported_response_type", "response_type must be 'code'"));
+        }
+        if params.code_challenge.is_empty() || params.code_challenge_method != "S256" {
+            return Err(AppError::bad_request("invalid_request", "PKCE with code_challenge_method 'S256' is required"));
+        }
+
+        let mut scopes: Vec<String> = vec![];
+        for scope in params.scope.split_whitespace() {
+            if !client.scopes.iter().any(|allowed| allowed == scope) {
+                return Err(AppError::bad_request(
+                    "invalid_scope",
+                    format!("The client may not ask for the '{}' scope", scope),
+                ));
+            }
+            // Like an API token, a client gets no more than the user's role allows
+            let permitted = scope
+                .parse::<Scope>()
+                .ok()
+                .and_then(|scope| scope.permission())
+                .is_none_or(|permission| user.role.permissions().contains(&permission));
+            if permitted && !scopes.iter().any(|s| s == scope) {
+                scopes.push(scope.to_string());
+            }
+        }
+        if scopes.is_empty() {
+            return Err(AppError::bad_request("invalid_scope", "scope is required"));
+        }
+
+        Ok(Authorization { client, scopes })
+    }
+
+    /// Where to send the user's browser back to: the client's redirect URI
+    /// with `params` added, plus `state` and the issuer (RFC 9207).
+    pub fn redirect(&self, request: &OAuthAuthorizeParams, params: &[(&str, &str)]) -> Result<String, AppError> {
+        let mut url = Url::parse(&request.redirect_uri)
+            .map_err(|_| AppError::bad_request("invalid_request", "redirect_uri is not a valid URL"))?;
+        {
+            let mut query = url.query_pairs_mut();
+            query.extend_pairs(params);
+            if let Some(state) = &request.state {
+                query.append_pair("state", state);
+            }
+            query.append_pair("iss", &self.issuer);
+        }
+        Ok(url.into())
+    }
+
+    /// Claims about `user` that `scope` lets the client see.
+    pub fn user_info(user: &User, scope: &str) -> UserInfo {
+        let email = has_scope(scope, "email");
+        let profile = has_scope(scope, "profile");
+
+        UserInfo {
+            sub: user.id.clone(),
+            email: email.then(|| user.email.clone()),
+            email_verified: email.then_some(user.email_verified_at.is_some()),
+            given_name: user.first_name.clone().filter(|_| profile),
+            family_name: user.last_name.clone().filter(|_| profile),
+        }
+    }
+
+    /// Claims of an ID token for `user`, issued to `client_id`.
+    pub fn id_token_claims<'a>(
+        &'a self,
+        user: &User,
+        client_id: &'a str,
+        scope: &str,
+        nonce: Option<&'a str>,
+    ) -> impl Serialize + 'a {
+        let now = Utc::now();
+        IdTokenClaims {
+            iss: &self.issuer,
+            aud: client_id,
+            exp: (now + Duration::minutes(ID_TOKEN_TTL_MINUTES)).timestamp(),
+            iat: now.timestamp(),
+            nonce,
+            user: Self::user_info(user, scope),
+        }
+    }
+
+    /// Discovery metadata, for `/.well-known/openid-configuration`.
+    pub fn discovery(&self, signing_algorithm: &str) -> OpenIdConfiguration {
+        let strings = |values: &[&str]| values.iter().map(|value| value.to_string()).collect();
+        let scopes = OIDC_SCOPES
+            .iter()
+            .copied()
+            .chain(
+                [Scope::ProfileRead, Scope::ProfileWrite, Scope::UsersRead, Scope::UsersWrite, Scope::UsersManage, Scope::RolesAssign]
+                    .iter()
+                    .map(Scope::as_str),
+            )
+            .collect::<Vec<_>>();
+
+        OpenIdConfiguration {
+            issuer: self.issuer.clone(),
+            authorization_endpoint: self.authorization_endpoint.clone(),
+            token_endpoint: format!("{}/oauth/token", self.issuer),
+            userinfo_endpoint: format!("{}/userinfo", self.issuer),
+            introspection_endpoint: format!("{}/oauth/introspect", self.issuer),
+            revocation_endpoint: format!("{}/oauth/revoke", self.issuer),
+            jwks_uri: format!("{}/.well-known/jwks.json", self.issuer),
+            scopes_supported: strings(&scopes),
+            response_types_supported: strings(&["code"]),
+            grant_types_supported: strings(&["authorization_code"]),
+            subject_types_supported: strings(&["public"]),
+            id_token_signing_alg_values_supported: vec![signing_algorithm.to_string()],
+            token_endpoint_auth_methods_supported: strings(&["client_secret_basic", "client_secret_post", "none"]),
+            code_challenge_methods_supported: strings(&["S256"]),
+            claims_supported: strings(&["sub", "iss", "aud", "exp", "iat", "nonce", "email", "email_verified", "given_name", "family_name"]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Role;
+
+    fn server() -> OAuthServer {
+        OAuthServer::new(&OAuthConfig {
+            issuer: "https://api.example.com/".to_string(),
+            authorization_endpoint: Some("https://app.example.com/oauth/consent".to_string()),
+            clients: vec![OAuthClientConfig {
+                client_id: "tool".to_string(),
+                name: "Tool".to_string(),
+                client_secret: Some("s3cret".to_string()),
+                redirect_uris: vec!["https://tool.example.com/cb?from=api".to_string()],
+                scopes: ["openid", "email", "profile:read", "users:read"].map(String::from).to_vec(),
+                trusted: false,
+            }],
+        })
+    }
+
+    fn params(scope: &str) -> OAuthAuthorizeParams {
+        OAuthAuthorizeParams {
+            response_type: "code".to_string(),
+            client_id: "tool".to_string(),
+            redirect_uri: "https://tool.example.com/cb?from=api".to_string(),
+            scope: scope.to_string(),
+            state: Some("xyz".to_string()),
+            nonce: None,
+            code_challenge: "challenge".to_string(),
+            code_challenge_method: "S256".to_string(),
+        }
+    }
+
+    fn user(role: Role) -> User {
+        User {
+            id: "user-1".to_string(),
+            email: "test@example.com".to_string(),
+            password_hash: String::new(),
+            first_name: Some("Test".to_string()),
+            last_name: None,
+            phone: None,
+            membership_id: Some("LBK000001".to_string()),
+            membership_level: "Bronze".to_string(),
+            points: 0,
+            role,
+            disabled_at: None,
+            password_reset_required: false,
+            email_verified_at: None,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_authorize_checks_the_request() {
+        let server = server();
+        let member = user(Role::Member);
+
+        // Scopes the user's role lacks are dropped rather than refused
+        let authorization = server.authorize(&params("openid users:read email openid"), &member).unwrap();
+        assert_eq!(authorization.scope(), "openid email");
+        let authorization = server.authorize(&params("openid users:read"), &user(Role::Staff)).unwrap();
+        assert_eq!(authorization.scope(), "openid users:read");
+
+        let code = |params: OAuthAuthorizeParams| server.authorize(&params, &member).err().unwrap().code();
+        assert_eq!(code(params("openid users:manage")), "invalid_scope");
+        assert_eq!(code(params("users:read")), "invalid_scope");
+        assert_eq!(code(OAuthAuthorizeParams { client_id: "other".to_string(), ..params("openid") }), "invalid_client");
+        assert_eq!(code(OAuthAuthorizeParams { redirect_uri: "https://tool.example.com/cb".to_string(), ..params("openid") }), "invalid_request");
+        assert_eq!(code(OAuthAuthorizeParams { response_type: "token".to_string(), ..params("openid") }), "unsupported_response_type");
+        assert_eq!(code(OAuthAuthorizeParams { code_challenge_method: "plain".to_string(), ..params("openid") }), "invalid_request");
+    }
+
+    #[test]
+    fn test_client_authentication_and_redirects() {
+        let server = server();
+        assert!(server.authenticate_client("tool", Some("s3cret")).is_some());
+        assert!(server.authenticate_client("tool", Some("wrong")).is_none());
+        assert!(server.authenticate_client("tool", None).is_none());
+
+        let redirect = server.redirect(&params("openid"), &[("code", "abc")]).unwrap();
+        assert_eq!(
+            redirect,
+            "https://tool.example.com/cb?from=api&code=abc&state=xyz&iss=https%3A%2F%2Fapi.example.com"
+        );
+    }
+
+    #[test]
+    fn test_user_info_follows_scopes() {
+        let user = user(Role::Member);
+        let info = OAuthServer::user_info(&user, "openid");
+        assert!(info.email.is_none() && info.given_name.is_none());
+
+        let info = OAuthServer::user_info(&user, "openid email profile");
+        assert_eq!(info.email.as_deref(), Some("test@example.com"));
+        assert_eq!(info.email_verified, Some(false));
+        assert_eq!(info.given_name.as_deref(), Some("Test"));
+    }
+
+    #[test]
+    fn test_discovery_sends_users_to_the_consent_page() {
+        let discovery = server().discovery("RS256");
+        assert_eq!(discovery.issuer, "https://api.example.com");
+        assert_eq!(discovery.authorization_endpoint, "https://app.example.com/oauth/consent");
+        assert_eq!(discovery.token_endpoint, "https://api.example.com/oauth/token");
+    }
+}
@@ -0,0 +1,363 @@
+use anyhow::{anyhow, bail, Context, Result};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use reqwest::Url;
+use serde::{Deserialize, Deserializer};
+use std::{
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use crate::{
+    config::{OidcConfig, OidcProviderConfig},
+    tokens::{generate_opaque_token, pkce_challenge},
+};
+
+/// How long to wait for a provider's discovery, JWKS or token endpoint.
+const HTTP_TIMEOUT_SECS: u64 = 10;
+
+/// ID tokens are only accepted when signed with a public key published by the
+/// provider; `none` and shared-secret algorithms are refused.
+const ID_TOKEN_ALGORITHMS: [Algorithm; 7] = [
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::EdDSA,
+];
+
+/// The parts of a provider's `/.well-known/openid-configuration` we use.
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct IdTokenClaims {
+    sub: String,
+    nonce: Option<String>,
+    azp: Option<String>,
+    email: Option<String>,
+    #[serde(default, deserialize_with = "bool_or_string")]
+    email_verified: bool,
+}
+
+/// Some providers send `email_verified` as the string `"true"`.
+fn bool_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrString {
+        Bool(bool),
+        String(String),
+    }
+
+    Ok(match BoolOrString::deserialize(deserializer)? {
+        BoolOrString::Bool(value) => value,
+        BoolOrString::String(value) => value == "true",
+    })
+}
+
+/// A sign-in to send the user off on. `state`, `nonce` and `code_verifier`
+/// must be kept until the user comes back.
+pub struct AuthorizationRequest {
+    pub url: String,
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+/// Who the provider says signed in, taken from a validated ID token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalIdentity {
+    pub subject: String,
+    pub email: Option<String>,
+    pub email_verified: bool,
+}
+
+struct Provider {
+    config: OidcProviderConfig,
+    metadata: RwLock<Option<Arc<ProviderMetadata>>>,
+    jwks: RwLock<Option<Arc<JwkSet>>>,
+}
+
+/// Relying party for the configured OpenID Connect providers, using the
+/// authorization code flow with PKCE. Each provider's discovery document and
+/// keys are fetched on first use and cached.
+pub struct OidcClient {
+    providers: Vec<Provider>,
+    http: reqwest::Client,
+}
+
+impl OidcClient {
+    pub fn new(config: &OidcConfig) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(HTTP_TIMEOUT_SECS))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .context("Failed to create HTTP client")?;
+
+        let providers = config
+            .providers
+            .iter()
+            .map(|config| Provider {
+                config: config.clone(),
+                metadata: RwLock::new(None),
+                jwks: RwLock::new(None),
+            })
+            .collect();
+
+        Ok(Self { providers, http })
+    }
+
+    pub fn has_provider(&self, name: &str) -> bool {
+        self.provider(name).is_ok()
+    }
+
+    /// Whether `name` may sign into existing accounts by email address.
+    pub fn links_existing_accounts(&self, name: &str) -> bool {
+        self.provider(name).is_ok_and(|provider| provider.config.link_existing_accounts)
+    }
+
+    fn provider(&self, name: &str) -> Result<&Provider> {
+        self.providers
+            .iter()
+            .find(|provider| provider.config.name == name)
+            .ok_or_else(|| anyhow!("Unknown OpenID Connect provider '{}'", name))
+    }
+
+    async fn get_json<T: for<'de> Deserialize<'de>>(&self, url: &str) -> Result<T> {
+        self.http
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("Failed to fetch {}", url))?
+            .json()
+            .await
+            .with_context(|| format!("Invalid JSON from {}", url))
+    }
+
+    async fn metadata(&self, provider: &Provider) -> Result<Arc<ProviderMetadata>> {
+        if let Some(metadata) = provider.metadata.read().unwrap().clone() {
+            return Ok(metadata);
+        }
+
+        let issuer = provider.config.issuer.trim_end_matches('/');
+        let metadata: ProviderMetadata = self
+            .get_json(&format!("{}/.well-known/openid-configuration", issuer))
+            .await?;
+        // OpenID Connect Discovery 1.0, section 4.3
+        if metadata.issuer != provider.config.issuer {
+            bail!(
+                "Provider '{}' reports issuer '{}', expected '{}'",
+                provider.config.name,
+                metadata.issuer,
+                provider.config.issuer
+            );
+        }
+
+        let metadata = Arc::new(metadata);
+        *provider.metadata.write().unwrap() = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    /// The provider's key with this `kid`. The key set is fetched again when
+    /// the `kid` is unknown, since providers rotate their keys.
+    async fn decoding_key(&self, provider: &Provider, kid: Option<&str>) -> Result<DecodingKey> {
+        let cached = provider.jwks.read().unwrap().clone();
+        let find = |jwks: &JwkSet| {
+            jwks.keys
+                .iter()
+                .find(|jwk| kid.is_none_or(|kid| jwk.common.key_id.as_deref() == Some(kid)))
+                .cloned()
+        };
+
+        let jwk = match cached.as_deref().and_then(find) {
+            Some(jwk) => jwk,
+            None => {
+                let metadata = self.metadata(provider).await?;
+                let jwks: JwkSet = self.get_json(&metadata.jwks_uri).await?;
+                let jwk = find(&jwks);
+                *provider.jwks.write().unwrap() = Some(Arc::new(jwks));
+                jwk.ok_or_else(|| anyhow!("Provider '{}' has no key '{}'", provider.config.name, kid.unwrap_or("")))?
+            }
+        };
+
+        if matches!(jwk.algorithm, AlgorithmParameters::OctetKey(_)) {
+            bail!("Provider '{}' published a shared-secret key", provider.config.name);
+        }
+        DecodingKey::from_jwk(&jwk).context("Invalid key in provider JWKS")
+    }
+
+    /// Start a sign-in with `provider`.
+    pub async fn authorization_request(&self, provider: &str) -> Result<AuthorizationRequest> {
+        let provider = self.provider(provider)?;
+        let metadata = self.metadata(provider).await?;
+        let config = &provider.config;
+
+        let state = generate_opaque_token();
+        let nonce = generate_opaque_token();
+        let code_verifier = generate_opaque_token();
+
+        let mut url = Url::parse(&metadata.authorization_endpoint).context("Invalid authorization_endpoint")?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &config.client_id)
+            .append_pair("redirect_uri", &config.redirect_uri)
+            .append_pair("scope", &config.scopes.join(" "))
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &pkce_challenge(&code_verifier))
+            .append_pair("code_challenge_method", "S256");
+
+        Ok(AuthorizationRequest {
+            url: url.into(),
+            state,
+            nonce,
+            code_verifier,
+        })
+    }
+
+    /// Exchange the code the user came back with for an ID token, and return
+    /// the identity in it once the token checks out.
+    pub async fn finish(&self, provider: &str, code: &str, nonce: &str, code_verifier: &str) -> Result<ExternalIdentity> {
+        let provider = self.provider(provider)?;
+        let metadata = self.metadata(provider).await?;
+        let config = &provider.config;
+
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &config.redirect_uri),
+            ("client_id", &config.client_id),
+            ("code_verifier", code_verifier),
+        ];
+        if let Some(secret) = &config.client_secret {
+            form.push(("client_secret", secret));
+        }
+
+        let response = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .context("Failed to reach token endpoint")?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            bail!("Token endpoint returned {}: {}", status, body);
+        }
+        let tokens: TokenResponse = response.json().await.context("Invalid token response")?;
+        let id_token = tokens.id_token.context("Token response has no id_token")?;
+
+        self.validate_id_token(provider, &metadata, &id_token, nonce).await
+    }
+
+    async fn validate_id_token(
+        &self,
+        provider: &Provider,
+        metadata: &ProviderMetadata,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<ExternalIdentity> {
+        let config = &provider.config;
+        let header = decode_header(id_token).context("Malformed ID token")?;
+        if !ID_TOKEN_ALGORITHMS.contains(&header.alg) {
+            bail!("ID token is signed with {:?}, which is not allowed", header.alg);
+        }
+        let key = self.decoding_key(provider, header.kid.as_deref()).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims = decode::<IdTokenClaims>(id_token, &key, &validation)
+            .context("ID token is invalid")?
+            .claims;
+
+        // Replay protection: the token must answer this sign-in
+        if claims.nonce.as_deref() != Some(nonce) {
+            bail!("ID token nonce does not match");
+        }
+        if claims.azp.as_ref().is_some_and(|azp| azp != &config.client_id) {
+            bail!("ID token was issued to another client");
+        }
+
+        Ok(ExternalIdentity {
+            subject: claims.sub,
+            email: claims.email,
+            email_verified: claims.email_verified,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::MockIdp;
+
+    #[tokio::test]
+    async fn test_sign_in_with_mock_provider() {
+        let idp = MockIdp::start().await;
+        let client = OidcClient::new(&idp.config()).unwrap();
+        assert!(!client.has_provider("other"));
+
+        let request = client.authorization_request("mock").await.unwrap();
+        let url = Url::parse(&request.url).unwrap();
+        let query = |name: &str| url.query_pairs().find(|(key, _)| key == name).unwrap().1.into_owned();
+        assert_eq!(query("state"), request.state);
+        assert_eq!(query("code_challenge"), pkce_challenge(&request.code_verifier));
+        assert_eq!(query("scope"), "openid email profile");
+
+        let code = idp.authorize(&url, "sub-1", "alice@example.com");
+        let identity = client.finish("mock", &code, &request.nonce, &request.code_verifier).await.unwrap();
+        assert_eq!(identity.subject, "sub-1");
+        assert_eq!(identity.email.as_deref(), Some("alice@example.com"));
+        assert!(identity.email_verified);
+
+        // A code works once, and only with its own PKCE verifier and nonce
+        assert!(client.finish("mock", &code, &request.nonce, &request.code_verifier).await.is_err());
+        let code = idp.authorize(&url, "sub-1", "alice@example.com");
+        assert!(client.finish("mock", &code, &request.nonce, "wrong-verifier").await.is_err());
+        let code = idp.authorize(&url, "sub-1", "alice@example.com");
+        assert!(client.finish("mock", &code, "wrong-nonce", &request.code_verifier).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_id_tokens_from_elsewhere_are_rejected() {
+        let idp = MockIdp::start().await;
+        let client = OidcClient::new(&idp.config()).unwrap();
+        let request = client.authorization_request("mock").await.unwrap();
+        let url = Url::parse(&request.url).unwrap();
+
+        for tamper in [
+            serde_json::json!({ "iss": "https://evil.example.com" }),
+            serde_json::json!({ "aud": "another-client" }),
+            serde_json::json!({ "exp": 1_000_000_000 }),
+        ] {
+            idp.tamper_next_id_token(tamper);
+            let code = idp.authorize(&url, "sub-1", "alice@example.com");
+            assert!(client.finish("mock", &code, &request.nonce, &request.code_verifier).await.is_err());
+        }
+
+        // Shared-secret signatures are refused outright
+        idp.sign_next_id_token_with_hmac();
+        let code = idp.authorize(&url, "sub-1", "alice@example.com");
+        let error = client.finish("mock", &code, &request.nonce, &request.code_verifier).await.unwrap_err();
+        assert!(error.to_string().contains("not allowed"));
+    }
+}
@@ -0,0 +1,141 @@
+use anyhow::{anyhow, Context, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, SaltString},
+    Algorithm, Argon2, Params, PasswordHasher as _, PasswordVerifier as _, Version,
+};
+
+use crate::config::{PasswordAlgorithm, PasswordHashingConfig};
+
+/// Hashes new passwords with the configured algorithm, and verifies stored
+/// argon2id or bcrypt hashes whatever the current policy.
+pub struct PasswordHasher {
+    config: PasswordHashingConfig,
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix))
+}
+
+impl PasswordHasher {
+    pub fn new(config: PasswordHashingConfig) -> Self {
+        Self { config }
+    }
+
+    fn params(&self) -> Result<Params> {
+        Params::new(
+            self.config.argon2_memory_kib,
+            self.config.argon2_iterations,
+            self.config.argon2_parallelism,
+            None,
+        )
+        .map_err(|e| anyhow!("Invalid argon2 parameters: {}", e))
+    }
+
+    /// Hash `password` with the current policy.
+    pub fn hash(&self, password: &str) -> Result<String> {
+        match self.config.algorithm {
+            PasswordAlgorithm::Argon2id => {
+                let salt = SaltString::generate(&mut OsRng);
+                let hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params()?)
+                    .hash_password(password.as_bytes(), &salt)
+                    .map_err(|e| anyhow!("Failed to hash password: {}", e))?;
+                Ok(hash.to_string())
+            }
+            PasswordAlgorithm::Bcrypt => {
+                bcrypt::hash(password, self.config.bcrypt_cost).context("Failed to hash password")
+            }
+        }
+    }
+
+    /// Check `password` against a stored hash of either format.
+    pub fn verify(&self, password: &str, hash: &str) -> Result<bool> {
+        if is_bcrypt(hash) {
+            return bcrypt::verify(password, hash).context("Failed to verify password");
+        }
+
+        let parsed = PasswordHash::new(hash).map_err(|e| anyhow!("Unrecognised password hash: {}", e))?;
+        // The algorithm and parameters are taken from the hash itself
+        match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(anyhow!("Failed to verify password: {}", e)),
+        }
+    }
+
+    /// Whether a stored hash was made with a different algorithm or
+    /// parameters than the current policy, and should be replaced.
+    pub fn needs_rehash(&self, hash: &str) -> bool {
+        match self.config.algorithm {
+            PasswordAlgorithm::Bcrypt => match hash.parse::<bcrypt::HashParts>() {
+                Ok(parts) => !is_bcrypt(hash) || parts.get_cost() != self.config.bcrypt_cost,
+                Err(_) => true,
+            },
+            PasswordAlgorithm::Argon2id => {
+                let Ok(parsed) = PasswordHash::new(hash) else {
+                    return true;
+                };
+                let Ok(params) = Params::try_from(&parsed) else {
+                    return true;
+                };
+                parsed.algorithm != Algorithm::Argon2id.ident()
+                    || parsed.version != Some(Version::V0x13.into())
+                    || params.m_cost() != self.config.argon2_memory_kib
+                    || params.t_cost() != self.config.argon2_iterations
+                    || params.p_cost() != self.config.argon2_parallelism
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hasher(algorithm: PasswordAlgorithm) -> PasswordHasher {
+        PasswordHasher::new(PasswordHashingConfig {
+            algorithm,
+            argon2_memory_kib: 256,
+            argon2_iterations: 1,
+            argon2_parallelism: 1,
+            bcrypt_cost: 4,
+        })
+    }
+
+    #[test]
+    fn test_hash_and_verify() {
+        for algorithm in [PasswordAlgorithm::Argon2id, PasswordAlgorithm::Bcrypt] {
+            let hasher = hasher(algorithm);
+            let hash = hasher.hash("password123").unwrap();
+
+            assert!(hasher.verify("password123", &hash).unwrap());
+            assert!(!hasher.verify("password124", &hash).unwrap());
+            assert!(!hasher.needs_rehash(&hash));
+        }
+
+        let hash = hasher(PasswordAlgorithm::Argon2id).hash("password123").unwrap();
+        assert!(hash.starts_with("$argon2id$v=19$m=256,t=1,p=1$"));
+        assert!(hasher(PasswordAlgorithm::Argon2id).verify("password123", "not-a-hash").is_err());
+    }
+
+    #[test]
+    fn test_needs_rehash_when_policy_changes() {
+        let argon2 = hasher(PasswordAlgorithm::Argon2id);
+        let bcrypt = hasher(PasswordAlgorithm::Bcrypt);
+        let argon2_hash = argon2.hash("password123").unwrap();
+        let bcrypt_hash = bcrypt.hash("password123").unwrap();
+
+        // Either format verifies under either policy
+        assert!(argon2.verify("password123", &bcrypt_hash).unwrap());
+        assert!(bcrypt.verify("password123", &argon2_hash).unwrap());
+
+        assert!(argon2.needs_rehash(&bcrypt_hash));
+        assert!(bcrypt.needs_rehash(&argon2_hash));
+
+        let stronger = PasswordHasher::new(PasswordHashingConfig {
+            argon2_iterations: 2,
+            bcrypt_cost: 5,
+            ..argon2.config.clone()
+        });
+        assert!(stronger.needs_rehash(&argon2_hash));
+    }
+}
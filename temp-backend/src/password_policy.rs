@@ -0,0 +1,250 @@
+use anyhow::{Context, Result};
+use sha1::{Digest, Sha1};
+use std::{
+    collections::HashSet,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::{config::PasswordPolicyConfig, error::AppError, models::FieldError};
+
+const KEYBOARD_ROWS: &[&str] = &["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+/// Length of the hash prefix that names a k-anonymity range file.
+const RANGE_PREFIX_LEN: usize = 5;
+
+enum BreachedPasswords {
+    None,
+    /// Full SHA-1 hashes in uppercase hex
+    Hashes(HashSet<String>),
+    /// A directory of range files, read on each lookup
+    Ranges(PathBuf),
+}
+
+/// The hash part of a `HASH:COUNT` line, in uppercase.
+fn hash_field(line: &str) -> Option<String> {
+    let hash = line.split(':').next()?.trim();
+    (!hash.is_empty()).then(|| hash.to_ascii_uppercase())
+}
+
+impl BreachedPasswords {
+    fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::None);
+        };
+        if path.is_dir() {
+            return Ok(Self::Ranges(path.to_path_buf()));
+        }
+
+        let list = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read breached passwords from {}", path.display()))?;
+        Ok(Self::Hashes(list.lines().filter_map(hash_field).collect()))
+    }
+
+    fn contains(&self, password: &str) -> Result<bool> {
+        let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+
+        match self {
+            Self::None => Ok(false),
+            Self::Hashes(hashes) => Ok(hashes.contains(&hash)),
+            Self::Ranges(dir) => {
+                let (prefix, suffix) = hash.split_at(RANGE_PREFIX_LEN);
+                let path = dir.join(format!("{}.txt", prefix));
+                let range = match fs::read_to_string(&path) {
+                    Ok(range) => range,
+                    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+                    Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+                };
+                Ok(range.lines().filter_map(hash_field).any(|line| line == suffix))
+            }
+        }
+    }
+}
+
+/// Whether `b` continues a run from `a`, like `ab`, `21` or `qw`.
+fn is_run(a: char, b: char) -> bool {
+    (a as i64 - b as i64).abs() == 1
+        || KEYBOARD_ROWS
+            .iter()
+            .any(|row| row.find(a).zip(row.find(b)).is_some_and(|(i, j)| i.abs_diff(j) == 1))
+}
+
+/// Rough guessability score from 0 (trivial) to 4 (strong), from the length
+/// and variety of characters. Repeats and runs such as `aaa`, `123` or
+/// `qwerty` count for little. Common words are left to the breached list.
+pub fn estimate_strength(password: &str) -> u8 {
+    let mut pool = 0u32;
+    if password.chars().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if password.chars().any(|c| !c.is_ascii_alphanumeric()) {
+        pool += 33;
+    }
+
+    let chars: Vec<char> = password.chars().map(|c| c.to_ascii_lowercase()).collect();
+    let length: f64 = chars
+        .iter()
+        .enumerate()
+        .map(|(i, &c)| {
+            let predictable = i > 0 && (chars[i - 1] == c || is_run(chars[i - 1], c));
+            if predictable { 0.25 } else { 1.0 }
+        })
+        .sum();
+
+    let bits = length * f64::from(pool.max(1)).log2();
+    match bits {
+        b if b < 28.0 => 0,
+        b if b < 36.0 => 1,
+        b if b < 60.0 => 2,
+        b if b < 80.0 => 3,
+        _ => 4,
+    }
+}
+
+/// Checks new passwords against the configured rules and breached-password list.
+pub struct PasswordPolicy {
+    config: PasswordPolicyConfig,
+    breached: BreachedPasswords,
+}
+
+impl PasswordPolicy {
+    pub fn load(config: PasswordPolicyConfig) -> Result<Self> {
+        let breached = BreachedPasswords::load(config.breached_passwords_path.as_deref())?;
+        Ok(Self { config, breached })
+    }
+
+    /// Check a new `password` for the account with `email`. Every problem is
+    /// reported as a reason against `field`.
+    pub fn check(&self, field: &str, password: &str, email: &str) -> Result<(), AppError> {
+        let config = &self.config;
+        let mut problems = vec![];
+        let mut problem = |code: &str, message: String| {
+            problems.push(FieldError {
+                field: field.to_string(),
+                code: code.to_string(),
+                message,
+            })
+        };
+
+        let length = password.chars().count();
+        if length < config.min_length {
+            problem("too_short", format!("Must be at least {} characters long", config.min_length));
+        }
+        if length > config.max_length {
+            problem("too_long", format!("Must be at most {} characters long", config.max_length));
+        }
+        if config.require_lowercase && !password.chars().any(|c| c.is_lowercase()) {
+            problem("missing_lowercase", "Must contain a lowercase letter".to_string());
+        }
+        if config.require_uppercase && !password.chars().any(|c| c.is_uppercase()) {
+            problem("missing_uppercase", "Must contain an uppercase letter".to_string());
+        }
+        if config.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            problem("missing_digit", "Must contain a digit".to_string());
+        }
+        if config.require_symbol && password.chars().all(|c| c.is_alphanumeric()) {
+            problem("missing_symbol", "Must contain a symbol".to_string());
+        }
+
+        let local_part = email.split('@').next().unwrap_or("").trim().to_lowercase();
+        if config.reject_email && local_part.chars().count() >= 3 && password.to_lowercase().contains(&local_part) {
+            problem("contains_email", "Must not contain your email address".to_string());
+        }
+
+        // A short password is weak by definition; the length reason says why
+        if length >= config.min_length && estimate_strength(password) < config.min_strength {
+            problem(
+                "too_weak",
+                "Is too easy to guess; use a longer password or more kinds of characters".to_string(),
+            );
+        }
+
+        if self.breached.contains(password)? {
+            problem(
+                "breached",
+                "Has appeared in a data breach, so attackers will try it; choose a different password".to_string(),
+            );
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::invalid_fields("Password does not meet the requirements", problems))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(result: Result<(), AppError>) -> Vec<String> {
+        match result {
+            Ok(()) => vec![],
+            Err(error) => error.fields().iter().map(|field| field.code.clone()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_estimate_strength() {
+        assert_eq!(estimate_strength(""), 0);
+        assert_eq!(estimate_strength("aaaaaaaaaaaa"), 0);
+        assert_eq!(estimate_strength("abcdefghijkl"), 0);
+        assert_eq!(estimate_strength("qwertyuiop123"), 0);
+        assert_eq!(estimate_strength("password123"), 2);
+        assert_eq!(estimate_strength("Tr0ub4dor&3x"), 3);
+        assert_eq!(estimate_strength("correct horse battery staple"), 4);
+    }
+
+    #[test]
+    fn test_policy_reports_every_problem() {
+        let policy = PasswordPolicy::load(PasswordPolicyConfig {
+            require_uppercase: true,
+            require_symbol: true,
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(
+            codes(policy.check("password", "alice", "alice@example.com")),
+            ["too_short", "missing_uppercase", "missing_symbol", "contains_email"]
+        );
+        assert_eq!(codes(policy.check("password", "1234567890", "bob@example.com")).len(), 3);
+        assert!(codes(policy.check("password", "Moonlit-Harbor-42", "alice@example.com")).is_empty());
+
+        let error = policy.check("new_password", "x", "bob@example.com").unwrap_err();
+        assert_eq!(error.code(), "validation_error");
+        assert!(error.fields().iter().all(|field| field.field == "new_password"));
+    }
+
+    #[test]
+    fn test_breached_passwords() {
+        let dir = std::env::temp_dir().join(format!("breached-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(dir.join("ranges")).unwrap();
+
+        // SHA-1 of "password123" is CBFDAC6008F9CAB4083784CBD1874F76618D2A97
+        let list = dir.join("hashes.txt");
+        fs::write(&list, "cbfdac6008f9cab4083784cbd1874f76618d2a97:2418984\r\n").unwrap();
+        fs::write(dir.join("ranges/CBFDA.txt"), "0000000000000000000000000000000000A:1\nC6008F9CAB4083784CBD1874F76618D2A97:2418984\n").unwrap();
+
+        for path in [list, dir.join("ranges")] {
+            let policy = PasswordPolicy::load(PasswordPolicyConfig {
+                breached_passwords_path: Some(path),
+                ..Default::default()
+            })
+            .unwrap();
+
+            assert_eq!(codes(policy.check("password", "password123", "bob@example.com")), ["breached"]);
+            assert!(codes(policy.check("password", "password124", "bob@example.com")).is_empty());
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
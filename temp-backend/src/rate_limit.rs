@@ -0,0 +1,250 @@
+use axum::{
+    extract::{Request, State},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::{auth::bearer_token, client_ip::ClientIp, config::RateLimitRule, error::AppError, AppState};
+
+pub const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+pub const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+pub const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// Past this many tracked clients, buckets that have refilled are dropped.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Whole seconds, rounded up, ignoring floating-point noise.
+fn ceil_secs(secs: f64) -> u64 {
+    (secs - 1e-6).ceil().max(0.0) as u64
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The outcome of taking a token, as reported in the `RateLimit-*` headers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimitStatus {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the bucket is full again.
+    pub reset: u64,
+    /// Seconds until the next request is allowed, if this one was refused.
+    pub retry_after: Option<u64>,
+}
+
+/// In-memory token buckets for one route group, one per client.
+pub struct RateLimiter {
+    rule: RateLimitRule,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rule: RateLimitRule) -> Self {
+        Self {
+            rule,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn tokens_per_sec(&self) -> f64 {
+        f64::from(self.rule.per_minute) / 60.0
+    }
+
+    fn level(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let refilled = now.saturating_duration_since(bucket.updated).as_secs_f64() * self.tokens_per_sec();
+        (bucket.tokens + refilled).min(f64::from(self.rule.burst))
+    }
+
+    /// Take a token from `key`'s bucket.
+    pub fn check(&self, key: &str) -> RateLimitStatus {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> RateLimitStatus {
+        let burst = f64::from(self.rule.burst);
+        let mut buckets = self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| self.level(bucket, now) < burst);
+        }
+
+        let bucket = buckets
+            .entry(key.to_string())
+            .or_insert(Bucket { tokens: burst, updated: now });
+        let mut tokens = self.level(bucket, now);
+        let allowed = tokens >= 1.0;
+        if allowed {
+            tokens -= 1.0;
+        }
+        bucket.tokens = tokens;
+        bucket.updated = now;
+
+        let rate = self.tokens_per_sec();
+        RateLimitStatus {
+            limit: self.rule.burst,
+            remaining: tokens.floor() as u32,
+            reset: ceil_secs((burst - tokens) / rate),
+            retry_after: (!allowed).then(|| ceil_secs((1.0 - tokens) / rate).max(1)),
+        }
+    }
+}
+
+type LimitFuture = Pin<Box<dyn Future<Output = Response> + Send>>;
+
+/// Layer that throttles requests with `limiter`, counting per authenticated
+/// user or, without a valid access token, per client IP. Refused requests get
+/// `429` with `Retry-After`; every response carries `RateLimit-*` headers.
+///
+/// ```ignore
+/// router.route_layer(middleware::from_fn_with_state(state, rate_limit(limiter)))
+/// ```
+pub fn rate_limit(
+    limiter: Arc<RateLimiter>,
+) -> impl Fn(State<AppState>, ClientIp, Request, Next) -> LimitFuture + Clone + Send + Sync + 'static {
+    move |State(state): State<AppState>, ClientIp(ip): ClientIp, request: Request, next: Next| -> LimitFuture {
+        let limiter = limiter.clone();
+        Box::pin(async move {
+            // Only the signature is checked here; the handler verifies the token fully
+            let key = match bearer_token(request.headers()).map(|token| state.jwt_service.decode_token(token)) {
+                Some(Ok(claims)) => format!("user:{}", claims.sub),
+                _ => format!("ip:{}", ip),
+            };
+
+            let status = limiter.check(&key);
+            let mut response = match status.retry_after {
+                Some(retry_after) => AppError::too_many_requests(
+                    "rate_limited",
+                    format!(
+                        "Too many requests. Try again in {} second{}",
+                        retry_after,
+                        if retry_after == 1 { "" } else { "s" }
+                    ),
+                    retry_after,
+                )
+                .into_response(),
+                None => next.run(request).await,
+            };
+
+            let headers = response.headers_mut();
+            headers.insert(RATELIMIT_LIMIT, HeaderValue::from(status.limit));
+            headers.insert(RATELIMIT_REMAINING, HeaderValue::from(status.remaining));
+            headers.insert(RATELIMIT_RESET, HeaderValue::from(status.reset));
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::create_test_app_state;
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{
+            header::{AUTHORIZATION, RETRY_AFTER},
+            StatusCode,
+        },
+        middleware,
+        routing::get,
+        Router,
+    };
+    use std::{net::SocketAddr, time::Duration};
+    use tower::ServiceExt;
+
+    fn limiter(burst: u32, per_minute: u32) -> RateLimiter {
+        RateLimiter::new(RateLimitRule { burst, per_minute })
+    }
+
+    #[test]
+    fn test_bucket_drains_and_refills() {
+        let limiter = limiter(3, 60);
+        let start = Instant::now();
+
+        for remaining in [2, 1, 0] {
+            let status = limiter.check_at("ip:192.0.2.1", start);
+            assert_eq!(status.remaining, remaining);
+            assert_eq!(status.retry_after, None);
+        }
+        let status = limiter.check_at("ip:192.0.2.1", start);
+        assert_eq!(status.retry_after, Some(1));
+        assert_eq!(status.reset, 3);
+
+        // Other clients have their own bucket
+        assert_eq!(limiter.check_at("ip:192.0.2.2", start).remaining, 2);
+
+        // One token a second comes back, up to the burst
+        assert_eq!(limiter.check_at("ip:192.0.2.1", start + Duration::from_secs(1)).retry_after, None);
+        let status = limiter.check_at("ip:192.0.2.1", start + Duration::from_secs(60));
+        assert_eq!(status.remaining, 2);
+        assert_eq!(status.reset, 1);
+    }
+
+    #[test]
+    fn test_slow_refill_retry_after() {
+        let limiter = limiter(1, 2);
+        let start = Instant::now();
+
+        limiter.check_at("ip:192.0.2.1", start);
+        let status = limiter.check_at("ip:192.0.2.1", start + Duration::from_secs(10));
+        assert_eq!(status.retry_after, Some(20));
+    }
+
+    async fn send(app: &Router, ip: [u8; 4], token: Option<&str>) -> Response {
+        let mut request = Request::builder().uri("/limited");
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        let mut request = request.body(Body::empty()).unwrap();
+        request.extensions_mut().insert(ConnectInfo(SocketAddr::from((ip, 4000))));
+
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_layer_limits_per_ip_or_user() {
+        let state = create_test_app_state().await.unwrap();
+        let app = Router::new()
+            .route("/limited", get(|| async { "ok" }))
+            .route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                rate_limit(Arc::new(limiter(2, 1))),
+            ))
+            .with_state(state.clone());
+
+        let response = send(&app, [192, 0, 2, 1], None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[&RATELIMIT_LIMIT], "2");
+        assert_eq!(response.headers()[&RATELIMIT_REMAINING], "1");
+        assert_eq!(response.headers()[&RATELIMIT_RESET], "60");
+
+        send(&app, [192, 0, 2, 1], None).await;
+        let response = send(&app, [192, 0, 2, 1], None).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[&RETRY_AFTER], "60");
+        assert_eq!(response.headers()[&RATELIMIT_REMAINING], "0");
+
+        // A signed-in user is counted on their own, wherever they connect from
+        let token = state.jwt_service.create_token("user-123", "user@example.com").unwrap();
+        for _ in 0..2 {
+            assert_eq!(send(&app, [192, 0, 2, 1], Some(&token)).await.status(), StatusCode::OK);
+        }
+        let response = send(&app, [198, 51, 100, 7], Some(&token)).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // A token that does not verify counts against the address
+        let response = send(&app, [192, 0, 2, 1], Some("not-a-token")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(send(&app, [192, 0, 2, 2], None).await.status(), StatusCode::OK);
+    }
+}
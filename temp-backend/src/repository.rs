@@ -1,97 +1,1533 @@
 use anyhow::Result;
-use chrono::Utc;
-use sqlx::SqlitePool;
+use chrono::{DateTime, Utc};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
 use uuid::Uuid;
 
-use crate::models::{User, UserProfile, UpdateProfileRequest};
+use crate::auth::Scope;
+use crate::models::{ApiToken, ListUsersQuery, LoginFailures, OAuthAuthorizationCode, OidcLoginAttempt, OneTimeToken, Passkey, PasskeyCeremony, PasskeyChallenge, PhoneOtp, PhoneOtpPurpose, RefreshToken, Role, Session, TokenPurpose, UpdateMembershipRequest, User, UserAccount, UserIdentity, UserProfile, UserTotp, UpdateProfileRequest};
+
+const USER_ACCOUNT_COLUMNS: &str = "id, email, first_name, last_name, phone, membership_id, membership_level, points, role, disabled_at, password_reset_required, email_verified_at, created_at, updated_at";
 
 pub struct UserRepository {
     pool: SqlitePool,
 }
 
-impl UserRepository {
+impl UserRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create_user(&self, email: &str, password_hash: &str) -> Result<User> {
+        let id = Uuid::new_v4().to_string();
+        let membership_id = format!("LBK{:06}", rand::random::<u32>() % 1000000);
+        let now = Utc::now();
+
+        let user = sqlx::query_as::<_, User>(
+            r#"
+            INSERT INTO users (id, email, password_hash, membership_id, membership_level, points, created_at, updated_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            RETURNING id, email, password_hash, first_name, last_name, phone, membership_id, membership_level, points, role, disabled_at, password_reset_required, email_verified_at, created_at, updated_at
+            "#,
+        )
+        .bind(&id)
+        .bind(email)
+        .bind(password_hash)
+        .bind(&membership_id)
+        .bind("Bronze")
+        .bind(0)
+        .bind(now)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    pub async fn find_by_email(&self, email: &str) -> Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(
+            "SELECT id, email, password_hash, first_name, last_name, phone, membership_id, membership_level, points, role, disabled_at, password_reset_required, email_verified_at, created_at, updated_at FROM users WHERE email = ?"
+        )
+        .bind(email)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    pub async fn find_by_id(&self, id: &str) -> Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(
+            "SELECT id, email, password_hash, first_name, last_name, phone, membership_id, membership_level, points, role, disabled_at, password_reset_required, email_verified_at, created_at, updated_at FROM users WHERE id = ?"
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    pub async fn get_profile(&self, user_id: &str) -> Result<Option<UserProfile>> {
+        let profile = sqlx::query_as::<_, UserProfile>(
+            "SELECT id, email, first_name, last_name, phone, membership_id, membership_level, points, role, email_verified_at, phone_verified_at, created_at FROM users WHERE id = ?"
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(profile)
+    }
+
+    pub async fn update_profile(&self, user_id: &str, request: &UpdateProfileRequest) -> Result<Option<UserProfile>> {
+        let now = Utc::now();
+
+        sqlx::query(
+            r#"
+            UPDATE users 
+            SET first_name = ?, last_name = ?, phone = ?,
+                phone_verified_at = CASE WHEN phone IS ? THEN phone_verified_at ELSE NULL END,
+                updated_at = ?
+            WHERE id = ?
+            "#,
+        )
+        .bind(&request.first_name)
+        .bind(&request.last_name)
+        .bind(&request.phone)
+        .bind(&request.phone)
+        .bind(now)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        self.get_profile(user_id).await
+    }
+
+    pub async fn set_role(&self, user_id: &str, role: Role) -> Result<Option<UserProfile>> {
+        sqlx::query("UPDATE users SET role = ?, updated_at = ? WHERE id = ?")
+            .bind(role)
+            .bind(Utc::now())
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        self.get_profile(user_id).await
+    }
+
+    pub async fn get_account(&self, user_id: &str) -> Result<Option<UserAccount>> {
+        let account = sqlx::query_as::<_, UserAccount>(&format!(
+            "SELECT {} FROM users WHERE id = ?",
+            USER_ACCOUNT_COLUMNS
+        ))
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(account)
+    }
+
+    /// One page of accounts matching `query`, oldest first, plus the total number of matches.
+    pub async fn list_accounts(&self, query: &ListUsersQuery, limit: i64, offset: i64) -> Result<(Vec<UserAccount>, i64)> {
+        let mut count = QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM users");
+        push_account_filters(&mut count, query);
+        let (total,): (i64,) = count.build_query_as().fetch_one(&self.pool).await?;
+
+        let mut select = QueryBuilder::<Sqlite>::new(format!("SELECT {} FROM users", USER_ACCOUNT_COLUMNS));
+        push_account_filters(&mut select, query);
+        select
+            .push(" ORDER BY created_at, id LIMIT ")
+            .push_bind(limit)
+            .push(" OFFSET ")
+            .push_bind(offset);
+        let accounts = select.build_query_as::<UserAccount>().fetch_all(&self.pool).await?;
+
+        Ok((accounts, total))
+    }
+
+    pub async fn update_membership(&self, user_id: &str, request: &UpdateMembershipRequest) -> Result<Option<UserAccount>> {
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET membership_level = COALESCE(?, membership_level), points = COALESCE(?, points), updated_at = ?
+            WHERE id = ?
+            "#,
+        )
+        .bind(&request.membership_level)
+        .bind(request.points)
+        .bind(Utc::now())
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        self.get_account(user_id).await
+    }
+
+    pub async fn set_disabled(&self, user_id: &str, disabled: bool) -> Result<Option<UserAccount>> {
+        let now = Utc::now();
+
+        sqlx::query("UPDATE users SET disabled_at = ?, updated_at = ? WHERE id = ?")
+            .bind(disabled.then_some(now))
+            .bind(now)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        self.get_account(user_id).await
+    }
+
+    pub async fn require_password_reset(&self, user_id: &str) -> Result<Option<UserAccount>> {
+        sqlx::query("UPDATE users SET password_reset_required = TRUE, updated_at = ? WHERE id = ?")
+            .bind(Utc::now())
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        self.get_account(user_id).await
+    }
+
+    /// Replace the password hash and clear any admin-forced reset.
+    pub async fn update_password(&self, user_id: &str, password_hash: &str) -> Result<()> {
+        sqlx::query(
+            "UPDATE users SET password_hash = ?, password_reset_required = FALSE, updated_at = ? WHERE id = ?"
+        )
+        .bind(password_hash)
+        .bind(Utc::now())
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Store the same password hashed under the current policy, unless the
+    /// password was changed since `old_hash` was read.
+    pub async fn rehash_password(&self, user_id: &str, old_hash: &str, new_hash: &str) -> Result<bool> {
+        let result = sqlx::query("UPDATE users SET password_hash = ? WHERE id = ? AND password_hash = ?")
+            .bind(new_hash)
+            .bind(user_id)
+            .bind(old_hash)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Switch to a new, already confirmed email address. Fails with a unique
+    /// violation if another account has taken it.
+    pub async fn update_email(&self, user_id: &str, email: &str) -> Result<()> {
+        let now = Utc::now();
+
+        sqlx::query("UPDATE users SET email = ?, email_verified_at = ?, updated_at = ? WHERE id = ?")
+            .bind(email)
+            .bind(now)
+            .bind(now)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Mark the user's email as verified, but only if it is still `email`.
+    /// Returns `false` if the address has changed since the token was sent.
+    pub async fn mark_email_verified(&self, user_id: &str, email: &str) -> Result<bool> {
+        let now = Utc::now();
+
+        let result = sqlx::query(
+            "UPDATE users SET email_verified_at = COALESCE(email_verified_at, ?), updated_at = ? WHERE id = ? AND email = ?"
+        )
+        .bind(now)
+        .bind(now)
+        .bind(user_id)
+        .bind(email)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Mark the user's phone number verified, storing it as `verified_phone`
+    /// (its E.164 form). Returns `false` if the number on the profile is no
+    /// longer `phone`. Fails with a unique violation if another account has
+    /// verified the same number.
+    pub async fn mark_phone_verified(&self, user_id: &str, phone: &str, verified_phone: &str) -> Result<bool> {
+        let now = Utc::now();
+
+        let result = sqlx::query(
+            "UPDATE users SET phone = ?, phone_verified_at = ?, updated_at = ? WHERE id = ? AND phone = ?"
+        )
+        .bind(verified_phone)
+        .bind(now)
+        .bind(now)
+        .bind(user_id)
+        .bind(phone)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// The user whose verified phone number is `phone` (E.164).
+    pub async fn find_by_verified_phone(&self, phone: &str) -> Result<Option<User>> {
+        let user = sqlx::query_as::<_, User>(
+            "SELECT id, email, password_hash, first_name, last_name, phone, membership_id, membership_level, points, role, disabled_at, password_reset_required, email_verified_at, created_at, updated_at FROM users WHERE phone = ? AND phone_verified_at IS NOT NULL"
+        )
+        .bind(phone)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    /// Returns `false` if there was no such user. Refresh tokens are deleted with the user.
+    pub async fn delete_user(&self, user_id: &str) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM users WHERE id = ?")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+}
+
+fn push_account_filters(builder: &mut QueryBuilder<'_, Sqlite>, query: &ListUsersQuery) {
+    let filters = [
+        ("email", &query.email),
+        ("phone", &query.phone),
+        ("membership_id", &query.membership_id),
+    ];
+
+    let mut separator = " WHERE ";
+    for (column, value) in filters {
+        let Some(value) = value.as_deref().filter(|v| !v.is_empty()) else {
+            continue;
+        };
+        builder
+            .push(separator)
+            .push(column)
+            .push(" LIKE ")
+            .push_bind(like_pattern(value))
+            .push(" ESCAPE '\\'");
+        separator = " AND ";
+    }
+}
+
+/// A LIKE pattern matching `value` anywhere, with wildcards in `value` taken literally.
+fn like_pattern(value: &str) -> String {
+    let escaped = value.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+pub struct RefreshTokenRepository {
+    pool: SqlitePool,
+}
+
+impl RefreshTokenRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(
+        &self,
+        user_id: &str,
+        family_id: &str,
+        token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<RefreshToken> {
+        let id = Uuid::new_v4().to_string();
+        let now = Utc::now();
+
+        let token = sqlx::query_as::<_, RefreshToken>(
+            r#"
+            INSERT INTO refresh_tokens (id, user_id, family_id, token_hash, expires_at, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            RETURNING id, user_id, family_id, token_hash, expires_at, created_at, used_at, revoked_at
+            "#,
+        )
+        .bind(&id)
+        .bind(user_id)
+        .bind(family_id)
+        .bind(token_hash)
+        .bind(expires_at)
+        .bind(now)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    pub async fn find_by_hash(&self, token_hash: &str) -> Result<Option<RefreshToken>> {
+        let token = sqlx::query_as::<_, RefreshToken>(
+            "SELECT id, user_id, family_id, token_hash, expires_at, created_at, used_at, revoked_at FROM refresh_tokens WHERE token_hash = ?"
+        )
+        .bind(token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    /// Mark a token as used. Returns `false` if it had already been used,
+    /// so two concurrent refreshes with the same token cannot both succeed.
+    pub async fn mark_used(&self, id: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE refresh_tokens SET used_at = ? WHERE id = ? AND used_at IS NULL AND revoked_at IS NULL"
+        )
+        .bind(Utc::now())
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+}
+
+const SESSION_COLUMNS: &str = "id, user_id, user_agent, ip_address, created_at, last_seen_at, expires_at, revoked_at";
+
+/// Sign-ins, one per device. Revoking a session also revokes its refresh tokens.
+pub struct SessionRepository {
+    pool: SqlitePool,
+}
+
+impl SessionRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(
+        &self,
+        user_id: &str,
+        user_agent: Option<&str>,
+        ip_address: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Session> {
+        let now = Utc::now();
+
+        let session = sqlx::query_as::<_, Session>(&format!(
+            r#"
+            INSERT INTO sessions (id, user_id, user_agent, ip_address, created_at, last_seen_at, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            RETURNING {}
+            "#,
+            SESSION_COLUMNS
+        ))
+        .bind(Uuid::new_v4().to_string())
+        .bind(user_id)
+        .bind(user_agent)
+        .bind(ip_address)
+        .bind(now)
+        .bind(now)
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(session)
+    }
+
+    pub async fn find(&self, id: &str) -> Result<Option<Session>> {
+        let session = sqlx::query_as::<_, Session>(&format!("SELECT {} FROM sessions WHERE id = ?", SESSION_COLUMNS))
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(session)
+    }
+
+    /// Record a refresh: the session was just used from `ip_address` and now
+    /// lasts until `expires_at`.
+    pub async fn record_refresh(&self, id: &str, ip_address: &str, expires_at: DateTime<Utc>) -> Result<()> {
+        sqlx::query(
+            "UPDATE sessions SET last_seen_at = ?, ip_address = ?, expires_at = ? WHERE id = ? AND revoked_at IS NULL"
+        )
+        .bind(Utc::now())
+        .bind(ip_address)
+        .bind(expires_at)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Move `last_seen_at` to now if it is older than `stale_before`, so busy
+    /// sessions are not written on every request.
+    pub async fn mark_seen(&self, id: &str, stale_before: DateTime<Utc>) -> Result<()> {
+        sqlx::query("UPDATE sessions SET last_seen_at = ? WHERE id = ? AND last_seen_at < ?")
+            .bind(Utc::now())
+            .bind(id)
+            .bind(stale_before)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// The user's unrevoked, unexpired sessions, most recently used first.
+    pub async fn list_active(&self, user_id: &str) -> Result<Vec<Session>> {
+        let sessions = sqlx::query_as::<_, Session>(&format!(
+            r#"
+            SELECT {} FROM sessions
+            WHERE user_id = ? AND revoked_at IS NULL AND expires_at > ?
+            ORDER BY last_seen_at DESC
+            "#,
+            SESSION_COLUMNS
+        ))
+        .bind(user_id)
+        .bind(Utc::now())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(sessions)
+    }
+
+    /// Revoke one of the user's sessions and its refresh tokens. Returns
+    /// `false` if there is no such active session.
+    pub async fn revoke(&self, user_id: &str, id: &str) -> Result<bool> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query("UPDATE sessions SET revoked_at = ? WHERE id = ? AND user_id = ? AND revoked_at IS NULL")
+            .bind(now)
+            .bind(id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE refresh_tokens SET revoked_at = ? WHERE family_id = ? AND user_id = ? AND revoked_at IS NULL")
+            .bind(now)
+            .bind(id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Revoke every session of the user but `except`, and their refresh
+    /// tokens. Returns the number of sessions revoked.
+    pub async fn revoke_all_for_user(&self, user_id: &str, except: Option<&str>) -> Result<u64> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query(
+            "UPDATE sessions SET revoked_at = ? WHERE user_id = ? AND revoked_at IS NULL AND (? IS NULL OR id != ?)"
+        )
+        .bind(now)
+        .bind(user_id)
+        .bind(except)
+        .bind(except)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "UPDATE refresh_tokens SET revoked_at = ? WHERE user_id = ? AND revoked_at IS NULL AND (? IS NULL OR family_id != ?)"
+        )
+        .bind(now)
+        .bind(user_id)
+        .bind(except)
+        .bind(except)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+}
+
+const API_TOKEN_COLUMNS: &str = "id, user_id, name, token_hash, scopes, created_at, expires_at, last_used_at, revoked_at";
+
+/// Personal access tokens, looked up by the hash of the token.
+pub struct ApiTokenRepository {
+    pool: SqlitePool,
+}
+
+impl ApiTokenRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(
+        &self,
+        user_id: &str,
+        name: &str,
+        token_hash: &str,
+        scopes: &[Scope],
+        expires_at: DateTime<Utc>,
+    ) -> Result<ApiToken> {
+        let scopes = scopes.iter().map(Scope::as_str).collect::<Vec<_>>().join(" ");
+
+        let token = sqlx::query_as::<_, ApiToken>(&format!(
+            r#"
+            INSERT INTO api_tokens (id, user_id, name, token_hash, scopes, created_at, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            RETURNING {}
+            "#,
+            API_TOKEN_COLUMNS
+        ))
+        .bind(Uuid::new_v4().to_string())
+        .bind(user_id)
+        .bind(name)
+        .bind(token_hash)
+        .bind(scopes)
+        .bind(Utc::now())
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    /// The unrevoked, unexpired token with this hash.
+    pub async fn find_active_by_hash(&self, token_hash: &str) -> Result<Option<ApiToken>> {
+        let token = sqlx::query_as::<_, ApiToken>(&format!(
+            "SELECT {} FROM api_tokens WHERE token_hash = ? AND revoked_at IS NULL AND expires_at > ?",
+            API_TOKEN_COLUMNS
+        ))
+        .bind(token_hash)
+        .bind(Utc::now())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    /// The user's unrevoked, unexpired tokens, newest first.
+    pub async fn list_active(&self, user_id: &str) -> Result<Vec<ApiToken>> {
+        let tokens = sqlx::query_as::<_, ApiToken>(&format!(
+            r#"
+            SELECT {} FROM api_tokens
+            WHERE user_id = ? AND revoked_at IS NULL AND expires_at > ?
+            ORDER BY created_at DESC
+            "#,
+            API_TOKEN_COLUMNS
+        ))
+        .bind(user_id)
+        .bind(Utc::now())
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(tokens)
+    }
+
+    /// Move `last_used_at` to now if it is unset or older than `stale_before`.
+    pub async fn mark_used(&self, id: &str, stale_before: DateTime<Utc>) -> Result<()> {
+        sqlx::query("UPDATE api_tokens SET last_used_at = ? WHERE id = ? AND (last_used_at IS NULL OR last_used_at < ?)")
+            .bind(Utc::now())
+            .bind(id)
+            .bind(stale_before)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Revoke one of the user's tokens. Returns `false` if there is no such
+    /// unrevoked token.
+    pub async fn revoke(&self, user_id: &str, id: &str) -> Result<bool> {
+        let result = sqlx::query("UPDATE api_tokens SET revoked_at = ? WHERE id = ? AND user_id = ? AND revoked_at IS NULL")
+            .bind(Utc::now())
+            .bind(id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Revoke every token of the user. Returns the number revoked.
+    pub async fn revoke_all_for_user(&self, user_id: &str) -> Result<u64> {
+        let result = sqlx::query("UPDATE api_tokens SET revoked_at = ? WHERE user_id = ? AND revoked_at IS NULL")
+            .bind(Utc::now())
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+/// Single-use tokens delivered out of band, stored only as hashes.
+pub struct OneTimeTokenRepository {
+    pool: SqlitePool,
+}
+
+impl OneTimeTokenRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create(
+        &self,
+        user_id: &str,
+        purpose: TokenPurpose,
+        email: &str,
+        token_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<OneTimeToken> {
+        let token = sqlx::query_as::<_, OneTimeToken>(
+            r#"
+            INSERT INTO one_time_tokens (id, user_id, purpose, email, token_hash, expires_at, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            RETURNING id, user_id, purpose, email, token_hash, expires_at, created_at, used_at, attempts
+            "#,
+        )
+        .bind(Uuid::new_v4().to_string())
+        .bind(user_id)
+        .bind(purpose)
+        .bind(email)
+        .bind(token_hash)
+        .bind(expires_at)
+        .bind(Utc::now())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    /// Use up an unexpired token. Returns `None` if it does not exist, has the
+    /// wrong purpose, has expired or was already used.
+    pub async fn consume(&self, purpose: TokenPurpose, token_hash: &str) -> Result<Option<OneTimeToken>> {
+        let now = Utc::now();
+
+        let token = sqlx::query_as::<_, OneTimeToken>(
+            r#"
+            UPDATE one_time_tokens SET used_at = ?
+            WHERE token_hash = ? AND purpose = ? AND used_at IS NULL AND expires_at > ?
+            RETURNING id, user_id, purpose, email, token_hash, expires_at, created_at, used_at, attempts
+            "#,
+        )
+        .bind(now)
+        .bind(token_hash)
+        .bind(purpose)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    /// Look up an unexpired, unused token without using it up. For tokens that
+    /// are only consumed once a code sent alongside them checks out.
+    pub async fn find_active(&self, purpose: TokenPurpose, token_hash: &str) -> Result<Option<OneTimeToken>> {
+        let token = sqlx::query_as::<_, OneTimeToken>(
+            r#"
+            SELECT id, user_id, purpose, email, token_hash, expires_at, created_at, used_at, attempts
+            FROM one_time_tokens
+            WHERE token_hash = ? AND purpose = ? AND used_at IS NULL AND expires_at > ?
+            "#,
+        )
+        .bind(token_hash)
+        .bind(purpose)
+        .bind(Utc::now())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    /// Count a wrong code entered against the token, using the token up once
+    /// `max_attempts` is reached.
+    pub async fn record_failed_attempt(&self, id: &str, max_attempts: i64) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE one_time_tokens
+            SET attempts = attempts + 1,
+                used_at = CASE WHEN attempts + 1 >= ? THEN ? ELSE used_at END
+            WHERE id = ?
+            "#,
+        )
+        .bind(max_attempts)
+        .bind(Utc::now())
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Invalidate the user's outstanding tokens for `purpose`, e.g. before sending a new one.
+    pub async fn invalidate(&self, user_id: &str, purpose: TokenPurpose) -> Result<u64> {
+        let result = sqlx::query(
+            "UPDATE one_time_tokens SET used_at = ? WHERE user_id = ? AND purpose = ? AND used_at IS NULL"
+        )
+        .bind(Utc::now())
+        .bind(user_id)
+        .bind(purpose)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+const PHONE_OTP_COLUMNS: &str = "id, user_id, purpose, phone, code_hash, attempts, created_at, expires_at, used_at";
+
+/// Codes sent by text message to verify a phone number or sign in.
+pub struct PhoneOtpRepository {
+    pool: SqlitePool,
+}
+
+impl PhoneOtpRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    /// Record a code sent to `phone`, using up the user's earlier codes for
+    /// `purpose` so only the newest works.
+    pub async fn create(
+        &self,
+        user_id: &str,
+        purpose: PhoneOtpPurpose,
+        phone: &str,
+        code_hash: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<PhoneOtp> {
+        let now = Utc::now();
+
+        sqlx::query("UPDATE phone_otps SET used_at = ? WHERE user_id = ? AND purpose = ? AND used_at IS NULL")
+            .bind(now)
+            .bind(user_id)
+            .bind(purpose)
+            .execute(&self.pool)
+            .await?;
+
+        let otp = sqlx::query_as::<_, PhoneOtp>(&format!(
+            r#"
+            INSERT INTO phone_otps (id, user_id, purpose, phone, code_hash, created_at, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            RETURNING {}
+            "#,
+            PHONE_OTP_COLUMNS
+        ))
+        .bind(Uuid::new_v4().to_string())
+        .bind(user_id)
+        .bind(purpose)
+        .bind(phone)
+        .bind(code_hash)
+        .bind(now)
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(otp)
+    }
+
+    /// When the user was last sent a code for `purpose`, used or not.
+    pub async fn last_sent_at(&self, user_id: &str, purpose: PhoneOtpPurpose) -> Result<Option<DateTime<Utc>>> {
+        let sent_at = sqlx::query_scalar::<_, DateTime<Utc>>(
+            "SELECT created_at FROM phone_otps WHERE user_id = ? AND purpose = ? ORDER BY created_at DESC LIMIT 1",
+        )
+        .bind(user_id)
+        .bind(purpose)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(sent_at)
+    }
+
+    /// The user's unused, unexpired code for `purpose`, if any.
+    pub async fn find_active(&self, user_id: &str, purpose: PhoneOtpPurpose) -> Result<Option<PhoneOtp>> {
+        let otp = sqlx::query_as::<_, PhoneOtp>(&format!(
+            "SELECT {} FROM phone_otps WHERE user_id = ? AND purpose = ? AND used_at IS NULL AND expires_at > ?",
+            PHONE_OTP_COLUMNS
+        ))
+        .bind(user_id)
+        .bind(purpose)
+        .bind(Utc::now())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(otp)
+    }
+
+    /// Use up a code that checked out. Returns `false` if it was already used,
+    /// e.g. by a concurrent request with the same code.
+    pub async fn consume(&self, id: &str) -> Result<bool> {
+        let result = sqlx::query("UPDATE phone_otps SET used_at = ? WHERE id = ? AND used_at IS NULL")
+            .bind(Utc::now())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Count a wrong code, using the code up once `max_attempts` is reached.
+    pub async fn record_failed_attempt(&self, id: &str, max_attempts: i64) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE phone_otps
+            SET attempts = attempts + 1,
+                used_at = CASE WHEN attempts + 1 >= ? THEN ? ELSE used_at END
+            WHERE id = ?
+            "#,
+        )
+        .bind(max_attempts)
+        .bind(Utc::now())
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Pending sign-ins with external OpenID Connect providers, and the
+/// provider accounts linked to local users.
+pub struct OidcRepository {
+    pool: SqlitePool,
+}
+
+impl OidcRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    /// Record a sign-in sent to `provider`, dropping any that have expired.
+    pub async fn create_attempt(
+        &self,
+        provider: &str,
+        state_hash: &str,
+        nonce: &str,
+        code_verifier: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let now = Utc::now();
+
+        sqlx::query("DELETE FROM oidc_login_attempts WHERE expires_at <= ?")
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO oidc_login_attempts (state_hash, provider, nonce, code_verifier, created_at, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(state_hash)
+        .bind(provider)
+        .bind(nonce)
+        .bind(code_verifier)
+        .bind(now)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Remove and return an unexpired sign-in for `provider`, so each `state`
+    /// works once. Returns `None` if there is no such sign-in.
+    pub async fn take_attempt(&self, provider: &str, state_hash: &str) -> Result<Option<OidcLoginAttempt>> {
+        let attempt = sqlx::query_as::<_, OidcLoginAttempt>(
+            r#"
+            DELETE FROM oidc_login_attempts
+            WHERE state_hash = ? AND provider = ?
+            RETURNING state_hash, provider, nonce, code_verifier, created_at, expires_at
+            "#,
+        )
+        .bind(state_hash)
+        .bind(provider)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(attempt.filter(|attempt| attempt.expires_at > Utc::now()))
+    }
+
+    pub async fn find_identity(&self, provider: &str, subject: &str) -> Result<Option<UserIdentity>> {
+        let identity = sqlx::query_as::<_, UserIdentity>(
+            r#"
+            SELECT provider, subject, user_id, email, created_at, last_login_at
+            FROM user_identities
+            WHERE provider = ? AND subject = ?
+            "#,
+        )
+        .bind(provider)
+        .bind(subject)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(identity)
+    }
+
+    pub async fn link_identity(&self, provider: &str, subject: &str, user_id: &str, email: Option<&str>) -> Result<()> {
+        let now = Utc::now();
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_identities (provider, subject, user_id, email, created_at, last_login_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(provider)
+        .bind(subject)
+        .bind(user_id)
+        .bind(email)
+        .bind(now)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Note a sign-in with a linked identity, and the email address the provider now reports.
+    pub async fn record_login(&self, provider: &str, subject: &str, email: Option<&str>) -> Result<()> {
+        sqlx::query("UPDATE user_identities SET last_login_at = ?, email = ? WHERE provider = ? AND subject = ?")
+            .bind(Utc::now())
+            .bind(email)
+            .bind(provider)
+            .bind(subject)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Authorization codes and consents for clients of this server's OAuth endpoints.
+pub struct OAuthRepository {
+    pool: SqlitePool,
+}
+
+impl OAuthRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    /// Store a code, dropping any that have expired.
+    pub async fn create_code(&self, code: &OAuthAuthorizationCode) -> Result<()> {
+        sqlx::query("DELETE FROM oauth_authorization_codes WHERE expires_at <= ?")
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO oauth_authorization_codes (code_hash, client_id, user_id, redirect_uri, scope, nonce, code_challenge, created_at, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&code.code_hash)
+        .bind(&code.client_id)
+        .bind(&code.user_id)
+        .bind(&code.redirect_uri)
+        .bind(&code.scope)
+        .bind(&code.nonce)
+        .bind(&code.code_challenge)
+        .bind(code.created_at)
+        .bind(code.expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Remove and return an unexpired code, so each code works once.
+    pub async fn take_code(&self, code_hash: &str) -> Result<Option<OAuthAuthorizationCode>> {
+        let code = sqlx::query_as::<_, OAuthAuthorizationCode>(
+            r#"
+            DELETE FROM oauth_authorization_codes
+            WHERE code_hash = ?
+            RETURNING code_hash, client_id, user_id, redirect_uri, scope, nonce, code_challenge, created_at, expires_at
+            "#,
+        )
+        .bind(code_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(code.filter(|code| code.expires_at > Utc::now()))
+    }
+
+    /// The space-separated scopes the user has agreed to give the client.
+    pub async fn find_consent(&self, user_id: &str, client_id: &str) -> Result<Option<String>> {
+        let scope = sqlx::query_scalar::<_, String>("SELECT scope FROM oauth_consents WHERE user_id = ? AND client_id = ?")
+            .bind(user_id)
+            .bind(client_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(scope)
+    }
+
+    /// Record the scopes the user has agreed to give the client, replacing any earlier consent.
+    pub async fn save_consent(&self, user_id: &str, client_id: &str, scope: &str) -> Result<()> {
+        let now = Utc::now();
+
+        sqlx::query(
+            r#"
+            INSERT INTO oauth_consents (user_id, client_id, scope, created_at, updated_at)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT (user_id, client_id) DO UPDATE SET scope = excluded.scope, updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(client_id)
+        .bind(scope)
+        .bind(now)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+const PASSKEY_COLUMNS: &str = "id, user_id, credential_id, public_key, sign_count, name, created_at, last_used_at";
+
+/// Passkeys and the WebAuthn challenges issued for registering and using them.
+pub struct PasskeyRepository {
+    pool: SqlitePool,
+}
+
+impl PasskeyRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    /// Record a challenge handed to the browser, dropping any that have expired.
+    pub async fn create_challenge(
+        &self,
+        challenge_hash: &str,
+        ceremony: PasskeyCeremony,
+        user_id: Option<&str>,
+        expires_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let now = Utc::now();
+
+        sqlx::query("DELETE FROM passkey_challenges WHERE expires_at <= ?")
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO passkey_challenges (challenge_hash, ceremony, user_id, created_at, expires_at)
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(challenge_hash)
+        .bind(ceremony)
+        .bind(user_id)
+        .bind(now)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Remove and return an unexpired challenge for `ceremony`, so each
+    /// challenge works once.
+    pub async fn take_challenge(&self, challenge_hash: &str, ceremony: PasskeyCeremony) -> Result<Option<PasskeyChallenge>> {
+        let challenge = sqlx::query_as::<_, PasskeyChallenge>(
+            r#"
+            DELETE FROM passkey_challenges
+            WHERE challenge_hash = ? AND ceremony = ?
+            RETURNING challenge_hash, ceremony, user_id, created_at, expires_at
+            "#,
+        )
+        .bind(challenge_hash)
+        .bind(ceremony)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(challenge.filter(|challenge| challenge.expires_at > Utc::now()))
+    }
+
+    pub async fn create(
+        &self,
+        user_id: &str,
+        credential_id: &str,
+        public_key: &[u8],
+        sign_count: u32,
+        name: &str,
+    ) -> Result<Passkey> {
+        let passkey = Passkey {
+            id: Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            credential_id: credential_id.to_string(),
+            public_key: public_key.to_vec(),
+            sign_count: sign_count.into(),
+            name: name.to_string(),
+            created_at: Utc::now(),
+            last_used_at: None,
+        };
+
+        sqlx::query(
+            r#"
+            INSERT INTO passkeys (id, user_id, credential_id, public_key, sign_count, name, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&passkey.id)
+        .bind(&passkey.user_id)
+        .bind(&passkey.credential_id)
+        .bind(&passkey.public_key)
+        .bind(passkey.sign_count)
+        .bind(&passkey.name)
+        .bind(passkey.created_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(passkey)
+    }
+
+    pub async fn find_by_credential_id(&self, credential_id: &str) -> Result<Option<Passkey>> {
+        let passkey = sqlx::query_as::<_, Passkey>(&format!("SELECT {} FROM passkeys WHERE credential_id = ?", PASSKEY_COLUMNS))
+            .bind(credential_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(passkey)
+    }
+
+    pub async fn list(&self, user_id: &str) -> Result<Vec<Passkey>> {
+        let passkeys = sqlx::query_as::<_, Passkey>(&format!(
+            "SELECT {} FROM passkeys WHERE user_id = ? ORDER BY created_at",
+            PASSKEY_COLUMNS
+        ))
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(passkeys)
+    }
+
+    /// Record a sign-in and the authenticator's new signature counter.
+    /// Returns `false` if another sign-in with the passkey got there first.
+    pub async fn record_use(&self, passkey: &Passkey, sign_count: u32) -> Result<bool> {
+        let result = sqlx::query("UPDATE passkeys SET sign_count = ?, last_used_at = ? WHERE id = ? AND sign_count = ?")
+            .bind(i64::from(sign_count))
+            .bind(Utc::now())
+            .bind(&passkey.id)
+            .bind(passkey.sign_count)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Returns `false` if the user has no such passkey.
+    pub async fn delete(&self, user_id: &str, id: &str) -> Result<bool> {
+        let result = sqlx::query("DELETE FROM passkeys WHERE id = ? AND user_id = ?")
+            .bind(id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+}
+
+/// TOTP authenticators and recovery codes.
+pub struct MfaRepository {
+    pool: SqlitePool,
+}
+
+impl MfaRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn find_totp(&self, user_id: &str) -> Result<Option<UserTotp>> {
+        let totp = sqlx::query_as::<_, UserTotp>(
+            "SELECT user_id, secret, confirmed_at, last_used_step, created_at FROM user_totp WHERE user_id = ?"
+        )
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(totp)
+    }
+
+    /// Store a new, unconfirmed secret, replacing an earlier unconfirmed one.
+    /// Returns `false` if the user already has a confirmed authenticator.
+    pub async fn start_totp_enrollment(&self, user_id: &str, secret: &str) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO user_totp (user_id, secret, created_at) VALUES (?, ?, ?)
+            ON CONFLICT (user_id) DO UPDATE
+            SET secret = excluded.secret, last_used_step = NULL, created_at = excluded.created_at
+            WHERE user_totp.confirmed_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(secret)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Enable the pending authenticator, recording the step of the code that confirmed it.
+    pub async fn confirm_totp(&self, user_id: &str, step: i64) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE user_totp SET confirmed_at = ?, last_used_step = ? WHERE user_id = ? AND confirmed_at IS NULL"
+        )
+        .bind(Utc::now())
+        .bind(step)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Record that the code for `step` was used. Returns `false` if that step or
+    /// a later one already was, so each code works once even under concurrent requests.
+    pub async fn use_totp_step(&self, user_id: &str, step: i64) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE user_totp SET last_used_step = ?
+            WHERE user_id = ? AND (last_used_step IS NULL OR last_used_step < ?)
+            "#,
+        )
+        .bind(step)
+        .bind(user_id)
+        .bind(step)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Remove the authenticator and recovery codes, turning two-factor authentication off.
+    pub async fn delete_totp(&self, user_id: &str) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        let result = sqlx::query("DELETE FROM user_totp WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Replace the user's recovery codes with `code_hashes`.
+    pub async fn replace_recovery_codes(&self, user_id: &str, code_hashes: &[String]) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM mfa_recovery_codes WHERE user_id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        let now = Utc::now();
+        for code_hash in code_hashes {
+            sqlx::query("INSERT INTO mfa_recovery_codes (id, user_id, code_hash, created_at) VALUES (?, ?, ?, ?)")
+                .bind(Uuid::new_v4().to_string())
+                .bind(user_id)
+                .bind(code_hash)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Use up one of the user's recovery codes. Returns `false` if it does not match an unused code.
+    pub async fn use_recovery_code(&self, user_id: &str, code_hash: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE mfa_recovery_codes SET used_at = ? WHERE user_id = ? AND code_hash = ? AND used_at IS NULL"
+        )
+        .bind(Utc::now())
+        .bind(user_id)
+        .bind(code_hash)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn count_unused_recovery_codes(&self, user_id: &str) -> Result<i64> {
+        let count: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM mfa_recovery_codes WHERE user_id = ? AND used_at IS NULL"
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(count)
+    }
+}
+
+/// Failed sign-in attempts per account and per IP address.
+pub struct LoginFailureRepository {
+    pool: SqlitePool,
+}
+
+impl LoginFailureRepository {
     pub fn new(pool: SqlitePool) -> Self {
         Self { pool }
     }
 
-    pub async fn create_user(&self, email: &str, password_hash: &str) -> Result<User> {
-        let id = Uuid::new_v4().to_string();
-        let membership_id = format!("LBK{:06}", rand::random::<u32>() % 1000000);
+    pub async fn find(&self, key: &str) -> Result<Option<LoginFailures>> {
+        let failures = sqlx::query_as::<_, LoginFailures>(
+            "SELECT key, failures, first_failed_at, last_failed_at, locked_until FROM login_failures WHERE key = ?"
+        )
+        .bind(key)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(failures)
+    }
+
+    /// Count a failure against `key`. Counting starts over if the first counted
+    /// failure is older than `window_start` and no lock is in force.
+    pub async fn record_failure(&self, key: &str, window_start: DateTime<Utc>) -> Result<LoginFailures> {
         let now = Utc::now();
 
-        let user = sqlx::query_as::<_, User>(
+        // `?2` is now, `?3` the start of the window
+        let failures = sqlx::query_as::<_, LoginFailures>(
             r#"
-            INSERT INTO users (id, email, password_hash, membership_id, membership_level, points, created_at, updated_at)
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
-            RETURNING id, email, password_hash, first_name, last_name, phone, membership_id, membership_level, points, created_at, updated_at
+            INSERT INTO login_failures (key, failures, first_failed_at, last_failed_at)
+            VALUES (?1, 1, ?2, ?2)
+            ON CONFLICT (key) DO UPDATE SET
+                failures = CASE
+                    WHEN first_failed_at <= ?3 AND (locked_until IS NULL OR locked_until <= ?2) THEN 1
+                    ELSE failures + 1
+                END,
+                first_failed_at = CASE
+                    WHEN first_failed_at <= ?3 AND (locked_until IS NULL OR locked_until <= ?2) THEN ?2
+                    ELSE first_failed_at
+                END,
+                locked_until = CASE
+                    WHEN first_failed_at <= ?3 AND (locked_until IS NULL OR locked_until <= ?2) THEN NULL
+                    ELSE locked_until
+                END,
+                last_failed_at = ?2
+            RETURNING key, failures, first_failed_at, last_failed_at, locked_until
             "#,
         )
-        .bind(&id)
-        .bind(email)
-        .bind(password_hash)
-        .bind(&membership_id)
-        .bind("Bronze")
-        .bind(0)
-        .bind(&now)
-        .bind(&now)
+        .bind(key)
+        .bind(now)
+        .bind(window_start)
         .fetch_one(&self.pool)
         .await?;
 
-        Ok(user)
+        Ok(failures)
     }
 
-    pub async fn find_by_email(&self, email: &str) -> Result<Option<User>> {
-        let user = sqlx::query_as::<_, User>(
-            "SELECT id, email, password_hash, first_name, last_name, phone, membership_id, membership_level, points, created_at, updated_at FROM users WHERE email = ?"
+    /// Lock `key` until `until`. Returns `false` if it was already locked.
+    pub async fn lock(&self, key: &str, until: DateTime<Utc>) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE login_failures SET locked_until = ? WHERE key = ? AND (locked_until IS NULL OR locked_until <= ?)"
         )
-        .bind(email)
-        .fetch_optional(&self.pool)
+        .bind(until)
+        .bind(key)
+        .bind(Utc::now())
+        .execute(&self.pool)
         .await?;
 
-        Ok(user)
+        Ok(result.rows_affected() > 0)
     }
 
-    pub async fn find_by_id(&self, id: &str) -> Result<Option<User>> {
-        let user = sqlx::query_as::<_, User>(
-            "SELECT id, email, password_hash, first_name, last_name, phone, membership_id, membership_level, points, created_at, updated_at FROM users WHERE id = ?"
+    /// Forget the failures counted against `key`, lifting any lock.
+    pub async fn clear(&self, key: &str) -> Result<()> {
+        sqlx::query("DELETE FROM login_failures WHERE key = ?")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Delete entries with no failure since `before` and no lock in force.
+    pub async fn prune(&self, before: DateTime<Utc>) -> Result<u64> {
+        let result = sqlx::query(
+            "DELETE FROM login_failures WHERE last_failed_at < ? AND (locked_until IS NULL OR locked_until <= ?)"
         )
-        .bind(id)
-        .fetch_optional(&self.pool)
+        .bind(before)
+        .bind(Utc::now())
+        .execute(&self.pool)
         .await?;
 
-        Ok(user)
+        Ok(result.rows_affected())
     }
+}
 
-    pub async fn get_profile(&self, user_id: &str) -> Result<Option<UserProfile>> {
-        let profile = sqlx::query_as::<_, UserProfile>(
-            "SELECT id, email, first_name, last_name, phone, membership_id, membership_level, points, created_at FROM users WHERE id = ?"
+/// Persisted denylist of access-token ids (`jti`) that were revoked before expiry.
+pub struct RevokedTokenRepository {
+    pool: SqlitePool,
+}
+
+impl RevokedTokenRepository {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn revoke(&self, jti: &str, user_id: &str, expires_at: DateTime<Utc>) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO revoked_tokens (jti, user_id, expires_at, revoked_at)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT (jti) DO NOTHING
+            "#,
         )
+        .bind(jti)
         .bind(user_id)
-        .fetch_optional(&self.pool)
+        .bind(expires_at)
+        .bind(Utc::now())
+        .execute(&self.pool)
         .await?;
 
-        Ok(profile)
+        Ok(())
     }
 
-    pub async fn update_profile(&self, user_id: &str, request: &UpdateProfileRequest) -> Result<Option<UserProfile>> {
-        let now = Utc::now();
+    pub async fn is_revoked(&self, jti: &str) -> Result<bool> {
+        let row: Option<(String,)> = sqlx::query_as("SELECT jti FROM revoked_tokens WHERE jti = ?")
+            .bind(jti)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        Ok(row.is_some())
+    }
 
+    /// Refuse every access token issued to the user up to now. Times are kept
+    /// to the microsecond, so tokens issued right after stay valid.
+    pub async fn revoke_all_for_user(&self, user_id: &str) -> Result<()> {
         sqlx::query(
             r#"
-            UPDATE users 
-            SET first_name = ?, last_name = ?, phone = ?, updated_at = ?
-            WHERE id = ?
+            INSERT INTO user_token_revocations (user_id, revoked_before_us)
+            VALUES (?, ?)
+            ON CONFLICT (user_id) DO UPDATE SET revoked_before_us = MAX(revoked_before_us, excluded.revoked_before_us)
             "#,
         )
-        .bind(&request.first_name)
-        .bind(&request.last_name)
-        .bind(&request.phone)
-        .bind(&now)
         .bind(user_id)
+        .bind(Utc::now().timestamp_micros())
         .execute(&self.pool)
         .await?;
 
-        self.get_profile(user_id).await
+        Ok(())
+    }
+
+    /// Whether a token issued to `user_id` at `issued_at` was revoked by [`Self::revoke_all_for_user`].
+    pub async fn is_revoked_for_user(&self, user_id: &str, issued_at: DateTime<Utc>) -> Result<bool> {
+        let row: Option<(String,)> = sqlx::query_as(
+            "SELECT user_id FROM user_token_revocations WHERE user_id = ? AND revoked_before_us > ?"
+        )
+        .bind(user_id)
+        .bind(issued_at.timestamp_micros())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.is_some())
+    }
+
+    /// Delete entries whose token has expired anyway. Returns the number removed.
+    pub async fn prune_expired(&self) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM revoked_tokens WHERE expires_at <= ?")
+            .bind(Utc::now())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
     }
 }
 
@@ -209,4 +1645,506 @@ mod tests {
         assert!(user.updated_at <= after_creation);
         assert_eq!(user.created_at, user.updated_at); // Should be same on creation
     }
+
+    #[tokio::test]
+    async fn test_refresh_token_mark_used_only_once() {
+        let pool = create_test_pool().await.unwrap();
+        let user_repo = UserRepository::new(pool.clone());
+        let token_repo = RefreshTokenRepository::new(pool);
+
+        let user = user_repo.create_user("test@example.com", "password").await.unwrap();
+        let expires_at = chrono::Utc::now() + chrono::Duration::days(30);
+        let token = token_repo.create(&user.id, "family-1", "hash-1", expires_at).await.unwrap();
+
+        assert!(token.used_at.is_none());
+        assert!(token_repo.mark_used(&token.id).await.unwrap());
+        assert!(!token_repo.mark_used(&token.id).await.unwrap());
+
+        let found = token_repo.find_by_hash("hash-1").await.unwrap().unwrap();
+        assert!(found.used_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_revoked_token_prune_expired() {
+        let pool = create_test_pool().await.unwrap();
+        let repo = RevokedTokenRepository::new(pool);
+
+        let now = chrono::Utc::now();
+        repo.revoke("expired-jti", "user-id", now - chrono::Duration::minutes(1)).await.unwrap();
+        repo.revoke("active-jti", "user-id", now + chrono::Duration::hours(1)).await.unwrap();
+
+        // Revoking twice is harmless
+        repo.revoke("active-jti", "user-id", now + chrono::Duration::hours(1)).await.unwrap();
+
+        assert!(repo.is_revoked("expired-jti").await.unwrap());
+        assert!(repo.is_revoked("active-jti").await.unwrap());
+        assert!(!repo.is_revoked("unknown-jti").await.unwrap());
+
+        let pruned = repo.prune_expired().await.unwrap();
+        assert_eq!(pruned, 1);
+        assert!(!repo.is_revoked("expired-jti").await.unwrap());
+        assert!(repo.is_revoked("active-jti").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_new_user_is_member_and_role_can_change() {
+        let pool = create_test_pool().await.unwrap();
+        let repo = UserRepository::new(pool);
+
+        let user = repo.create_user("test@example.com", "password").await.unwrap();
+        assert_eq!(user.role, Role::Member);
+
+        let profile = repo.set_role(&user.id, Role::Staff).await.unwrap().unwrap();
+        assert_eq!(profile.role, Role::Staff);
+
+        let found = repo.find_by_id(&user.id).await.unwrap().unwrap();
+        assert_eq!(found.role, Role::Staff);
+
+        assert!(repo.set_role("non-existent-id", Role::Admin).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_list_accounts_filters_and_paginates() {
+        let pool = create_test_pool().await.unwrap();
+        let repo = UserRepository::new(pool);
+
+        for i in 0..5 {
+            repo.create_user(&format!("user{}@example.com", i), "hash").await.unwrap();
+        }
+        repo.create_user("someone@other.org", "hash").await.unwrap();
+
+        let (page, total) = repo.list_accounts(&ListUsersQuery::default(), 4, 4).await.unwrap();
+        assert_eq!(total, 6);
+        assert_eq!(page.len(), 2);
+
+        let query = ListUsersQuery {
+            email: Some("@EXAMPLE.com".to_string()),
+            ..Default::default()
+        };
+        let (page, total) = repo.list_accounts(&query, 2, 0).await.unwrap();
+        assert_eq!(total, 5);
+        assert_eq!(page.len(), 2);
+
+        // LIKE wildcards in the search term are matched literally
+        let query = ListUsersQuery {
+            email: Some("%".to_string()),
+            ..Default::default()
+        };
+        let (_, total) = repo.list_accounts(&query, 10, 0).await.unwrap();
+        assert_eq!(total, 0);
+    }
+
+    #[tokio::test]
+    async fn test_account_status_changes() {
+        let pool = create_test_pool().await.unwrap();
+        let repo = UserRepository::new(pool);
+        let user = repo.create_user("test@example.com", "hash").await.unwrap();
+        assert!(user.disabled_at.is_none());
+        assert!(!user.password_reset_required);
+
+        let request = UpdateMembershipRequest {
+            points: Some(250),
+            ..Default::default()
+        };
+        let account = repo.update_membership(&user.id, &request).await.unwrap().unwrap();
+        assert_eq!(account.points, 250);
+        assert_eq!(account.membership_level, "Bronze");
+
+        let account = repo.set_disabled(&user.id, true).await.unwrap().unwrap();
+        assert!(account.disabled_at.is_some());
+        let account = repo.set_disabled(&user.id, false).await.unwrap().unwrap();
+        assert!(account.disabled_at.is_none());
+
+        let account = repo.require_password_reset(&user.id).await.unwrap().unwrap();
+        assert!(account.password_reset_required);
+
+        repo.update_password(&user.id, "new-hash").await.unwrap();
+        let found = repo.find_by_id(&user.id).await.unwrap().unwrap();
+        assert_eq!(found.password_hash, "new-hash");
+        assert!(!found.password_reset_required);
+
+        repo.update_email(&user.id, "new@example.com").await.unwrap();
+        let found = repo.find_by_email("new@example.com").await.unwrap().unwrap();
+        assert!(found.email_verified_at.is_some());
+        repo.create_user("other@example.com", "hash").await.unwrap();
+        assert!(repo.update_email(&user.id, "other@example.com").await.is_err());
+
+        assert!(repo.delete_user(&user.id).await.unwrap());
+        assert!(!repo.delete_user(&user.id).await.unwrap());
+        assert!(repo.get_account(&user.id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_one_time_token_is_single_use() {
+        let pool = create_test_pool().await.unwrap();
+        let user_repo = UserRepository::new(pool.clone());
+        let repo = OneTimeTokenRepository::new(pool);
+        let user = user_repo.create_user("test@example.com", "hash").await.unwrap();
+        let expires_at = Utc::now() + chrono::Duration::hours(1);
+
+        repo.create(&user.id, TokenPurpose::EmailVerification, &user.email, "hash-1", expires_at)
+            .await
+            .unwrap();
+
+        let token = repo.consume(TokenPurpose::EmailVerification, "hash-1").await.unwrap().unwrap();
+        assert_eq!(token.user_id, user.id);
+        assert_eq!(token.email, "test@example.com");
+        assert!(repo.consume(TokenPurpose::EmailVerification, "hash-1").await.unwrap().is_none());
+
+        // Expired and invalidated tokens cannot be used
+        repo.create(&user.id, TokenPurpose::EmailVerification, &user.email, "hash-2", Utc::now())
+            .await
+            .unwrap();
+        assert!(repo.consume(TokenPurpose::EmailVerification, "hash-2").await.unwrap().is_none());
+
+        repo.create(&user.id, TokenPurpose::EmailVerification, &user.email, "hash-3", expires_at)
+            .await
+            .unwrap();
+        repo.invalidate(&user.id, TokenPurpose::EmailVerification).await.unwrap();
+        assert!(repo.consume(TokenPurpose::EmailVerification, "hash-3").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failed_attempts_use_up_token() {
+        let pool = create_test_pool().await.unwrap();
+        let user_repo = UserRepository::new(pool.clone());
+        let repo = OneTimeTokenRepository::new(pool);
+        let user = user_repo.create_user("test@example.com", "hash").await.unwrap();
+        let expires_at = Utc::now() + chrono::Duration::minutes(5);
+
+        let token = repo
+            .create(&user.id, TokenPurpose::MfaChallenge, &user.email, "hash-1", expires_at)
+            .await
+            .unwrap();
+
+        repo.record_failed_attempt(&token.id, 2).await.unwrap();
+        let found = repo.find_active(TokenPurpose::MfaChallenge, "hash-1").await.unwrap().unwrap();
+        assert_eq!(found.attempts, 1);
+        assert!(repo.find_active(TokenPurpose::EmailVerification, "hash-1").await.unwrap().is_none());
+
+        repo.record_failed_attempt(&token.id, 2).await.unwrap();
+        assert!(repo.find_active(TokenPurpose::MfaChallenge, "hash-1").await.unwrap().is_none());
+        assert!(repo.consume(TokenPurpose::MfaChallenge, "hash-1").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_totp_enrollment_lifecycle() {
+        let pool = create_test_pool().await.unwrap();
+        let user_repo = UserRepository::new(pool.clone());
+        let repo = MfaRepository::new(pool);
+        let user = user_repo.create_user("test@example.com", "hash").await.unwrap();
+
+        assert!(repo.start_totp_enrollment(&user.id, "SECRET1").await.unwrap());
+        // Restarting an unconfirmed enrollment replaces the secret
+        assert!(repo.start_totp_enrollment(&user.id, "SECRET2").await.unwrap());
+        let totp = repo.find_totp(&user.id).await.unwrap().unwrap();
+        assert_eq!(totp.secret, "SECRET2");
+        assert!(totp.confirmed_at.is_none());
+
+        assert!(repo.confirm_totp(&user.id, 10).await.unwrap());
+        assert!(!repo.confirm_totp(&user.id, 11).await.unwrap());
+        // A confirmed authenticator is not replaced
+        assert!(!repo.start_totp_enrollment(&user.id, "SECRET3").await.unwrap());
+
+        // Each step can be used once, and only moving forward
+        assert!(!repo.use_totp_step(&user.id, 10).await.unwrap());
+        assert!(repo.use_totp_step(&user.id, 11).await.unwrap());
+        assert!(!repo.use_totp_step(&user.id, 11).await.unwrap());
+
+        assert!(repo.delete_totp(&user.id).await.unwrap());
+        assert!(repo.find_totp(&user.id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_recovery_codes_are_single_use() {
+        let pool = create_test_pool().await.unwrap();
+        let user_repo = UserRepository::new(pool.clone());
+        let repo = MfaRepository::new(pool);
+        let user = user_repo.create_user("test@example.com", "hash").await.unwrap();
+
+        repo.replace_recovery_codes(&user.id, &["a".to_string(), "b".to_string()]).await.unwrap();
+        assert_eq!(repo.count_unused_recovery_codes(&user.id).await.unwrap(), 2);
+
+        assert!(repo.use_recovery_code(&user.id, "a").await.unwrap());
+        assert!(!repo.use_recovery_code(&user.id, "a").await.unwrap());
+        assert!(!repo.use_recovery_code(&user.id, "c").await.unwrap());
+        assert_eq!(repo.count_unused_recovery_codes(&user.id).await.unwrap(), 1);
+
+        // Regenerating invalidates the old set
+        repo.replace_recovery_codes(&user.id, &["c".to_string()]).await.unwrap();
+        assert!(!repo.use_recovery_code(&user.id, "b").await.unwrap());
+        assert!(repo.use_recovery_code(&user.id, "c").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_mark_email_verified_checks_address() {
+        let pool = create_test_pool().await.unwrap();
+        let repo = UserRepository::new(pool);
+        let user = repo.create_user("test@example.com", "hash").await.unwrap();
+        assert!(user.email_verified_at.is_none());
+
+        assert!(!repo.mark_email_verified(&user.id, "old@example.com").await.unwrap());
+        assert!(repo.mark_email_verified(&user.id, "test@example.com").await.unwrap());
+
+        let profile = repo.get_profile(&user.id).await.unwrap().unwrap();
+        assert!(profile.email_verified_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_revoke_all_for_user() {
+        let pool = create_test_pool().await.unwrap();
+        let repo = RevokedTokenRepository::new(pool);
+        let an_hour_ago = Utc::now() - chrono::Duration::hours(1);
+
+        assert!(!repo.is_revoked_for_user("user-1", an_hour_ago).await.unwrap());
+
+        repo.revoke_all_for_user("user-1").await.unwrap();
+        assert!(repo.is_revoked_for_user("user-1", an_hour_ago).await.unwrap());
+        assert!(!repo.is_revoked_for_user("user-1", Utc::now()).await.unwrap());
+        assert!(!repo.is_revoked_for_user("user-2", an_hour_ago).await.unwrap());
+
+        // Revoking again is an upsert
+        repo.revoke_all_for_user("user-1").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_revoking_sessions_revokes_their_refresh_tokens() {
+        let pool = create_test_pool().await.unwrap();
+        let user_repo = UserRepository::new(pool.clone());
+        let token_repo = RefreshTokenRepository::new(pool.clone());
+        let sessions = SessionRepository::new(pool);
+
+        let user = user_repo.create_user("test@example.com", "password").await.unwrap();
+        let other = user_repo.create_user("other@example.com", "password").await.unwrap();
+        let expires_at = Utc::now() + chrono::Duration::days(30);
+        let phone = sessions.create(&user.id, Some("Phone"), "192.0.2.1", expires_at).await.unwrap();
+        let laptop = sessions.create(&user.id, None, "192.0.2.2", expires_at).await.unwrap();
+        let tablet = sessions.create(&user.id, None, "192.0.2.3", expires_at).await.unwrap();
+        token_repo.create(&user.id, &phone.id, "hash-1", expires_at).await.unwrap();
+        token_repo.create(&user.id, &laptop.id, "hash-2", expires_at).await.unwrap();
+        token_repo.create(&user.id, &tablet.id, "hash-3", expires_at).await.unwrap();
+        assert_eq!(sessions.list_active(&user.id).await.unwrap().len(), 3);
+
+        // Only the owner can revoke a session, and only once
+        assert!(!sessions.revoke(&other.id, &phone.id).await.unwrap());
+        assert!(sessions.revoke(&user.id, &phone.id).await.unwrap());
+        assert!(!sessions.revoke(&user.id, &phone.id).await.unwrap());
+        assert!(token_repo.find_by_hash("hash-1").await.unwrap().unwrap().revoked_at.is_some());
+        assert!(token_repo.find_by_hash("hash-2").await.unwrap().unwrap().revoked_at.is_none());
+
+        assert_eq!(sessions.revoke_all_for_user(&user.id, Some(&laptop.id)).await.unwrap(), 1);
+        assert!(token_repo.find_by_hash("hash-2").await.unwrap().unwrap().revoked_at.is_none());
+        assert!(token_repo.find_by_hash("hash-3").await.unwrap().unwrap().revoked_at.is_some());
+
+        let active = sessions.list_active(&user.id).await.unwrap();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].id, laptop.id);
+        assert_eq!(active[0].user_agent, None);
+    }
+
+    #[tokio::test]
+    async fn test_api_tokens() {
+        let pool = create_test_pool().await.unwrap();
+        let user_repo = UserRepository::new(pool.clone());
+        let tokens = ApiTokenRepository::new(pool);
+
+        let user = user_repo.create_user("test@example.com", "password").await.unwrap();
+        let other = user_repo.create_user("other@example.com", "password").await.unwrap();
+        let expires_at = Utc::now() + chrono::Duration::days(30);
+        let ci = tokens
+            .create(&user.id, "CI", "hash-1", &[Scope::ProfileRead, Scope::UsersRead], expires_at)
+            .await
+            .unwrap();
+        tokens.create(&user.id, "Expired", "hash-2", &[Scope::ProfileRead], Utc::now()).await.unwrap();
+
+        let found = tokens.find_active_by_hash("hash-1").await.unwrap().unwrap();
+        assert_eq!(found.scopes(), [Scope::ProfileRead, Scope::UsersRead]);
+        assert!(found.last_used_at.is_none());
+        assert!(tokens.find_active_by_hash("hash-2").await.unwrap().is_none());
+        assert_eq!(tokens.list_active(&user.id).await.unwrap().len(), 1);
+
+        tokens.mark_used(&ci.id, Utc::now()).await.unwrap();
+        assert!(tokens.find_active_by_hash("hash-1").await.unwrap().unwrap().last_used_at.is_some());
+
+        // Only the owner can revoke a token, and only once
+        assert!(!tokens.revoke(&other.id, &ci.id).await.unwrap());
+        assert!(tokens.revoke(&user.id, &ci.id).await.unwrap());
+        assert!(!tokens.revoke(&user.id, &ci.id).await.unwrap());
+        assert!(tokens.find_active_by_hash("hash-1").await.unwrap().is_none());
+
+        // Revoking all of a user's tokens leaves other users' alone
+        tokens.create(&user.id, "Deploy", "hash-3", &[Scope::ProfileRead], expires_at).await.unwrap();
+        tokens.create(&other.id, "Other", "hash-4", &[Scope::ProfileRead], expires_at).await.unwrap();
+        assert_eq!(tokens.revoke_all_for_user(&user.id).await.unwrap(), 2);
+        assert!(tokens.find_active_by_hash("hash-3").await.unwrap().is_none());
+        assert!(tokens.find_active_by_hash("hash-4").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_oidc_login_attempts_are_single_use() {
+        let pool = create_test_pool().await.unwrap();
+        let oidc = OidcRepository::new(pool);
+        let expires_at = Utc::now() + chrono::Duration::minutes(10);
+
+        oidc.create_attempt("acme", "state-1", "nonce", "verifier", expires_at).await.unwrap();
+        oidc.create_attempt("acme", "state-2", "nonce", "verifier", Utc::now()).await.unwrap();
+
+        // The state only works with the provider it was sent to
+        assert!(oidc.take_attempt("other", "state-1").await.unwrap().is_none());
+        let attempt = oidc.take_attempt("acme", "state-1").await.unwrap().unwrap();
+        assert_eq!(attempt.code_verifier, "verifier");
+        assert!(oidc.take_attempt("acme", "state-1").await.unwrap().is_none());
+        assert!(oidc.take_attempt("acme", "state-2").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_user_identities() {
+        let pool = create_test_pool().await.unwrap();
+        let user_repo = UserRepository::new(pool.clone());
+        let oidc = OidcRepository::new(pool);
+        let user = user_repo.create_user("test@example.com", "password").await.unwrap();
+
+        oidc.link_identity("acme", "sub-1", &user.id, Some("test@example.com")).await.unwrap();
+        assert!(oidc.link_identity("acme", "sub-1", &user.id, None).await.is_err());
+
+        oidc.record_login("acme", "sub-1", Some("new@example.com")).await.unwrap();
+        let identity = oidc.find_identity("acme", "sub-1").await.unwrap().unwrap();
+        assert_eq!(identity.user_id, user.id);
+        assert_eq!(identity.email.as_deref(), Some("new@example.com"));
+        assert!(oidc.find_identity("other", "sub-1").await.unwrap().is_none());
+
+        user_repo.delete_user(&user.id).await.unwrap();
+        assert!(oidc.find_identity("acme", "sub-1").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_oauth_codes_and_consents() {
+        let pool = create_test_pool().await.unwrap();
+        let user_repo = UserRepository::new(pool.clone());
+        let oauth = OAuthRepository::new(pool);
+        let user = user_repo.create_user("test@example.com", "password").await.unwrap();
+        let expires_at = Utc::now() + chrono::Duration::minutes(5);
+
+        let code = |code_hash: &str, expires_at| OAuthAuthorizationCode {
+            code_hash: code_hash.to_string(),
+            client_id: "tool".to_string(),
+            user_id: user.id.clone(),
+            redirect_uri: "https://tool.test/cb".to_string(),
+            scope: "openid".to_string(),
+            nonce: Some("n".to_string()),
+            code_challenge: "challenge".to_string(),
+            created_at: Utc::now(),
+            expires_at,
+        };
+        oauth.create_code(&code("code-1", expires_at)).await.unwrap();
+        oauth.create_code(&code("code-2", Utc::now())).await.unwrap();
+
+        let code = oauth.take_code("code-1").await.unwrap().unwrap();
+        assert_eq!(code.nonce.as_deref(), Some("n"));
+        assert!(oauth.take_code("code-1").await.unwrap().is_none());
+        assert!(oauth.take_code("code-2").await.unwrap().is_none());
+
+        assert!(oauth.find_consent(&user.id, "tool").await.unwrap().is_none());
+        oauth.save_consent(&user.id, "tool", "openid").await.unwrap();
+        oauth.save_consent(&user.id, "tool", "openid email").await.unwrap();
+        assert_eq!(oauth.find_consent(&user.id, "tool").await.unwrap().as_deref(), Some("openid email"));
+    }
+
+    #[tokio::test]
+    async fn test_passkeys() {
+        let pool = create_test_pool().await.unwrap();
+        let user_repo = UserRepository::new(pool.clone());
+        let passkeys = PasskeyRepository::new(pool);
+        let user = user_repo.create_user("test@example.com", "password").await.unwrap();
+        let other = user_repo.create_user("other@example.com", "password").await.unwrap();
+
+        // Challenges work once, for their own ceremony, until they expire
+        let expires_at = Utc::now() + chrono::Duration::minutes(5);
+        passkeys.create_challenge("challenge-1", PasskeyCeremony::Registration, Some(&user.id), expires_at).await.unwrap();
+        passkeys.create_challenge("challenge-2", PasskeyCeremony::Authentication, None, Utc::now()).await.unwrap();
+        assert!(passkeys.take_challenge("challenge-1", PasskeyCeremony::Authentication).await.unwrap().is_none());
+        let challenge = passkeys.take_challenge("challenge-1", PasskeyCeremony::Registration).await.unwrap().unwrap();
+        assert_eq!(challenge.user_id.as_deref(), Some(user.id.as_str()));
+        assert!(passkeys.take_challenge("challenge-1", PasskeyCeremony::Registration).await.unwrap().is_none());
+        assert!(passkeys.take_challenge("challenge-2", PasskeyCeremony::Authentication).await.unwrap().is_none());
+
+        let passkey = passkeys.create(&user.id, "cred-1", b"key", 0, "Laptop").await.unwrap();
+        assert!(passkeys.create(&other.id, "cred-1", b"key", 0, "Phone").await.is_err());
+        assert_eq!(passkeys.list(&user.id).await.unwrap().len(), 1);
+
+        // Only one of two sign-ins that read the same counter is recorded
+        assert!(passkeys.record_use(&passkey, 1).await.unwrap());
+        assert!(!passkeys.record_use(&passkey, 2).await.unwrap());
+        let used = passkeys.find_by_credential_id("cred-1").await.unwrap().unwrap();
+        assert_eq!(used.sign_count, 1);
+        assert!(used.last_used_at.is_some());
+
+        assert!(!passkeys.delete(&other.id, &passkey.id).await.unwrap());
+        user_repo.delete_user(&user.id).await.unwrap();
+        assert!(passkeys.find_by_credential_id("cred-1").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_phone_otps() {
+        let pool = create_test_pool().await.unwrap();
+        let user_repo = UserRepository::new(pool.clone());
+        let otps = PhoneOtpRepository::new(pool);
+        let user = user_repo.create_user("test@example.com", "password").await.unwrap();
+        let expires_at = Utc::now() + chrono::Duration::minutes(5);
+
+        assert!(otps.last_sent_at(&user.id, PhoneOtpPurpose::PhoneLogin).await.unwrap().is_none());
+        let first = otps.create(&user.id, PhoneOtpPurpose::PhoneLogin, "+66812345678", "hash-1", expires_at).await.unwrap();
+        let second = otps.create(&user.id, PhoneOtpPurpose::PhoneLogin, "+66812345678", "hash-2", expires_at).await.unwrap();
+        assert!(otps.last_sent_at(&user.id, PhoneOtpPurpose::PhoneLogin).await.unwrap().is_some());
+
+        // Only the newest code works, and only for its purpose
+        assert!(otps.find_active(&user.id, PhoneOtpPurpose::PhoneVerification).await.unwrap().is_none());
+        let active = otps.find_active(&user.id, PhoneOtpPurpose::PhoneLogin).await.unwrap().unwrap();
+        assert_eq!(active.id, second.id);
+        assert!(!otps.consume(&first.id).await.unwrap());
+
+        // Wrong guesses use the code up
+        otps.record_failed_attempt(&second.id, 2).await.unwrap();
+        assert_eq!(otps.find_active(&user.id, PhoneOtpPurpose::PhoneLogin).await.unwrap().unwrap().attempts, 1);
+        otps.record_failed_attempt(&second.id, 2).await.unwrap();
+        assert!(otps.find_active(&user.id, PhoneOtpPurpose::PhoneLogin).await.unwrap().is_none());
+
+        let third = otps.create(&user.id, PhoneOtpPurpose::PhoneLogin, "+66812345678", "hash-3", expires_at).await.unwrap();
+        assert!(otps.consume(&third.id).await.unwrap());
+        assert!(!otps.consume(&third.id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_verified_phone_numbers() {
+        let pool = create_test_pool().await.unwrap();
+        let repo = UserRepository::new(pool);
+        let user = repo.create_user("test@example.com", "password").await.unwrap();
+        let other = repo.create_user("other@example.com", "password").await.unwrap();
+        let update = |phone: &str| UpdateProfileRequest {
+            first_name: None,
+            last_name: None,
+            phone: Some(phone.to_string()),
+        };
+
+        repo.update_profile(&user.id, &update("081 234 5678")).await.unwrap();
+        assert!(repo.find_by_verified_phone("+66812345678").await.unwrap().is_none());
+        assert!(!repo.mark_phone_verified(&user.id, "089 999 9999", "+66899999999").await.unwrap());
+        assert!(repo.mark_phone_verified(&user.id, "081 234 5678", "+66812345678").await.unwrap());
+        let profile = repo.get_profile(&user.id).await.unwrap().unwrap();
+        assert_eq!(profile.phone.as_deref(), Some("+66812345678"));
+        assert!(profile.phone_verified_at.is_some());
+        assert_eq!(repo.find_by_verified_phone("+66812345678").await.unwrap().unwrap().id, user.id);
+
+        // Saving the profile keeps the verification unless the number changes
+        repo.update_profile(&user.id, &update("+66812345678")).await.unwrap();
+        assert!(repo.get_profile(&user.id).await.unwrap().unwrap().phone_verified_at.is_some());
+
+        // Another account can enter the number, but not verify it too
+        repo.update_profile(&other.id, &update("+66812345678")).await.unwrap();
+        assert!(repo.mark_phone_verified(&other.id, "+66812345678", "+66812345678").await.is_err());
+
+        repo.update_profile(&user.id, &update("089 999 9999")).await.unwrap();
+        assert!(repo.get_profile(&user.id).await.unwrap().unwrap().phone_verified_at.is_none());
+        assert!(repo.find_by_verified_phone("+66812345678").await.unwrap().is_none());
+    }
 }
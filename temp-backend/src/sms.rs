@@ -0,0 +1,268 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use serde_json::json;
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use uuid::Uuid;
+
+use crate::config::{SmsConfig, SmsTransport};
+
+/// A text message to a phone number in E.164 form (`+66812345678`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sms {
+    pub to: String,
+    pub body: String,
+}
+
+/// Delivers text messages. Implementations decide where they go: an SMS
+/// gateway, the server log, files on disk, or memory.
+#[async_trait]
+pub trait SmsSender: Send + Sync {
+    async fn send(&self, sms: &Sms) -> Result<()>;
+}
+
+/// Posts each message as JSON (`to`, `from`, `message`) to a gateway URL.
+/// Providers with another API can sit behind a small relay that speaks this one.
+pub struct HttpSmsSender {
+    client: reqwest::Client,
+    url: String,
+    token: Option<String>,
+    sender: Option<String>,
+}
+
+impl HttpSmsSender {
+    pub fn from_config(config: &SmsConfig) -> Result<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .context("Failed to build HTTP client")?,
+            url: config.http_url.clone().context("sms.http_url is not set")?,
+            token: config.http_token.clone(),
+            sender: config.sender.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl SmsSender for HttpSmsSender {
+    async fn send(&self, sms: &Sms) -> Result<()> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .json(&json!({ "to": sms.to, "from": self.sender, "message": sms.body }));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+
+        let response = request.send().await.context("SMS gateway request failed")?;
+        if !response.status().is_success() {
+            bail!("SMS gateway answered {}", response.status());
+        }
+        Ok(())
+    }
+}
+
+/// Writes each message to the server log, for local development.
+pub struct LogSmsSender;
+
+#[async_trait]
+impl SmsSender for LogSmsSender {
+    async fn send(&self, sms: &Sms) -> Result<()> {
+        tracing::info!(to = %sms.to, body = %sms.body, "SMS not sent (sms.transport = log)");
+        Ok(())
+    }
+}
+
+/// Writes each message to `<dir>/<timestamp>-<id>.txt`, for local development.
+pub struct FileSmsSender {
+    dir: PathBuf,
+}
+
+impl FileSmsSender {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+#[async_trait]
+impl SmsSender for FileSmsSender {
+    async fn send(&self, sms: &Sms) -> Result<()> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("Failed to create {}", self.dir.display()))?;
+
+        let path = self.dir.join(format!(
+            "{}-{}.txt",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S"),
+            Uuid::new_v4()
+        ));
+        tokio::fs::write(&path, format!("To: {}\n\n{}\n", sms.to, sms.body))
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+
+        tracing::info!(to = %sms.to, path = %path.display(), "SMS written to file");
+        Ok(())
+    }
+}
+
+/// Keeps sent messages in memory so tests can read them back. Clones share the outbox.
+#[derive(Clone, Default)]
+pub struct MemorySmsSender {
+    sent: Arc<Mutex<Vec<Sms>>>,
+}
+
+impl MemorySmsSender {
+    pub fn sent(&self) -> Vec<Sms> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    /// The most recent message sent to `to`.
+    pub fn last_to(&self, to: &str) -> Option<Sms> {
+        self.sent().into_iter().rev().find(|sms| sms.to == to)
+    }
+}
+
+#[async_trait]
+impl SmsSender for MemorySmsSender {
+    async fn send(&self, sms: &Sms) -> Result<()> {
+        self.sent.lock().unwrap().push(sms.clone());
+        Ok(())
+    }
+}
+
+pub fn build_sms_sender(config: &SmsConfig) -> Result<Arc<dyn SmsSender>> {
+    Ok(match config.transport {
+        SmsTransport::Http => Arc::new(HttpSmsSender::from_config(config)?),
+        SmsTransport::Log => Arc::new(LogSmsSender),
+        SmsTransport::File => Arc::new(FileSmsSender::new(&config.file_dir)),
+    })
+}
+
+/// Put a phone number in E.164 form. Spaces, dashes, dots and parentheses are
+/// ignored; a number with a single leading `0` is taken to be in national
+/// format for `default_country_code`. Returns `None` if it cannot be a number.
+pub fn normalize_phone(input: &str, default_country_code: &str) -> Option<String> {
+    let compact: String = input
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '.' | '(' | ')'))
+        .collect();
+
+    let digits = if let Some(international) = compact.strip_prefix('+') {
+        international.to_string()
+    } else if let Some(international) = compact.strip_prefix("00") {
+        international.to_string()
+    } else if let Some(national) = compact.strip_prefix('0') {
+        format!("{}{}", default_country_code, national)
+    } else {
+        return None;
+    };
+
+    // E.164 allows at most 15 digits; the shortest real numbers have about 8
+    let valid = (8..=15).contains(&digits.len())
+        && digits.bytes().all(|b| b.is_ascii_digit())
+        && !digits.starts_with('0');
+    valid.then(|| format!("+{}", digits))
+}
+
+/// Composes the text messages the application sends and hands them to an [`SmsSender`].
+pub struct SmsService {
+    sender: Arc<dyn SmsSender>,
+    default_country_code: String,
+}
+
+impl SmsService {
+    pub fn new(sender: Arc<dyn SmsSender>, default_country_code: impl Into<String>) -> Self {
+        Self {
+            sender,
+            default_country_code: default_country_code.into(),
+        }
+    }
+
+    /// See [`normalize_phone`].
+    pub fn normalize(&self, phone: &str) -> Option<String> {
+        normalize_phone(phone, &self.default_country_code)
+    }
+
+    pub async fn send_phone_verification_code(&self, to: &str, code: &str) -> Result<()> {
+        let sms = Sms {
+            to: to.to_string(),
+            body: format!(
+                "{} is your code to verify this phone number. It expires in {} minutes.",
+                code,
+                crate::tokens::PHONE_OTP_TTL_MINUTES
+            ),
+        };
+
+        self.sender.send(&sms).await
+    }
+
+    pub async fn send_login_code(&self, to: &str, code: &str) -> Result<()> {
+        let sms = Sms {
+            to: to.to_string(),
+            body: format!(
+                "{} is your sign-in code. It expires in {} minutes. Never share it with anyone.",
+                code,
+                crate::tokens::PHONE_OTP_TTL_MINUTES
+            ),
+        };
+
+        self.sender.send(&sms).await
+    }
+}
+
+/// Extract the code from the start of a text message.
+#[cfg(test)]
+pub fn code_from(sms: &Sms) -> String {
+    sms.body.split_whitespace().next().expect("message starts with a code").to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_phone() {
+        assert_eq!(normalize_phone("081-234-5678", "66").as_deref(), Some("+66812345678"));
+        assert_eq!(normalize_phone("+66 81 234 5678", "66").as_deref(), Some("+66812345678"));
+        assert_eq!(normalize_phone("0066812345678", "66").as_deref(), Some("+66812345678"));
+        assert_eq!(normalize_phone("(020) 7946 0958", "44").as_deref(), Some("+442079460958"));
+
+        assert!(normalize_phone("812345678", "66").is_none());
+        assert!(normalize_phone("+66 81 234 567x", "66").is_none());
+        assert!(normalize_phone("+1234", "66").is_none());
+        assert!(normalize_phone("", "66").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_memory_sender_records_messages() {
+        let sender = MemorySmsSender::default();
+        let service = SmsService::new(Arc::new(sender.clone()), "66");
+
+        service.send_login_code("+66812345678", "123456").await.unwrap();
+
+        let sms = sender.last_to("+66812345678").unwrap();
+        assert_eq!(code_from(&sms), "123456");
+        assert!(sender.last_to("+66800000000").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_file_sender_writes_message() {
+        let dir = std::env::temp_dir().join(format!("sms-test-{}", Uuid::new_v4()));
+        let sender = FileSmsSender::new(&dir);
+        let sms = Sms {
+            to: "+66812345678".to_string(),
+            body: "Hello".to_string(),
+        };
+
+        sender.send(&sms).await.unwrap();
+
+        let entry = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap();
+        let contents = std::fs::read_to_string(entry.path()).unwrap();
+        assert_eq!(contents, "To: +66812345678\n\nHello\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
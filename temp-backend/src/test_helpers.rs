@@ -1,7 +1,37 @@
-use crate::{database::create_tables, jwt::JwtService, repository::UserRepository, AppState};
+use crate::{
+    database::run_migrations,
+    config::{LoginProtectionConfig, OAuthConfig, OidcConfig, OidcProviderConfig, PasswordHashingConfig, WebAuthnConfig},
+    jwt::{JwtKey, JwtService},
+    login_guard::LoginGuard,
+    mailer::{MailService, MemoryMailer},
+    models::{AuthenticationCredential, AuthenticatorAssertionResponse, AuthenticatorAttestationResponse, RegistrationCredential},
+    oauth::OAuthServer,
+    oidc::OidcClient,
+    password::PasswordHasher,
+    password_policy::PasswordPolicy,
+    sms::{MemorySmsSender, SmsService},
+    repository::{ApiTokenRepository, LoginFailureRepository, MfaRepository, OAuthRepository, OidcRepository, OneTimeTokenRepository, PasskeyRepository, PhoneOtpRepository, RefreshTokenRepository, RevokedTokenRepository, SessionRepository, UserRepository},
+    webauthn::WebAuthn,
+    AppState,
+};
 use anyhow::Result;
+use axum::{
+    http::StatusCode,
+    routing::{get, post},
+    Form, Json, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::Value as CborValue;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use p256::ecdsa::signature::Signer;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
 use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::task::JoinHandle;
 
 pub async fn create_test_pool() -> Result<SqlitePool> {
     let pool = SqlitePoolOptions::new()
@@ -9,17 +39,345 @@ pub async fn create_test_pool() -> Result<SqlitePool> {
         .connect("sqlite::memory:")
         .await?;
     
-    create_tables(&pool).await?;
+    run_migrations(&pool).await?;
     Ok(pool)
 }
 
 pub async fn create_test_app_state() -> Result<AppState> {
+    let (state, _) = create_test_app_state_with_outbox().await?;
+    Ok(state)
+}
+
+/// Like [`create_test_app_state`], also returning the mailer so tests can read sent emails.
+pub async fn create_test_app_state_with_outbox() -> Result<(AppState, MemoryMailer)> {
     let pool = create_test_pool().await?;
-    let user_repo = Arc::new(UserRepository::new(pool));
-    let jwt_service = Arc::new(JwtService::new("test-secret-key"));
-    
-    Ok(AppState {
-        user_repo,
-        jwt_service,
+    let user_repo = Arc::new(UserRepository::new(pool.clone()));
+    let refresh_token_repo = Arc::new(RefreshTokenRepository::new(pool.clone()));
+    let session_repo = Arc::new(SessionRepository::new(pool.clone()));
+    let api_token_repo = Arc::new(ApiTokenRepository::new(pool.clone()));
+    let one_time_token_repo = Arc::new(OneTimeTokenRepository::new(pool.clone()));
+    let mfa_repo = Arc::new(MfaRepository::new(pool.clone()));
+    let oidc_repo = Arc::new(OidcRepository::new(pool.clone()));
+    let oauth_repo = Arc::new(OAuthRepository::new(pool.clone()));
+    let passkey_repo = Arc::new(PasskeyRepository::new(pool.clone()));
+    let phone_otp_repo = Arc::new(PhoneOtpRepository::new(pool.clone()));
+    let login_guard = Arc::new(LoginGuard::new(
+        LoginFailureRepository::new(pool.clone()),
+        LoginProtectionConfig::default(),
+    ));
+    let revoked_token_repo = Arc::new(RevokedTokenRepository::new(pool));
+    let jwt_service = Arc::new(
+        JwtService::new("test-secret-key")
+            .with_denylist(revoked_token_repo)
+            .with_sessions(session_repo.clone()),
+    );
+    let outbox = MemoryMailer::default();
+    let mail = Arc::new(MailService::new(Arc::new(outbox.clone()), "http://localhost:3000"));
+
+    Ok((
+        AppState {
+            user_repo,
+            refresh_token_repo,
+            session_repo,
+            api_token_repo,
+            one_time_token_repo,
+            mfa_repo,
+            oidc_repo,
+            oauth_repo,
+            passkey_repo,
+            phone_otp_repo,
+            oidc: Arc::new(OidcClient::new(&OidcConfig::default())?),
+            oauth: Arc::new(OAuthServer::new(&OAuthConfig::default())),
+            webauthn: Arc::new(WebAuthn::new(&WebAuthnConfig::default())),
+            jwt_service,
+            mail,
+            sms: Arc::new(SmsService::new(Arc::new(MemorySmsSender::default()), "66")),
+            login_guard,
+            password_hasher: Arc::new(test_password_hasher()),
+            password_policy: Arc::new(PasswordPolicy::load(Default::default())?),
+            trust_proxy_headers: false,
+        },
+        outbox,
+    ))
+}
+
+/// The default policy with the cheapest parameters, to keep tests fast.
+pub fn test_password_hasher() -> PasswordHasher {
+    PasswordHasher::new(PasswordHashingConfig {
+        argon2_memory_kib: 256,
+        argon2_iterations: 1,
+        bcrypt_cost: 4,
+        ..Default::default()
     })
 }
+
+/// A code handed out by [`MockIdp::authorize`], waiting to be exchanged.
+struct IssuedCode {
+    client_id: String,
+    redirect_uri: String,
+    code_challenge: String,
+    nonce: String,
+    subject: String,
+    email: String,
+}
+
+#[derive(Default)]
+struct MockIdpState {
+    codes: HashMap<String, IssuedCode>,
+    tamper: Option<Value>,
+    sign_with_hmac: bool,
+}
+
+/// A minimal OpenID Connect provider on a local port, for testing sign-in
+/// without a real one. It publishes discovery and an Ed25519 key, and its
+/// token endpoint checks the client secret and PKCE verifier like a real
+/// provider would. There is no login page: [`MockIdp::authorize`] stands in
+/// for the user approving the sign-in.
+pub struct MockIdp {
+    pub issuer: String,
+    state: Arc<Mutex<MockIdpState>>,
+    server: JoinHandle<()>,
+}
+
+pub const MOCK_IDP_CLIENT_ID: &str = "temp-backend";
+pub const MOCK_IDP_CLIENT_SECRET: &str = "mock-secret";
+const MOCK_IDP_KEY: &str = include_str!("../tests/fixtures/jwt_ed25519_private.pem");
+
+impl MockIdp {
+    pub async fn start() -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(MockIdpState::default()));
+
+        let discovery = json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+        });
+        let jwks = JwtService::with_keys(JwtKey::from_pem("mock-key", MOCK_IDP_KEY).unwrap(), vec![])
+            .unwrap()
+            .jwks();
+
+        let token_state = state.clone();
+        let token_issuer = issuer.clone();
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(move || async move { Json(discovery) }))
+            .route("/jwks", get(move || async move { Json(jwks) }))
+            .route(
+                "/token",
+                post(move |Form(form): Form<HashMap<String, String>>| async move {
+                    match Self::exchange(&token_state, &token_issuer, &form) {
+                        Some(id_token) => (StatusCode::OK, Json(json!({ "access_token": "mock", "token_type": "Bearer", "id_token": id_token }))),
+                        None => (StatusCode::BAD_REQUEST, Json(json!({ "error": "invalid_grant" }))),
+                    }
+                }),
+            );
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        Self { issuer, state, server }
+    }
+
+    /// Config with this provider, named `mock`.
+    pub fn config(&self) -> OidcConfig {
+        OidcConfig {
+            providers: vec![OidcProviderConfig {
+                name: "mock".to_string(),
+                issuer: self.issuer.clone(),
+                client_id: MOCK_IDP_CLIENT_ID.to_string(),
+                client_secret: Some(MOCK_IDP_CLIENT_SECRET.to_string()),
+                redirect_uri: "http://localhost:3000/oidc/callback".to_string(),
+                scopes: vec!["openid".to_string(), "email".to_string(), "profile".to_string()],
+                link_existing_accounts: false,
+            }],
+        }
+    }
+
+    /// Approve the sign-in at `authorization_url` as the user `subject`, and
+    /// return the code the provider would redirect back with.
+    pub fn authorize(&self, authorization_url: &reqwest::Url, subject: &str, email: &str) -> String {
+        let query: HashMap<String, String> = authorization_url.query_pairs().into_owned().collect();
+        assert_eq!(query["code_challenge_method"], "S256");
+
+        let code = crate::tokens::generate_opaque_token();
+        self.state.lock().unwrap().codes.insert(
+            code.clone(),
+            IssuedCode {
+                client_id: query["client_id"].clone(),
+                redirect_uri: query["redirect_uri"].clone(),
+                code_challenge: query["code_challenge"].clone(),
+                nonce: query["nonce"].clone(),
+                subject: subject.to_string(),
+                email: email.to_string(),
+            },
+        );
+        code
+    }
+
+    /// Override claims of the next ID token issued, e.g. to issue a bad one.
+    pub fn tamper_next_id_token(&self, claims: Value) {
+        self.state.lock().unwrap().tamper = Some(claims);
+    }
+
+    /// Sign the next ID token with HS256 instead of the published key.
+    pub fn sign_next_id_token_with_hmac(&self) {
+        self.state.lock().unwrap().sign_with_hmac = true;
+    }
+
+    fn exchange(state: &Mutex<MockIdpState>, issuer: &str, form: &HashMap<String, String>) -> Option<String> {
+        let mut state = state.lock().unwrap();
+        let code = state.codes.remove(form.get("code")?)?;
+        let verifier = form.get("code_verifier")?;
+        let valid = form.get("grant_type")? == "authorization_code"
+            && form.get("client_id")? == &code.client_id
+            && form.get("client_secret")? == MOCK_IDP_CLIENT_SECRET
+            && form.get("redirect_uri")? == &code.redirect_uri
+            && crate::tokens::pkce_challenge(verifier) == code.code_challenge;
+        if !valid {
+            return None;
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        let mut claims = json!({
+            "iss": issuer,
+            "aud": code.client_id,
+            "sub": code.subject,
+            "email": code.email,
+            "email_verified": true,
+            "nonce": code.nonce,
+            "iat": now,
+            "exp": now + 300,
+        });
+        if let Some(Value::Object(tamper)) = state.tamper.take() {
+            claims.as_object_mut()?.extend(tamper);
+        }
+
+        let (header, key) = if std::mem::take(&mut state.sign_with_hmac) {
+            (Header::new(Algorithm::HS256), EncodingKey::from_secret(MOCK_IDP_CLIENT_SECRET.as_bytes()))
+        } else {
+            (Header::new(Algorithm::EdDSA), EncodingKey::from_ed_pem(MOCK_IDP_KEY.as_bytes()).ok()?)
+        };
+        encode(&Header { kid: Some("mock-key".to_string()), ..header }, &claims, &key).ok()
+    }
+}
+
+impl Drop for MockIdp {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+/// A passkey authenticator in software, for testing WebAuthn ceremonies
+/// without a browser. It holds one P-256 credential, attests with format
+/// `none` like most passkey providers, and verifies the user unless
+/// `user_verified` is cleared.
+pub struct SoftAuthenticator {
+    rp_id: String,
+    origin: String,
+    key: p256::ecdsa::SigningKey,
+    credential_id: Vec<u8>,
+    user_handle: Option<String>,
+    sign_count: u32,
+    pub user_verified: bool,
+}
+
+impl SoftAuthenticator {
+    /// An authenticator used by a browser at `origin`, creating credentials for `rp_id`.
+    pub fn new(rp_id: &str, origin: &str) -> Self {
+        Self {
+            rp_id: rp_id.to_string(),
+            origin: origin.to_string(),
+            key: p256::ecdsa::SigningKey::random(&mut rand::rngs::OsRng),
+            credential_id: rand::random::<[u8; 16]>().to_vec(),
+            user_handle: None,
+            sign_count: 0,
+            user_verified: true,
+        }
+    }
+
+    fn client_data(&self, type_: &str, challenge: &str) -> String {
+        let client_data = json!({ "type": type_, "challenge": challenge, "origin": self.origin, "crossOrigin": false });
+        URL_SAFE_NO_PAD.encode(client_data.to_string())
+    }
+
+    fn authenticator_data(&self, attested_credential: &[u8]) -> Vec<u8> {
+        let mut flags = 0x01;
+        if self.user_verified {
+            flags |= 0x04;
+        }
+        if !attested_credential.is_empty() {
+            flags |= 0x40;
+        }
+
+        let mut data = Sha256::digest(self.rp_id.as_bytes()).to_vec();
+        data.push(flags);
+        data.extend_from_slice(&self.sign_count.to_be_bytes());
+        data.extend_from_slice(attested_credential);
+        data
+    }
+
+    /// Answer `navigator.credentials.create()` for the user `user_id`.
+    pub fn register(&mut self, challenge: &str, user_id: &str) -> RegistrationCredential {
+        self.user_handle = Some(URL_SAFE_NO_PAD.encode(user_id));
+
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let cose_key = CborValue::Map(vec![
+            (CborValue::from(1), CborValue::from(2)),
+            (CborValue::from(3), CborValue::from(crate::webauthn::COSE_ES256)),
+            (CborValue::from(-1), CborValue::from(1)),
+            (CborValue::from(-2), CborValue::Bytes(point.x().unwrap().to_vec())),
+            (CborValue::from(-3), CborValue::Bytes(point.y().unwrap().to_vec())),
+        ]);
+        let mut attested_credential = vec![0; 16]; // AAGUID
+        attested_credential.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+        attested_credential.extend_from_slice(&self.credential_id);
+        ciborium::into_writer(&cose_key, &mut attested_credential).unwrap();
+
+        let attestation = CborValue::Map(vec![
+            (CborValue::from("fmt"), CborValue::from("none")),
+            (CborValue::from("attStmt"), CborValue::Map(vec![])),
+            (CborValue::from("authData"), CborValue::Bytes(self.authenticator_data(&attested_credential))),
+        ]);
+        let mut attestation_object = vec![];
+        ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+
+        let id = URL_SAFE_NO_PAD.encode(&self.credential_id);
+        RegistrationCredential {
+            id: id.clone(),
+            raw_id: id,
+            type_: "public-key".to_string(),
+            response: AuthenticatorAttestationResponse {
+                client_data_json: self.client_data("webauthn.create", challenge),
+                attestation_object: URL_SAFE_NO_PAD.encode(attestation_object),
+            },
+        }
+    }
+
+    /// Answer `navigator.credentials.get()` with the registered credential.
+    pub fn sign_in(&mut self, challenge: &str) -> AuthenticationCredential {
+        self.sign_count += 1;
+        let client_data_json = self.client_data("webauthn.get", challenge);
+        let authenticator_data = self.authenticator_data(&[]);
+        let signed = [
+            authenticator_data.as_slice(),
+            Sha256::digest(URL_SAFE_NO_PAD.decode(&client_data_json).unwrap()).as_slice(),
+        ]
+        .concat();
+        let signature: p256::ecdsa::Signature = self.key.sign(&signed);
+
+        let id = URL_SAFE_NO_PAD.encode(&self.credential_id);
+        AuthenticationCredential {
+            id: id.clone(),
+            raw_id: id,
+            type_: "public-key".to_string(),
+            response: AuthenticatorAssertionResponse {
+                client_data_json,
+                authenticator_data: URL_SAFE_NO_PAD.encode(authenticator_data),
+                signature: URL_SAFE_NO_PAD.encode(signature.to_der()),
+                user_handle: self.user_handle.clone(),
+            },
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::{Rng, RngCore};
+use sha2::{Digest, Sha256};
+
+/// Lifetime of a refresh token issued by `/auth/login`, `/auth/register` or `/auth/refresh`.
+pub const REFRESH_TOKEN_TTL_DAYS: i64 = 30;
+
+/// Lifetime of the link sent to confirm an email address.
+pub const EMAIL_VERIFICATION_TTL_HOURS: i64 = 24;
+
+/// Lifetime of a password reset link. Kept short because the link grants account access.
+pub const PASSWORD_RESET_TTL_MINUTES: i64 = 30;
+
+/// Lifetime of the link sent when an account is locked after failed sign-ins.
+pub const ACCOUNT_UNLOCK_TTL_HOURS: i64 = 24;
+
+/// Lifetime of a sign-in link. Kept short because the link grants account access.
+pub const MAGIC_LINK_TTL_MINUTES: i64 = 15;
+
+/// Time allowed to sign in at an external OpenID Connect provider and come back.
+pub const OIDC_LOGIN_TTL_MINUTES: i64 = 10;
+
+/// Lifetime of an authorization code issued to an OAuth client.
+pub const OAUTH_CODE_TTL_MINUTES: i64 = 5;
+
+/// Lifetime of an ID token issued to an OAuth client.
+pub const ID_TOKEN_TTL_MINUTES: i64 = 60;
+
+/// Time allowed to create or use a passkey once the browser has the challenge.
+pub const PASSKEY_CHALLENGE_TTL_MINUTES: i64 = 5;
+
+/// Lifetime of a code sent by text message to verify a phone number or sign in.
+pub const PHONE_OTP_TTL_MINUTES: i64 = 5;
+
+/// Wrong guesses allowed against one text message code before it stops working.
+pub const PHONE_OTP_MAX_ATTEMPTS: i64 = 5;
+
+/// Least time between two codes sent to the same account, since messages cost money.
+pub const PHONE_OTP_RESEND_SECONDS: i64 = 60;
+
+/// Time allowed between the password step of a login and the second factor.
+pub const MFA_CHALLENGE_TTL_MINUTES: i64 = 5;
+
+/// Lifetime of an API token when the user does not choose one.
+pub const API_TOKEN_DEFAULT_TTL_DAYS: i64 = 30;
+
+/// Longest lifetime a user may give an API token.
+pub const API_TOKEN_MAX_TTL_DAYS: i64 = 365;
+
+/// Marks a bearer token as an API token rather than a JWT.
+pub const API_TOKEN_PREFIX: &str = "pat_";
+
+/// A session's `last_seen_at` or an API token's `last_used_at` is only moved
+/// forward when at least this old.
+pub const LAST_SEEN_RESOLUTION_MINUTES: i64 = 5;
+
+/// Generate a random, URL-safe opaque token (256 bits of entropy).
+pub fn generate_opaque_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Hash an opaque token for storage. Only the hash is ever persisted.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// A random six-digit code to send by text message.
+pub fn generate_otp_code() -> String {
+    format!("{:06}", rand::thread_rng().gen_range(0..1_000_000))
+}
+
+/// Hash a text message code for storage. The phone number it was sent to is
+/// mixed in, so equal codes for different numbers hash differently.
+pub fn hash_otp_code(phone: &str, code: &str) -> String {
+    hash_token(&format!("{}:{}", phone, code))
+}
+
+/// PKCE `S256` challenge for a code verifier (RFC 7636).
+pub fn pkce_challenge(code_verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_opaque_token_is_unique() {
+        let token1 = generate_opaque_token();
+        let token2 = generate_opaque_token();
+
+        assert_eq!(token1.len(), 43);
+        assert_ne!(token1, token2);
+    }
+
+    #[test]
+    fn test_hash_token_is_deterministic() {
+        let token = generate_opaque_token();
+
+        assert_eq!(hash_token(&token), hash_token(&token));
+        assert_ne!(hash_token(&token), token);
+        assert_ne!(hash_token(&token), hash_token("other-token"));
+    }
+
+    #[test]
+    fn test_otp_codes() {
+        let code = generate_otp_code();
+        assert_eq!(code.len(), 6);
+        assert!(code.bytes().all(|b| b.is_ascii_digit()));
+
+        assert_eq!(hash_otp_code("+66812345678", &code), hash_otp_code("+66812345678", &code));
+        assert_ne!(hash_otp_code("+66812345678", &code), hash_otp_code("+66800000000", &code));
+    }
+
+    #[test]
+    fn test_pkce_challenge() {
+        // RFC 7636, appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+}
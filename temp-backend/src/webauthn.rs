@@ -0,0 +1,419 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::Value;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::WebAuthnConfig,
+    models::{
+        AuthenticationCredential, AuthenticatorSelection, Passkey, PasskeyCreationOptions, PasskeyRequestOptions,
+        PasskeyUserEntity, PublicKeyCredentialDescriptor, PublicKeyCredentialParameters, RegistrationCredential,
+        RelyingParty, User,
+    },
+    tokens::PASSKEY_CHALLENGE_TTL_MINUTES,
+};
+
+/// COSE algorithms accepted for passkeys, in order of preference.
+pub const COSE_ES256: i64 = -7;
+pub const COSE_EDDSA: i64 = -8;
+pub const COSE_RS256: i64 = -257;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+/// Decode base64url with or without padding; browsers and libraries differ.
+pub fn decode_base64url(value: &str) -> Result<Vec<u8>> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .context("Invalid base64url")
+}
+
+/// What the browser says it signed (WebAuthn, section 5.8.1).
+#[derive(Debug, Deserialize)]
+struct CollectedClientData {
+    #[serde(rename = "type")]
+    type_: String,
+    challenge: String,
+    origin: String,
+    #[serde(default, rename = "crossOrigin")]
+    cross_origin: bool,
+}
+
+fn parse_client_data(client_data_json: &str) -> Result<(Vec<u8>, CollectedClientData)> {
+    let bytes = decode_base64url(client_data_json).context("Invalid clientDataJSON")?;
+    let client_data = serde_json::from_slice(&bytes).context("Invalid clientDataJSON")?;
+    Ok((bytes, client_data))
+}
+
+/// The challenge a browser's answer is for, to find the ceremony it belongs to.
+pub fn challenge_of(client_data_json: &str) -> Result<String> {
+    Ok(parse_client_data(client_data_json)?.1.challenge)
+}
+
+/// The fixed part of authenticator data (WebAuthn, section 6.1), and what follows it.
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    rest: &'a [u8],
+}
+
+impl<'a> AuthenticatorData<'a> {
+    fn parse(data: &'a [u8]) -> Result<Self> {
+        ensure!(data.len() >= 37, "Authenticator data is too short");
+        Ok(Self {
+            rp_id_hash: &data[..32],
+            flags: data[32],
+            sign_count: u32::from_be_bytes(data[33..37].try_into()?),
+            rest: &data[37..],
+        })
+    }
+}
+
+/// A passkey's public key, from its COSE_Key encoding (RFC 9053).
+pub enum CoseKey {
+    Es256(p256::ecdsa::VerifyingKey),
+    EdDsa(ed25519_dalek::VerifyingKey),
+    Rs256(rsa::RsaPublicKey),
+}
+
+impl CoseKey {
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self> {
+        let value: Value = ciborium::from_reader(bytes).context("Invalid COSE key")?;
+        let entries = value.as_map().ok_or_else(|| anyhow!("COSE key is not a map"))?;
+        let get = |label: i64| {
+            entries
+                .iter()
+                .find(|(key, _)| key.as_integer().is_some_and(|key| i128::from(key) == i128::from(label)))
+                .map(|(_, value)| value)
+        };
+        let integer = |label: i64| get(label).and_then(Value::as_integer).and_then(|value| i64::try_from(value).ok());
+        let bytes = |label: i64| {
+            get(label)
+                .and_then(Value::as_bytes)
+                .ok_or_else(|| anyhow!("COSE key is missing parameter {}", label))
+        };
+
+        // Labels: 1 kty, 3 alg, -1 crv (EC, OKP) or n (RSA), -2 x or e, -3 y
+        match (integer(1), integer(3)) {
+            (Some(2), Some(COSE_ES256)) => {
+                ensure!(integer(-1) == Some(1), "ES256 key is not on P-256");
+                let (x, y) = (bytes(-2)?, bytes(-3)?);
+                ensure!(x.len() == 32 && y.len() == 32, "Invalid P-256 coordinates");
+                let point = [&[0x04][..], x, y].concat();
+                Ok(Self::Es256(p256::ecdsa::VerifyingKey::from_sec1_bytes(&point).context("Invalid P-256 key")?))
+            }
+            (Some(1), Some(COSE_EDDSA)) => {
+                ensure!(integer(-1) == Some(6), "EdDSA key is not Ed25519");
+                let x: [u8; 32] = bytes(-2)?.as_slice().try_into().map_err(|_| anyhow!("Invalid Ed25519 key length"))?;
+                Ok(Self::EdDsa(ed25519_dalek::VerifyingKey::from_bytes(&x).context("Invalid Ed25519 key")?))
+            }
+            (Some(3), Some(COSE_RS256)) => {
+                let n = rsa::BigUint::from_bytes_be(bytes(-1)?);
+                let e = rsa::BigUint::from_bytes_be(bytes(-2)?);
+                Ok(Self::Rs256(rsa::RsaPublicKey::new(n, e).context("Invalid RSA key")?))
+            }
+            (kty, alg) => bail!("Unsupported COSE key type {:?} with algorithm {:?}", kty, alg),
+        }
+    }
+
+    /// Check a WebAuthn signature: DER-encoded for ES256, raw for the others.
+    fn verify(&self, message: &[u8], signature: &[u8]) -> Result<()> {
+        match self {
+            Self::Es256(key) => {
+                use p256::ecdsa::signature::Verifier;
+                let signature = p256::ecdsa::Signature::from_der(signature).context("Invalid ES256 signature")?;
+                key.verify(message, &signature).context("Bad signature")
+            }
+            Self::EdDsa(key) => {
+                let signature = ed25519_dalek::Signature::from_slice(signature).context("Invalid EdDSA signature")?;
+                key.verify_strict(message, &signature).context("Bad signature")
+            }
+            Self::Rs256(key) => {
+                use rsa::signature::Verifier;
+                let key = rsa::pkcs1v15::VerifyingKey::<Sha256>::new(key.clone());
+                let signature = rsa::pkcs1v15::Signature::try_from(signature).context("Invalid RS256 signature")?;
+                key.verify(message, &signature).context("Bad signature")
+            }
+        }
+    }
+}
+
+/// A credential that passed registration, ready to be stored.
+#[derive(Debug)]
+pub struct NewPasskey {
+    /// base64url
+    pub credential_id: String,
+    /// COSE key
+    pub public_key: Vec<u8>,
+    pub sign_count: u32,
+}
+
+/// Relying party for passkey registration and sign-in ceremonies. Every
+/// ceremony requires user verification (a PIN or biometric on the device), so
+/// a passkey counts as two factors. Attestation is not requested: which
+/// authenticator made the passkey is not checked.
+pub struct WebAuthn {
+    rp_id: String,
+    rp_name: String,
+    origins: Vec<String>,
+}
+
+impl WebAuthn {
+    pub fn new(config: &WebAuthnConfig) -> Self {
+        Self {
+            rp_id: config.rp_id.clone(),
+            rp_name: config.rp_name.clone(),
+            origins: config.origins.clone(),
+        }
+    }
+
+    fn timeout_ms() -> u64 {
+        PASSKEY_CHALLENGE_TTL_MINUTES as u64 * 60 * 1000
+    }
+
+    fn descriptor(credential_id: &str) -> PublicKeyCredentialDescriptor {
+        PublicKeyCredentialDescriptor {
+            type_: "public-key".to_string(),
+            id: credential_id.to_string(),
+        }
+    }
+
+    /// Options for creating a passkey for `user`, who already has `existing`.
+    pub fn creation_options(&self, challenge: &str, user: &User, existing: &[Passkey]) -> PasskeyCreationOptions {
+        let display_name = match (&user.first_name, &user.last_name) {
+            (Some(first), Some(last)) => format!("{} {}", first, last),
+            (Some(name), None) | (None, Some(name)) => name.clone(),
+            (None, None) => user.email.clone(),
+        };
+
+        PasskeyCreationOptions {
+            challenge: challenge.to_string(),
+            rp: RelyingParty {
+                id: self.rp_id.clone(),
+                name: self.rp_name.clone(),
+            },
+            user: PasskeyUserEntity {
+                id: URL_SAFE_NO_PAD.encode(user.id.as_bytes()),
+                name: user.email.clone(),
+                display_name,
+            },
+            pub_key_cred_params: [COSE_ES256, COSE_EDDSA, COSE_RS256]
+                .into_iter()
+                .map(|alg| PublicKeyCredentialParameters {
+                    type_: "public-key".to_string(),
+                    alg,
+                })
+                .collect(),
+            timeout: Self::timeout_ms(),
+            exclude_credentials: existing.iter().map(|passkey| Self::descriptor(&passkey.credential_id)).collect(),
+            authenticator_selection: AuthenticatorSelection {
+                resident_key: "required".to_string(),
+                require_resident_key: true,
+                user_verification: "required".to_string(),
+            },
+            attestation: "none".to_string(),
+        }
+    }
+
+    /// Options for signing in with any of the user's passkeys for this site.
+    pub fn request_options(&self, challenge: &str) -> PasskeyRequestOptions {
+        PasskeyRequestOptions {
+            challenge: challenge.to_string(),
+            rp_id: self.rp_id.clone(),
+            timeout: Self::timeout_ms(),
+            allow_credentials: vec![],
+            user_verification: "required".to_string(),
+        }
+    }
+
+    /// Check the client data of a ceremony. The challenge is checked by the
+    /// caller, which found the ceremony by it.
+    fn check_client_data(&self, client_data: &CollectedClientData, expected_type: &str) -> Result<()> {
+        ensure!(client_data.type_ == expected_type, "Unexpected client data type '{}'", client_data.type_);
+        ensure!(self.origins.contains(&client_data.origin), "Origin '{}' is not allowed", client_data.origin);
+        ensure!(!client_data.cross_origin, "Cross-origin ceremonies are not allowed");
+        Ok(())
+    }
+
+    fn check_authenticator_data(&self, data: &AuthenticatorData) -> Result<()> {
+        ensure!(data.rp_id_hash == Sha256::digest(self.rp_id.as_bytes()).as_slice(), "RP id hash mismatch");
+        ensure!(data.flags & FLAG_USER_PRESENT != 0, "User was not present");
+        ensure!(data.flags & FLAG_USER_VERIFIED != 0, "User was not verified");
+        Ok(())
+    }
+
+    /// Verify a new credential from `navigator.credentials.create()`
+    /// (WebAuthn, section 7.1).
+    pub fn verify_registration(&self, credential: &RegistrationCredential) -> Result<NewPasskey> {
+        ensure!(credential.type_ == "public-key", "Unexpected credential type");
+        let (_, client_data) = parse_client_data(&credential.response.client_data_json)?;
+        self.check_client_data(&client_data, "webauthn.create")?;
+
+        let attestation = decode_base64url(&credential.response.attestation_object).context("Invalid attestationObject")?;
+        let attestation: Value = ciborium::from_reader(attestation.as_slice()).context("Invalid attestationObject")?;
+        let auth_data = attestation
+            .as_map()
+            .and_then(|entries| entries.iter().find(|(key, _)| key.as_text() == Some("authData")))
+            .and_then(|(_, value)| value.as_bytes())
+            .ok_or_else(|| anyhow!("attestationObject has no authData"))?;
+
+        let data = AuthenticatorData::parse(auth_data)?;
+        self.check_authenticator_data(&data)?;
+        ensure!(data.flags & FLAG_ATTESTED_CREDENTIAL_DATA != 0, "No attested credential data");
+
+        // AAGUID (16 bytes), credential id length (2 bytes), credential id, COSE key
+        ensure!(data.rest.len() >= 18, "Attested credential data is too short");
+        let id_len = usize::from(u16::from_be_bytes([data.rest[16], data.rest[17]]));
+        let rest = &data.rest[18..];
+        ensure!(rest.len() > id_len, "Attested credential data is too short");
+        let (credential_id, rest) = rest.split_at(id_len);
+
+        // The key is followed by extension data, if any; read just the key
+        let mut reader = rest;
+        let _: Value = ciborium::from_reader(&mut reader).context("Invalid credential public key")?;
+        let public_key = &rest[..rest.len() - reader.len()];
+        CoseKey::from_cbor(public_key)?;
+
+        ensure!(
+            decode_base64url(&credential.raw_id)? == credential_id,
+            "rawId does not match the attested credential"
+        );
+
+        Ok(NewPasskey {
+            credential_id: URL_SAFE_NO_PAD.encode(credential_id),
+            public_key: public_key.to_vec(),
+            sign_count: data.sign_count,
+        })
+    }
+
+    /// Verify a signed challenge from `navigator.credentials.get()` made with
+    /// `passkey` (WebAuthn, section 7.2). Returns the authenticator's new
+    /// signature counter.
+    pub fn verify_authentication(&self, credential: &AuthenticationCredential, passkey: &Passkey) -> Result<u32> {
+        ensure!(credential.type_ == "public-key", "Unexpected credential type");
+        let (client_data_bytes, client_data) = parse_client_data(&credential.response.client_data_json)?;
+        self.check_client_data(&client_data, "webauthn.get")?;
+
+        if let Some(user_handle) = &credential.response.user_handle {
+            ensure!(
+                decode_base64url(user_handle)? == passkey.user_id.as_bytes(),
+                "userHandle does not match the passkey's owner"
+            );
+        }
+
+        let auth_data = decode_base64url(&credential.response.authenticator_data).context("Invalid authenticatorData")?;
+        let data = AuthenticatorData::parse(&auth_data)?;
+        self.check_authenticator_data(&data)?;
+
+        let signature = decode_base64url(&credential.response.signature).context("Invalid signature")?;
+        let signed = [auth_data.as_slice(), Sha256::digest(&client_data_bytes).as_slice()].concat();
+        CoseKey::from_cbor(&passkey.public_key)?.verify(&signed, &signature)?;
+
+        // Authenticators that keep a counter must move it forward on every
+        // use; one that goes back suggests the key was copied
+        let stored = passkey.sign_count;
+        if (data.sign_count != 0 || stored != 0) && i64::from(data.sign_count) <= stored {
+            bail!("Signature counter went from {} to {}; the passkey may be cloned", stored, data.sign_count);
+        }
+
+        Ok(data.sign_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers::SoftAuthenticator;
+    use chrono::Utc;
+
+    fn webauthn() -> WebAuthn {
+        WebAuthn::new(&WebAuthnConfig::default())
+    }
+
+    fn passkey(new: NewPasskey) -> Passkey {
+        Passkey {
+            id: "passkey-1".to_string(),
+            user_id: "user-1".to_string(),
+            credential_id: new.credential_id,
+            public_key: new.public_key,
+            sign_count: new.sign_count.into(),
+            name: "Laptop".to_string(),
+            created_at: Utc::now(),
+            last_used_at: None,
+        }
+    }
+
+    #[test]
+    fn test_registration_and_authentication() {
+        let webauthn = webauthn();
+        let mut authenticator = SoftAuthenticator::new("localhost", "http://localhost:3000");
+
+        let registration = authenticator.register("challenge-1", "user-1");
+        assert_eq!(challenge_of(&registration.response.client_data_json).unwrap(), "challenge-1");
+        let mut passkey = passkey(webauthn.verify_registration(&registration).unwrap());
+        assert_eq!(passkey.credential_id, registration.raw_id);
+
+        let assertion = authenticator.sign_in("challenge-2");
+        let sign_count = webauthn.verify_authentication(&assertion, &passkey).unwrap();
+        assert_eq!(i64::from(sign_count), passkey.sign_count + 1);
+
+        // The same counter again looks like a cloned authenticator
+        passkey.sign_count = sign_count.into();
+        assert!(webauthn.verify_authentication(&assertion, &passkey).is_err());
+    }
+
+    #[test]
+    fn test_rejects_tampered_ceremonies() {
+        let webauthn = webauthn();
+        let mut authenticator = SoftAuthenticator::new("localhost", "http://localhost:3000");
+        let passkey = passkey(webauthn.verify_registration(&authenticator.register("challenge-1", "user-1")).unwrap());
+
+        // A phishing site has another origin, or asks for its own RP id
+        let phished = SoftAuthenticator::new("localhost", "https://evil.example").register("challenge-1", "user-1");
+        assert!(webauthn.verify_registration(&phished).is_err());
+        let other_rp = SoftAuthenticator::new("evil.example", "http://localhost:3000").register("challenge-1", "user-1");
+        assert!(webauthn.verify_registration(&other_rp).is_err());
+
+        // A signature over other data, a registration replayed as a sign-in, another user's handle
+        let mut assertion = authenticator.sign_in("challenge-2");
+        assertion.response.signature = authenticator.sign_in("challenge-3").response.signature;
+        assert!(webauthn.verify_authentication(&assertion, &passkey).is_err());
+        let mut assertion = authenticator.sign_in("challenge-4");
+        assertion.response.client_data_json = authenticator.register("challenge-4", "user-1").response.client_data_json;
+        assert!(webauthn.verify_authentication(&assertion, &passkey).is_err());
+        let mut assertion = authenticator.sign_in("challenge-5");
+        assertion.response.user_handle = Some(URL_SAFE_NO_PAD.encode("user-2"));
+        assert!(webauthn.verify_authentication(&assertion, &passkey).is_err());
+
+        // Without user verification a passkey is only one factor
+        authenticator.user_verified = false;
+        let assertion = authenticator.sign_in("challenge-6");
+        assert!(webauthn.verify_authentication(&assertion, &passkey).is_err());
+    }
+
+    #[test]
+    fn test_cose_keys() {
+        // RFC 8152, appendix C.7.1: an ES256 key ("meriadoc.brandybuck@buckland.example")
+        let x = hex::decode("65eda5a12577c2bae829437fe338701a10aaa375e1bb5b5de108de439c08551d").unwrap();
+        let y = hex::decode("1e52ed75701163f7f9e40ddf9f341b3dc9ba860af7e0ca7ca7e9eecd0084d19c").unwrap();
+        let key = Value::Map(vec![
+            (Value::from(1), Value::from(2)),
+            (Value::from(3), Value::from(COSE_ES256)),
+            (Value::from(-1), Value::from(1)),
+            (Value::from(-2), Value::Bytes(x)),
+            (Value::from(-3), Value::Bytes(y)),
+        ]);
+        let mut bytes = vec![];
+        ciborium::into_writer(&key, &mut bytes).unwrap();
+        assert!(matches!(CoseKey::from_cbor(&bytes).unwrap(), CoseKey::Es256(_)));
+
+        // ES384 is not offered, so it is not accepted either
+        let key = Value::Map(vec![(Value::from(1), Value::from(2)), (Value::from(3), Value::from(-35))]);
+        let mut bytes = vec![];
+        ciborium::into_writer(&key, &mut bytes).unwrap();
+        assert!(CoseKey::from_cbor(&bytes).is_err());
+    }
+}